@@ -1,13 +1,28 @@
+use std::collections::HashMap;
+
 use bson::serde_helpers::serialize_u32_as_timestamp;
-use common::{user::User, channel::Channel, crypt};
+use common::{user::User, channel::{channel_name, ChannelInfo, ChannelUpdatePayload, ChannelAction}, crypt};
+use common::attachment::{AttachmentMeta, Download, Upload};
+use common::message::Message;
 use rand_core::OsRng;
 use x25519_dalek::{StaticSecret, PublicKey};
 
 pub struct Client {
     user: User,
-    channels: Vec<Channel>,
+    // the channels the server listed, kept up to date with its changes
+    channels: Vec<ChannelInfo>,
+    // where typed messages go, without the #
+    current: String,
     secret: Vec<u8>,
     shared_key: Vec<u8>,
+    read_markers: HashMap<String, u64>,
+    // attachments seen in messages, so they can be downloaded by id
+    attachments: HashMap<u64, AttachmentMeta>,
+    // uploads keyed by hash, the server assigns the id
+    pub uploads: HashMap<String, Upload>,
+    pub downloads: HashMap<u64, Download>,
+    // messages we sent that the server has not taken yet, by the id we gave them
+    pub pending: HashMap<u64, Message>,
 }
 
 impl Client {
@@ -15,8 +30,14 @@ impl Client {
         Self {
             user,
             channels: Vec::new(),
+            current: "general".to_string(),
             secret: crypt::serialize_private_key(secret),
             shared_key: Vec::new(),
+            read_markers: HashMap::new(),
+            attachments: HashMap::new(),
+            uploads: HashMap::new(),
+            downloads: HashMap::new(),
+            pending: HashMap::new(),
         }
     }
 
@@ -27,4 +48,91 @@ impl Client {
     pub fn get_shared_key(&self) -> Vec<u8> {
         self.shared_key.clone()
     }
-}
\ No newline at end of file
+
+    pub fn set_read_markers(&mut self, read_markers: HashMap<String, u64>) {
+        self.read_markers = read_markers;
+    }
+
+    pub fn get_read_marker(&self, channel: &str) -> u64 {
+        self.read_markers.get(channel_name(channel)).copied().unwrap_or(0)
+    }
+
+    /// Moves the read marker of a channel forward, returns false if it was already past the message
+    pub fn mark_read(&mut self, channel: &str, message_id: u64) -> bool {
+        let marker = self.read_markers.entry(channel_name(channel).to_string()).or_insert(0);
+        if message_id <= *marker {
+            return false;
+        }
+
+        *marker = message_id;
+        true
+    }
+
+    pub fn add_attachments(&mut self, attachments: &[AttachmentMeta]) {
+        for attachment in attachments {
+            self.attachments.insert(attachment.id, attachment.clone());
+        }
+    }
+
+    pub fn get_attachment(&self, id: u64) -> Option<AttachmentMeta> {
+        self.attachments.get(&id).cloned()
+    }
+
+    pub fn set_channels(&mut self, channels: Vec<ChannelInfo>) {
+        self.channels = channels;
+    }
+
+    pub fn channels(&self) -> &[ChannelInfo] {
+        &self.channels
+    }
+
+    pub fn get_channel(&self, name: &str) -> Option<&ChannelInfo> {
+        self.channels.iter().find(|channel| channel.name == channel_name(name))
+    }
+
+    /// The channel typed messages go to, with its #
+    pub fn current_channel(&self) -> String {
+        format!("#{}", self.current)
+    }
+
+    pub fn is_current(&self, channel: &str) -> bool {
+        channel_name(channel) == self.current
+    }
+
+    /// Makes a listed channel the current one
+    pub fn switch(&mut self, name: &str) -> Result<(), String> {
+        match self.get_channel(name) {
+            Some(channel) => {
+                self.current = channel.name.clone();
+                Ok(())
+            }
+            None => Err(format!("There is no channel #{}", channel_name(name))),
+        }
+    }
+
+    /// Makes a channel that was just joined the current one, the server lists it right after
+    pub fn enter(&mut self, name: &str) {
+        self.current = channel_name(name).to_string();
+    }
+
+    /// Keeps the channel list in step with a change, the current channel follows a rename
+    /// and falls back to #general when it is deleted
+    pub fn apply_update(&mut self, update: &ChannelUpdatePayload) {
+        update.apply(&mut self.channels);
+        if update.channel == self.current {
+            match &update.action {
+                ChannelAction::Rename(name) => self.current = name.clone(),
+                ChannelAction::Delete => self.current = "general".to_string(),
+                _ => {}
+            }
+        }
+    }
+
+    /// Forgets a channel that was left
+    pub fn remove_channel(&mut self, name: &str) {
+        self.channels.retain(|channel| channel.name != channel_name(name));
+        if self.is_current(name) {
+            self.current = "general".to_string();
+        }
+    }
+}
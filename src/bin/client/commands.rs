@@ -0,0 +1,156 @@
+/// Something typed into the client, either a slash command or text for the current channel
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    // text for the current channel
+    Say(String),
+    // an action in the current channel, shown as "* alice waves"
+    Me(String),
+    // a direct message to a user
+    Msg(String, String),
+    // a channel to switch to or an invite code to redeem
+    Join(String),
+    // leaves a private channel, the current one if none is named
+    Part(Option<String>),
+    Switch(String),
+    Nick(String),
+    Who(Option<String>),
+    // sets the topic of the current channel, or shows it without text
+    Topic(Option<String>),
+    Quit,
+    Help,
+    Upload(String),
+    Download(String),
+    Channel(String),
+    Invite(String),
+    Search(String),
+    Export(String),
+    Find(String),
+    Admin(String),
+}
+
+/// Every command with its arguments and what it does, /help prints these
+pub const COMMANDS: &[(&str, &str)] = &[
+    ("/join <channel|code>", "switch to a channel, or join a private one with an invite code"),
+    ("/part [channel]", "leave a private channel, the current one by default"),
+    ("/switch <channel>", "send what you type to another channel"),
+    ("/msg <user> <text>", "send a direct message"),
+    ("/me <text>", "say what you are doing"),
+    ("/nick <name>", "change your username"),
+    ("/who [channel]", "list who is online in a channel"),
+    ("/topic [text]", "show or set the topic of the current channel"),
+    ("/channel <action> <channel> [...]", "create, rename, archive or delete channels"),
+    ("/invite <channel> <user|code> [...]", "invite a user to a private channel or create a code"),
+    ("/upload <path>", "attach a file in the current channel"),
+    ("/download <id>", "save an attachment to downloads"),
+    ("/search <query>", "search the server's messages"),
+    ("/find <query>", "search the local history"),
+    ("/export <channel> <jsonl|html> [path]", "export a channel from the local history"),
+    ("/admin <command>", "run a server command, /admin help lists them"),
+    ("/help", "show this list"),
+    ("/quit", "disconnect and exit"),
+];
+
+/// The argument of a command that needs one
+fn required(args: &str, usage: &str) -> Result<String, String> {
+    match args.is_empty() {
+        true => Err(format!("Usage: {}", usage)),
+        false => Ok(args.to_string()),
+    }
+}
+
+fn optional(args: &str) -> Option<String> {
+    match args.is_empty() {
+        true => None,
+        false => Some(args.to_string()),
+    }
+}
+
+/// Parses a line of input. A line starting with `//` is sent as text with one slash less,
+/// that is how commands of the server's plugins are reached
+pub fn parse(line: &str) -> Result<Command, String> {
+    let line = line.trim();
+    if line.starts_with("//") {
+        return Ok(Command::Say(line[1..].to_string()));
+    }
+    let line = match line.strip_prefix('/') {
+        Some(line) => line,
+        None => return Ok(Command::Say(line.to_string())),
+    };
+
+    let (name, args) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    let args = args.trim();
+
+    let command = match name {
+        "me" => Command::Me(required(args, "/me <text>")?),
+        "msg" => match args.split_once(char::is_whitespace) {
+            Some((user, text)) if !text.trim().is_empty() => Command::Msg(user.trim_start_matches('@').to_string(), text.trim().to_string()),
+            _ => return Err("Usage: /msg <user> <text>".to_string()),
+        },
+        "join" => Command::Join(required(args, "/join <channel|code>")?),
+        "part" => Command::Part(optional(args)),
+        "switch" => Command::Switch(required(args, "/switch <channel>")?),
+        "nick" => match args.split_whitespace().count() {
+            1 => Command::Nick(args.to_string()),
+            _ => return Err("Usage: /nick <name>, names have no spaces".to_string()),
+        },
+        "who" => Command::Who(optional(args)),
+        "topic" => Command::Topic(optional(args)),
+        "quit" => Command::Quit,
+        "help" => Command::Help,
+        "upload" => Command::Upload(required(args, "/upload <path>")?),
+        "download" => Command::Download(required(args, "/download <id>")?),
+        "channel" => Command::Channel(args.to_string()),
+        "invite" => Command::Invite(args.to_string()),
+        "search" => Command::Search(args.to_string()),
+        "export" => Command::Export(args.to_string()),
+        "find" => Command::Find(required(args, "/find <query>")?),
+        "admin" => Command::Admin(required(args, "/admin <command>")?),
+        "" => return Err("Type a command after the /, /help lists them".to_string()),
+        _ => return Err(format!("Unknown command /{}, /help lists them and // sends a line starting with /", name)),
+    };
+    Ok(command)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_text_is_said() {
+        assert_eq!(parse("  hello there "), Ok(Command::Say("hello there".to_string())));
+        assert_eq!(parse("//roll 2d6"), Ok(Command::Say("/roll 2d6".to_string())));
+    }
+
+    #[test]
+    fn commands_take_their_arguments() {
+        assert_eq!(parse("/me waves"), Ok(Command::Me("waves".to_string())));
+        assert_eq!(parse("/msg @bob  see you  "), Ok(Command::Msg("bob".to_string(), "see you".to_string())));
+        assert_eq!(parse("/join #random"), Ok(Command::Join("#random".to_string())));
+        assert_eq!(parse("/nick carol"), Ok(Command::Nick("carol".to_string())));
+        assert_eq!(parse("/admin mute bob 10m"), Ok(Command::Admin("mute bob 10m".to_string())));
+        assert_eq!(parse("/quit"), Ok(Command::Quit));
+    }
+
+    #[test]
+    fn optional_arguments_may_be_left_out() {
+        assert_eq!(parse("/part"), Ok(Command::Part(None)));
+        assert_eq!(parse("/part ops"), Ok(Command::Part(Some("ops".to_string()))));
+        assert_eq!(parse("/who"), Ok(Command::Who(None)));
+        assert_eq!(parse("/topic Release day"), Ok(Command::Topic(Some("Release day".to_string()))));
+    }
+
+    #[test]
+    fn missing_arguments_show_the_usage() {
+        assert_eq!(parse("/me"), Err("Usage: /me <text>".to_string()));
+        assert_eq!(parse("/msg bob"), Err("Usage: /msg <user> <text>".to_string()));
+        assert_eq!(parse("/msg bob   "), Err("Usage: /msg <user> <text>".to_string()));
+        assert!(parse("/nick two words").is_err());
+        assert!(parse("/find").is_err());
+    }
+
+    #[test]
+    fn unknown_commands_are_refused() {
+        assert!(parse("/").unwrap_err().contains("Type a command"));
+        assert!(parse("/roll 2d6").unwrap_err().starts_with("Unknown command /roll"));
+    }
+}
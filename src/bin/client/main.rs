@@ -1,11 +1,19 @@
-use std::{error::{Error, self}, io::{self, Write, BufReader}, net::SocketAddr, fs::File, path, thread};
+use std::{collections::HashMap, error::{Error, self}, io::{self, BufReader}, net::SocketAddr, fs::File, path, thread};
 
 use client::Client;
-use common::{user::User, message::{self, MessagePayload, Payload}, id, crypt};
+use commands::{Command, COMMANDS};
+use ui::Ui;
+use common::attachment::{AttachmentChunkPayload, AttachmentStatusPayload, Download, Upload};
+use common::invite::{InvitePayload, JoinPayload};
+use common::export;
+use common::history::LocalHistory;
+use common::search::{SearchPayload, SearchResult};
+use common::profile::{Profile, ProfileStore};
+use common::{channel::{channel_name, ChannelAction, ChannelListPayload, ChannelUpdatePayload, WhoPayload}, user::{NickPayload, User}, message::{self, CommandPayload, HistoryPayload, MessagePayload, MessageStatusPayload, Payload, ReactionPayload, ReadMarkerPayload}, id, crypt};
 use futures::{channel::mpsc, future::InspectOk, StreamExt};
 use log::debug;
-use tokio_util::codec::{Framed, BytesCodec, FramedWrite, FramedRead};
-use bytes::{Bytes, BytesMut};
+use tokio_util::codec::{Framed, BytesCodec, FramedWrite, FramedRead, LengthDelimitedCodec};
+use bytes::Bytes;
 use common::message::{Message, MessageType};
 use tokio::net::{TcpListener, TcpStream};
 
@@ -16,19 +24,75 @@ use std::sync::Arc;
 use simplelog::*;
 
 mod client;
+mod commands;
+mod profiles;
+mod script;
+mod tui;
+mod ui;
 extern crate common;
 
-fn setup() -> User {
-    // if file exists, read from file
+// where the terminal UI writes its logs
+const LOG_PATH: &str = "client.log";
+
+/// The value given to an option like `--profile work`
+pub fn option_value(args: &[String], flag: &str) -> Option<String> {
+    args.iter().position(|arg| arg == flag).and_then(|i| args.get(i + 1)).cloned()
+}
+
+/// Finds the profile to log in with: the one asked for, the one the me.dat here belongs to
+/// or the default one. A me.dat from before there were profiles becomes a profile
+/// when `migrate` is set, otherwise finding one is an error
+pub fn load_profile(name: Option<&str>, migrate: bool) -> Result<Profile, String> {
+    let mut store = ProfileStore::load()?;
+    if let Some(name) = name {
+        return store.get(name).cloned().ok_or(format!("There is no profile {}", name));
+    }
+
     if path::Path::new("me.dat").exists() {
-        let file = File::open("me.dat").unwrap();
-        let reader = BufReader::new(file);
+        let file = File::open("me.dat").map_err(|e| e.to_string())?;
         // convert from bson to user
-        let user: User = rmp_serde::from_read(reader).unwrap();
-        return user;
+        let user: User = rmp_serde::from_read(BufReader::new(file)).map_err(|e| format!("me.dat is not a user: {}", e))?;
+        if let Some(profile) = store.profiles().iter().find(|profile| profile.user.id == user.id) {
+            return Ok(profile.clone());
+        }
+        if !migrate {
+            return Err("me.dat is not a profile yet, run the client once to convert it".to_string());
+        }
+
+        // keep the username as the name unless a profile already has it
+        let name = match store.get(&user.username) {
+            Some(_) => format!("{}-{}", user.username, user.id),
+            None => user.username.clone(),
+        };
+        let profile = Profile::from_user(&name, user);
+        store.add(profile.clone())?;
+        store.save();
+        return Ok(profile);
     }
 
-    // else, create new user
+    store.default_profile().cloned().ok_or("There are no profiles, run the client once to pick a username".to_string())
+}
+
+fn setup(name: Option<&str>) -> Profile {
+    let error = match load_profile(name, true) {
+        Ok(profile) => return profile,
+        Err(e) => e,
+    };
+    // profiles that can't be read are not replaced with a new one
+    let mut store = match ProfileStore::load() {
+        Ok(store) => store,
+        Err(e) => {
+            log::error!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    // a profile that was asked for by name has to exist
+    if name.is_some() && !store.profiles().is_empty() {
+        log::error!("{}", error);
+        std::process::exit(1);
+    }
+
+    // else, create new profile
     // ask for the username
     let mut username = String::new();
     println!("Enter your username: ");
@@ -37,39 +101,201 @@ fn setup() -> User {
         .expect("Failed to read line");
     let username = username.trim().to_string();
 
-    let user = User::new(username);
+    let profile = Profile::new(name.unwrap_or(&username), &username);
+    if let Err(e) = store.add(profile.clone()) {
+        log::error!("{}", e);
+        std::process::exit(1);
+    }
+    store.save();
 
-    // save the user to a file
-    let mut file = std::fs::File::create("me.dat").unwrap();
+    profile
+}
 
-    file.write_all(&user.to_bytes()).unwrap();
+/// Keeps a new username in the profile, the next start logs in with it
+fn save_username(profile: &str, username: &str) {
+    let renamed = ProfileStore::load().and_then(|mut store| store.rename(profile, username).map(|()| store));
+    match renamed {
+        Ok(store) => store.save(),
+        Err(e) => log::error!("{}", e),
+    }
+}
+
+/// Puts a server at the top of the profile's list
+fn remember_server(profile: &str, address: &str) {
+    let mut store = match ProfileStore::load() {
+        Ok(store) => store,
+        Err(e) => {
+            log::error!("{}", e);
+            return;
+        }
+    };
+    if let Some(profile) = store.get_mut(profile) {
+        profile.remember_server(address, address);
+        store.save();
+    }
+}
+
+/// Where a message is shown, None for server notices to everyone and direct messages
+fn shown_in(channel: &str) -> Option<&str> {
+    match channel {
+        "ALL" => None,
+        channel if channel.starts_with('@') => None,
+        channel => Some(channel),
+    }
+}
+
+fn print_message(ui: &mut Ui, message: &Message, payload: &MessagePayload) {
+    ui.print(shown_in(&payload.channel), format_message(message, payload));
+}
+
+fn format_message(message: &Message, payload: &MessagePayload) -> String {
+    // load the internal message from the payload
+    let text = String::from_utf8_lossy(&payload.message);
 
-    user
+    // mark replies and thread messages so they can be told apart from the timeline
+    let context = match (payload.thread, payload.reply_to) {
+        (Some(thread), _) => format!(" (thread {})", thread),
+        (None, Some(reply_to)) => format!(" (reply to {})", reply_to),
+        (None, None) => String::new(),
+    };
+
+    // print the message
+    /* format in HH:MM:SS */
+    let timestamp = id::to_formatted_timestamp(message.id, "%H:%M:%S");
+    let bot = if payload.bot { " [bot]" } else { "" };
+    let author = match payload.channel.strip_prefix('@') {
+        Some(to) => format!("{}{} -> {}", payload.username, bot, to),
+        None => format!("{}{}", payload.username, bot),
+    };
+    let mut line = match payload.action {
+        true => format!("[{}] * {}{} {}", timestamp, author, context, text),
+        false => format!("[{}] {}{}: {}", timestamp, author, context, text),
+    };
+
+    for attachment in payload.attachments.iter() {
+        line.push_str(&format!("\n    [attachment {}] {} ({} bytes), /download {}", attachment.id, attachment.name, attachment.size, attachment.id));
+    }
+    line
+}
+
+/// Opens the local history, the passphrase comes from YUTTARI_PASSPHRASE or is asked for.
+/// An empty passphrase keeps no history
+fn open_history(username: &str, addr: &str) -> Option<LocalHistory> {
+    let passphrase = match env::var("YUTTARI_PASSPHRASE") {
+        Ok(passphrase) => passphrase,
+        Err(_) => {
+            let mut passphrase = String::new();
+            println!("Enter the passphrase for your local history (empty to keep none): ");
+            io::stdin()
+                .read_line(&mut passphrase)
+                .expect("Failed to read line");
+            passphrase.trim_end_matches(['\r', '\n']).to_string()
+        }
+    };
+    if passphrase.is_empty() {
+        return None;
+    }
+
+    match LocalHistory::open(username, addr, &passphrase) {
+        Ok(history) => Some(history),
+        Err(e) => {
+            println!("{}, not keeping history this time", e);
+            None
+        }
+    }
+}
+
+/// Exports a channel from the local history, `args` is `<channel> <jsonl|html> [path]`
+fn export_history(history: &LocalHistory, args: &str) -> Result<String, String> {
+    let (channel, format, path) = match args.split_whitespace().collect::<Vec<&str>>().as_slice() {
+        [channel, format] => (channel_name(channel).to_string(), format.to_string(), None),
+        [channel, format, path] => (channel_name(channel).to_string(), format.to_string(), Some(path.to_string())),
+        _ => return Err("Usage: /export <channel> <jsonl|html> [path]".to_string()),
+    };
+
+    let messages = history.recent(&channel, usize::MAX);
+    let (extension, contents) = export::export(&channel, &format, &messages)?;
+    let path = path.unwrap_or_else(|| format!("{}.{}", channel, extension));
+    std::fs::write(&path, contents).map_err(|e| e.to_string())?;
+    Ok(format!("Exported {} messages from #{} to {}", messages.len(), channel, path))
+}
+
+fn print_search(ui: &mut Ui, search: &SearchPayload) {
+    ui.print(None, format!("{} results for \"{}\", page {} of {}", search.total, search.get_query(), search.page + 1, search.pages().max(1)));
+    print_results(ui, &search.results);
+}
+
+fn print_results(ui: &mut Ui, results: &[SearchResult]) {
+    for result in results.iter() {
+        let payload = MessagePayload::from_bytes(result.message.payload.clone());
+        let text = String::from_utf8_lossy(&payload.message);
+
+        // wrap each match in asterisks
+        let mut marked = String::new();
+        let mut last = 0;
+        for &(start, end) in result.highlights.iter() {
+            marked.push_str(&text[last..start]);
+            marked.push_str(&format!("*{}*", &text[start..end]));
+            last = end;
+        }
+        marked.push_str(&text[last..]);
+
+        let timestamp = id::to_formatted_timestamp(result.message.id, "%Y-%m-%d %H:%M");
+        ui.print(None, format!("[{}] {} {}: {}", timestamp, payload.channel, payload.username, marked));
+    }
+}
+
+fn read_marker(user: &User, channel: &str, message_id: u64) -> Message {
+    let markers = HashMap::from([(channel.to_string(), message_id)]);
+    let payload = ReadMarkerPayload::new(user.username.clone(), markers);
+    Message::new(MessageType::ReadMarker, payload.to_bytes())
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    SimpleLogger::init(LevelFilter::Debug, Config::default()).unwrap();
+    // `send` and `listen` are for scripts, they don't prompt and only print what was asked for
+    let args: Vec<String> = env::args().collect();
+    if let Some(mode @ ("send" | "listen")) = args.get(1).map(String::as_str) {
+        // stdout is for the output alone
+        WriteLogger::init(LevelFilter::Warn, Config::default(), io::stderr()).unwrap();
+        std::process::exit(script::run(mode, &args[2..]).await);
+    }
+    if args.get(1).map(String::as_str) == Some("profile") {
+        std::process::exit(profiles::run(&args[2..]));
+    }
 
-    // get the user either from a file or from the user
-    let mut user = setup();
+    // --tui draws a full-screen interface instead of printing lines
+    let tui = env::args().any(|arg| arg == "--tui");
 
-    let secret_key = crypt::create_private_key();
+    // the terminal UI owns the screen, so its logs go to a file
+    match tui {
+        true => WriteLogger::init(LevelFilter::Debug, Config::default(), File::create(LOG_PATH)?).unwrap(),
+        false => SimpleLogger::init(LevelFilter::Debug, Config::default()).unwrap(),
+    }
 
-    let public_key = crypt::create_public_key(secret_key.clone());
+    // get the profile either from the profiles or from the user
+    let profile = setup(option_value(&args, "--profile").as_deref());
+    let mut user = profile.user.clone();
 
-    user.set_public_key(crypt::serialize_public_key(public_key));
+    let secret_key = crypt::deserialize_private_key(profile.secret_key.clone());
 
     let mut client = Client::new(user.clone(), secret_key.clone());
 
-    let stdin = FramedRead::new(tokio::io::stdin(), BytesCodec::new());
-    let mut stdin = stdin.map(|i| i.map(BytesMut::freeze));
     let mut stdout = FramedWrite::new(tokio::io::stdout(), BytesCodec::new());
 
     // get address from args, or panic
-    let addr = env::args()
-        .nth(1)
-        .unwrap_or_else(|| "".to_string());
+    let mut addr = String::new();
+    let mut rest = args.iter().skip(1);
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "--profile" => {
+                rest.next();
+            }
+            flag if flag.starts_with("--") => {}
+            arg if addr.is_empty() => addr = arg.to_string(),
+            _ => {}
+        }
+    }
     
     if addr == "" {
         log::error!("No address provided");
@@ -77,12 +303,40 @@ async fn main() -> Result<(), Box<dyn Error>> {
         std::process::exit(1);
     }
 
-    let mut stream = TcpStream::connect(addr).await?;
+    let mut history = open_history(&user.username, &addr);
+    let mut ui = match tui {
+        true => Ui::terminal(&addr)?,
+        false => Ui::lines(),
+    };
+    if let Some(history) = &history {
+        // scrollback from earlier sessions, the server's replay skips what is shown here
+        let messages = history.messages();
+        if !messages.is_empty() {
+            ui.print(None, "--- local history ---".to_string());
+        }
+        for message in messages.iter().skip(messages.len().saturating_sub(20)) {
+            print_message(&mut ui, message, &MessagePayload::from_bytes(message.payload.clone()));
+        }
+    }
+
+    let mut stream = TcpStream::connect(&addr).await?;
+    remember_server(&profile.name, &addr);
     let (reader, writer) = stream.split();
-    let mut sink = FramedWrite::new(writer, BytesCodec::new());
-    let mut stream = FramedRead::new(reader, BytesCodec::new());
+    let mut sink = FramedWrite::new(writer, LengthDelimitedCodec::new());
+    let mut stream = FramedRead::new(reader, LengthDelimitedCodec::new());
+
+    // the channel whose members the terminal UI lists, they are asked for again when it changes
+    let mut members_of: Option<String> = None;
 
     loop {
+        ui.sync(&client, &user.username);
+        // the server only takes requests once we are logged in, which the shared key says
+        if ui.is_terminal() && !client.get_shared_key().is_empty() && members_of.as_deref() != Some(&client.current_channel()) {
+            let who = WhoPayload::new(client.current_channel());
+            sink.send(Bytes::from(Message::new(MessageType::Who, who.to_bytes()).to_bytes())).await?;
+            members_of = Some(client.current_channel());
+        }
+
         tokio::select! {
             msg = stream.next() => {
                 if let Some(Ok(msg)) = msg {
@@ -93,18 +347,201 @@ async fn main() -> Result<(), Box<dyn Error>> {
                     match message.message_type {
                         MessageType::Message => {
                             // load the payload
-                            let mut payload = MessagePayload::from_bytes(message.payload);
+                            let mut payload = MessagePayload::from_bytes(message.payload.clone());
 
                             // decrypt the message
                             payload.decrypt(client.get_shared_key());
 
-                            // load the internal message from the payload
-                            let text = String::from_utf8(payload.message).unwrap();
+                            let channel = payload.channel.clone();
+                            print_message(&mut ui, &message, &payload);
+                            // server notices are where joins and leaves show up
+                            if payload.username == "SERVER" {
+                                members_of = None;
+                            }
+                            client.add_attachments(&payload.attachments);
+                            if let Some(history) = history.as_mut() {
+                                history.add(Message { payload: payload.to_bytes(), ..message.clone() });
+                            }
 
-                            // print the message
-                            /* format in HH:MM:SS */
+                            // everything the cli prints counts as read
+                            if client.mark_read(&channel, message.id) {
+                                let marker = read_marker(&user, &channel, message.id);
+                                sink.send(Bytes::from(marker.to_bytes())).await?;
+                            }
+                        },
+                        MessageType::ChannelList => {
+                            let payload = ChannelListPayload::from_bytes(message.payload);
+                            client.set_channels(payload.channels.clone());
+                            // the terminal UI lists them on the side
+                            let listed = if ui.is_terminal() { &[][..] } else { &payload.channels[..] };
+                            for channel in listed {
+                                let archived = if channel.archived { " (archived)" } else { "" };
+                                match channel.topic.is_empty() {
+                                    true => ui.print(None, format!("#{}{}", channel.name, archived)),
+                                    false => ui.print(None, format!("#{}{}: {}", channel.name, archived, channel.topic)),
+                                }
+                            }
+                        },
+                        MessageType::Invite => {
+                            let mut payload = InvitePayload::from_bytes(message.payload);
+                            payload.decrypt(client.get_shared_key());
+                            let limits = match (payload.max_uses, payload.expires) {
+                                (Some(uses), Some(expires)) => format!(" ({} uses, until {})", uses, expires),
+                                (Some(uses), None) => format!(" ({} uses)", uses),
+                                (None, Some(expires)) => format!(" (until {})", expires),
+                                (None, None) => String::new(),
+                            };
+                            ui.print(None, format!("Invite code for #{}: {}{}, /join {}", payload.channel, String::from_utf8_lossy(&payload.code), limits, String::from_utf8_lossy(&payload.code)));
+                        },
+                        MessageType::Search => {
+                            let mut payload = SearchPayload::from_bytes(message.payload);
+                            payload.decrypt(client.get_shared_key());
+                            print_search(&mut ui, &payload);
+                        },
+                        MessageType::Join => {
+                            let payload = JoinPayload::from_bytes(message.payload);
+                            ui.print(None, format!("Joined #{}, messages now go there", payload.channel));
+                            // the channel list with it follows
+                            client.enter(&payload.channel);
+                        },
+                        MessageType::Part => {
+                            let payload = JoinPayload::from_bytes(message.payload);
+                            ui.print(None, format!("Left #{}", payload.channel));
+                            client.remove_channel(&payload.channel);
+                        },
+                        MessageType::ChannelUpdate => {
+                            let payload = ChannelUpdatePayload::from_bytes(message.payload);
+                            ui.print(None, payload.describe());
+                            client.apply_update(&payload);
+                        },
+                        MessageType::Who => {
+                            let payload = WhoPayload::from_bytes(message.payload);
+                            ui.members(&payload.channel, payload.users);
+                        },
+                        MessageType::Nick => {
+                            let payload = NickPayload::from_bytes(message.payload);
+                            user.change_username(payload.username.clone());
+                            save_username(&profile.name, &user.username);
+                            ui.print(None, format!("You are now known as {}", user.username));
+                        },
+                        MessageType::ReadMarker => {
+                            let payload = ReadMarkerPayload::from_bytes(message.payload);
+                            client.set_read_markers(payload.markers);
+                        },
+                        MessageType::History => {
+                            let mut payload = HistoryPayload::from_bytes(message.payload);
+                            payload.decrypt(client.get_shared_key());
+
+                            let marker = client.get_read_marker(&payload.channel);
+                            let unread = payload.messages.iter().filter(|m| m.id > marker).count();
+                            if !payload.messages.is_empty() {
+                                ui.print(Some(&payload.channel), format!("--- #{} ({} unread) ---", channel_name(&payload.channel), unread));
+                            }
+
+                            let mut divided = false;
+                            let known = |id: u64| history.as_ref().is_some_and(|history| history.contains(id));
+                            for message in payload.messages.iter().filter(|m| !known(m.id)) {
+                                if !divided && message.id > marker {
+                                    ui.print(Some(&payload.channel), "--- new messages ---".to_string());
+                                    divided = true;
+                                }
+                                let message_payload = MessagePayload::from_bytes(message.payload.clone());
+                                print_message(&mut ui, message, &message_payload);
+                                client.add_attachments(&message_payload.attachments);
+                                if let Some(reactions) = payload.reactions.get(&message.id) {
+                                    let counts: Vec<String> = reactions
+                                        .iter()
+                                        .map(|(emoji, users)| format!("{} {}", emoji, users.len()))
+                                        .collect();
+                                    ui.print(Some(&payload.channel), format!("    {}", counts.join("  ")));
+                                }
+                            }
+
+                            if let Some(history) = history.as_mut() {
+                                history.add_all(payload.messages.clone());
+                            }
+
+                            if let Some(last) = payload.messages.last() {
+                                if client.mark_read(&payload.channel, last.id) {
+                                    let marker = read_marker(&user, &payload.channel, last.id);
+                                    sink.send(Bytes::from(marker.to_bytes())).await?;
+                                }
+                            }
+                        },
+                        MessageType::MessageStatus => {
+                            let status = MessageStatusPayload::from_bytes(message.payload);
+                            let sent = client.pending.remove(&status.sent_id);
+                            ui.settle(status.sent_id, status.id.is_some());
+                            match (sent, status.id, status.error) {
+                                (Some(sent), Some(id), _) => {
+                                    if let Some(history) = history.as_mut() {
+                                        history.add(Message { id, ..sent });
+                                    }
+                                }
+                                (_, _, Some(error)) => ui.print(Some(&status.channel), format!("Not sent: {}", error)),
+                                _ => {}
+                            }
+                        },
+                        MessageType::Reaction => {
+                            let reaction = ReactionPayload::from_bytes(message.payload);
+                            let sign = if reaction.add { "+" } else { "-" };
                             let timestamp = id::to_formatted_timestamp(message.id, "%H:%M:%S");
-                            println!("[{}] {}: {}", timestamp, payload.username, text);
+                            ui.print(None, format!("[{}] {} {}{} on {}", timestamp, reaction.username, sign, reaction.emoji, reaction.message_id));
+                        },
+                        MessageType::AttachmentStatus => {
+                            let status = AttachmentStatusPayload::from_bytes(message.payload);
+                            let mut upload = match client.uploads.remove(&status.hash) {
+                                Some(upload) => upload,
+                                None => continue,
+                            };
+
+                            if let Some(error) = status.error {
+                                ui.print(None, format!("Upload of {} failed: {}", upload.meta.name, error));
+                                continue;
+                            }
+
+                            match upload.next_chunk(&status) {
+                                Some(mut chunk) => {
+                                    chunk.encrypt(client.get_shared_key());
+                                    let message = Message::new(MessageType::AttachmentChunk, chunk.to_bytes());
+                                    sink.send(Bytes::from(message.to_bytes())).await?;
+                                    client.uploads.insert(status.hash, upload);
+                                }
+                                None => {
+                                    // the file is on the server, post it
+                                    let mut message_payload = MessagePayload::new(user.clone().username, client.current_channel(), Vec::new());
+                                    message_payload.add_attachment(upload.meta.clone());
+                                    message_payload.encrypt(client.get_shared_key());
+                                    let message = Message::new(MessageType::Message, message_payload.to_bytes());
+                                    sink.send(Bytes::from(message.to_bytes())).await?;
+                                    ui.print(None, format!("Uploaded {} as attachment {}", upload.meta.name, upload.meta.id));
+                                    client.add_attachments(&[upload.meta]);
+                                }
+                            }
+                        },
+                        MessageType::AttachmentChunk => {
+                            let mut chunk = AttachmentChunkPayload::from_bytes(message.payload);
+                            chunk.decrypt(client.get_shared_key());
+                            let download = match client.downloads.get_mut(&chunk.id) {
+                                Some(download) => download,
+                                None => continue,
+                            };
+
+                            match download.write_chunk(&chunk) {
+                                // the server sends a chunk per request
+                                Ok(false) => {
+                                    let message = Message::new(MessageType::AttachmentRequest, download.request().to_bytes());
+                                    sink.send(Bytes::from(message.to_bytes())).await?;
+                                }
+                                Ok(true) => {
+                                    ui.print(None, format!("Saved {}", download.path.display()));
+                                    client.downloads.remove(&chunk.id);
+                                }
+                                Err(e) => {
+                                    ui.print(None, format!("Download of {} failed: {}", download.meta.name, e));
+                                    client.downloads.remove(&chunk.id);
+                                }
+                            }
                         },
                         MessageType::ConnectionReceive => {
                             let login_message = Message::new(MessageType::Login, user.clone().to_bytes());
@@ -126,25 +563,192 @@ async fn main() -> Result<(), Box<dyn Error>> {
                     break
                 }
             },
-            input = stdin.next() => {
-                if let Some(Ok(input)) = input {
-                    let input = String::from_utf8(input.to_vec()).unwrap();
-                    // remove the newline
-                    let input = input.trim().to_string();
-
-                    // split string into byte chunks
-                    let chunks = input.as_bytes().chunks(256);
-
-                    for chunk in chunks {
-                        let chunk = crypt::encrypt_data(chunk.to_vec(), client.get_shared_key());
-                        // TODO: Channels
-                        let message_payload = MessagePayload::new(user.clone().username, "#general".to_string(), chunk);
-                        let message = Message::new(MessageType::Message, message_payload.to_bytes());
+            input = ui.next_line() => {
+                let input = match input {
+                    Some(input) => input,
+                    None => {
+                        drop(ui);
+                        std::process::exit(0);
+                    }
+                };
+
+                let command = match commands::parse(&input) {
+                    Ok(command) => command,
+                    Err(e) => {
+                        ui.print(None, e);
+                        continue;
+                    }
+                };
+
+                // text goes to a channel or user, every other command is handled here
+                let (channel, text, action) = match command {
+                    Command::Say(text) if text.is_empty() => continue,
+                    Command::Say(text) => (client.current_channel(), text, false),
+                    Command::Me(text) => (client.current_channel(), text, true),
+                    Command::Msg(username, text) => (format!("@{}", username), text, false),
+                    Command::Join(target) => {
+                        // channels everyone can read are switched to, private ones take an invite code
+                        if target.starts_with('#') || client.get_channel(&target).is_some() {
+                            match client.switch(&target) {
+                                Ok(()) => ui.print(None, format!("Now talking in {}", client.current_channel())),
+                                Err(e) => ui.print(None, format!("{}, private channels are joined with an invite code", e)),
+                            }
+                            continue;
+                        }
+
+                        let mut join = JoinPayload::new(String::new(), target.as_bytes().to_vec());
+                        join.encrypt(client.get_shared_key());
+                        let message = Message::new(MessageType::Join, join.to_bytes());
                         sink.send(Bytes::from(message.to_bytes())).await?;
+                        continue;
                     }
-                } else {
-                    break;
-                }
+                    Command::Part(channel) => {
+                        let channel = channel.unwrap_or_else(|| client.current_channel());
+                        let part = JoinPayload::new(channel, Vec::new());
+                        let message = Message::new(MessageType::Part, part.to_bytes());
+                        sink.send(Bytes::from(message.to_bytes())).await?;
+                        continue;
+                    }
+                    Command::Switch(channel) => {
+                        match client.switch(&channel) {
+                            Ok(()) => ui.print(None, format!("Now talking in {}", client.current_channel())),
+                            Err(e) => ui.print(None, e),
+                        }
+                        continue;
+                    }
+                    Command::Nick(username) => {
+                        let message = Message::new(MessageType::Nick, NickPayload::new(username).to_bytes());
+                        sink.send(Bytes::from(message.to_bytes())).await?;
+                        continue;
+                    }
+                    Command::Who(channel) => {
+                        let channel = channel.unwrap_or_else(|| client.current_channel());
+                        let who = WhoPayload::new(format!("#{}", channel_name(&channel)));
+                        let message = Message::new(MessageType::Who, who.to_bytes());
+                        sink.send(Bytes::from(message.to_bytes())).await?;
+                        continue;
+                    }
+                    Command::Topic(None) => {
+                        match client.get_channel(&client.current_channel()) {
+                            Some(channel) if !channel.topic.is_empty() => ui.print(None, format!("The topic of #{} is: {}", channel.name, channel.topic)),
+                            _ => ui.print(None, format!("{} has no topic", client.current_channel())),
+                        }
+                        continue;
+                    }
+                    Command::Topic(Some(topic)) => {
+                        let channel = channel_name(&client.current_channel()).to_string();
+                        let update = ChannelUpdatePayload::new(user.username.clone(), channel, ChannelAction::Topic(topic));
+                        let message = Message::new(MessageType::ChannelUpdate, update.to_bytes());
+                        sink.send(Bytes::from(message.to_bytes())).await?;
+                        continue;
+                    }
+                    Command::Quit => {
+                        drop(ui);
+                        std::process::exit(0);
+                    }
+                    Command::Help => {
+                        for (usage, description) in COMMANDS {
+                            ui.print(None, format!("{:<40} {}", usage, description));
+                        }
+                        continue;
+                    }
+                    Command::Upload(path) => {
+                        match Upload::from_file(&path) {
+                            Ok(upload) => {
+                                // the server answers with where to start, which resumes earlier attempts
+                                let message = Message::new(MessageType::AttachmentOffer, upload.meta.to_bytes());
+                                client.uploads.insert(upload.meta.hash.clone(), upload);
+                                sink.send(Bytes::from(message.to_bytes())).await?;
+                            }
+                            Err(e) => ui.print(None, format!("Could not read {}: {}", path, e)),
+                        }
+                        continue;
+                    }
+                    Command::Download(id) => {
+                        match id.parse().ok().and_then(|id| client.get_attachment(id)) {
+                            Some(meta) => {
+                                let download = Download::new(meta, "downloads");
+                                let message = Message::new(MessageType::AttachmentRequest, download.request().to_bytes());
+                                client.downloads.insert(download.meta.id, download);
+                                sink.send(Bytes::from(message.to_bytes())).await?;
+                            }
+                            None => ui.print(None, format!("Unknown attachment {}", id)),
+                        }
+                        continue;
+                    }
+                    Command::Channel(command) => {
+                        match ChannelUpdatePayload::from_command(&user.username, &command) {
+                            Ok(update) => {
+                                let message = Message::new(MessageType::ChannelUpdate, update.to_bytes());
+                                sink.send(Bytes::from(message.to_bytes())).await?;
+                            }
+                            Err(e) => ui.print(None, e),
+                        }
+                        continue;
+                    }
+                    Command::Invite(command) => {
+                        match InvitePayload::from_command(&command) {
+                            Ok(mut invite) => {
+                                invite.encrypt(client.get_shared_key());
+                                let message = Message::new(MessageType::Invite, invite.to_bytes());
+                                sink.send(Bytes::from(message.to_bytes())).await?;
+                            }
+                            Err(e) => ui.print(None, e),
+                        }
+                        continue;
+                    }
+                    Command::Search(command) => {
+                        match SearchPayload::from_command(&command) {
+                            Ok(mut search) => {
+                                search.encrypt(client.get_shared_key());
+                                let message = Message::new(MessageType::Search, search.to_bytes());
+                                sink.send(Bytes::from(message.to_bytes())).await?;
+                            }
+                            Err(e) => ui.print(None, e),
+                        }
+                        continue;
+                    }
+                    Command::Export(args) => {
+                        match &history {
+                            Some(history) => match export_history(history, &args) {
+                                Ok(output) => ui.print(None, output),
+                                Err(e) => ui.print(None, e),
+                            },
+                            None => ui.print(None, "No local history is kept, start with a passphrase to keep one".to_string()),
+                        }
+                        continue;
+                    }
+                    Command::Find(query) => {
+                        match &history {
+                            Some(history) => {
+                                let results = history.search(&query);
+                                ui.print(None, format!("{} results for \"{}\" in the local history", results.len(), query));
+                                print_results(&mut ui, &results);
+                            }
+                            None => ui.print(None, "No local history is kept, start with a passphrase to keep one".to_string()),
+                        }
+                        continue;
+                    }
+                    Command::Admin(command) => {
+                        // the server answers with a notice in the same channel
+                        let mut payload = CommandPayload::new(client.current_channel(), command.as_bytes().to_vec());
+                        payload.encrypt(client.get_shared_key());
+                        let message = Message::new(MessageType::Command, payload.to_bytes());
+                        sink.send(Bytes::from(message.to_bytes())).await?;
+                        continue;
+                    }
+                };
+
+                // frames are length-delimited, so a line of any length goes in one message
+                let mut message_payload = MessagePayload::new(user.clone().username, channel, text.into_bytes());
+                message_payload.action = action;
+                let mut message = Message::new(MessageType::Message, message_payload.to_bytes());
+                // the server doesn't echo our own messages back, it says which id it stored them under
+                ui.sent(shown_in(&message_payload.channel), message.id, format_message(&message, &message_payload));
+                client.pending.insert(message.id, message.clone());
+                message_payload.message = crypt::encrypt_data(message_payload.message, client.get_shared_key());
+                message.payload = message_payload.to_bytes();
+                sink.send(Bytes::from(message.to_bytes())).await?;
             }
             // on close
             else => {
@@ -153,6 +757,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
         }
     }
 
+    drop(ui);
     log::error!("Connection closed");
     // exit the program
     std::process::exit(1);
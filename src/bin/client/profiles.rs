@@ -0,0 +1,90 @@
+use common::profile::{Profile, ProfileStore};
+
+const USAGE: &str = "Usage: client profile list
+       client profile new <name> [username]
+       client profile rename <name> <username>
+       client profile export <name> <file>
+       client profile import <file>
+       client profile delete <name>
+       client profile default <name>";
+
+/// Lists the profiles, the default one is marked with a *
+fn list(store: &ProfileStore) {
+    if store.profiles().is_empty() {
+        println!("There are no profiles");
+        return;
+    }
+
+    let default = store.default_profile().map(|profile| profile.name.clone());
+    for profile in store.profiles() {
+        let mark = if Some(&profile.name) == default.as_ref() { "*" } else { " " };
+        let servers: Vec<&str> = profile.servers.iter().map(|server| server.address.as_str()).collect();
+        println!("{} {} ({}) {}", mark, profile.name, profile.user.username, servers.join(", "));
+    }
+}
+
+/// Changes the profiles as asked, returns what to tell the user
+fn change(store: &mut ProfileStore, args: &[&str]) -> Result<String, String> {
+    match args {
+        ["new", name] | ["new", name, _] => {
+            let username = args.get(2).unwrap_or(name);
+            store.add(Profile::new(name, username))?;
+            Ok(format!("Made profile {} for {}", name, username))
+        }
+        ["rename", name, username] => {
+            store.rename(name, username)?;
+            Ok(format!("Profile {} is now {}, servers see it on the next login", name, username))
+        }
+        ["export", name, path] => {
+            store.export(name, path)?;
+            Ok(format!("Wrote profile {} to {}, keep it safe, it holds the secret key", name, path))
+        }
+        ["import", path] => {
+            let name = store.import(path)?;
+            Ok(format!("Added profile {}", name))
+        }
+        ["delete", name] => {
+            store.remove(name)?;
+            Ok(format!("Deleted profile {}", name))
+        }
+        ["default", name] => {
+            store.set_default(name)?;
+            Ok(format!("{} is the default profile", name))
+        }
+        _ => Err(USAGE.to_string()),
+    }
+}
+
+/// Runs `client profile`, returns the exit code
+pub fn run(args: &[String]) -> i32 {
+    let mut store = match ProfileStore::load() {
+        Ok(store) => store,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 1;
+        }
+    };
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    if args.is_empty() || args == ["list"] {
+        list(&store);
+        return 0;
+    }
+
+    match change(&mut store, &args) {
+        Ok(done) => {
+            // only export leaves the profiles as they were
+            if args[0] != "export" {
+                store.save();
+            }
+            println!("{}", done);
+            0
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            match e == USAGE {
+                true => 2,
+                false => 1,
+            }
+        }
+    }
+}
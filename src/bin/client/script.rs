@@ -0,0 +1,220 @@
+use std::time::Duration;
+
+use bytes::Bytes;
+use common::channel::{channel_name, ChannelListPayload};
+use common::crypt;
+use common::export::ExportedMessage;
+use common::message::{Message, MessagePayload, MessageStatusPayload, MessageType, Payload};
+use futures::{SinkExt, StreamExt};
+use tokio::io::AsyncReadExt;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use tokio_util::codec::{FramedRead, FramedWrite, LengthDelimitedCodec};
+
+// how long `send` may take, connecting and logging in included
+const SEND_TIMEOUT: Duration = Duration::from_secs(10);
+
+const USAGE: &str = "Usage: client send --server <address> [--profile <name>] [--channel <channel>] (--stdin | <text>...)
+       client listen --server <address> [--profile <name>] [--channel <channel>]";
+
+/// What `send` and `listen` were asked to do
+struct Options {
+    server: String,
+    profile: Option<String>,
+    channel: Option<String>,
+    stdin: bool,
+    text: Vec<String>,
+}
+
+fn parse_options(args: &[String]) -> Result<Options, String> {
+    let mut options = Options {
+        server: String::new(),
+        profile: None,
+        channel: None,
+        stdin: false,
+        text: Vec::new(),
+    };
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--server" => options.server = args.next().ok_or("--server needs an address")?.clone(),
+            "--profile" => options.profile = Some(args.next().ok_or("--profile needs a name")?.clone()),
+            "--channel" => options.channel = Some(channel_name(args.next().ok_or("--channel needs a channel")?).to_string()),
+            "--stdin" => options.stdin = true,
+            "--" => options.text.extend(args.by_ref().cloned()),
+            flag if flag.starts_with("--") => return Err(format!("Unknown option {}", flag)),
+            text => options.text.push(text.to_string()),
+        }
+    }
+
+    if options.server.is_empty() {
+        return Err("--server is required".to_string());
+    }
+    Ok(options)
+}
+
+/// A logged in connection to a server
+struct Connection {
+    sink: FramedWrite<OwnedWriteHalf, LengthDelimitedCodec>,
+    stream: FramedRead<OwnedReadHalf, LengthDelimitedCodec>,
+    shared_key: Vec<u8>,
+    username: String,
+    // the channels the server listed once the login went through
+    channels: Vec<String>,
+}
+
+impl Connection {
+    /// Connects and logs in with a profile, the default one when none is named,
+    /// returns once the server has accepted the login
+    async fn open(server: &str, profile: Option<&str>) -> Result<Connection, String> {
+        // a one-shot run leaves an old me.dat for the interactive client to migrate
+        let profile = crate::load_profile(profile, false)?;
+        let user = profile.user;
+        let secret_key = crypt::deserialize_private_key(profile.secret_key);
+
+        let (reader, writer) = TcpStream::connect(server).await.map_err(|e| format!("Could not connect to {}: {}", server, e))?.into_split();
+        let mut connection = Connection {
+            sink: FramedWrite::new(writer, LengthDelimitedCodec::new()),
+            stream: FramedRead::new(reader, LengthDelimitedCodec::new()),
+            shared_key: Vec::new(),
+            username: user.username.clone(),
+            channels: Vec::new(),
+        };
+
+        // the server opens with its public key
+        let hello = connection.next().await?;
+        if hello.message_type != MessageType::ConnectionReceive {
+            return Err("The server did not start the handshake".to_string());
+        }
+        connection.send(Message::new(MessageType::Login, user.to_bytes())).await?;
+        connection.shared_key = crypt::create_shared_key(secret_key, crypt::deserialize_public_key(hello.payload));
+
+        // the channel list says the login went through, a refused login gets a notice before the server hangs up
+        let mut notice = None;
+        loop {
+            let message = match connection.next().await {
+                Ok(message) => message,
+                Err(e) => return Err(notice.unwrap_or(e)),
+            };
+            match message.message_type {
+                MessageType::ChannelList => {
+                    let list = ChannelListPayload::from_bytes(message.payload);
+                    connection.channels = list.channels.into_iter().map(|info| info.name).collect();
+                    return Ok(connection);
+                }
+                MessageType::Message => {
+                    let mut payload = MessagePayload::from_bytes(message.payload);
+                    payload.decrypt(connection.shared_key.clone());
+                    if payload.username == "SERVER" {
+                        notice = Some(String::from_utf8_lossy(&payload.message).to_string());
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    async fn next(&mut self) -> Result<Message, String> {
+        match self.stream.next().await {
+            Some(Ok(bytes)) => Ok(Message::from_bytes(bytes.to_vec())),
+            Some(Err(e)) => Err(e.to_string()),
+            None => Err("The server closed the connection".to_string()),
+        }
+    }
+
+    async fn send(&mut self, message: Message) -> Result<(), String> {
+        self.sink.send(Bytes::from(message.to_bytes())).await.map_err(|e| e.to_string())
+    }
+}
+
+/// Posts one message and returns once the server has handled it
+async fn send(options: Options) -> Result<(), String> {
+    let channel = options.channel.unwrap_or_else(|| "general".to_string());
+    let text = match options.stdin {
+        true => {
+            let mut text = String::new();
+            tokio::io::stdin().read_to_string(&mut text).await.map_err(|e| e.to_string())?;
+            text.trim_end_matches(['\r', '\n']).to_string()
+        }
+        false => options.text.join(" "),
+    };
+    if text.trim().is_empty() {
+        return Err("There is nothing to send".to_string());
+    }
+
+    let mut connection = Connection::open(&options.server, options.profile.as_deref()).await?;
+    if !connection.channels.contains(&channel) {
+        return Err(format!("There is no channel #{}", channel));
+    }
+
+    let mut payload = MessagePayload::new(connection.username.clone(), format!("#{}", channel), text.into_bytes());
+    payload.encrypt(connection.shared_key.clone());
+    let message = Message::new(MessageType::Message, payload.to_bytes());
+    let sent_id = message.id;
+    connection.send(message).await?;
+
+    // the server answers this message alone with what became of it
+    loop {
+        let message = connection.next().await?;
+        if message.message_type != MessageType::MessageStatus {
+            continue;
+        }
+        let status = MessageStatusPayload::from_bytes(message.payload);
+        if status.sent_id != sent_id {
+            continue;
+        }
+        return match (status.id, status.error) {
+            (Some(_), _) | (None, None) => Ok(()),
+            (None, Some(error)) => Err(error),
+        };
+    }
+}
+
+/// Prints every message as it arrives, one JSON object per line
+async fn listen(options: Options) -> Result<(), String> {
+    let mut connection = Connection::open(&options.server, options.profile.as_deref()).await?;
+
+    loop {
+        let mut message = connection.next().await?;
+        if message.message_type != MessageType::Message {
+            continue;
+        }
+
+        let mut payload = MessagePayload::from_bytes(message.payload.clone());
+        payload.decrypt(connection.shared_key.clone());
+        if options.channel.as_ref().is_some_and(|channel| channel != channel_name(&payload.channel)) {
+            continue;
+        }
+
+        message.payload = payload.to_bytes();
+        println!("{}", serde_json::to_string(&ExportedMessage::from_message(&message)).unwrap());
+    }
+}
+
+/// Runs `client send` or `client listen`, returns the exit code
+pub async fn run(mode: &str, args: &[String]) -> i32 {
+    let options = match parse_options(args) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}\n{}", e, USAGE);
+            return 2;
+        }
+    };
+
+    let result = match mode {
+        "send" => match tokio::time::timeout(SEND_TIMEOUT, send(options)).await {
+            Ok(result) => result,
+            Err(_) => Err("Timed out waiting for the server".to_string()),
+        },
+        _ => listen(options).await,
+    };
+
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            1
+        }
+    }
+}
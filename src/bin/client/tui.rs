@@ -0,0 +1,375 @@
+use std::collections::HashMap;
+use std::io::{self, Stdout};
+
+use crossterm::event::{Event, EventStream, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use crossterm::execute;
+use crossterm::terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen};
+use futures::StreamExt;
+use ratatui::backend::CrosstermBackend;
+use ratatui::layout::{Constraint, Direction, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Span, Spans};
+use ratatui::widgets::{Block, Borders, List, ListItem, Paragraph};
+use ratatui::{Frame, Terminal};
+
+// the oldest lines are dropped past this many
+const MAX_LINES: usize = 5000;
+const MAX_INPUT_HISTORY: usize = 500;
+const SIDE_WIDTH: u16 = 20;
+
+type Backend = CrosstermBackend<Stdout>;
+
+/// Puts the terminal back the way it was, also when the client panics
+fn restore_terminal() {
+    let _ = disable_raw_mode();
+    let _ = execute!(io::stdout(), LeaveAlternateScreen);
+}
+
+/// A full-screen client: channels on the left, members on the right and
+/// the messages of the current channel between them
+pub struct Tui {
+    terminal: Terminal<Backend>,
+    events: EventStream,
+    view: View,
+}
+
+/// A row of the message pane before it wraps
+struct Line {
+    // the channel it belongs to, None shows in every channel
+    channel: Option<String>,
+    text: String,
+    // the id of a message we sent that the server hasn't confirmed yet
+    pending: Option<u64>,
+}
+
+/// What the screen shows and what is being typed
+#[derive(Default)]
+struct View {
+    server: String,
+    username: String,
+    encrypted: bool,
+    channels: Vec<String>,
+    current: String,
+    lines: Vec<Line>,
+    // lines that arrived in channels that weren't open
+    unread: HashMap<String, usize>,
+    members: HashMap<String, Vec<String>>,
+    // how many rows the message pane is scrolled up from the newest line
+    scroll: usize,
+    // the height of the message pane when it was last drawn, paging moves by half of it
+    page: usize,
+    input: Vec<char>,
+    cursor: usize,
+    // lines entered before, Up and Down walk through them
+    history: Vec<String>,
+    history_position: Option<usize>,
+}
+
+impl Tui {
+    pub fn new(server: &str) -> io::Result<Tui> {
+        enable_raw_mode()?;
+        execute!(io::stdout(), EnterAlternateScreen)?;
+
+        let hook = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            restore_terminal();
+            hook(info);
+        }));
+
+        Ok(Tui {
+            terminal: Terminal::new(CrosstermBackend::new(io::stdout()))?,
+            events: EventStream::new(),
+            view: View {
+                server: server.to_string(),
+                ..View::default()
+            },
+        })
+    }
+
+    pub fn sync(&mut self, username: &str, encrypted: bool, channels: Vec<String>, current: &str) {
+        self.view.sync(username, encrypted, channels, current);
+    }
+
+    pub fn push(&mut self, channel: Option<&str>, text: String) {
+        self.view.push(channel, text, None);
+    }
+
+    /// Shows a message we sent, greyed out until the server confirms it
+    pub fn push_pending(&mut self, channel: Option<&str>, id: u64, text: String) {
+        self.view.push(channel, text, Some(id));
+    }
+
+    /// The server stored a message we sent, it is shown like any other
+    pub fn confirm(&mut self, id: u64) {
+        for line in self.view.lines.iter_mut().filter(|line| line.pending == Some(id)) {
+            line.pending = None;
+        }
+    }
+
+    /// The server refused a message we sent, it leaves the timeline
+    pub fn discard(&mut self, id: u64) {
+        self.view.lines.retain(|line| line.pending != Some(id));
+    }
+
+    pub fn set_members(&mut self, channel: &str, users: Vec<String>) {
+        self.view.members.insert(channel.to_string(), users);
+    }
+
+    /// Waits for the next line the user enters, drawing the screen as keys come in.
+    /// Returns None when the user asks to quit
+    pub async fn next_line(&mut self) -> Option<String> {
+        loop {
+            if let Err(e) = self.terminal.draw(|frame| self.view.render(frame)) {
+                log::error!("Failed to draw the screen: {}", e);
+            }
+
+            let event = match self.events.next().await {
+                Some(Ok(event)) => event,
+                Some(Err(e)) => {
+                    log::error!("Failed to read the terminal: {}", e);
+                    return None;
+                }
+                None => return None,
+            };
+
+            match event {
+                // only presses, some terminals report releases too
+                Event::Key(key) if key.kind == KeyEventKind::Press => match self.view.key(key) {
+                    Key::Line(line) => return Some(line),
+                    Key::Quit => return None,
+                    Key::Handled => {}
+                },
+                // anything else, like a resize, just needs a redraw
+                _ => {}
+            }
+        }
+    }
+}
+
+impl Drop for Tui {
+    fn drop(&mut self) {
+        restore_terminal();
+    }
+}
+
+impl View {
+    fn sync(&mut self, username: &str, encrypted: bool, channels: Vec<String>, current: &str) {
+        if self.current != current {
+            self.unread.remove(current);
+            self.scroll = 0;
+        }
+        self.username = username.to_string();
+        self.encrypted = encrypted;
+        self.channels = channels;
+        self.current = current.to_string();
+    }
+
+    fn push(&mut self, channel: Option<&str>, text: String, pending: Option<u64>) {
+        if let Some(channel) = channel {
+            if channel != self.current {
+                *self.unread.entry(channel.to_string()).or_insert(0) += 1;
+            }
+        }
+
+        // multi-line messages take a row per line
+        for line in text.lines() {
+            self.lines.push(Line {
+                channel: channel.map(str::to_string),
+                text: line.to_string(),
+                pending,
+            });
+        }
+        if self.lines.len() > MAX_LINES {
+            self.lines.drain(..self.lines.len() - MAX_LINES);
+        }
+    }
+
+    fn key(&mut self, key: KeyEvent) -> Key {
+        let control = key.modifiers.contains(KeyModifiers::CONTROL);
+        match key.code {
+            KeyCode::Char('c') | KeyCode::Char('d') if control => return Key::Quit,
+            KeyCode::Char('a') if control => self.cursor = 0,
+            KeyCode::Char('e') if control => self.cursor = self.input.len(),
+            KeyCode::Char('u') if control => {
+                self.input.drain(..self.cursor);
+                self.cursor = 0;
+            }
+            KeyCode::Char(c) => {
+                self.input.insert(self.cursor, c);
+                self.cursor += 1;
+            }
+            KeyCode::Backspace if self.cursor > 0 => {
+                self.cursor -= 1;
+                self.input.remove(self.cursor);
+            }
+            KeyCode::Delete if self.cursor < self.input.len() => {
+                self.input.remove(self.cursor);
+            }
+            KeyCode::Left => self.cursor = self.cursor.saturating_sub(1),
+            KeyCode::Right => self.cursor = (self.cursor + 1).min(self.input.len()),
+            KeyCode::Home => self.cursor = 0,
+            KeyCode::End => self.cursor = self.input.len(),
+            KeyCode::Up => self.walk_history(true),
+            KeyCode::Down => self.walk_history(false),
+            KeyCode::PageUp => self.scroll += self.page.max(2) / 2,
+            KeyCode::PageDown => self.scroll = self.scroll.saturating_sub(self.page.max(2) / 2),
+            // Tab moves to the next channel by typing the command for it
+            KeyCode::Tab if !self.channels.is_empty() => {
+                let i = self.channels.iter().position(|channel| *channel == self.current).map_or(0, |i| i + 1);
+                return Key::Line(format!("/switch {}", self.channels[i % self.channels.len()]));
+            }
+            KeyCode::Enter => {
+                let line: String = self.input.drain(..).collect();
+                self.cursor = 0;
+                self.history_position = None;
+                if line.trim().is_empty() {
+                    return Key::Handled;
+                }
+                if self.history.last() != Some(&line) {
+                    self.history.push(line.clone());
+                    if self.history.len() > MAX_INPUT_HISTORY {
+                        self.history.remove(0);
+                    }
+                }
+                self.scroll = 0;
+                return Key::Line(line);
+            }
+            _ => {}
+        }
+        Key::Handled
+    }
+
+    fn walk_history(&mut self, back: bool) {
+        if self.history.is_empty() {
+            return;
+        }
+
+        let position = match (self.history_position, back) {
+            (None, true) => Some(self.history.len() - 1),
+            (None, false) => None,
+            (Some(i), true) => Some(i.saturating_sub(1)),
+            (Some(i), false) if i + 1 < self.history.len() => Some(i + 1),
+            (Some(_), false) => None,
+        };
+
+        self.history_position = position;
+        self.input = match position {
+            Some(i) => self.history[i].chars().collect(),
+            None => Vec::new(),
+        };
+        self.cursor = self.input.len();
+    }
+
+    fn render(&mut self, frame: &mut Frame<Backend>) {
+        let rows = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([Constraint::Min(3), Constraint::Length(3), Constraint::Length(1)])
+            .split(frame.size());
+        let columns = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([Constraint::Length(SIDE_WIDTH), Constraint::Min(10), Constraint::Length(SIDE_WIDTH)])
+            .split(rows[0]);
+
+        self.render_channels(frame, columns[0]);
+        self.render_messages(frame, columns[1]);
+        self.render_members(frame, columns[2]);
+        self.render_input(frame, rows[1]);
+        self.render_status(frame, rows[2]);
+    }
+
+    fn render_channels(&self, frame: &mut Frame<Backend>, area: Rect) {
+        let items: Vec<ListItem> = self
+            .channels
+            .iter()
+            .map(|channel| {
+                let unread = match self.unread.get(channel) {
+                    Some(count) => format!(" ({})", count),
+                    None => String::new(),
+                };
+                let item = ListItem::new(format!("#{}{}", channel, unread));
+                match (*channel == self.current, unread.is_empty()) {
+                    (true, _) => item.style(Style::default().add_modifier(Modifier::REVERSED)),
+                    (false, false) => item.style(Style::default().add_modifier(Modifier::BOLD)),
+                    (false, true) => item,
+                }
+            })
+            .collect();
+        frame.render_widget(List::new(items).block(Block::default().borders(Borders::ALL).title("Channels")), area);
+    }
+
+    fn render_messages(&mut self, frame: &mut Frame<Backend>, area: Rect) {
+        let width = area.width.saturating_sub(2).max(1) as usize;
+        let height = area.height.saturating_sub(2) as usize;
+        self.page = height;
+
+        // long lines wrap onto as many rows as they need
+        let mut rows: Vec<(String, bool)> = Vec::new();
+        for line in self.lines.iter().filter(|line| line.channel.as_ref().is_none_or(|channel| *channel == self.current)) {
+            let chars: Vec<char> = line.text.chars().collect();
+            let pending = line.pending.is_some();
+            if chars.is_empty() {
+                rows.push((String::new(), pending));
+            }
+            rows.extend(chars.chunks(width).map(|chunk| (chunk.iter().collect::<String>(), pending)));
+        }
+
+        self.scroll = self.scroll.min(rows.len().saturating_sub(height));
+        let end = rows.len() - self.scroll;
+        let start = end.saturating_sub(height);
+        let text: Vec<Spans> = rows[start..end]
+            .iter()
+            .map(|(row, pending)| match pending {
+                true => Spans::from(Span::styled(row.as_str(), Style::default().fg(Color::DarkGray))),
+                false => Spans::from(row.as_str()),
+            })
+            .collect();
+
+        let title = match self.current.is_empty() {
+            true => "Messages".to_string(),
+            false => format!("#{}", self.current),
+        };
+        frame.render_widget(Paragraph::new(text).block(Block::default().borders(Borders::ALL).title(title)), area);
+    }
+
+    fn render_members(&self, frame: &mut Frame<Backend>, area: Rect) {
+        let items: Vec<ListItem> = self
+            .members
+            .get(&self.current)
+            .map(|members| members.iter().map(|member| ListItem::new(member.as_str())).collect())
+            .unwrap_or_default();
+        frame.render_widget(List::new(items).block(Block::default().borders(Borders::ALL).title("Online")), area);
+    }
+
+    fn render_input(&self, frame: &mut Frame<Backend>, area: Rect) {
+        // the input scrolls sideways to keep the cursor in view
+        let width = area.width.saturating_sub(2).max(1) as usize;
+        let offset = (self.cursor + 1).saturating_sub(width);
+        let visible: String = self.input.iter().skip(offset).take(width).collect();
+
+        frame.render_widget(Paragraph::new(visible).block(Block::default().borders(Borders::ALL)), area);
+        frame.set_cursor(area.x + 1 + (self.cursor - offset) as u16, area.y + 1);
+    }
+
+    fn render_status(&self, frame: &mut Frame<Backend>, area: Rect) {
+        let (state, color) = match self.encrypted {
+            true => ("connected, encrypted", Color::Green),
+            false => ("connecting", Color::Yellow),
+        };
+        let mut spans = vec![
+            Span::styled(format!(" {} ", state), Style::default().fg(Color::Black).bg(color)),
+            Span::raw(format!(" {} on {} ", self.username, self.server)),
+        ];
+        if self.scroll > 0 {
+            spans.push(Span::styled(" scrolled up, PageDown for newer ", Style::default().add_modifier(Modifier::REVERSED)));
+        }
+        spans.push(Span::raw(" Tab next channel, /help commands, Ctrl-C quit"));
+        frame.render_widget(Paragraph::new(Spans::from(spans)), area);
+    }
+}
+
+/// What a key press did
+enum Key {
+    Line(String),
+    Quit,
+    Handled,
+}
@@ -0,0 +1,97 @@
+use std::io;
+
+use common::channel::channel_name;
+use futures::StreamExt;
+use tokio::io::Stdin;
+use tokio_util::codec::{FramedRead, LinesCodec};
+
+use crate::client::Client;
+use crate::tui::Tui;
+
+/// Where the client shows what happens and reads what is typed
+pub enum Ui {
+    // plain lines on stdout, typed lines come from stdin
+    Lines {
+        stdin: FramedRead<Stdin, LinesCodec>,
+        current: String,
+    },
+    // the full-screen terminal UI
+    Terminal(Box<Tui>),
+}
+
+impl Ui {
+    pub fn lines() -> Ui {
+        Ui::Lines {
+            stdin: FramedRead::new(tokio::io::stdin(), LinesCodec::new()),
+            current: String::new(),
+        }
+    }
+
+    pub fn terminal(server: &str) -> io::Result<Ui> {
+        Ok(Ui::Terminal(Box::new(Tui::new(server)?)))
+    }
+
+    pub fn is_terminal(&self) -> bool {
+        matches!(self, Ui::Terminal(_))
+    }
+
+    /// Takes in the client's channels and connection state, call it before waiting for input
+    pub fn sync(&mut self, client: &Client, username: &str) {
+        match self {
+            Ui::Lines { current, .. } => *current = channel_name(&client.current_channel()).to_string(),
+            Ui::Terminal(tui) => {
+                let channels = client.channels().iter().map(|channel| channel.name.clone()).collect();
+                tui.sync(username, !client.get_shared_key().is_empty(), channels, channel_name(&client.current_channel()));
+            }
+        }
+    }
+
+    /// Shows a line, `channel` is where it belongs or None for notices that belong nowhere
+    pub fn print(&mut self, channel: Option<&str>, text: String) {
+        let channel = channel.map(channel_name);
+        match self {
+            // lines from outside the current channel say where they are from
+            Ui::Lines { current, .. } => match channel {
+                Some(channel) if channel != current => println!("[#{}] {}", channel, text),
+                _ => println!("{}", text),
+            },
+            Ui::Terminal(tui) => tui.push(channel, text),
+        }
+    }
+
+    /// Shows a message the user sent as pending, the terminal already echoed it in line mode
+    pub fn sent(&mut self, channel: Option<&str>, id: u64, text: String) {
+        if let Ui::Terminal(tui) = self {
+            tui.push_pending(channel.map(channel_name), id, text);
+        }
+    }
+
+    /// Settles a message shown by `sent`: the server either stored it or refused it
+    pub fn settle(&mut self, id: u64, stored: bool) {
+        if let Ui::Terminal(tui) = self {
+            match stored {
+                true => tui.confirm(id),
+                false => tui.discard(id),
+            }
+        }
+    }
+
+    /// Shows who is online in a channel
+    pub fn members(&mut self, channel: &str, users: Vec<String>) {
+        match self {
+            Ui::Lines { .. } => println!("Online in #{}: {}", channel_name(channel), users.join(", ")),
+            Ui::Terminal(tui) => tui.set_members(channel_name(channel), users),
+        }
+    }
+
+    /// Waits for the next line typed, None once there is no more input
+    pub async fn next_line(&mut self) -> Option<String> {
+        match self {
+            Ui::Lines { stdin, .. } => match stdin.next().await {
+                Some(Ok(line)) => Some(line),
+                _ => None,
+            },
+            Ui::Terminal(tui) => tui.next_line().await,
+        }
+    }
+}
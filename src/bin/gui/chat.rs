@@ -1,15 +1,67 @@
-use common::{channel::{self, Channel}, crypt, message::{Message, MessagePayload, Payload}, user::User};
+use common::{channel::{self, channel_name, Channel, ChannelAction, ChannelInfo, ChannelListPayload, ChannelUpdatePayload}, crypt, message::{CommandPayload, HistoryPayload, Message, MessagePayload, MessageStatusPayload, Payload, ReactionPayload, Reactions, ReadMarkerPayload, TypingPayload}, user::User};
+use common::attachment::{AttachmentChunkPayload, AttachmentMeta, AttachmentStatusPayload, Download, Upload};
+use common::invite::{InvitePayload, JoinPayload};
+use common::export;
+use common::history::LocalHistory;
+use common::profile::ConnectPayload;
+use common::search::SearchPayload;
 use egui::Layout;
 use rand_core::OsRng;
 use x25519_dalek::{StaticSecret, PublicKey};
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
 use std::sync::mpsc::{Sender, Receiver, self};
 use tokio::sync::mpsc::UnboundedSender;
 use common::message::MessageType;
 
+// the emoji offered by the "React" menu
+const QUICK_REACTIONS: [&str; 6] = ["👍", "❤", "😂", "😮", "😢", "🎉"];
+
+// how often we tell the server we are typing, and how long someone counts as typing after that
+const TYPING_INTERVAL: Duration = Duration::from_secs(2);
+const TYPING_TIMEOUT: Duration = Duration::from_secs(5);
+
 pub struct ChatApp {
     pub user: User,
     pub messages: Vec<Message>,
+    // the channel shown in the timeline and every channel we know of, without the '#'
+    pub channel: String,
+    pub channels: Vec<String>,
+    // topics, descriptions and whether channels are archived
+    pub channel_info: Vec<ChannelInfo>,
+    // the id of the last message read, keyed by channel
+    pub read_markers: HashMap<String, u64>,
+    // where the "new messages" divider goes in the current channel
+    divider: Option<u64>,
     pub next_message: String,
+    // the message the next message will quote
+    pub reply_to: Option<u64>,
+    // the thread shown in the side panel and its replies
+    pub thread: Option<u64>,
+    pub thread_messages: Vec<Message>,
+    pub next_thread_message: String,
+    // messages we sent that the server hasn't confirmed yet, shown greyed out
+    pending: HashSet<u64>,
+    pub reactions: HashMap<u64, Reactions>,
+    // who is typing, in which channel and when we last heard about it
+    pub typing: HashMap<String, (String, Instant)>,
+    last_typing: Option<Instant>,
+    // the path typed next to the "Attach" button and the transfers in flight
+    pub attach_path: String,
+    uploads: HashMap<String, Upload>,
+    downloads: HashMap<u64, Download>,
+    // the outcome of the last transfer or channel change, shown in the status bar
+    status: String,
+    // the last page of search results, shown in a window until it is closed
+    search: Option<SearchPayload>,
+    // what we have seen, kept on disk encrypted with the passphrase from the welcome screen
+    history: Option<LocalHistory>,
+    passphrase: String,
+    // the address of the server this chat is on and what it is called in the server list
+    pub server: String,
+    pub name: String,
+    // what the connect task said last, until the server list takes it
+    connect_result: Option<ConnectPayload>,
     pub tx: UnboundedSender<Message>,
     pub rx: mpsc::Receiver<Message>,
     secret: Vec<u8>,
@@ -22,7 +74,30 @@ impl ChatApp {
         Self {
             user,
             messages: Vec::new(),
+            channel: String::new(),
+            channels: Vec::new(),
+            channel_info: Vec::new(),
+            read_markers: HashMap::new(),
+            divider: None,
             next_message: String::new(),
+            reply_to: None,
+            thread: None,
+            thread_messages: Vec::new(),
+            next_thread_message: String::new(),
+            pending: HashSet::new(),
+            reactions: HashMap::new(),
+            typing: HashMap::new(),
+            last_typing: None,
+            attach_path: String::new(),
+            uploads: HashMap::new(),
+            downloads: HashMap::new(),
+            status: String::new(),
+            search: None,
+            history: None,
+            passphrase: String::new(),
+            server: String::new(),
+            name: String::new(),
+            connect_result: None,
             tx,
             rx,
             secret: Vec::new(),
@@ -41,7 +116,116 @@ impl ChatApp {
                     payload.message = crypt::decrypt_data(payload.message.clone(), self.shared_key.clone());
                     let mut new_message = message.clone();
                     new_message.payload = payload.to_bytes();
-                    self.messages.push(new_message);
+                    if let Some(history) = self.history.as_mut() {
+                        history.add(new_message.clone());
+                    }
+                    // whoever sent a message is done typing it
+                    self.typing.remove(&payload.username);
+                    match payload.thread {
+                        Some(thread) => {
+                            // thread replies only show up in the thread view
+                            if self.thread == Some(thread) {
+                                self.thread_messages.push(new_message);
+                            }
+                        }
+                        None => {
+                            // messages relayed from other servers can arrive after newer ones
+                            let i = self.messages.partition_point(|m| m.id <= new_message.id);
+                            self.messages.insert(i, new_message);
+                        }
+                    }
+                }
+                MessageType::MessageStatus => {
+                    let status = MessageStatusPayload::from_bytes(message.payload);
+                    self.message_status(status);
+                }
+                MessageType::Typing => {
+                    let typing = TypingPayload::from_bytes(message.payload);
+                    let channel = channel_name(&typing.channel).to_string();
+                    self.typing.insert(typing.username, (channel, Instant::now()));
+                }
+                MessageType::Reaction => {
+                    let reaction = ReactionPayload::from_bytes(message.payload);
+                    self.apply_reaction(&reaction);
+                }
+                MessageType::History => {
+                    let mut payload = HistoryPayload::from_bytes(message.payload);
+                    payload.decrypt(self.shared_key.clone());
+                    self.reactions.extend(payload.reactions.drain());
+                    if let Some(history) = self.history.as_mut() {
+                        history.add_all(payload.messages.clone());
+                    }
+                    match payload.thread {
+                        Some(thread) => {
+                            if self.thread == Some(thread) {
+                                self.thread_messages = payload.messages;
+                            }
+                        }
+                        None => {
+                            self.add_channel(channel_name(&payload.channel).to_string());
+                            // the replay may overlap with what we already have
+                            for message in payload.messages {
+                                if !self.messages.iter().any(|m| m.id == message.id) {
+                                    self.messages.push(message);
+                                }
+                            }
+                            self.messages.sort_by_key(|m| m.id);
+                        }
+                    }
+                }
+                MessageType::ChannelList => {
+                    let payload = ChannelListPayload::from_bytes(message.payload);
+                    for info in payload.channels.iter() {
+                        self.add_channel(info.name.clone());
+                    }
+                    self.channel_info = payload.channels;
+                }
+                MessageType::ChannelUpdate => {
+                    let update = ChannelUpdatePayload::from_bytes(message.payload);
+                    self.apply_channel_update(update);
+                }
+                MessageType::Invite => {
+                    let mut invite = InvitePayload::from_bytes(message.payload);
+                    invite.decrypt(self.shared_key.clone());
+                    self.status = format!("Invite code for #{}: {}", invite.channel, String::from_utf8_lossy(&invite.code));
+                }
+                MessageType::Search => {
+                    let mut search = SearchPayload::from_bytes(message.payload);
+                    search.decrypt(self.shared_key.clone());
+                    self.search = Some(search);
+                }
+                MessageType::Join => {
+                    let join = JoinPayload::from_bytes(message.payload);
+                    self.add_channel(join.channel.clone());
+                    self.switch_channel(join.channel);
+                }
+                MessageType::Part => {
+                    let part = JoinPayload::from_bytes(message.payload);
+                    self.status = format!("Left #{}", part.channel);
+                    self.channel_info.retain(|info| info.name != part.channel);
+                    self.remove_channel(&part.channel);
+                }
+                MessageType::ReadMarker => {
+                    let payload = ReadMarkerPayload::from_bytes(message.payload);
+                    self.read_markers = payload
+                        .markers
+                        .into_iter()
+                        .map(|(channel, id)| (channel_name(&channel).to_string(), id))
+                        .collect();
+                    self.divider = self.read_markers.get(&self.channel).copied();
+                }
+                MessageType::AttachmentStatus => {
+                    let status = AttachmentStatusPayload::from_bytes(message.payload);
+                    self.continue_upload(status);
+                }
+                MessageType::AttachmentChunk => {
+                    let mut chunk = AttachmentChunkPayload::from_bytes(message.payload);
+                    chunk.decrypt(self.shared_key.clone());
+                    self.continue_download(chunk);
+                }
+                MessageType::Connect => {
+                    let connect = ConnectPayload::from_bytes(message.payload);
+                    self.connected(connect);
                 }
                 MessageType::ConnectionReceive => {
                     // the payload is the public key
@@ -57,7 +241,6 @@ impl ChatApp {
     }
 
     pub fn update_main_app(&mut self, ctx: &egui::Context, frame: &mut eframe::Frame) {
-        self.update();
         egui::TopBottomPanel::top("top_panel").show(ctx, |ui| {
             ui.horizontal(|ui| {
                 ui.label("Chat");
@@ -66,57 +249,750 @@ impl ChatApp {
                 ui.label(self.user.username.clone());
             });
         });
+        self.update_channel_panel(ctx);
+        if self.thread.is_some() {
+            self.update_thread_panel(ctx);
+        }
+        if self.search.is_some() {
+            self.update_search_window(ctx);
+        }
+        egui::TopBottomPanel::bottom("bottom_panel").show(ctx, |ui| {
+            if let Some(reply_to) = self.reply_to {
+                ui.horizontal(|ui| {
+                    ui.label(format!("Replying to {}", self.quote(reply_to)));
+                    if ui.small_button("Cancel").clicked() {
+                        self.reply_to = None;
+                    }
+                });
+            }
+            self.typing.retain(|_, (_, last)| last.elapsed() < TYPING_TIMEOUT);
+            let mut users: Vec<&str> = self
+                .typing
+                .iter()
+                .filter(|(_, (channel, _))| channel == &self.channel)
+                .map(|(user, _)| user.as_str())
+                .collect();
+            if !users.is_empty() {
+                users.sort();
+                let verb = if users.len() == 1 { "is" } else { "are" };
+                ui.label(egui::RichText::new(format!("{} {} typing…", users.join(", "), verb)).weak());
+                // clear the indicator once it times out, even without input
+                ctx.request_repaint_after(TYPING_TIMEOUT);
+            }
+            ui.horizontal(|ui| {
+                if ui.text_edit_singleline(&mut self.next_message).changed() && !self.next_message.is_empty() {
+                    self.send_typing();
+                }
+                if ui.button("Send").clicked() {
+                    let text = std::mem::take(&mut self.next_message);
+                    if let Some(command) = text.strip_prefix("/admin ") {
+                        self.send_command(command.trim().to_string());
+                    } else if let Some(command) = text.strip_prefix("/channel ") {
+                        self.send_channel_update(command);
+                    } else if let Some(command) = text.strip_prefix("/invite ") {
+                        self.send_invite(command);
+                    } else if let Some(format) = text.strip_prefix("/export ") {
+                        self.export(format.trim());
+                    } else if let Some(query) = text.strip_prefix("/find ") {
+                        self.find(query);
+                    } else if let Some(command) = text.strip_prefix("/search ") {
+                        match SearchPayload::from_command(command) {
+                            Ok(search) => self.send_search(search),
+                            Err(e) => self.status = e,
+                        }
+                    } else if let Some(code) = text.strip_prefix("/join ") {
+                        let mut join = JoinPayload::new(String::new(), code.trim().as_bytes().to_vec());
+                        join.encrypt(self.shared_key.clone());
+                        self.tx.send(Message::new(MessageType::Join, join.to_bytes())).unwrap();
+                    } else if let Some(channel) = text.strip_prefix("/part ") {
+                        let part = JoinPayload::new(channel.trim().to_string(), Vec::new());
+                        self.tx.send(Message::new(MessageType::Part, part.to_bytes())).unwrap();
+                    } else {
+                        let reply_to = self.reply_to.take();
+                        self.send_message(text, reply_to, None, Vec::new());
+                    }
+                }
+            });
+            ui.horizontal(|ui| {
+                ui.add(egui::TextEdit::singleline(&mut self.attach_path).hint_text("path to a file"));
+                if ui.button("Attach").clicked() {
+                    let path = std::mem::take(&mut self.attach_path);
+                    self.start_upload(path);
+                }
+            });
+            ui.horizontal(|ui| {
+                ui.label("Status");
+                ui.end_row();
+                ui.label("Connected");
+                if !self.status.is_empty() {
+                    ui.label(&self.status);
+                }
+            });
+        });
         egui::CentralPanel::default().show(ctx, |ui| {
-            ui.label("Messages");
+            let info = self.channel_info.iter().find(|info| info.name == self.channel).cloned().unwrap_or_default();
+            ui.horizontal(|ui| {
+                let title = ui.label(egui::RichText::new(format!("#{}", self.channel)).strong());
+                if !info.description.is_empty() {
+                    title.on_hover_text(&info.description);
+                }
+                if !info.topic.is_empty() {
+                    ui.label(&info.topic);
+                }
+                if info.archived {
+                    ui.label(egui::RichText::new("archived").weak());
+                }
+            });
             ui.separator();
+            // buttons can't change self while the messages are borrowed
+            let mut reply_to = None;
+            let mut open_thread = None;
+            let mut toggle_reaction = None;
+            let mut download = None;
             // put input at the bottom
             egui::containers::ScrollArea::vertical().show(ui, |ui| {
-                self.messages.reverse();
                 ui.with_layout(Layout::top_down_justified(egui::Align::TOP), |ui| {
-                    // show the messages
-                    for message in self.messages.iter() {
+                    let mut unread = false;
+                    // show the messages, newest first
+                    for message in self.messages.iter().rev() {
                         // convert payload to messagepayload
                         let payload = MessagePayload::from_bytes(message.payload.clone());
-                        let text = String::from_utf8(payload.message).unwrap();
-                        ui.label(format!(
-                            "[{}] {}: {}",
-                            common::id::to_formatted_timestamp(message.id, "%H:%M:%S"),
-                            payload.username,
-                            text
-                        ));
+                        // direct messages show up in whatever channel is open
+                        if channel_name(&payload.channel) != self.channel && !payload.channel.starts_with('@') {
+                            continue;
+                        }
+                        // everything above the divider arrived since we last read the channel
+                        match self.divider {
+                            Some(divider) if message.id > divider => unread = true,
+                            Some(_) if unread => {
+                                ui.separator();
+                                ui.label(egui::RichText::new("new messages").weak());
+                                ui.separator();
+                                unread = false;
+                            }
+                            _ => {}
+                        }
+                        if let Some(quoted) = payload.reply_to {
+                            ui.label(egui::RichText::new(format!("> {}", self.quote(quoted))).weak());
+                        }
+                        ui.horizontal(|ui| {
+                            ui.label(self.message_text(message));
+                            if ui.small_button("Reply").clicked() {
+                                reply_to = Some(message.id);
+                            }
+                            if ui.small_button("Thread").clicked() {
+                                open_thread = Some(message.id);
+                            }
+                            for attachment in payload.attachments.iter() {
+                                let label = format!("📎 {} ({} bytes)", attachment.name, attachment.size);
+                                if ui.small_button(label).on_hover_text("Download").clicked() {
+                                    download = Some(attachment.clone());
+                                }
+                            }
+                            ui.menu_button("React", |ui| {
+                                for emoji in QUICK_REACTIONS {
+                                    if ui.button(emoji).clicked() {
+                                        toggle_reaction = Some((message.id, emoji.to_string()));
+                                        ui.close_menu();
+                                    }
+                                }
+                            });
+                        });
+                        if let Some(reactions) = self.reactions.get(&message.id) {
+                            ui.horizontal(|ui| {
+                                let mut reactions: Vec<_> = reactions.iter().collect();
+                                reactions.sort();
+                                for (emoji, users) in reactions {
+                                    let button = egui::Button::new(format!("{} {}", emoji, users.len()))
+                                        .small()
+                                        .fill(if users.contains(&self.user.username) {
+                                            ui.visuals().selection.bg_fill
+                                        } else {
+                                            ui.visuals().widgets.inactive.bg_fill
+                                        });
+                                    if ui.add(button).on_hover_text(users.join(", ")).clicked() {
+                                        toggle_reaction = Some((message.id, emoji.clone()));
+                                    }
+                                }
+                            });
+                        }
                     }
 
                     ui.end_row();
                 });
-                self.messages.reverse();
                 // add spacing
                 ui.label("");
             });
+            if reply_to.is_some() {
+                self.reply_to = reply_to;
+            }
+            if let Some(thread) = open_thread {
+                self.open_thread(thread);
+            }
+            if let Some((message_id, emoji)) = toggle_reaction {
+                self.toggle_reaction(message_id, emoji);
+            }
+            if let Some(attachment) = download {
+                self.start_download(attachment);
+            }
         });
-        egui::TopBottomPanel::bottom("bottom_panel").show(ctx, |ui| {
+        // whatever is in the open channel has been seen now
+        self.mark_read();
+    }
+
+    fn update_channel_panel(&mut self, ctx: &egui::Context) {
+        let mut switch_to = None;
+        egui::SidePanel::left("channel_panel").show(ctx, |ui| {
+            ui.label("Channels");
+            ui.separator();
+            for channel in self.channels.iter() {
+                let unread = self.unread_count(channel);
+                let label = if unread > 0 {
+                    format!("#{} ({})", channel, unread)
+                } else {
+                    format!("#{}", channel)
+                };
+                let archived = self.channel_info.iter().any(|info| &info.name == channel && info.archived);
+                let label = if archived { egui::RichText::new(label).weak() } else { egui::RichText::new(label) };
+                if ui.selectable_label(channel == &self.channel, label).clicked() {
+                    switch_to = Some(channel.clone());
+                }
+            }
+        });
+
+        if let Some(channel) = switch_to {
+            self.switch_channel(channel);
+        }
+    }
+
+    fn add_channel(&mut self, channel: String) {
+        if self.channels.contains(&channel) {
+            return;
+        }
+
+        self.channels.push(channel.clone());
+        self.channels.sort();
+        // open general first, or whatever the server told us about first
+        if self.channel.is_empty() || channel == "general" {
+            self.switch_channel(channel);
+        }
+    }
+
+    fn apply_channel_update(&mut self, update: ChannelUpdatePayload) {
+        update.apply(&mut self.channel_info);
+        self.status = update.describe();
+
+        match &update.action {
+            ChannelAction::Create => self.add_channel(update.channel.clone()),
+            ChannelAction::Rename(name) => {
+                for list in [&mut self.messages, &mut self.thread_messages] {
+                    let (mut renamed, rest): (Vec<Message>, Vec<Message>) = list
+                        .drain(..)
+                        .partition(|m| channel_name(&MessagePayload::from_bytes(m.payload.clone()).channel) == update.channel);
+                    channel::rename_messages(&mut renamed, name);
+                    *list = rest;
+                    list.append(&mut renamed);
+                    list.sort_by_key(|m| m.id);
+                }
+                if let Some(marker) = self.read_markers.remove(&update.channel) {
+                    self.read_markers.insert(name.clone(), marker);
+                }
+                self.channels.retain(|channel| channel != &update.channel);
+                self.channels.push(name.clone());
+                self.channels.sort();
+                if self.channel == update.channel {
+                    self.channel = name.clone();
+                }
+            }
+            ChannelAction::Delete => self.remove_channel(&update.channel),
+            _ => {}
+        }
+    }
+
+    /// Forgets a channel that was deleted or left
+    fn remove_channel(&mut self, channel: &str) {
+        self.messages.retain(|m| channel_name(&MessagePayload::from_bytes(m.payload.clone()).channel) != channel);
+        self.channels.retain(|c| c != channel);
+        if self.channel == channel {
+            self.thread = None;
+            let next = self.channels.first().cloned().unwrap_or_default();
+            self.switch_channel(next);
+        }
+    }
+
+    fn send_channel_update(&mut self, command: &str) {
+        match ChannelUpdatePayload::from_command(&self.user.username, command) {
+            Ok(update) => {
+                let message = Message::new(MessageType::ChannelUpdate, update.to_bytes());
+                self.tx.send(message).unwrap();
+            }
+            Err(e) => self.status = e,
+        }
+    }
+
+    fn send_invite(&mut self, command: &str) {
+        match InvitePayload::from_command(command) {
+            Ok(mut invite) => {
+                invite.encrypt(self.shared_key.clone());
+                self.tx.send(Message::new(MessageType::Invite, invite.to_bytes())).unwrap();
+            }
+            Err(e) => self.status = e,
+        }
+    }
+
+    fn switch_channel(&mut self, channel: String) {
+        self.divider = self.read_markers.get(&channel).copied();
+        self.channel = channel;
+        self.reply_to = None;
+    }
+
+    fn unread_count(&self, channel: &str) -> usize {
+        let marker = self.read_markers.get(channel).copied().unwrap_or(0);
+        self.messages
+            .iter()
+            .filter(|m| m.id > marker)
+            .map(|m| MessagePayload::from_bytes(m.payload.clone()))
+            .filter(|p| channel_name(&p.channel) == channel && p.username != self.user.username)
+            .count()
+    }
+
+    fn mark_read(&mut self) {
+        let latest = self
+            .messages
+            .iter()
+            .filter(|m| channel_name(&MessagePayload::from_bytes(m.payload.clone()).channel) == self.channel)
+            .map(|m| m.id)
+            .max();
+        let latest = match latest {
+            Some(latest) => latest,
+            None => return,
+        };
+
+        let marker = self.read_markers.entry(self.channel.clone()).or_insert(0);
+        if latest <= *marker {
+            return;
+        }
+        *marker = latest;
+
+        let markers = HashMap::from([(self.channel.clone(), latest)]);
+        let payload = ReadMarkerPayload::new(self.user.username.clone(), markers);
+        let message = Message::new(MessageType::ReadMarker, payload.to_bytes());
+        self.tx.send(message).unwrap();
+    }
+
+    fn update_thread_panel(&mut self, ctx: &egui::Context) {
+        let thread = match self.thread {
+            Some(thread) => thread,
+            None => return,
+        };
+
+        egui::SidePanel::right("thread_panel").show(ctx, |ui| {
             ui.horizontal(|ui| {
-                ui.text_edit_singleline(&mut self.next_message);
-                if ui.button("Send").clicked() {
-                    // send the message
-                    let mut payload = MessagePayload::new(
-                        self.user.clone().username,
-                        "#general".to_string(),
-                        self.next_message.clone().as_bytes().to_vec(),
-                    );
-                    let mut message = Message::new(common::message::MessageType::Message, payload.to_bytes());
-                    self.messages.push(message.clone());
-                    payload.message = crypt::encrypt_data(payload.message.clone(), self.shared_key.clone());
-                    message.payload = payload.to_bytes();
-                    self.tx.send(message).unwrap();
-                    self.next_message = String::new();
+                ui.label("Thread");
+                if ui.small_button("Close").clicked() {
+                    self.thread = None;
+                    self.thread_messages.clear();
                 }
             });
+            ui.label(egui::RichText::new(self.quote(thread)).strong());
+            ui.separator();
+            egui::containers::ScrollArea::vertical().show(ui, |ui| {
+                for message in self.thread_messages.iter() {
+                    ui.label(self.message_text(message));
+                }
+            });
+            ui.separator();
             ui.horizontal(|ui| {
-                ui.label("Status");
-                ui.end_row();
-                ui.label("Connected");
+                ui.text_edit_singleline(&mut self.next_thread_message);
+                if ui.button("Reply").clicked() {
+                    let text = std::mem::take(&mut self.next_thread_message);
+                    self.send_message(text, None, Some(thread), Vec::new());
+                }
+            });
+        });
+    }
+
+    fn update_search_window(&mut self, ctx: &egui::Context) {
+        let search = match &self.search {
+            Some(search) => search.clone(),
+            None => return,
+        };
+        let mut open = true;
+        let mut page = None;
+        let mut jump_to = None;
+
+        egui::Window::new(format!("Search: {}", search.get_query())).open(&mut open).show(ctx, |ui| {
+            ui.label(format!("{} results, page {} of {}", search.total, search.page + 1, search.pages().max(1)));
+            ui.separator();
+            egui::containers::ScrollArea::vertical().max_height(400.0).show(ui, |ui| {
+                for result in search.results.iter() {
+                    let payload = MessagePayload::from_bytes(result.message.payload.clone());
+                    let text = String::from_utf8_lossy(&payload.message);
+
+                    ui.horizontal_wrapped(|ui| {
+                        ui.spacing_mut().item_spacing.x = 0.0;
+                        let heading = format!(
+                            "[{}] {} {}: ",
+                            common::id::to_formatted_timestamp(result.message.id, "%Y-%m-%d %H:%M"),
+                            payload.channel,
+                            payload.username
+                        );
+                        if ui.link(heading).clicked() {
+                            jump_to = Some(channel_name(&payload.channel).to_string());
+                        }
+
+                        // matches are drawn strong on a highlighted background
+                        let mut last = 0;
+                        for &(start, end) in result.highlights.iter() {
+                            ui.label(&text[last..start]);
+                            ui.label(egui::RichText::new(&text[start..end]).strong().background_color(egui::Color32::from_rgb(90, 80, 20)));
+                            last = end;
+                        }
+                        ui.label(&text[last..]);
+                    });
+                }
+            });
+            ui.separator();
+            ui.horizontal(|ui| {
+                if ui.add_enabled(search.page > 0, egui::Button::new("Previous")).clicked() {
+                    page = Some(search.page - 1);
+                }
+                if ui.add_enabled(search.page + 1 < search.pages(), egui::Button::new("Next")).clicked() {
+                    page = Some(search.page + 1);
+                }
             });
         });
+
+        if let Some(page) = page {
+            let mut next = search.clone();
+            next.page = page;
+            next.results.clear();
+            self.send_search(next);
+        }
+        if let Some(channel) = jump_to {
+            self.switch_channel(channel);
+        }
+        if !open {
+            self.search = None;
+        }
+    }
+
+    /// Exports the current channel from the local history to the exports folder in the config directory
+    fn export(&mut self, format: &str) {
+        let history = match &self.history {
+            Some(history) => history,
+            None => {
+                self.status = "No local history is kept, connect with a passphrase to keep one".to_string();
+                return;
+            }
+        };
+
+        let messages = history.recent(&self.channel, usize::MAX);
+        self.status = match export::export(&self.channel, format, &messages) {
+            Ok((extension, contents)) => {
+                let directory = format!("{}/exports", common::get_config_dir());
+                let path = format!("{}/{}.{}", directory, self.channel, extension);
+                match std::fs::create_dir_all(&directory).and_then(|_| std::fs::write(&path, contents)) {
+                    Ok(_) => format!("Exported {} messages to {}", messages.len(), path),
+                    Err(e) => format!("Export failed: {}", e),
+                }
+            }
+            Err(e) => e,
+        };
+    }
+
+    /// Searches the local history, the results show in the same window as a server search
+    fn find(&mut self, query: &str) {
+        let history = match &self.history {
+            Some(history) => history,
+            None => {
+                self.status = "No local history is kept, connect with a passphrase to keep one".to_string();
+                return;
+            }
+        };
+
+        let mut search = SearchPayload::new(query.trim().to_string());
+        search.results = history.search(query);
+        search.total = search.results.len();
+        // everything fits on one page
+        search.per_page = search.total.max(1);
+        self.search = Some(search);
+    }
+
+    /// Opens the local history for the server we connect to and shows what it holds
+    fn open_history(&mut self, server: &str) -> Result<(), String> {
+        if self.passphrase.is_empty() {
+            return Ok(());
+        }
+
+        let history = LocalHistory::open(&self.user.username, server, &self.passphrase)?;
+        self.passphrase.clear();
+        for message in history.messages() {
+            let payload = MessagePayload::from_bytes(message.payload.clone());
+            if payload.thread.is_none() {
+                if !payload.channel.starts_with('@') {
+                    self.add_channel(channel_name(&payload.channel).to_string());
+                }
+                self.messages.push(message.clone());
+            }
+        }
+        self.history = Some(history);
+        Ok(())
+    }
+
+    /// Asks the connect task to log in to our server, with the key pinned for it if it is saved
+    pub fn connect(&mut self, fingerprint: Option<String>, passphrase: String) -> Result<(), String> {
+        self.passphrase = passphrase;
+        self.open_history(&self.server.clone())?;
+        let message = Message::new(MessageType::Connect, ConnectPayload::new(self.server.clone(), fingerprint).to_bytes());
+        self.tx.send(message).map_err(|e| e.to_string())
+    }
+
+    /// Shows the chat once the connect task logged in, the server list takes the outcome from here
+    fn connected(&mut self, connect: ConnectPayload) {
+        self.setup = connect.error.is_none();
+        self.connect_result = Some(connect);
+    }
+
+    /// How connecting went or why the connection ended, once
+    pub fn take_connect_result(&mut self) -> Option<ConnectPayload> {
+        self.connect_result.take()
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.setup
+    }
+
+    /// The unread messages in every channel of this server
+    pub fn unread_total(&self) -> usize {
+        self.channels.iter().map(|channel| self.unread_count(channel)).sum()
+    }
+
+    fn send_search(&mut self, mut search: SearchPayload) {
+        search.encrypt(self.shared_key.clone());
+        self.tx.send(Message::new(MessageType::Search, search.to_bytes())).unwrap();
+    }
+
+    fn open_thread(&mut self, thread: u64) {
+        self.thread = Some(thread);
+        self.thread_messages.clear();
+
+        // thread replies are not part of the timeline, ask the server for them
+        let request = HistoryPayload::new(self.get_channel(thread), Some(thread), Vec::new());
+        let message = Message::new(MessageType::History, request.to_bytes());
+        self.tx.send(message).unwrap();
+    }
+
+    fn send_message(&mut self, text: String, reply_to: Option<u64>, thread: Option<u64>, attachments: Vec<AttachmentMeta>) {
+        let channel = match thread {
+            Some(thread) => self.get_channel(thread),
+            None => format!("#{}", self.channel),
+        };
+        let mut payload = MessagePayload::new(
+            self.user.clone().username,
+            channel,
+            text.as_bytes().to_vec(),
+        );
+        payload.set_reply_to(reply_to);
+        payload.set_thread(thread);
+        for attachment in attachments {
+            payload.add_attachment(attachment);
+        }
+        // the next keystroke starts a new message
+        self.last_typing = None;
+        let mut message = Message::new(common::message::MessageType::Message, payload.to_bytes());
+        // the server doesn't echo our own messages back, it says which id it stored them under
+        self.pending.insert(message.id);
+        match thread {
+            Some(_) => self.thread_messages.push(message.clone()),
+            None => self.messages.push(message.clone()),
+        }
+        payload.message = crypt::encrypt_data(payload.message.clone(), self.shared_key.clone());
+        message.payload = payload.to_bytes();
+        self.tx.send(message).unwrap();
+    }
+
+    /// Gives a message we sent the id the server stored it under, or says why it was not posted
+    fn message_status(&mut self, status: MessageStatusPayload) {
+        if !self.pending.remove(&status.sent_id) {
+            return;
+        }
+        match (status.id, status.error) {
+            (Some(id), _) => {
+                for messages in [&mut self.messages, &mut self.thread_messages] {
+                    if let Some(sent) = messages.iter_mut().find(|m| m.id == status.sent_id) {
+                        sent.id = id;
+                        if let Some(history) = self.history.as_mut() {
+                            history.add(sent.clone());
+                        }
+                    }
+                    messages.sort_by_key(|m| m.id);
+                }
+            }
+            (None, error) => {
+                for messages in [&mut self.messages, &mut self.thread_messages] {
+                    messages.retain(|m| m.id != status.sent_id);
+                }
+                if let Some(error) = error {
+                    self.status = format!("Not sent: {}", error);
+                }
+            }
+        }
+    }
+
+    fn send_command(&mut self, command: String) {
+        let mut payload = CommandPayload::new(format!("#{}", self.channel), command.into_bytes());
+        payload.encrypt(self.shared_key.clone());
+        let message = Message::new(MessageType::Command, payload.to_bytes());
+        self.tx.send(message).unwrap();
+    }
+
+    fn start_upload(&mut self, path: String) {
+        match Upload::from_file(path.trim()) {
+            Ok(upload) => {
+                // the server answers with where to start, which resumes earlier attempts
+                let message = Message::new(MessageType::AttachmentOffer, upload.meta.to_bytes());
+                self.status = format!("Uploading {}", upload.meta.name);
+                self.uploads.insert(upload.meta.hash.clone(), upload);
+                self.tx.send(message).unwrap();
+            }
+            Err(e) => self.status = format!("Could not read {}: {}", path.trim(), e),
+        }
+    }
+
+    fn continue_upload(&mut self, status: AttachmentStatusPayload) {
+        let mut upload = match self.uploads.remove(&status.hash) {
+            Some(upload) => upload,
+            None => return,
+        };
+
+        if let Some(error) = status.error {
+            self.status = format!("Upload of {} failed: {}", upload.meta.name, error);
+            return;
+        }
+
+        match upload.next_chunk(&status) {
+            Some(mut chunk) => {
+                chunk.encrypt(self.shared_key.clone());
+                let message = Message::new(MessageType::AttachmentChunk, chunk.to_bytes());
+                self.tx.send(message).unwrap();
+                self.uploads.insert(status.hash, upload);
+            }
+            None => {
+                // the file is on the server, post it
+                self.status = format!("Uploaded {}", upload.meta.name);
+                self.send_message(String::new(), None, None, vec![upload.meta]);
+            }
+        }
+    }
+
+    fn start_download(&mut self, attachment: AttachmentMeta) {
+        let directory = format!("{}/downloads", common::get_config_dir());
+        let download = Download::new(attachment, &directory);
+        let message = Message::new(MessageType::AttachmentRequest, download.request().to_bytes());
+        self.status = format!("Downloading {}", download.meta.name);
+        self.downloads.insert(download.meta.id, download);
+        self.tx.send(message).unwrap();
+    }
+
+    fn continue_download(&mut self, chunk: AttachmentChunkPayload) {
+        let download = match self.downloads.get_mut(&chunk.id) {
+            Some(download) => download,
+            None => return,
+        };
+
+        match download.write_chunk(&chunk) {
+            // the server sends a chunk per request
+            Ok(false) => {
+                let message = Message::new(MessageType::AttachmentRequest, download.request().to_bytes());
+                self.tx.send(message).unwrap();
+            }
+            Ok(true) => {
+                self.status = format!("Saved {}", download.path.display());
+                self.downloads.remove(&chunk.id);
+            }
+            Err(e) => {
+                self.status = format!("Download of {} failed: {}", download.meta.name, e);
+                self.downloads.remove(&chunk.id);
+            }
+        }
+    }
+
+    fn send_typing(&mut self) {
+        if let Some(last) = self.last_typing {
+            if last.elapsed() < TYPING_INTERVAL {
+                return;
+            }
+        }
+        self.last_typing = Some(Instant::now());
+
+        let typing = TypingPayload::new(self.user.username.clone(), format!("#{}", self.channel));
+        let message = Message::new(MessageType::Typing, typing.to_bytes());
+        self.tx.send(message).unwrap();
+    }
+
+    fn toggle_reaction(&mut self, message_id: u64, emoji: String) {
+        // reacting again with the same emoji takes the reaction back
+        let add = !self
+            .reactions
+            .get(&message_id)
+            .and_then(|reactions| reactions.get(&emoji))
+            .is_some_and(|users| users.contains(&self.user.username));
+
+        let reaction = ReactionPayload::new(self.user.username.clone(), self.get_channel(message_id), message_id, emoji, add);
+        self.apply_reaction(&reaction);
+
+        let message = Message::new(MessageType::Reaction, reaction.to_bytes());
+        self.tx.send(message).unwrap();
+    }
+
+    fn apply_reaction(&mut self, reaction: &ReactionPayload) {
+        let reactions = self.reactions.entry(reaction.message_id).or_default();
+        reaction.apply(reactions);
+        if reactions.is_empty() {
+            self.reactions.remove(&reaction.message_id);
+        }
+    }
+
+    /// A message as the timeline shows it, greyed out until the server confirms it
+    fn message_text(&self, message: &Message) -> egui::RichText {
+        let text = egui::RichText::new(self.format_message(message));
+        match self.pending.contains(&message.id) {
+            true => text.weak().italics(),
+            false => text,
+        }
+    }
+
+    fn format_message(&self, message: &Message) -> String {
+        let payload = MessagePayload::from_bytes(message.payload.clone());
+        let mut author = format!("{}{}", payload.username, if payload.bot { " [bot]" } else { "" });
+        if let Some(to) = payload.channel.strip_prefix('@') {
+            author = format!("{} -> {}", author, to);
+        }
+        format!(
+            "[{}] {}{} {}",
+            common::id::to_formatted_timestamp(message.id, "%H:%M:%S"),
+            if payload.action { "* " } else { "" },
+            if payload.action { author } else { format!("{}:", author) },
+            String::from_utf8_lossy(&payload.message)
+        )
+    }
+
+    fn quote(&self, id: u64) -> String {
+        match self.messages.iter().find(|m| m.id == id) {
+            Some(message) => {
+                let payload = MessagePayload::from_bytes(message.payload.clone());
+                format!("{}: {}", payload.username, String::from_utf8_lossy(&payload.message))
+            }
+            None => "an older message".to_string(),
+        }
+    }
+
+    fn get_channel(&self, id: u64) -> String {
+        match self.messages.iter().find(|m| m.id == id) {
+            Some(message) => MessagePayload::from_bytes(message.payload.clone()).channel,
+            None => format!("#{}", self.channel),
+        }
     }
 
     pub fn set_secret(&mut self, secret: Vec<u8>) {
@@ -135,28 +1011,3 @@ impl ChatApp {
         self.secret.clone()
     }
 }
-
-impl eframe::App for ChatApp {
-    fn update(&mut self, ctx: &egui::Context, frame: &mut eframe::Frame) {
-        if self.setup {
-            self.update_main_app(ctx, frame);
-        } else {
-            // ask for socket address
-            egui::CentralPanel::default().show(ctx, |ui| {
-                ui.heading("Welcome to Yuttari!");
-                ui.add(egui::Label::new("Enter the server to connect to:"));
-                ui.add(egui::TextEdit::singleline(&mut self.next_message));
-                if ui.button("Done").clicked() {
-                    self.setup = true;
-                    // send message to tokio thread
-                    let message = Message::new(
-                        common::message::MessageType::Connect,
-                        self.next_message.clone().into_bytes(),
-                    );
-                    self.tx.send(message).unwrap();
-                    self.next_message = String::new();
-                }
-            });
-        }
-    }
-}
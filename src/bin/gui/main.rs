@@ -3,9 +3,7 @@ extern crate common;
 use std::{
     env,
     error::Error,
-    fs::File,
-    io::{self, BufReader, Write},
-    net::SocketAddr,
+    io::{self, Write},
     path, sync::mpsc,
 };
 
@@ -13,31 +11,45 @@ use bytes::Bytes;
 use futures::SinkExt;
 use log::{debug, LevelFilter};
 use simplelog::{Config, SimpleLogger};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+use tokio::sync::mpsc::UnboundedReceiver;
 use tokio_stream::StreamExt;
-use tokio_util::codec::{BytesCodec, Framed, FramedRead, FramedWrite};
+use tokio_util::codec::{Framed, FramedRead, FramedWrite, LengthDelimitedCodec};
 
 use chat::ChatApp;
-use common::{crypt, id, message::{MessagePayload, MessageType, Payload}, message::Message, user::User};
+use common::{crypt, id, message::{MessagePayload, MessageType, Payload}, message::Message, profile::{ConnectPayload, Profile, ProfileStore}, user::User};
 
 mod chat;
+mod servers;
 mod setup;
 
-fn setup() -> User {
-    // if file exists, read from file
-    let mut path = common::get_config_dir();
-    path.push_str("/config.yut");
-    let file = File::open(path);
-    if file.is_ok() {
-        let file = file.unwrap();
-        let reader = BufReader::new(file);
-        let user: User = rmp_serde::from_read(reader).unwrap();
-        return user;
+/// The profiles, the GUI doesn't start when they can't be read so they aren't saved over
+fn load_profiles() -> ProfileStore {
+    match ProfileStore::load() {
+        Ok(store) => store,
+        Err(e) => {
+            log::error!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn setup() -> Profile {
+    // a profile picked with --profile, or the default one
+    let args: Vec<String> = env::args().collect();
+    let name = args.iter().position(|arg| arg == "--profile").and_then(|i| args.get(i + 1));
+    let store = load_profiles();
+    let profile = match name {
+        Some(name) => store.get(name),
+        None => store.default_profile(),
+    };
+    if let Some(profile) = profile {
+        return profile.clone();
     }
 
-    // else, create new user
-    let app = setup::Setup::default();
+    // else, create new profile
+    let app = setup::Setup::new(name.cloned());
     eframe::run_native(
         "Setup",
         eframe::NativeOptions {
@@ -47,14 +59,13 @@ fn setup() -> User {
         Box::new(|_ctx| Box::new(app)),
     );
 
-    // load the user from the file
-    let mut path = common::get_config_dir();
-    path.push_str("/config.yut");
-    let file = File::open(path).unwrap();
-    let reader = BufReader::new(file);
-    let user: User = rmp_serde::from_read(reader).unwrap();
-
-    user
+    // load the profile the setup saved
+    let store = load_profiles();
+    let profile = match name {
+        Some(name) => store.get(name),
+        None => store.default_profile(),
+    };
+    profile.expect("No profile was made").clone()
 }
 
 #[tokio::main]
@@ -62,38 +73,10 @@ async fn main() {
     SimpleLogger::init(LevelFilter::Debug, Config::default()).unwrap();
 
 
-    let mut user = setup();
-
-    let (tx, rx) = unbounded_channel();
-
-    let (tx2, rx2) = mpsc::channel();
-
-    let mut app = chat::ChatApp::new(user.clone(), tx, rx2);
-
-    let priv_key = crypt::create_private_key();
-
-    let pub_key = crypt::create_public_key(priv_key.clone());
-
-    user.set_public_key(crypt::serialize_public_key(pub_key.clone()));
-
-    app.set_secret(crypt::serialize_private_key(priv_key.clone()));
-
+    let profile = setup();
 
-    // spawn the connect task
-    tokio::spawn(async move {
-        match connect(user, rx, tx2).await {
-            Ok(_) => {
-                println!("Disconnected");
-                // exit the program
-                std::process::exit(0);
-            },
-            Err(e) => {
-                println!("Error: {}", e);
-                // exit the program
-                std::process::exit(1);
-            },
-        }
-    });
+    // each server gets its own connect task once it is picked
+    let app = servers::Servers::new(&profile);
 
     eframe::run_native(
         "Chat",
@@ -102,23 +85,73 @@ async fn main() {
     );
 }
 
+type ServerSink = FramedWrite<OwnedWriteHalf, LengthDelimitedCodec>;
+type ServerStream = FramedRead<OwnedReadHalf, LengthDelimitedCodec>;
+
+/// Connects to a server and logs in, unless its key isn't the one pinned for it.
+/// Returns the connection, the greeting with the server's key and the key's fingerprint
+async fn login(user: &User, request: &ConnectPayload) -> Result<(ServerSink, ServerStream, Message, String), String> {
+    // host names are looked up here
+    let stream = TcpStream::connect(request.address.as_str())
+        .await
+        .map_err(|e| format!("Could not connect to {}: {}", request.address, e))?;
+    let (reader, writer) = stream.into_split();
+    let mut sink = FramedWrite::new(writer, LengthDelimitedCodec::new());
+    let mut stream = FramedRead::new(reader, LengthDelimitedCodec::new());
+
+    let greeting = match stream.next().await {
+        Some(Ok(bytes)) => Message::from_bytes(bytes.to_vec()),
+        Some(Err(e)) => return Err(e.to_string()),
+        None => return Err(format!("{} closed the connection", request.address)),
+    };
+    if greeting.message_type != MessageType::ConnectionReceive {
+        return Err(format!("{} did not start the handshake", request.address));
+    }
+
+    let fingerprint = crypt::fingerprint(&greeting.payload);
+    if let Some(pinned) = &request.fingerprint {
+        if *pinned != fingerprint {
+            return Err(format!(
+                "The key of {} is {}, not the {} pinned for it. Not logging in, forget the server to trust the new key",
+                request.address, fingerprint, pinned
+            ));
+        }
+    }
+
+    // send login message
+    let login_message = Message::new(MessageType::Login, user.clone().to_bytes());
+    sink.send(Bytes::from(login_message.to_bytes())).await.map_err(|e| e.to_string())?;
+    Ok((sink, stream, greeting, fingerprint))
+}
+
 async fn connect(
     user: User,
     mut to_server_rx: UnboundedReceiver<Message>,
     tx: mpsc::Sender<Message>,
 ) -> Result<(), Box<dyn Error>> {
 
-    // wait for the user to enter the server address from the gui
-    let raw_bytes = to_server_rx.recv().await.unwrap().payload;
-    // convert the bytes to a string
-    let addr = String::from_utf8(raw_bytes.to_vec()).unwrap();
-    // convert the string to a SocketAddr
-    let addr = addr.parse::<SocketAddr>().unwrap();
-
-    let mut stream = TcpStream::connect(addr).await?;
-    let (reader, writer) = stream.split();
-    let mut sink = FramedWrite::new(writer, BytesCodec::new());
-    let mut stream = FramedRead::new(reader, BytesCodec::new());
+    // wait for the gui to say which server this task is for
+    let request = match to_server_rx.recv().await {
+        Some(message) if message.message_type == MessageType::Connect => ConnectPayload::from_bytes(message.payload),
+        _ => return Ok(()),
+    };
+
+    let (mut sink, mut stream) = match login(&user, &request).await {
+        Ok((sink, stream, greeting, fingerprint)) => {
+            let connected = ConnectPayload::new(request.address.clone(), Some(fingerprint));
+            tx.send(Message::new(MessageType::Connect, connected.to_bytes()))?;
+            // the gui makes the shared key from the server's key
+            tx.send(greeting)?;
+            (sink, stream)
+        }
+        Err(e) => {
+            log::error!("{}", e);
+            let mut failed = ConnectPayload::new(request.address, None);
+            failed.error = Some(e);
+            tx.send(Message::new(MessageType::Connect, failed.to_bytes()))?;
+            return Ok(());
+        }
+    };
 
     loop {
         tokio::select! {
@@ -130,16 +163,24 @@ async fn connect(
               let message = Message::from_bytes(bytes.to_vec());
               // send the message to the rx channel
               match message.message_type {
-                MessageType::Message => {
+                MessageType::Message
+                | MessageType::History
+                | MessageType::Reaction
+                | MessageType::Typing
+                | MessageType::ReadMarker
+                | MessageType::AttachmentStatus
+                | MessageType::AttachmentChunk
+                | MessageType::ChannelList
+                | MessageType::ChannelUpdate
+                | MessageType::Invite
+                | MessageType::Join
+                | MessageType::Part
+                | MessageType::Search => {
                   debug!("Received message: {:?}", message);
-                  tx.send(message).unwrap();
-                },
-                MessageType::ConnectionReceive => {
-                  // send login message
-                  let login_message = Message::new(MessageType::Login, user.clone().to_bytes());
-                  sink.send(Bytes::from(login_message.to_bytes())).await?;
-                  // send the connection receive message to the rx channel
-                  tx.send(message).unwrap();
+                  // the chat was closed
+                  if tx.send(message).is_err() {
+                    break;
+                  }
                 },
                 _ => {
                   log::error!("Invalid message type");
@@ -160,16 +201,23 @@ async fn connect(
             match msg {
                 Some(message) => {
                 debug!("Sending message: {:?}", message);
-                sink.send(Bytes::from(message.to_bytes())).await?;
+                if let Err(e) = sink.send(Bytes::from(message.to_bytes())).await {
+                  log::error!("Error: {}", e);
+                  break;
+                }
                 }
                 None => {
-                log::error!("Connection closed");
-                break;
+                // the chat was closed, it needs no telling
+                return Ok(());
                 }
             }
         }
       }
     }
 
+    // the server list drops the chat and says why
+    let mut closed = ConnectPayload::new(request.address, None);
+    closed.error = Some("The connection was closed".to_string());
+    let _ = tx.send(Message::new(MessageType::Connect, closed.to_bytes()));
     Ok(())
 }
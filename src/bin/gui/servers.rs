@@ -0,0 +1,312 @@
+use std::sync::mpsc;
+use std::time::Duration;
+
+use common::profile::{check_address, Profile, ProfileStore, SavedServer};
+use common::user::User;
+use tokio::sync::mpsc::unbounded_channel;
+
+use crate::chat::ChatApp;
+
+// messages for servers in the background arrive without any input, so the unread counts
+// are looked at this often
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// The window: every server we are connected to, each with its own connect task and keys,
+/// a switcher between them and the list of saved servers
+pub struct Servers {
+    user: User,
+    secret: Vec<u8>,
+    // the profile we are logged in with and the servers it has saved, the most recent first
+    profile: String,
+    servers: Vec<SavedServer>,
+    auto_connect: bool,
+    chats: Vec<ChatApp>,
+    // the chat shown, None shows the server list
+    current: Option<usize>,
+    // the bookmark being typed on the server list
+    server_name: String,
+    server_address: String,
+    // the passphrase of the local histories, kept for the session so every server opens its own
+    passphrase: String,
+    // why the last connection failed or ended
+    status: String,
+    // whether the last server was tried on start already
+    auto_connect_tried: bool,
+}
+
+impl Servers {
+    pub fn new(profile: &Profile) -> Self {
+        Self {
+            user: profile.user.clone(),
+            secret: profile.secret_key.clone(),
+            profile: profile.name.clone(),
+            servers: profile.servers.clone(),
+            auto_connect: profile.auto_connect,
+            chats: Vec::new(),
+            current: None,
+            server_name: String::new(),
+            server_address: String::new(),
+            passphrase: String::new(),
+            status: String::new(),
+            auto_connect_tried: false,
+        }
+    }
+
+    /// Starts a connect task for a server and shows its chat, or just shows it if it is open already
+    fn open(&mut self, address: String) {
+        if let Some(i) = self.chats.iter().position(|chat| chat.server == address) {
+            self.current = Some(i);
+            return;
+        }
+        if let Err(e) = check_address(&address) {
+            self.status = e;
+            return;
+        }
+
+        let (tx, rx) = unbounded_channel();
+        let (tx2, rx2) = mpsc::channel();
+        let mut chat = ChatApp::new(self.user.clone(), tx, rx2);
+        chat.set_secret(self.secret.clone());
+        let saved = self.servers.iter().find(|server| server.address == address);
+        chat.name = saved.map_or(address.clone(), |server| server.name.clone());
+        chat.server = address;
+
+        let fingerprint = saved.and_then(|server| server.fingerprint.clone());
+        if let Err(e) = chat.connect(fingerprint, self.passphrase.clone()) {
+            self.status = e;
+            return;
+        }
+
+        let user = self.user.clone();
+        tokio::spawn(async move {
+            if let Err(e) = crate::connect(user, rx, tx2).await {
+                log::error!("Error: {}", e);
+            }
+        });
+
+        self.status.clear();
+        self.chats.push(chat);
+        self.current = Some(self.chats.len() - 1);
+    }
+
+    /// Closes the connection to a server, its connect task ends once the chat is gone
+    fn close(&mut self, i: usize) {
+        self.chats.remove(i);
+        self.current = match self.current {
+            Some(current) if current == i => None,
+            Some(current) if current > i => Some(current - 1),
+            current => current,
+        };
+    }
+
+    /// Takes in how connecting went: pins the key of new servers, drops chats that failed or ended
+    fn check_connections(&mut self) {
+        let mut i = 0;
+        while i < self.chats.len() {
+            let chat = &mut self.chats[i];
+            chat.update();
+            let result = match chat.take_connect_result() {
+                Some(result) => result,
+                None => {
+                    i += 1;
+                    continue;
+                }
+            };
+
+            if let Some(error) = result.error {
+                self.status = format!("{}: {}", chat.name, error);
+                self.close(i);
+                continue;
+            }
+
+            let (name, address) = (chat.name.clone(), chat.server.clone());
+            self.change_profile(|profile| {
+                profile.remember_server(&name, &address);
+                if let Some(fingerprint) = &result.fingerprint {
+                    if profile.get_server(&address).is_some_and(|server| server.fingerprint.is_none()) {
+                        profile.pin_server(&address, fingerprint);
+                    }
+                }
+            });
+            i += 1;
+        }
+    }
+
+    /// Changes the profile on disk and takes in its servers again
+    fn change_profile(&mut self, change: impl FnOnce(&mut Profile)) {
+        let mut store = match ProfileStore::load() {
+            Ok(store) => store,
+            Err(e) => {
+                self.status = e;
+                return;
+            }
+        };
+        if let Some(profile) = store.get_mut(&self.profile) {
+            change(profile);
+            self.servers = profile.servers.clone();
+            self.auto_connect = profile.auto_connect;
+            store.save();
+        }
+    }
+
+    /// The servers we are connected to with their unread messages, and a way back to the server list
+    fn update_server_panel(&mut self, ctx: &egui::Context) {
+        let mut show = None;
+        let mut close = None;
+        egui::SidePanel::left("server_panel").show(ctx, |ui| {
+            ui.label("Servers");
+            ui.separator();
+            for (i, chat) in self.chats.iter().enumerate() {
+                let unread = chat.unread_total();
+                let label = match (chat.is_connected(), unread) {
+                    (false, _) => format!("{} (connecting)", chat.name),
+                    (true, 0) => chat.name.clone(),
+                    (true, unread) => format!("{} ({})", chat.name, unread),
+                };
+                let label = match unread > 0 && self.current != Some(i) {
+                    true => egui::RichText::new(label).strong(),
+                    false => egui::RichText::new(label),
+                };
+                ui.horizontal(|ui| {
+                    if ui.selectable_label(self.current == Some(i), label).clicked() {
+                        show = Some(Some(i));
+                    }
+                    if ui.small_button("x").on_hover_text("Disconnect").clicked() {
+                        close = Some(i);
+                    }
+                });
+            }
+            ui.separator();
+            if ui.selectable_label(self.current.is_none(), "+ Add server").clicked() {
+                show = Some(None);
+            }
+        });
+
+        if let Some(current) = show {
+            self.current = current;
+        }
+        if let Some(i) = close {
+            self.close(i);
+        }
+    }
+
+    /// The welcome screen: the saved servers, a form for another one and the local history passphrase
+    fn update_server_list(&mut self, ctx: &egui::Context) {
+        egui::TopBottomPanel::top("servers_menu").show(ctx, |ui| {
+            ui.horizontal(|ui| {
+                ui.menu_button("Recent servers", |ui| {
+                    if self.servers.is_empty() {
+                        ui.label("None yet");
+                    }
+                    for server in self.servers.clone() {
+                        if ui.button(format!("{} ({})", server.name, server.address)).clicked() {
+                            ui.close_menu();
+                            self.open(server.address);
+                        }
+                    }
+                });
+            });
+        });
+
+        egui::CentralPanel::default().show(ctx, |ui| {
+            ui.heading("Welcome to Yuttari!");
+            if !self.servers.is_empty() {
+                ui.label("Saved servers:");
+                egui::Grid::new("servers").striped(true).show(ui, |ui| {
+                    for server in self.servers.clone() {
+                        ui.label(&server.name);
+                        ui.label(&server.address);
+                        match &server.fingerprint {
+                            Some(fingerprint) => ui.label(egui::RichText::new(fingerprint).monospace()),
+                            None => ui.label(egui::RichText::new("key not pinned yet").weak()),
+                        };
+                        let open = self.chats.iter().any(|chat| chat.server == server.address);
+                        if ui.button(if open { "Show" } else { "Connect" }).clicked() {
+                            self.open(server.address.clone());
+                        }
+                        if ui.add_enabled(!open, egui::Button::new("Forget")).clicked() {
+                            self.change_profile(|profile| profile.forget_server(&server.address));
+                        }
+                        ui.end_row();
+                    }
+                });
+                ui.separator();
+            }
+
+            ui.horizontal(|ui| {
+                ui.label("Name:");
+                ui.text_edit_singleline(&mut self.server_name);
+            });
+            ui.horizontal(|ui| {
+                ui.label("Address:");
+                ui.add(egui::TextEdit::singleline(&mut self.server_address).hint_text("host:port"));
+            });
+            let address = self.server_address.trim().to_string();
+            let address_error = match address.is_empty() {
+                true => None,
+                false => check_address(&address).err(),
+            };
+            if let Some(error) = &address_error {
+                ui.label(egui::RichText::new(error).color(egui::Color32::RED));
+            }
+
+            ui.add(egui::Label::new("Passphrase for the local history, leave empty to keep none:"));
+            ui.add(egui::TextEdit::singleline(&mut self.passphrase).password(true));
+            let mut auto_connect = self.auto_connect;
+            if ui.checkbox(&mut auto_connect, "Connect to the last server on start").changed() {
+                self.change_profile(|profile| profile.auto_connect = auto_connect);
+            }
+            if !self.status.is_empty() {
+                ui.label(egui::RichText::new(&self.status).color(egui::Color32::RED));
+            }
+
+            ui.horizontal(|ui| {
+                let valid = !address.is_empty() && address_error.is_none();
+                if ui.add_enabled(valid, egui::Button::new("Connect")).clicked() {
+                    self.open(address.clone());
+                }
+                if ui.add_enabled(valid, egui::Button::new("Save")).clicked() {
+                    let name = match self.server_name.trim() {
+                        "" => address.clone(),
+                        name => name.to_string(),
+                    };
+                    self.change_profile(|profile| profile.save_server(&name, &address));
+                    self.server_name.clear();
+                    self.server_address.clear();
+                }
+            });
+        });
+    }
+}
+
+impl eframe::App for Servers {
+    fn update(&mut self, ctx: &egui::Context, frame: &mut eframe::Frame) {
+        // the last server is tried once, showing the server list if that fails
+        if !self.auto_connect_tried {
+            self.auto_connect_tried = true;
+            if let Some(server) = self.servers.first().filter(|_| self.auto_connect) {
+                self.passphrase = std::env::var("YUTTARI_PASSPHRASE").unwrap_or_default();
+                self.open(server.address.clone());
+            }
+        }
+
+        self.check_connections();
+        self.update_server_panel(ctx);
+        match self.current {
+            Some(i) if self.chats[i].is_connected() => self.chats[i].update_main_app(ctx, frame),
+            Some(i) => {
+                egui::CentralPanel::default().show(ctx, |ui| {
+                    ui.horizontal(|ui| {
+                        ui.spinner();
+                        ui.label(format!("Connecting to {}…", self.chats[i].server));
+                    });
+                });
+            }
+            None => self.update_server_list(ctx),
+        }
+
+        if !self.chats.is_empty() {
+            ctx.request_repaint_after(POLL_INTERVAL);
+        }
+    }
+}
@@ -1,45 +1,40 @@
-use std::io::Write;
-use common::user::User;
+use common::profile::{Profile, ProfileStore};
 
+#[derive(Clone, Default)]
 pub struct Setup {
     username: String,
-    pub user: User,
+    // the name asked for with --profile, the username otherwise
+    name: Option<String>,
+    error: Option<String>,
 }
 
-impl Clone for Setup {
-    fn clone(&self) -> Self {
+impl Setup {
+    pub fn new(name: Option<String>) -> Self {
         Self {
-            username: self.username.clone(),
-            user: self.user.clone(),
+            name,
+            ..Self::default()
         }
     }
-}
-
-impl Setup {
-    fn setup(&mut self) {
-        let user = User::new(self.username.clone());
-
-        // save the user to a file in the config dir
-        let mut path = common::get_config_dir();
-        path.push_str("/config.yut");
-        let mut file = std::fs::File::create(path).unwrap();
-
-        file.write_all(&user.to_bytes()).unwrap();
-
-        self.user = user;
-    }
 
-    fn get_user(&self) -> User {
-        self.user.clone()
-    }
-}
-
-impl Default for Setup {
-    fn default() -> Self {
-        Self {
-            username: String::new(),
-            user: User::new("".to_string()),
+    /// Saves a new profile for the username, returns false if it could not be made
+    fn setup(&mut self) -> bool {
+        let name = self.name.clone().unwrap_or_else(|| self.username.clone());
+        let mut store = match ProfileStore::load() {
+            Ok(store) => store,
+            Err(e) => {
+                self.error = Some(e);
+                return false;
+            }
+        };
+        if let Err(e) = store.add(Profile::new(&name, &self.username)) {
+            self.error = Some(e);
+            return false;
+        }
+        if self.name.is_none() {
+            store.set_default(&name).unwrap();
         }
+        store.save();
+        true
     }
 }
 
@@ -49,11 +44,13 @@ impl eframe::App for Setup {
             ui.heading("Welcome to Yuttari!");
             ui.add(egui::Label::new("Enter your username:"));
             ui.add(egui::TextEdit::singleline(&mut self.username));
-            if ui.button("Done").clicked() {
-                self.setup();
+            if let Some(error) = &self.error {
+                ui.colored_label(egui::Color32::RED, error);
+            }
+            if ui.button("Done").clicked() && self.setup() {
                 // close the window
                 frame.close();
             }
         });
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,214 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use common::channel::RetentionPolicy;
+use common::permission::{Permission, Role};
+
+use crate::server::Server;
+
+const HELP: &str = "Commands: roles, role <user> <role>, permissions <channel>, \
+permission <channel> <role> <read|post|manage> <allow|deny|default>, kick <user> [reason], \
+mute <user> <duration>, unmute <user>, ban <user> [reason], unban <user>, \
+banip <address or range> [reason], unbanip <address or range>, bans, \
+audit [count] [kind], reload, invites <channel>, revoke <code>, retention <channel>, \
+retention <channel> <count|age|size> <value|off>, retention <channel> memory <count>, \
+retention <channel> disk <on|off>, export <channel> <jsonl|html>, import <channel> <file>, federation, \
+bots, bot add <name> [channels], bot remove <name>, webhooks <channel>, webhook add <channel> <name>, webhook remove <id>";
+
+/// Commands that are moderation actions, outgoing webhooks can subscribe to them
+const MODERATION: &[&str] = &["kick", "mute", "unmute", "ban", "unban", "banip", "unbanip"];
+
+/// Whether a command only reads, everything else is written to the audit log when it succeeds
+fn is_query(args: &[&str]) -> bool {
+    matches!(
+        args,
+        [] | ["help"] | ["roles"] | ["permissions", ..] | ["bans"] | ["audit", ..] | ["invites", ..] | ["retention", _] | ["federation"] | ["bots"] | ["webhooks", _]
+    )
+}
+
+/// Parses sizes like `512`, `64K`, `10M` or `1G`, a bare number is bytes
+fn parse_size(size: &str) -> Option<u64> {
+    let (number, unit) = match size.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => size.split_at(i),
+        None => (size, ""),
+    };
+    let number: u64 = number.parse().ok()?;
+
+    let bytes = match unit.to_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" => 1024,
+        "M" | "MB" => 1024 * 1024,
+        "G" | "GB" => 1024 * 1024 * 1024,
+        _ => return None,
+    };
+    number.checked_mul(bytes)
+}
+
+/// Changes one setting of a channel's retention, `off` lifts a limit
+fn set_retention(server: &mut Server, sender: SocketAddr, channel: &str, setting: &str, value: &str) -> Result<String, String> {
+    let (mut retention, mut max_messages, mut backup_messages): (RetentionPolicy, usize, bool) = match server.get_retention(channel) {
+        Some(retention) => retention,
+        None => return Err(format!("There is no channel {}", channel)),
+    };
+    let invalid = || format!("{} is not a valid {}", value, setting);
+
+    match (setting, value) {
+        ("count", "off") => retention.max_messages = None,
+        ("count", count) => retention.max_messages = Some(count.parse().map_err(|_| invalid())?),
+        ("age", "off") => retention.max_age = None,
+        ("age", age) => retention.max_age = Some(parse_duration(age).ok_or_else(invalid)?.as_secs()),
+        ("size", "off") => retention.max_size = None,
+        ("size", size) => retention.max_size = Some(parse_size(size).ok_or_else(invalid)?),
+        ("memory", count) => max_messages = count.parse().map_err(|_| invalid())?,
+        ("disk", "on") => backup_messages = true,
+        ("disk", "off") => backup_messages = false,
+        _ => return Err(format!("Unknown retention setting {} {}", setting, value)),
+    }
+
+    server.set_retention(sender, channel, retention, max_messages, backup_messages)
+}
+
+/// Parses durations like `90`, `30s`, `10m`, `2h` or `1d`, a bare number is seconds
+fn parse_duration(duration: &str) -> Option<Duration> {
+    let (number, unit) = match duration.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => duration.split_at(i),
+        None => (duration, "s"),
+    };
+    let number: u64 = number.parse().ok()?;
+
+    let seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return None,
+    };
+    Some(Duration::from_secs(number.checked_mul(seconds)?))
+}
+
+/// Everything after the first `skip` words, the reason given for a moderation action
+fn reason(args: &[&str], skip: usize) -> String {
+    match args.len() > skip {
+        true => args[skip..].join(" "),
+        false => "no reason given".to_string(),
+    }
+}
+
+/// Parses an admin command and runs it on behalf of the client at `sender`,
+/// moderation notices go to the channel the command was sent from
+pub fn run(server: &mut Server, sender: SocketAddr, channel: &str, command: &str) -> String {
+    let args: Vec<&str> = command.split_whitespace().collect();
+
+    let result = match args.as_slice() {
+        [] | ["help"] => Ok(HELP.to_string()),
+        ["roles"] => server.list_roles(sender),
+        ["role", username, role] => match Role::from_name(role) {
+            Some(role) => server.set_role(sender, username, role),
+            None => Err(format!("Unknown role {}", role)),
+        },
+        ["permissions", channel] => server.list_permissions(channel),
+        ["permission", channel, role, permission, value] => {
+            let role = Role::from_name(role).ok_or(format!("Unknown role {}", role));
+            let permission = Permission::from_name(permission).ok_or(format!("Unknown permission {}", permission));
+            let allow = match *value {
+                "allow" => Ok(Some(true)),
+                "deny" => Ok(Some(false)),
+                "default" => Ok(None),
+                _ => Err(format!("Expected allow, deny or default, got {}", value)),
+            };
+
+            match (role, permission, allow) {
+                (Ok(role), Ok(permission), Ok(allow)) => server.set_permission(sender, channel, role, permission, allow),
+                (Err(e), _, _) | (_, Err(e), _) | (_, _, Err(e)) => Err(e),
+            }
+        }
+        ["kick", username, ..] => server.kick(sender, channel, username, &reason(&args, 2)),
+        ["mute", username, duration] => match parse_duration(duration) {
+            Some(duration) => server.mute(sender, channel, username, duration),
+            None => Err(format!("{} is not a duration, try 30s, 10m, 2h or 1d", duration)),
+        },
+        ["unmute", username] => server.unmute(sender, channel, username),
+        ["ban", username, ..] => server.ban_user(sender, channel, username, &reason(&args, 2)),
+        ["unban", username] => server.unban_user(sender, channel, username),
+        ["banip", network, ..] => server.ban_network(sender, channel, network, &reason(&args, 2)),
+        ["unbanip", network] => server.unban_network(sender, channel, network),
+        ["bans"] => server.list_bans(sender),
+        ["audit"] => server.query_audit(sender, None, 20),
+        ["audit", count] => match count.parse() {
+            Ok(count) => server.query_audit(sender, None, count),
+            // a single word that is not a number is a kind
+            Err(_) => server.query_audit(sender, Some(count), 20),
+        },
+        ["audit", count, kind] => match count.parse() {
+            Ok(count) => server.query_audit(sender, Some(kind), count),
+            Err(_) => Err(format!("{} is not a number", count)),
+        },
+        ["reload"] => server.reload_config(sender),
+        ["invites", channel] => server.list_invites(sender, channel),
+        ["revoke", code] => server.revoke_invite(sender, code),
+        ["retention", channel] => server.describe_retention(sender, channel),
+        ["retention", channel, setting, value] => set_retention(server, sender, channel, setting, value),
+        ["federation"] => server.describe_federation(sender),
+        ["export", channel, format] => server.export_channel(sender, channel, format),
+        ["import", channel, file] => server.import_channel(sender, channel, file),
+        ["bots"] => server.list_bots(sender),
+        ["bot", "add", name, channels @ ..] => server.add_bot(sender, name, channels),
+        ["bot", "remove", name] => server.remove_bot(sender, name),
+        ["webhooks", channel] => server.list_webhooks(sender, channel),
+        ["webhook", "add", channel, name] => server.add_webhook(sender, channel, name),
+        ["webhook", "remove", id] => server.remove_webhook(sender, id),
+        _ => Err(format!("Unknown command \"{}\". {}", command, HELP)),
+    };
+
+    let name = args.first().copied().unwrap_or_default();
+    if result.is_ok() && !is_query(&args) {
+        let actor = server.get_actor(sender);
+        server.record_audit(name, &actor, format!("{} in {}", command.trim(), channel));
+    }
+    if result.is_ok() && MODERATION.contains(&name) {
+        // the target always comes first, a reason or duration may follow
+        let target = args.get(1).copied().unwrap_or_default();
+        let detail = args.get(2..).map(|rest| rest.join(" ")).unwrap_or_default();
+        server.emit_moderation(sender, name, channel, target, &detail);
+    }
+
+    match result {
+        Ok(output) => output,
+        Err(error) => format!("Error: {}", error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("90"), Some(Duration::from_secs(90)));
+        assert_eq!(parse_duration("30s"), Some(Duration::from_secs(30)));
+        assert_eq!(parse_duration("10m"), Some(Duration::from_secs(600)));
+        assert_eq!(parse_duration("2h"), Some(Duration::from_secs(7200)));
+        assert_eq!(parse_duration("1d"), Some(Duration::from_secs(86400)));
+
+        assert_eq!(parse_duration(""), None);
+        assert_eq!(parse_duration("m"), None);
+        assert_eq!(parse_duration("5w"), None);
+        assert_eq!(parse_duration("-5m"), None);
+        // too many days to count in seconds
+        assert_eq!(parse_duration(&format!("{}d", u64::MAX)), None);
+    }
+
+    #[test]
+    fn parses_sizes() {
+        assert_eq!(parse_size("512"), Some(512));
+        assert_eq!(parse_size("512B"), Some(512));
+        assert_eq!(parse_size("64K"), Some(64 * 1024));
+        assert_eq!(parse_size("10mb"), Some(10 * 1024 * 1024));
+        assert_eq!(parse_size("1G"), Some(1024 * 1024 * 1024));
+
+        assert_eq!(parse_size(""), None);
+        assert_eq!(parse_size("K"), None);
+        assert_eq!(parse_size("3T"), None);
+        assert_eq!(parse_size(&format!("{}G", u64::MAX / 2)), None);
+    }
+}
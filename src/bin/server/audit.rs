@@ -0,0 +1,80 @@
+use std::fs::OpenOptions;
+use std::io::{BufRead, BufReader, Write};
+
+use common::id::{self, IdType};
+use log::error;
+use serde::{Deserialize, Serialize};
+
+const AUDIT_PATH: &str = "data/audit.log";
+
+/// One line of the audit log
+#[derive(Serialize, Deserialize, Clone)]
+pub struct AuditEvent {
+    pub id: u64,
+    // unix seconds, taken from the id
+    pub timestamp: u64,
+    pub kind: String,
+    // the username behind the event, or the address if nobody logged in yet
+    pub actor: String,
+    pub detail: String,
+}
+
+impl AuditEvent {
+    pub fn new(kind: &str, actor: &str, detail: String) -> AuditEvent {
+        let id = id::create_id(IdType::Unknown);
+        AuditEvent {
+            id,
+            timestamp: id::to_timestamp(id),
+            kind: kind.to_string(),
+            actor: actor.to_string(),
+            detail,
+        }
+    }
+}
+
+impl std::fmt::Display for AuditEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {} {}: {}", id::to_timestamp_string(self.id), self.kind, self.actor, self.detail)
+    }
+}
+
+/// An append-only log of administrative and security events, one json document per line.
+/// It is kept apart from the debug output so it can be kept and searched on its own
+#[derive(Default)]
+pub struct AuditLog;
+
+impl AuditLog {
+    pub fn record(&self, event: AuditEvent) {
+        let line = match serde_json::to_string(&event) {
+            Ok(line) => line,
+            Err(e) => {
+                error!("Error serializing an audit event: {}", e);
+                return;
+            }
+        };
+
+        std::fs::create_dir_all("data").unwrap();
+        let file = OpenOptions::new().create(true).append(true).open(AUDIT_PATH);
+        if let Err(e) = file.and_then(|mut file| writeln!(file, "{}", line)) {
+            error!("Error writing the audit log: {}", e);
+        }
+    }
+
+    /// Gets the latest events, oldest first, optionally only those of one kind
+    pub fn query(&self, kind: Option<&str>, count: usize) -> Vec<AuditEvent> {
+        let file = match std::fs::File::open(AUDIT_PATH) {
+            Ok(file) => file,
+            Err(_) => return Vec::new(),
+        };
+
+        let events: Vec<AuditEvent> = BufReader::new(file)
+            .lines()
+            .map_while(Result::ok)
+            .filter_map(|line| serde_json::from_str::<AuditEvent>(&line).ok())
+            .filter(|event| kind.is_none_or(|kind| event.kind == kind))
+            .collect();
+
+        let skip = events.len().saturating_sub(count);
+        events.into_iter().skip(skip).collect()
+    }
+}
@@ -0,0 +1,219 @@
+use std::net::IpAddr;
+
+use log::error;
+use serde::{Deserialize, Serialize};
+
+const BANS_PATH: &str = "data/bans.bson";
+
+/// An address or a range of addresses written as `10.0.0.0/8`, a bare address is a range of one
+#[derive(Clone, Copy, PartialEq)]
+pub struct Network {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Network {
+    pub fn parse(network: &str) -> Option<Network> {
+        let (addr, prefix) = match network.split_once('/') {
+            Some((addr, prefix)) => (addr.parse::<IpAddr>().ok()?, Some(prefix.parse::<u8>().ok()?)),
+            None => (network.parse::<IpAddr>().ok()?, None),
+        };
+        let addr = addr.to_canonical();
+
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = prefix.unwrap_or(max);
+        if prefix > max {
+            return None;
+        }
+
+        Some(Network { addr, prefix })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // an ipv4 client on a dual stack socket shows up as ::ffff:a.b.c.d
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl std::fmt::Display for Network {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub enum BanTarget {
+    User(u64),
+    // kept as written, see Network::parse
+    Network(String),
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Ban {
+    pub target: BanTarget,
+    // who the ban is for and who issued it, for listing bans
+    pub name: String,
+    pub by: String,
+    pub reason: String,
+}
+
+/// The on-disk layout of the ban list, bson needs a document at the top level
+#[derive(Serialize, Deserialize, Default)]
+struct BanFile {
+    bans: Vec<Ban>,
+}
+
+#[derive(Default)]
+pub struct BanList {
+    bans: Vec<Ban>,
+}
+
+impl BanList {
+    /// Loads the bans, only a missing file starts an empty list
+    pub fn load() -> Result<BanList, String> {
+        let file: BanFile = match std::fs::read(BANS_PATH) {
+            Ok(data) => bson::from_slice(&data).map_err(|e| format!("{} is damaged: {}", BANS_PATH, e))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BanFile::default(),
+            Err(e) => return Err(format!("Could not read {}: {}", BANS_PATH, e)),
+        };
+
+        Ok(BanList { bans: file.bans })
+    }
+
+    pub fn save(&self) {
+        let file = BanFile {
+            bans: self.bans.clone(),
+        };
+
+        let data = match bson::to_vec(&file) {
+            Ok(data) => data,
+            Err(e) => {
+                error!("Error serializing the ban list: {}", e);
+                return;
+            }
+        };
+
+        std::fs::create_dir_all("data").unwrap();
+        if let Err(e) = std::fs::write(BANS_PATH, data) {
+            error!("Error saving the ban list: {}", e);
+        }
+    }
+
+    pub fn all(&self) -> &[Ban] {
+        &self.bans
+    }
+
+    /// Adds a ban, replacing an earlier ban of the same target
+    pub fn ban(&mut self, ban: Ban) {
+        self.bans.retain(|b| b.target != ban.target);
+        self.bans.push(ban);
+        self.save();
+    }
+
+    /// Lifts a ban, returns false if the target was not banned
+    pub fn unban(&mut self, target: &BanTarget) -> bool {
+        let count = self.bans.len();
+        self.bans.retain(|b| &b.target != target);
+        if self.bans.len() == count {
+            return false;
+        }
+
+        self.save();
+        true
+    }
+
+    pub fn is_user_banned(&self, id: u64) -> bool {
+        self.bans.iter().any(|ban| ban.target == BanTarget::User(id))
+    }
+
+    pub fn is_ip_banned(&self, ip: IpAddr) -> bool {
+        self.bans.iter().any(|ban| match &ban.target {
+            BanTarget::Network(network) => Network::parse(network).is_some_and(|network| network.contains(ip)),
+            BanTarget::User(_) => false,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    fn ban(target: BanTarget) -> Ban {
+        Ban {
+            target,
+            name: String::new(),
+            by: "alice".to_string(),
+            reason: "spam".to_string(),
+        }
+    }
+
+    #[test]
+    fn parses_addresses_and_ranges() {
+        assert_eq!(Network::parse("10.0.0.0/8").unwrap().to_string(), "10.0.0.0/8");
+        assert_eq!(Network::parse("192.168.1.7").unwrap().to_string(), "192.168.1.7/32");
+        assert_eq!(Network::parse("2001:db8::/32").unwrap().to_string(), "2001:db8::/32");
+        // an ipv4 address written as ipv6 is the ipv4 address
+        assert_eq!(Network::parse("::ffff:10.1.2.3").unwrap().to_string(), "10.1.2.3/32");
+
+        assert!(Network::parse("10.0.0.0/33").is_none());
+        assert!(Network::parse("2001:db8::/129").is_none());
+        assert!(Network::parse("10.0.0.0/").is_none());
+        assert!(Network::parse("example.com").is_none());
+    }
+
+    #[test]
+    fn ranges_contain_their_addresses() {
+        let network = Network::parse("10.0.0.0/8").unwrap();
+        assert!(network.contains(ip("10.255.1.2")));
+        assert!(!network.contains(ip("11.0.0.1")));
+        // a dual stack socket reports ipv4 clients as mapped addresses
+        assert!(network.contains(ip("::ffff:10.0.0.1")));
+        assert!(!network.contains(ip("::1")));
+
+        let single = Network::parse("127.0.0.1").unwrap();
+        assert!(single.contains(ip("127.0.0.1")));
+        assert!(!single.contains(ip("127.0.0.2")));
+
+        let everything = Network::parse("0.0.0.0/0").unwrap();
+        assert!(everything.contains(ip("203.0.113.9")));
+
+        let v6 = Network::parse("2001:db8::/32").unwrap();
+        assert!(v6.contains(ip("2001:db8:1::5")));
+        assert!(!v6.contains(ip("2001:db9::5")));
+        assert!(!v6.contains(ip("10.0.0.1")));
+    }
+
+    #[test]
+    fn bans_match_users_and_networks() {
+        let bans = BanList {
+            bans: vec![ban(BanTarget::User(7)), ban(BanTarget::Network("192.168.0.0/16".to_string()))],
+        };
+
+        assert!(bans.is_user_banned(7));
+        assert!(!bans.is_user_banned(8));
+        assert!(bans.is_ip_banned(ip("192.168.4.20")));
+        assert!(!bans.is_ip_banned(ip("192.169.0.1")));
+    }
+
+    #[test]
+    fn damaged_networks_ban_nobody() {
+        let bans = BanList {
+            bans: vec![ban(BanTarget::Network("not a network".to_string()))],
+        };
+        assert!(!bans.is_ip_banned(ip("127.0.0.1")));
+    }
+}
@@ -0,0 +1,313 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use common::attachment::{is_valid_hash, AttachmentChunkPayload, AttachmentMeta, AttachmentStatusPayload, CHUNK_SIZE};
+use common::id::{create_id, IdType};
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use log::{debug, error};
+use serde::{Deserialize, Serialize};
+
+use crate::config::ServerConfig;
+
+const BLOB_DIR: &str = "data/blobs";
+const INDEX_PATH: &str = "data/blobs/index.bson";
+// an upload that gets no chunk for this long is dropped, it stops counting towards the quota
+const UPLOAD_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct StoredAttachment {
+    pub meta: AttachmentMeta,
+    // the id of the user that uploaded it, quotas are counted per user
+    pub owner: u64,
+    // the channels it was posted in, their readers may download it
+    #[serde(default)]
+    pub channels: Vec<String>,
+}
+
+/// The on-disk layout of the index, bson needs a document at the top level
+#[derive(Serialize, Deserialize, Default)]
+struct BlobIndex {
+    attachments: Vec<StoredAttachment>,
+}
+
+/// An upload in progress, the partial file lives in data/blobs/partial
+struct Upload {
+    attachment: StoredAttachment,
+    // when the last chunk came in, or the offer when none did yet
+    last_active: Instant,
+    // the hash of what is in the partial file so far, each chunk is added as it is written
+    hasher: Sha256,
+}
+
+/// Attachments stored under data/blobs, named by their hash so the same file is only kept once
+#[derive(Default)]
+pub struct BlobStore {
+    attachments: HashMap<u64, StoredAttachment>,
+    uploads: HashMap<u64, Upload>,
+}
+
+impl BlobStore {
+    pub fn load() -> BlobStore {
+        let index: BlobIndex = match std::fs::read(INDEX_PATH) {
+            Ok(data) => bson::from_slice(&data).unwrap_or_default(),
+            Err(_) => BlobIndex::default(),
+        };
+
+        BlobStore {
+            attachments: index.attachments.into_iter().map(|a| (a.meta.id, a)).collect(),
+            uploads: HashMap::new(),
+        }
+    }
+
+    fn save(&self) {
+        let index = BlobIndex {
+            attachments: self.attachments.values().cloned().collect(),
+        };
+
+        match bson::to_vec(&index) {
+            Ok(data) => {
+                std::fs::create_dir_all(BLOB_DIR).unwrap();
+                if let Err(e) = std::fs::write(INDEX_PATH, data) {
+                    error!("Error saving the attachment index: {}", e);
+                }
+            }
+            Err(e) => error!("Error serializing the attachment index: {}", e),
+        }
+    }
+
+    pub fn get(&self, id: u64) -> Option<&AttachmentMeta> {
+        self.attachments.get(&id).map(|a| &a.meta)
+    }
+
+    pub fn get_stored(&self, id: u64) -> Option<&StoredAttachment> {
+        self.attachments.get(&id)
+    }
+
+    /// Records that an attachment was posted in a channel
+    pub fn post(&mut self, id: u64, channel: &str) {
+        if let Some(attachment) = self.attachments.get_mut(&id) {
+            if !attachment.channels.iter().any(|posted| posted == channel) {
+                attachment.channels.push(channel.to_string());
+                self.save();
+            }
+        }
+    }
+
+    /// Keeps the attachments of a renamed channel readable to its members
+    pub fn rename_channel(&mut self, old: &str, new: &str) {
+        for attachment in self.attachments.values_mut() {
+            for channel in attachment.channels.iter_mut().filter(|channel| *channel == old) {
+                *channel = new.to_string();
+            }
+        }
+        self.save();
+    }
+
+    /// Takes a channel off the attachments its messages no longer have, `kept` being the ones
+    /// they still do. An attachment no channel has anymore is deleted
+    pub fn release(&mut self, channel: &str, kept: &HashSet<u64>) {
+        let released: Vec<u64> = self
+            .attachments
+            .values()
+            .filter(|a| a.channels.iter().any(|posted| posted == channel) && !kept.contains(&a.meta.id))
+            .map(|a| a.meta.id)
+            .collect();
+        if released.is_empty() {
+            return;
+        }
+
+        for id in released {
+            let attachment = self.attachments.get_mut(&id).unwrap();
+            attachment.channels.retain(|posted| posted != channel);
+            if !attachment.channels.is_empty() {
+                continue;
+            }
+
+            let hash = self.attachments.remove(&id).unwrap().meta.hash;
+            debug!("Attachment {} is in no channel anymore, deleting it", id);
+            // other users' uploads of the same file share the blob
+            if !self.attachments.values().any(|a| a.meta.hash == hash) {
+                if let Err(e) = std::fs::remove_file(blob_path(&hash)) {
+                    error!("Error deleting attachment {}: {}", id, e);
+                }
+            }
+        }
+        self.save();
+    }
+
+    /// Reads the chunk of a stored attachment that starts at `offset`, it is empty at the end
+    pub fn read_chunk(&self, id: u64, offset: u64) -> Option<AttachmentChunkPayload> {
+        let attachment = self.attachments.get(&id)?;
+        let offset = offset.min(attachment.meta.size);
+        let mut data = vec![0; (attachment.meta.size - offset).min(CHUNK_SIZE as u64) as usize];
+
+        let read = File::open(blob_path(&attachment.meta.hash)).and_then(|mut file| {
+            file.seek(SeekFrom::Start(offset))?;
+            file.read_exact(&mut data)
+        });
+        match read {
+            Ok(()) => Some(AttachmentChunkPayload::new(id, offset, data)),
+            Err(e) => {
+                error!("Error reading attachment {}: {}", id, e);
+                None
+            }
+        }
+    }
+
+    fn used(&self, owner: u64) -> u64 {
+        self.attachments
+            .values()
+            .chain(self.uploads.values().map(|upload| &upload.attachment))
+            .filter(|a| a.owner == owner)
+            .map(|a| a.meta.size)
+            .sum()
+    }
+
+    /// Drops the uploads that were given up on, with what they left on disk
+    fn expire_uploads(&mut self) {
+        self.uploads.retain(|id, upload| {
+            if upload.last_active.elapsed() < UPLOAD_TIMEOUT {
+                return true;
+            }
+            debug!("Upload {} got no chunk for too long, dropping it", id);
+            let _ = std::fs::remove_file(part_path(upload.attachment.owner, &upload.attachment.meta.hash));
+            false
+        });
+    }
+
+    /// Starts an upload, or tells the client where to resume when part of it is already here
+    pub fn offer(&mut self, owner: u64, mut meta: AttachmentMeta, config: &ServerConfig) -> AttachmentStatusPayload {
+        if !is_valid_hash(&meta.hash) {
+            return AttachmentStatusPayload::error(meta.hash, "invalid hash".to_string());
+        }
+        self.expire_uploads();
+
+        // the same user sending the same file again gets the stored copy
+        if let Some(existing) = self.attachments.values().find(|a| a.owner == owner && a.meta.hash == meta.hash) {
+            return AttachmentStatusPayload::new(existing.meta.id, meta.hash, meta.size, true);
+        }
+
+        // an interrupted upload of this file resumes where it stopped
+        if let Some(upload) = self.uploads.values_mut().find(|upload| upload.attachment.owner == owner && upload.attachment.meta.hash == meta.hash) {
+            upload.last_active = Instant::now();
+            let received = part_size(owner, &meta.hash);
+            return AttachmentStatusPayload::new(upload.attachment.meta.id, meta.hash, received, false);
+        }
+
+        if meta.size == 0 {
+            return AttachmentStatusPayload::error(meta.hash, "empty attachment".to_string());
+        }
+
+        if meta.size > config.max_attachment_size {
+            return AttachmentStatusPayload::error(meta.hash, format!("attachments are limited to {} bytes", config.max_attachment_size));
+        }
+
+        if self.used(owner) + meta.size > config.attachment_quota {
+            return AttachmentStatusPayload::error(meta.hash, "attachment quota exceeded".to_string());
+        }
+
+        meta.id = create_id(IdType::Attachment);
+        // a partial file may be left over from before a restart
+        let received = part_size(owner, &meta.hash);
+        let hasher = hash_file(&part_path(owner, &meta.hash));
+        let status = AttachmentStatusPayload::new(meta.id, meta.hash.clone(), received, false);
+        let attachment = StoredAttachment { meta, owner, channels: Vec::new() };
+        self.uploads.insert(attachment.meta.id, Upload { attachment, last_active: Instant::now(), hasher });
+
+        status
+    }
+
+    /// Appends a chunk to an upload, the upload is stored once it is complete and its hash matches
+    pub fn receive(&mut self, owner: u64, chunk: AttachmentChunkPayload) -> AttachmentStatusPayload {
+        let upload = match self.uploads.get_mut(&chunk.id) {
+            Some(upload) if upload.attachment.owner == owner => upload,
+            _ => return AttachmentStatusPayload::error(String::new(), "unknown upload".to_string()),
+        };
+        upload.last_active = Instant::now();
+        let hash = upload.attachment.meta.hash.clone();
+        let size = upload.attachment.meta.size;
+
+        let received = part_size(owner, &hash);
+        if chunk.offset != received {
+            // tell the client where to pick up again
+            return AttachmentStatusPayload::new(chunk.id, hash, received, false);
+        }
+
+        if received + chunk.data.len() as u64 > size {
+            return AttachmentStatusPayload::error(hash, "upload is larger than offered".to_string());
+        }
+
+        let path = part_path(owner, &hash);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let written = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .and_then(|mut file| file.write_all(&chunk.data));
+        if let Err(e) = written {
+            error!("Error writing upload {}: {}", chunk.id, e);
+            return AttachmentStatusPayload::error(hash, "could not store the upload".to_string());
+        }
+        upload.hasher.input(&chunk.data);
+
+        let received = received + chunk.data.len() as u64;
+        if received < size {
+            return AttachmentStatusPayload::new(chunk.id, hash, received, false);
+        }
+
+        let mut upload = self.uploads.remove(&chunk.id).unwrap();
+        if upload.hasher.result_str() != hash {
+            let _ = std::fs::remove_file(&path);
+            return AttachmentStatusPayload::error(hash, "hash mismatch, upload discarded".to_string());
+        }
+
+        if let Err(e) = std::fs::rename(&path, blob_path(&hash)) {
+            error!("Error storing attachment {}: {}", chunk.id, e);
+            return AttachmentStatusPayload::error(hash, "could not store the upload".to_string());
+        }
+
+        self.attachments.insert(chunk.id, upload.attachment);
+        self.save();
+
+        AttachmentStatusPayload::new(chunk.id, hash, received, true)
+    }
+}
+
+fn blob_path(hash: &str) -> PathBuf {
+    PathBuf::from(BLOB_DIR).join(hash)
+}
+
+fn part_path(owner: u64, hash: &str) -> PathBuf {
+    PathBuf::from(BLOB_DIR).join("partial").join(format!("{}-{}", owner, hash))
+}
+
+fn part_size(owner: u64, hash: &str) -> u64 {
+    std::fs::metadata(part_path(owner, hash)).map(|m| m.len()).unwrap_or(0)
+}
+
+/// Hashes what is in a file a chunk at a time, nothing when there is no file
+fn hash_file(path: &Path) -> Sha256 {
+    let mut hasher = Sha256::new();
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(_) => return hasher,
+    };
+
+    let mut buffer = vec![0; CHUNK_SIZE];
+    loop {
+        match file.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) => hasher.input(&buffer[..read]),
+            Err(e) => {
+                // the hash won't match and the upload is discarded once it is complete
+                error!("Error reading {}: {}", path.display(), e);
+                break;
+            }
+        }
+    }
+    hasher
+}
@@ -1,27 +1,63 @@
-use std::{sync::Arc, net::SocketAddr};
-use bytes::Bytes;
-use futures::SinkExt;
+use std::{io, sync::Arc, net::SocketAddr};
+use bytes::{Bytes, BytesMut};
+use futures::{SinkExt, StreamExt};
 use log::debug;
 use tokio::sync::{mpsc, Mutex};
 
 use common::user::User;
 use tokio::net::TcpStream;
-use tokio_util::codec::{BytesCodec, Framed};
+use tokio_util::codec::{Decoder, Encoder, Framed, LengthDelimitedCodec};
 use x25519_dalek::{PublicKey, StaticSecret};
 
 use crate::server::{Server, Rx};
+use crate::websocket::WebSocketCodec;
 
-pub struct Client {
-    pub bytes: Framed<TcpStream, BytesCodec>,
+/// How a connection cuts its bytes into messages, LengthDelimitedCodec on raw TCP
+/// and WebSocketCodec on the gateway, everything above it is shared
+pub trait Frames: Decoder<Item = BytesMut, Error = io::Error> + Encoder<Bytes, Error = io::Error> + Send + Unpin + 'static {
+    /// Frames the codec has to answer on its own, like a WebSocket pong, already encoded
+    fn take_replies(&mut self) -> Option<BytesMut> {
+        None
+    }
+}
+
+impl Frames for LengthDelimitedCodec {}
+
+impl Frames for WebSocketCodec {
+    fn take_replies(&mut self) -> Option<BytesMut> {
+        self.take_replies()
+    }
+}
+
+/// The next message off a connection, the empty items a codec yields after it
+/// queued a reply are answered here and never reach the caller
+pub async fn next_frame<C: Frames>(bytes: &mut Framed<TcpStream, C>) -> Option<io::Result<BytesMut>> {
+    loop {
+        match bytes.next().await {
+            Some(Ok(frame)) if frame.is_empty() => {
+                if let Some(replies) = bytes.codec_mut().take_replies() {
+                    bytes.write_buffer_mut().unsplit(replies);
+                    if let Err(e) = bytes.flush().await {
+                        return Some(Err(e));
+                    }
+                }
+            }
+            result => return result,
+        }
+    }
+}
+
+pub struct Client<C: Frames> {
+    pub bytes: Framed<TcpStream, C>,
     pub rx: Rx,
     pub shared_key: Vec<u8>,
 }
 
-impl Client {
+impl<C: Frames> Client<C> {
     pub async fn new(
         server: Arc<Mutex<Server>>,
-        bytes: Framed<TcpStream, BytesCodec>,
-    ) -> std::io::Result<Client> {
+        bytes: Framed<TcpStream, C>,
+    ) -> std::io::Result<Client<C>> {
         let addr = bytes.get_ref().peer_addr()?;
 
         let (tx, rx) = mpsc::unbounded_channel();
@@ -0,0 +1,100 @@
+use log::error;
+use serde::{Deserialize, Serialize};
+
+const CONFIG_PATH: &str = "data/config.json";
+
+/// Server settings, read from data/config.json, anything missing takes its default
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct ServerConfig {
+    // the largest single attachment, in bytes
+    pub max_attachment_size: u64,
+    // how many bytes of attachments a single user may store
+    pub attachment_quota: u64,
+    // how often channel retention policies are applied, in seconds
+    pub retention_interval: u64,
+    // where to accept WebSocket connections, like 127.0.0.1:1235, none turns the gateway off
+    pub websocket_address: Option<String>,
+    // where to answer the bot API and incoming webhooks, none turns them off
+    pub http_address: Option<String>,
+    // what other servers call this one, remote users see local users as user@server_name
+    pub server_name: String,
+    // the servers this one federates with
+    pub peers: Vec<PeerConfig>,
+    // where to post events as they happen
+    pub outgoing_webhooks: Vec<OutgoingWebhookConfig>,
+    // the plugins to run, in the order their filters apply
+    pub plugins: Vec<PluginConfig>,
+}
+
+/// A URL the server posts events to, signed with its secret
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct OutgoingWebhookConfig {
+    // only http:// URLs
+    pub url: String,
+    pub secret: String,
+    // message, user_joined or moderation, empty for all of them
+    pub events: Vec<String>,
+    // the channels whose events are posted, empty for every channel
+    pub channels: Vec<String>,
+}
+
+/// A server this one federates with
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct PeerConfig {
+    // the server_name of the peer
+    pub name: String,
+    // where to reach it, when both ends have one only the end whose name sorts first dials
+    pub address: Option<String>,
+    // the peer's identity key from its data/server.pub, base64
+    pub public_key: String,
+    // the channels shared with it, they have to exist on both ends
+    pub channels: Vec<String>,
+}
+
+/// A plugin to run inside the server
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct PluginConfig {
+    // echo, roll or profanity
+    pub name: String,
+    // the channels it acts in, empty for every channel that isn't private
+    pub channels: Vec<String>,
+    // whatever the plugin takes, the profanity filter reads words, action and exempt
+    pub settings: serde_json::Value,
+}
+
+impl ServerConfig {
+    pub fn load() -> ServerConfig {
+        let data = match std::fs::read_to_string(CONFIG_PATH) {
+            Ok(data) => data,
+            Err(_) => return ServerConfig::default(),
+        };
+
+        match serde_json::from_str(&data) {
+            Ok(config) => config,
+            Err(e) => {
+                error!("Error reading {}, using the defaults: {}", CONFIG_PATH, e);
+                ServerConfig::default()
+            }
+        }
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        ServerConfig {
+            max_attachment_size: 25 * 1024 * 1024,
+            attachment_quota: 250 * 1024 * 1024,
+            retention_interval: 60 * 60,
+            websocket_address: None,
+            http_address: None,
+            server_name: "localhost".to_string(),
+            peers: Vec::new(),
+            outgoing_webhooks: Vec::new(),
+            plugins: Vec::new(),
+        }
+    }
+}
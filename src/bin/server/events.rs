@@ -0,0 +1,338 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use common::crypt;
+use common::id::{self, IdType};
+use log::{debug, error};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+
+use crate::http;
+use crate::server::Server;
+
+const QUEUE_PATH: &str = "data/deliveries.bson";
+// how often the queue is checked for deliveries that are due
+const DELIVERY_INTERVAL: Duration = Duration::from_secs(1);
+// a delivery is dropped after failing this many times
+const MAX_ATTEMPTS: u32 = 10;
+// the wait before the first retry in seconds, it doubles with every failure up to MAX_RETRY_DELAY
+const FIRST_RETRY_DELAY: u64 = 5;
+const MAX_RETRY_DELAY: u64 = 60 * 60;
+// the oldest deliveries are dropped when an endpoint is down for long enough to queue more
+const MAX_QUEUED: usize = 10_000;
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+/// What happened on the server, outgoing webhooks subscribe to these by name
+#[derive(Clone, Copy, PartialEq)]
+pub enum EventKind {
+    Message,
+    UserJoined,
+    Moderation,
+}
+
+impl EventKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            EventKind::Message => "message",
+            EventKind::UserJoined => "user_joined",
+            EventKind::Moderation => "moderation",
+        }
+    }
+}
+
+/// The JSON body of an event, every subscriber gets the same one
+#[derive(Serialize)]
+struct Event<'a> {
+    id: u64,
+    kind: &'a str,
+    timestamp: u64,
+    data: serde_json::Value,
+}
+
+/// Makes the body of an event
+pub fn event_body(kind: EventKind, data: serde_json::Value) -> String {
+    let id = id::create_id(IdType::Unknown);
+    let event = Event {
+        id,
+        kind: kind.name(),
+        timestamp: id::to_timestamp(id),
+        data,
+    };
+    serde_json::to_string(&event).unwrap()
+}
+
+/// An event waiting to be posted to one URL
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Delivery {
+    pub id: u64,
+    pub url: String,
+    pub kind: String,
+    pub body: String,
+    pub attempts: u32,
+    // unix seconds
+    pub next_attempt: u64,
+}
+
+/// The on-disk layout of the queue, bson needs a document at the top level
+#[derive(Serialize, Deserialize, Default)]
+struct QueueFile {
+    deliveries: Vec<Delivery>,
+}
+
+/// Events that still have to be posted, kept on disk so a restart doesn't lose them
+#[derive(Default)]
+pub struct DeliveryQueue {
+    deliveries: Vec<Delivery>,
+    // whether the queue changed since it was last written, it is written by the delivery
+    // task and not on every event
+    changed: bool,
+}
+
+impl DeliveryQueue {
+    pub fn load() -> DeliveryQueue {
+        let file: QueueFile = match std::fs::read(QUEUE_PATH) {
+            Ok(data) => bson::from_slice(&data).unwrap_or_default(),
+            Err(_) => QueueFile::default(),
+        };
+
+        DeliveryQueue {
+            deliveries: file.deliveries,
+            changed: false,
+        }
+    }
+
+    /// The queue as it has to be written, None when it didn't change since the last time
+    pub fn changes(&mut self) -> Option<Vec<u8>> {
+        if !self.changed {
+            return None;
+        }
+        self.changed = false;
+
+        let file = QueueFile {
+            deliveries: self.deliveries.clone(),
+        };
+        match bson::to_vec(&file) {
+            Ok(data) => Some(data),
+            Err(e) => {
+                error!("Error serializing the webhook deliveries: {}", e);
+                None
+            }
+        }
+    }
+
+    pub fn push(&mut self, url: &str, kind: EventKind, body: &str) {
+        if self.deliveries.len() >= MAX_QUEUED {
+            let dropped = self.deliveries.remove(0);
+            error!("Too many webhook deliveries queued, dropped one to {}", dropped.url);
+        }
+
+        self.deliveries.push(Delivery {
+            // ids made from the time collide when several events are queued at once, bson
+            // only stores integers up to i64::MAX
+            id: rand::thread_rng().gen_range(0..=i64::MAX as u64),
+            url: url.to_string(),
+            kind: kind.name().to_string(),
+            body: body.to_string(),
+            attempts: 0,
+            next_attempt: now(),
+        });
+        self.changed = true;
+    }
+
+    pub fn due(&self) -> Vec<Delivery> {
+        let now = now();
+        self.deliveries.iter().filter(|delivery| delivery.next_attempt <= now).cloned().collect()
+    }
+
+    pub fn remove(&mut self, id: u64) {
+        self.deliveries.retain(|delivery| delivery.id != id);
+        self.changed = true;
+    }
+
+    /// Schedules the next attempt with a longer wait, returns false when the delivery was given up
+    pub fn retry(&mut self, id: u64) -> bool {
+        let delivery = match self.deliveries.iter_mut().find(|delivery| delivery.id == id) {
+            Some(delivery) => delivery,
+            None => return false,
+        };
+
+        delivery.attempts += 1;
+        if delivery.attempts >= MAX_ATTEMPTS {
+            self.remove(id);
+            return false;
+        }
+
+        let delay = FIRST_RETRY_DELAY.saturating_mul(1 << (delivery.attempts - 1)).min(MAX_RETRY_DELAY);
+        delivery.next_attempt = now() + delay;
+        self.changed = true;
+        true
+    }
+}
+
+/// The headers that let a receiver check a delivery came from this server: the HMAC-SHA256
+/// of `<timestamp>.<body>` with the webhook's secret, in hex
+fn signature_headers(delivery: &Delivery, secret: &str) -> Vec<(&'static str, String)> {
+    let timestamp = now().to_string();
+    let signed = format!("{}.{}", timestamp, delivery.body);
+    let signature: String = crypt::sign(signed.as_bytes(), secret.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+
+    vec![
+        ("X-Yuttari-Event", delivery.kind.clone()),
+        ("X-Yuttari-Delivery", delivery.id.to_string()),
+        ("X-Yuttari-Timestamp", timestamp),
+        ("X-Yuttari-Signature", format!("sha256={}", signature)),
+    ]
+}
+
+async fn save_queue(data: Vec<u8>) {
+    if let Err(e) = tokio::fs::create_dir_all("data").await {
+        error!("Error creating the data directory: {}", e);
+    }
+    if let Err(e) = tokio::fs::write(QUEUE_PATH, data).await {
+        error!("Error saving the webhook deliveries: {}", e);
+    }
+}
+
+/// Posts a delivery once, Err says why it didn't go through
+async fn attempt(delivery: &Delivery, secret: &str) -> Result<(), String> {
+    let headers = signature_headers(delivery, secret);
+    match http::post(&delivery.url, &headers, &delivery.body).await {
+        Ok(status) if (200..300).contains(&status) => Ok(()),
+        Ok(status) => Err(format!("answered {}", status)),
+        Err(e) => Err(e),
+    }
+}
+
+/// Posts the due deliveries of one URL in the order they were queued
+async fn deliver_to(server: Arc<Mutex<Server>>, deliveries: Vec<Delivery>) {
+    for delivery in deliveries {
+        // the webhook may have left the config since the event was queued
+        let secret = server.lock().await.get_webhook_secret(&delivery.url);
+        let secret = match secret {
+            Some(secret) => secret,
+            None => {
+                debug!("Dropping a delivery to {}, it is no longer configured", delivery.url);
+                server.lock().await.delivered(delivery.id);
+                continue;
+            }
+        };
+
+        let result = attempt(&delivery, &secret).await;
+        let mut state = server.lock().await;
+        match result {
+            Ok(()) => state.delivered(delivery.id),
+            Err(e) => state.delivery_failed(&delivery, e),
+        }
+    }
+}
+
+/// Posts queued events to their URLs, retrying failed deliveries with backoff. Every URL
+/// is posted to by its own task, so a slow or dead endpoint only holds up its own events
+pub async fn deliver(server: Arc<Mutex<Server>>) {
+    let mut running: HashMap<String, JoinHandle<()>> = HashMap::new();
+    loop {
+        tokio::time::sleep(DELIVERY_INTERVAL).await;
+        running.retain(|_, task| !task.is_finished());
+
+        let (due, changes) = {
+            let mut state = server.lock().await;
+            (state.due_deliveries(), state.delivery_changes())
+        };
+        if let Some(data) = changes {
+            save_queue(data).await;
+        }
+
+        // a URL that is still being posted to gets its due deliveries on a later round
+        let mut by_url: HashMap<String, Vec<Delivery>> = HashMap::new();
+        for delivery in due.into_iter().filter(|delivery| !running.contains_key(&delivery.url)) {
+            by_url.entry(delivery.url.clone()).or_default().push(delivery);
+        }
+        for (url, deliveries) in by_url {
+            running.insert(url, tokio::spawn(deliver_to(server.clone(), deliveries)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    fn header<'a>(request: &'a str, name: &str) -> Option<&'a str> {
+        request.lines().find_map(|line| line.strip_prefix(name)?.strip_prefix(": "))
+    }
+
+    /// Accepts one request, answers it with a status and returns the request
+    async fn answer(listener: &TcpListener, status: &str) -> String {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut request = String::new();
+        let mut buffer = [0u8; 1024];
+        loop {
+            let read = stream.read(&mut buffer).await.unwrap();
+            request.push_str(&String::from_utf8_lossy(&buffer[..read]));
+            let complete = match (request.split_once("\r\n\r\n"), header(&request, "Content-Length")) {
+                (Some((_, body)), Some(length)) => body.len() >= length.parse().unwrap(),
+                _ => false,
+            };
+            if read == 0 || complete {
+                break;
+            }
+        }
+        let response = format!("HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status);
+        stream.write_all(response.as_bytes()).await.unwrap();
+        request
+    }
+
+    #[tokio::test]
+    async fn posts_signed_events_and_retries() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let body = event_body(EventKind::Message, serde_json::json!({ "text": "hello" }));
+
+        let mut queue = DeliveryQueue::default();
+        queue.push(&url, EventKind::Message, &body);
+        queue.push(&url, EventKind::Message, &body);
+        let due = queue.due();
+        assert_eq!(due.len(), 2);
+        assert_ne!(due[0].id, due[1].id);
+        let delivery = due[0].clone();
+
+        let (request, result) = tokio::join!(answer(&listener, "500 Internal Server Error"), attempt(&delivery, "secret"));
+        assert_eq!(result, Err("answered 500".to_string()));
+        assert!(request.starts_with("POST /hook HTTP/1.1\r\n"));
+        assert!(request.ends_with(&body));
+        assert_eq!(header(&request, "X-Yuttari-Event"), Some("message"));
+        assert_eq!(header(&request, "X-Yuttari-Delivery"), Some(delivery.id.to_string().as_str()));
+
+        let timestamp = header(&request, "X-Yuttari-Timestamp").unwrap();
+        let signature: String = crypt::sign(format!("{}.{}", timestamp, body).as_bytes(), b"secret")
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        assert_eq!(header(&request, "X-Yuttari-Signature"), Some(format!("sha256={}", signature).as_str()));
+
+        // a failed delivery waits before it is tried again
+        assert!(queue.retry(delivery.id));
+        assert!(queue.due().iter().all(|due| due.id != delivery.id));
+        let delivery = queue.deliveries.iter().find(|queued| queued.id == delivery.id).unwrap().clone();
+        assert_eq!(delivery.attempts, 1);
+        assert!(delivery.next_attempt >= now() + FIRST_RETRY_DELAY);
+
+        let (_, result) = tokio::join!(answer(&listener, "204 No Content"), attempt(&delivery, "secret"));
+        assert_eq!(result, Ok(()));
+        queue.remove(delivery.id);
+        assert_eq!(queue.deliveries.len(), 1);
+        assert!(queue.changes().is_some());
+        assert!(queue.changes().is_none());
+    }
+}
@@ -0,0 +1,152 @@
+use std::error::Error;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::Bytes;
+use common::federation::{link_key, PeerHelloPayload};
+use common::message::{Message, MessageType, Payload};
+use futures::SinkExt;
+use log::{debug, info};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, Mutex};
+use tokio_util::codec::{Framed, LengthDelimitedCodec};
+
+use crate::client::Frames;
+use crate::server::Server;
+
+// how long to wait before dialing a peer again after the link went down or couldn't be opened
+const REDIAL_INTERVAL: Duration = Duration::from_secs(10);
+
+// how many random bytes each end has to sign, a shorter nonce could be one seen before
+const NONCE_LENGTH: usize = 16;
+
+// peers dial over raw TCP, but a link may also be accepted on the WebSocket gateway
+type Link<C = LengthDelimitedCodec> = Framed<TcpStream, C>;
+
+async fn next_message<C: Frames>(bytes: &mut Link<C>) -> Result<Message, Box<dyn Error + Send + Sync>> {
+    match crate::client::next_frame(bytes).await {
+        Some(Ok(data)) => Ok(Message::from_bytes(data.to_vec())),
+        Some(Err(e)) => Err(e.into()),
+        None => Err("the peer closed the connection".into()),
+    }
+}
+
+/// Answers a server that dialed this one and sent its hello instead of a login
+pub async fn accept<C: Frames>(server: Arc<Mutex<Server>>, mut bytes: Link<C>, hello: Message) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let hello = PeerHelloPayload::from_bytes(hello.payload);
+
+    let (reply, shared_key) = {
+        let state = server.lock().await;
+        let shared_key = match state.check_peer(&hello.name, &hello.public_key) {
+            Ok(shared_key) => shared_key,
+            Err(e) => {
+                state.record_audit("peer_refused", &hello.name, e.clone());
+                return Err(e.into());
+            }
+        };
+        if hello.nonce.len() < NONCE_LENGTH {
+            state.record_audit("peer_refused", &hello.name, "sent no nonce".to_string());
+            return Err(format!("{} sent no nonce", hello.name).into());
+        }
+        let mut reply = state.peer_hello();
+        reply.prove(&hello.nonce, &shared_key);
+        (reply, shared_key)
+    };
+    bytes.send(Bytes::from(Message::new(MessageType::PeerHello, reply.to_bytes()).to_bytes())).await?;
+
+    // the dialer proves it holds its key by signing our nonce
+    let proof = PeerHelloPayload::from_bytes(next_message(&mut bytes).await?.payload);
+    if proof.name != hello.name || !proof.verify(&reply.nonce, &shared_key) {
+        server.lock().await.record_audit("peer_refused", &hello.name, "couldn't prove it holds its key".to_string());
+        return Err(format!("{} couldn't prove it holds its key", hello.name).into());
+    }
+
+    let key = link_key(&shared_key, &hello.nonce, &reply.nonce);
+    run_link(server, &hello.name, key, bytes, false).await
+}
+
+/// Dials a peer, checks it is who the config says and runs the link until it goes down
+async fn dial(server: Arc<Mutex<Server>>, name: &str, address: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut bytes = Framed::new(TcpStream::connect(address).await?, LengthDelimitedCodec::new());
+
+    // every connection is greeted with the server's public key
+    let greeting = next_message(&mut bytes).await?;
+    if greeting.message_type != MessageType::ConnectionReceive {
+        return Err(format!("{} didn't greet with its key", name).into());
+    }
+
+    let (mut hello, shared_key) = {
+        let state = server.lock().await;
+        let shared_key = state.check_peer(name, &greeting.payload)?;
+        (state.peer_hello(), shared_key)
+    };
+    bytes.send(Bytes::from(Message::new(MessageType::PeerHello, hello.to_bytes()).to_bytes())).await?;
+
+    let reply = next_message(&mut bytes).await?;
+    let reply = PeerHelloPayload::from_bytes(reply.payload);
+    if reply.name != name || reply.nonce.len() < NONCE_LENGTH || !reply.verify(&hello.nonce, &shared_key) {
+        return Err(format!("{} couldn't prove it holds its key", name).into());
+    }
+    hello.prove(&reply.nonce, &shared_key);
+    bytes.send(Bytes::from(Message::new(MessageType::PeerHello, hello.to_bytes()).to_bytes())).await?;
+
+    let key = link_key(&shared_key, &hello.nonce, &reply.nonce);
+    run_link(server, name, key, bytes, true).await
+}
+
+/// Keeps a link to a peer up for as long as the config lists it with an address
+pub async fn keep_linked(server: Arc<Mutex<Server>>, name: String) {
+    loop {
+        let address = {
+            let state = server.lock().await;
+            match state.get_peer_config(&name).and_then(|peer| peer.address) {
+                Some(_) if state.is_linked(&name) => None,
+                Some(address) => Some(address),
+                None => return,
+            }
+        };
+
+        if let Some(address) = address {
+            if let Err(e) = dial(server.clone(), &name, &address).await {
+                info!("Federation link to {} at {}: {}", name, address, e);
+            }
+        }
+        tokio::time::sleep(REDIAL_INTERVAL).await;
+    }
+}
+
+/// Relays messages both ways until either end closes the link
+async fn run_link<C: Frames>(server: Arc<Mutex<Server>>, name: &str, shared_key: Vec<u8>, mut bytes: Link<C>, dialed: bool) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let link = match server.lock().await.add_peer(name, tx, shared_key, dialed) {
+        Some(link) => link,
+        None => {
+            debug!("Already linked to {}, closing the new link", name);
+            return Ok(());
+        }
+    };
+    info!("Federation link to {} is up", name);
+
+    loop {
+        tokio::select! {
+            data = rx.recv() => match data {
+                Some(data) => bytes.send(Bytes::from(data)).await?,
+                // a better link to the same peer took over
+                None => break,
+            },
+            message = next_message(&mut bytes) => match message {
+                Ok(message) if message.message_type == MessageType::PeerMessage => {
+                    server.lock().await.receive_from_peer(name, message);
+                }
+                Ok(message) => debug!("{} sent a {:?} over the federation link", name, message.message_type),
+                Err(e) => {
+                    info!("Federation link to {} went down: {}", name, e);
+                    break;
+                }
+            },
+        }
+    }
+
+    server.lock().await.remove_peer(name, link);
+    Ok(())
+}
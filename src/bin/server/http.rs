@@ -0,0 +1,357 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::{debug, error};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Mutex;
+use tokio::time::timeout;
+
+use crate::server::Server;
+
+// the request line and headers have to fit in this many bytes
+const MAX_HEADER_SIZE: usize = 16 * 1024;
+const MAX_BODY_SIZE: usize = 1024 * 1024;
+// how many messages a history request returns unless it asks for a limit, and at most
+const DEFAULT_HISTORY: usize = 50;
+const MAX_HISTORY: usize = 500;
+// how long an outgoing request may take, connecting included
+const POST_TIMEOUT: Duration = Duration::from_secs(10);
+// how long a client has to send its request and read the answer, a slow one doesn't keep
+// its connection and task forever
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Why a request failed, as the status it is answered with
+pub enum HttpError {
+    BadRequest(String),
+    Unauthorized(String),
+    Forbidden(String),
+    NotFound(String),
+}
+
+impl HttpError {
+    fn status(&self) -> (u16, &'static str) {
+        match self {
+            HttpError::BadRequest(_) => (400, "Bad Request"),
+            HttpError::Unauthorized(_) => (401, "Unauthorized"),
+            HttpError::Forbidden(_) => (403, "Forbidden"),
+            HttpError::NotFound(_) => (404, "Not Found"),
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        match self {
+            HttpError::BadRequest(message)
+            | HttpError::Unauthorized(message)
+            | HttpError::Forbidden(message)
+            | HttpError::NotFound(message) => message,
+        }
+    }
+}
+
+/// A channel as the API lists it
+#[derive(Serialize)]
+pub struct ChannelSummary {
+    pub name: String,
+    pub topic: String,
+    pub description: String,
+    pub archived: bool,
+}
+
+/// The body of a bot's post to a channel
+#[derive(Deserialize)]
+struct PostRequest {
+    text: String,
+    #[serde(default)]
+    reply_to: Option<u64>,
+    #[serde(default)]
+    thread: Option<u64>,
+}
+
+/// The body of a post to an incoming webhook, what most CI and alerting tools send
+#[derive(Deserialize)]
+struct WebhookRequest {
+    text: String,
+    // who the message is from instead of the webhook's name
+    #[serde(default)]
+    username: Option<String>,
+}
+
+struct Request {
+    method: String,
+    path: String,
+    query: HashMap<String, String>,
+    headers: HashMap<String, String>,
+    body: Vec<u8>,
+}
+
+impl Request {
+    /// The token of an `Authorization: Bearer` header
+    fn token(&self) -> Option<&str> {
+        self.headers.get("authorization")?.strip_prefix("Bearer ").map(str::trim)
+    }
+
+    /// The path without the secret of a webhook URL
+    fn logged_path(&self) -> String {
+        match self.path.strip_prefix("/hooks/") {
+            Some(hook) => format!("/hooks/{}/...", hook.split('/').next().unwrap_or_default()),
+            None => self.path.clone(),
+        }
+    }
+}
+
+/// Decodes `%xx` escapes in a path segment or query value
+fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes.get(i + 1..i + 3).and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match (bytes[i], escaped) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (b'+', _) => {
+                decoded.push(b' ');
+                i += 1;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+async fn read_request(stream: &mut TcpStream) -> io::Result<Request> {
+    let mut data = Vec::new();
+    let mut buffer = [0u8; 4096];
+    let header_end = loop {
+        if let Some(end) = data.windows(4).position(|window| window == b"\r\n\r\n") {
+            break end;
+        }
+        if data.len() > MAX_HEADER_SIZE {
+            return Err(invalid("the request headers are too large"));
+        }
+        let read = stream.read(&mut buffer).await?;
+        if read == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        data.extend_from_slice(&buffer[..read]);
+    };
+
+    let head = String::from_utf8_lossy(&data[..header_end]).to_string();
+    let mut lines = head.lines();
+    let mut request_line = lines.next().unwrap_or_default().split_whitespace();
+    let (method, target) = match (request_line.next(), request_line.next()) {
+        (Some(method), Some(target)) => (method.to_string(), target.to_string()),
+        _ => return Err(invalid("malformed request line")),
+    };
+
+    let headers: HashMap<String, String> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_lowercase(), value.trim().to_string()))
+        .collect();
+
+    let length: usize = match headers.get("content-length") {
+        Some(length) => length.parse().map_err(|_| invalid("malformed Content-Length"))?,
+        None => 0,
+    };
+    if length > MAX_BODY_SIZE {
+        return Err(invalid("the request body is too large"));
+    }
+
+    let mut body = data.split_off(header_end + 4);
+    while body.len() < length {
+        let read = stream.read(&mut buffer).await?;
+        if read == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        body.extend_from_slice(&buffer[..read]);
+    }
+    body.truncate(length);
+
+    let (path, query) = target.split_once('?').unwrap_or((&target, ""));
+    let query = query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(key, value)| (percent_decode(key), percent_decode(value)))
+        .collect();
+
+    Ok(Request {
+        method,
+        path: path.to_string(),
+        query,
+        headers,
+        body,
+    })
+}
+
+async fn write_response(stream: &mut TcpStream, status: (u16, &str), body: serde_json::Value) -> io::Result<()> {
+    let body = body.to_string();
+    let response = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status.0,
+        status.1,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await
+}
+
+fn parse_body<'a, T: Deserialize<'a>>(request: &'a Request) -> Result<T, HttpError> {
+    serde_json::from_slice(&request.body).map_err(|e| HttpError::BadRequest(format!("The body is not valid: {}", e)))
+}
+
+/// Runs a request against the server, answering with the JSON the endpoint returns
+async fn route(server: &Arc<Mutex<Server>>, request: &Request) -> Result<((u16, &'static str), serde_json::Value), HttpError> {
+    let segments: Vec<String> = request.path.split('/').filter(|s| !s.is_empty()).map(percent_decode).collect();
+    let segments: Vec<&str> = segments.iter().map(String::as_str).collect();
+
+    // webhooks carry their secret in the URL, everything else needs a bot's token
+    if let (method, ["hooks", id, token]) = (request.method.as_str(), segments.as_slice()) {
+        if method != "POST" {
+            return Err(HttpError::NotFound(format!("{} {} is not an endpoint", method, request.logged_path())));
+        }
+        let post: WebhookRequest = parse_body(request)?;
+        let id = server.lock().await.post_to_webhook(id, token, post.username, post.text).await?;
+        return Ok(((201, "Created"), json!({ "id": id })));
+    }
+
+    let token = request
+        .token()
+        .ok_or_else(|| HttpError::Unauthorized("Send a bot token as Authorization: Bearer <token>".to_string()))?;
+    let mut state = server.lock().await;
+    let bot = state.find_bot(token).ok_or_else(|| HttpError::Unauthorized("Unknown bot token".to_string()))?;
+
+    match (request.method.as_str(), segments.as_slice()) {
+        ("GET", ["api", "channels"]) => Ok(((200, "OK"), json!(state.api_channels(&bot)))),
+        ("GET", ["api", "channels", channel, "messages"]) => {
+            let limit = match request.query.get("limit") {
+                Some(limit) => limit.parse().map_err(|_| HttpError::BadRequest(format!("{} is not a number", limit)))?,
+                None => DEFAULT_HISTORY,
+            };
+            let messages = state.api_history(&bot, channel, limit.min(MAX_HISTORY))?;
+            Ok(((200, "OK"), json!(messages)))
+        }
+        ("POST", ["api", "channels", channel, "messages"]) => {
+            let post: PostRequest = parse_body(request)?;
+            let id = state.post_as_bot(&bot, channel, post.text, post.reply_to, post.thread).await?;
+            Ok(((201, "Created"), json!({ "id": id })))
+        }
+        (method, _) => Err(HttpError::NotFound(format!("{} {} is not an endpoint", method, request.path))),
+    }
+}
+
+async fn handle_request(server: Arc<Mutex<Server>>, mut stream: TcpStream, addr: SocketAddr) -> io::Result<()> {
+    let request = match read_request(&mut stream).await {
+        Ok(request) => request,
+        Err(e) => {
+            debug!("Bad HTTP request from {}: {}", addr, e);
+            return write_response(&mut stream, (400, "Bad Request"), json!({ "error": e.to_string() })).await;
+        }
+    };
+    debug!("HTTP {} {} from {}", request.method, request.logged_path(), addr);
+
+    match route(&server, &request).await {
+        Ok((status, body)) => write_response(&mut stream, status, body).await,
+        Err(e) => {
+            if let HttpError::Unauthorized(_) = e {
+                server.lock().await.record_audit("api_refused", &addr.to_string(), format!("{} {}", request.method, request.logged_path()));
+            }
+            write_response(&mut stream, e.status(), json!({ "error": e.message() })).await
+        }
+    }
+}
+
+/// Answers the bot API and incoming webhooks, one request per connection
+pub async fn serve(server: Arc<Mutex<Server>>, listener: TcpListener) {
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(e) => {
+                error!("failed to accept an HTTP connection: {}", e);
+                continue;
+            }
+        };
+
+        if crate::is_refused(&server, addr).await {
+            continue;
+        }
+
+        let server = Arc::clone(&server);
+        tokio::spawn(async move {
+            match timeout(REQUEST_TIMEOUT, handle_request(server, stream, addr)).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => debug!("HTTP connection from {} failed: {}", addr, e),
+                Err(_) => debug!("HTTP connection from {} timed out", addr),
+            }
+        });
+    }
+}
+
+/// Posts a JSON body to a plain `http://` URL, returns the status it was answered with
+pub async fn post(url: &str, headers: &[(&str, String)], body: &str) -> Result<u16, String> {
+    let rest = url.strip_prefix("http://").ok_or_else(|| format!("{} is not an http:// URL", url))?;
+    let (host, path) = match rest.find('/') {
+        Some(i) => rest.split_at(i),
+        None => (rest, "/"),
+    };
+    let address = match host.contains(':') {
+        true => host.to_string(),
+        false => format!("{}:80", host),
+    };
+
+    let mut request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: yuttari\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n",
+        path,
+        host,
+        body.len()
+    );
+    for (name, value) in headers {
+        request.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    request.push_str("\r\n");
+    request.push_str(body);
+
+    let exchange = async {
+        let mut stream = TcpStream::connect(&address).await?;
+        stream.write_all(request.as_bytes()).await?;
+
+        // only the status line matters
+        let mut response = Vec::new();
+        let mut buffer = [0u8; 1024];
+        while !response.windows(2).any(|window| window == b"\r\n") && response.len() < MAX_HEADER_SIZE {
+            let read = stream.read(&mut buffer).await?;
+            if read == 0 {
+                break;
+            }
+            response.extend_from_slice(&buffer[..read]);
+        }
+        Ok::<Vec<u8>, io::Error>(response)
+    };
+
+    let response = match timeout(POST_TIMEOUT, exchange).await {
+        Ok(Ok(response)) => response,
+        Ok(Err(e)) => return Err(e.to_string()),
+        Err(_) => return Err("timed out".to_string()),
+    };
+
+    let response = String::from_utf8_lossy(&response);
+    response
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| "the answer is not HTTP".to_string())
+}
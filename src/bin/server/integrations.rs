@@ -0,0 +1,190 @@
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use crypto::util::fixed_time_eq;
+use log::error;
+use rand_core::RngCore;
+use serde::{Deserialize, Serialize};
+
+use common::invite::create_code;
+
+const INTEGRATIONS_PATH: &str = "data/integrations.bson";
+
+/// A new secret for a bot or webhook, only its hash is kept
+fn create_token() -> String {
+    let mut token = [0u8; 24];
+    rand_core::OsRng.fill_bytes(&mut token);
+    base64::encode_config(token, base64::URL_SAFE_NO_PAD)
+}
+
+fn hash_token(token: &str) -> String {
+    let mut sha256 = Sha256::new();
+    sha256.input_str(token);
+    sha256.result_str()
+}
+
+fn token_matches(token: &str, hash: &str) -> bool {
+    fixed_time_eq(hash_token(token).as_bytes(), hash.as_bytes())
+}
+
+/// A program posting through the HTTP API, it authenticates with a bearer token
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Bot {
+    pub name: String,
+    token_hash: String,
+    // the channels it may use, empty for every channel that isn't private
+    pub channels: Vec<String>,
+    pub created_by: String,
+}
+
+impl Bot {
+    /// A bot that can't authenticate over HTTP, plugins act as one
+    pub fn without_token(name: &str, channels: Vec<String>) -> Bot {
+        Bot {
+            name: name.to_string(),
+            // no token hashes to an empty string
+            token_hash: String::new(),
+            channels,
+            created_by: "SERVER".to_string(),
+        }
+    }
+}
+
+/// An incoming webhook, whoever has its URL may post to its channel
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Webhook {
+    pub id: String,
+    // who its messages are from, unless a post names someone else
+    pub name: String,
+    pub channel: String,
+    token_hash: String,
+    pub created_by: String,
+}
+
+/// The on-disk layout of the integrations, bson needs a document at the top level
+#[derive(Serialize, Deserialize, Default)]
+struct IntegrationFile {
+    bots: Vec<Bot>,
+    webhooks: Vec<Webhook>,
+}
+
+#[derive(Default)]
+pub struct Integrations {
+    bots: Vec<Bot>,
+    webhooks: Vec<Webhook>,
+}
+
+impl Integrations {
+    pub fn load() -> Integrations {
+        let file: IntegrationFile = match std::fs::read(INTEGRATIONS_PATH) {
+            Ok(data) => bson::from_slice(&data).unwrap_or_default(),
+            Err(_) => IntegrationFile::default(),
+        };
+
+        Integrations {
+            bots: file.bots,
+            webhooks: file.webhooks,
+        }
+    }
+
+    pub fn save(&self) {
+        let file = IntegrationFile {
+            bots: self.bots.clone(),
+            webhooks: self.webhooks.clone(),
+        };
+
+        let data = match bson::to_vec(&file) {
+            Ok(data) => data,
+            Err(e) => {
+                error!("Error serializing the integrations: {}", e);
+                return;
+            }
+        };
+
+        std::fs::create_dir_all("data").unwrap();
+        if let Err(e) = std::fs::write(INTEGRATIONS_PATH, data) {
+            error!("Error saving the integrations: {}", e);
+        }
+    }
+
+    /// Adds a bot, returns its token, which is never shown again
+    pub fn add_bot(&mut self, name: &str, channels: Vec<String>, created_by: &str) -> Result<String, String> {
+        if self.bots.iter().any(|bot| bot.name == name) {
+            return Err(format!("There already is a bot called {}", name));
+        }
+
+        let token = create_token();
+        self.bots.push(Bot {
+            name: name.to_string(),
+            token_hash: hash_token(&token),
+            channels,
+            created_by: created_by.to_string(),
+        });
+        self.save();
+        Ok(token)
+    }
+
+    pub fn remove_bot(&mut self, name: &str) -> bool {
+        let count = self.bots.len();
+        self.bots.retain(|bot| bot.name != name);
+        self.save();
+        self.bots.len() != count
+    }
+
+    pub fn bots(&self) -> &[Bot] {
+        &self.bots
+    }
+
+    pub fn find_bot(&self, token: &str) -> Option<&Bot> {
+        self.bots.iter().find(|bot| token_matches(token, &bot.token_hash))
+    }
+
+    /// Adds a webhook to a channel, returns it with its token, which is never shown again
+    pub fn add_webhook(&mut self, channel: &str, name: &str, created_by: &str) -> (Webhook, String) {
+        let token = create_token();
+        let webhook = Webhook {
+            id: create_code(),
+            name: name.to_string(),
+            channel: channel.to_string(),
+            token_hash: hash_token(&token),
+            created_by: created_by.to_string(),
+        };
+        self.webhooks.push(webhook.clone());
+        self.save();
+        (webhook, token)
+    }
+
+    pub fn get_webhook(&self, id: &str) -> Option<&Webhook> {
+        self.webhooks.iter().find(|webhook| webhook.id == id)
+    }
+
+    pub fn remove_webhook(&mut self, id: &str) {
+        self.webhooks.retain(|webhook| webhook.id != id);
+        self.save();
+    }
+
+    pub fn find_webhook(&self, id: &str, token: &str) -> Option<&Webhook> {
+        self.get_webhook(id).filter(|webhook| token_matches(token, &webhook.token_hash))
+    }
+
+    pub fn webhooks_for(&self, channel: &str) -> Vec<&Webhook> {
+        self.webhooks.iter().filter(|webhook| webhook.channel == channel).collect()
+    }
+
+    /// Keeps bots and webhooks pointing at a channel that was renamed
+    pub fn rename_channel(&mut self, old: &str, new: &str) {
+        for webhook in self.webhooks.iter_mut().filter(|webhook| webhook.channel == old) {
+            webhook.channel = new.to_string();
+        }
+        for channel in self.bots.iter_mut().flat_map(|bot| bot.channels.iter_mut()).filter(|channel| *channel == old) {
+            *channel = new.to_string();
+        }
+        self.save();
+    }
+
+    /// Drops the webhooks of a deleted channel, bots keep it in their list so one
+    /// limited to it doesn't end up with every channel
+    pub fn remove_channel(&mut self, channel: &str) {
+        self.webhooks.retain(|webhook| webhook.channel != channel);
+        self.save();
+    }
+}
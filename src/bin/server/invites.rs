@@ -0,0 +1,138 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use common::invite::create_code;
+use log::error;
+use serde::{Deserialize, Serialize};
+
+const INVITES_PATH: &str = "data/invites.bson";
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Invite {
+    pub code: String,
+    pub channel: String,
+    pub created_by: String,
+    // None for no limit
+    pub max_uses: Option<u32>,
+    pub uses: u32,
+    // unix seconds, None for never
+    pub expires: Option<u64>,
+}
+
+impl Invite {
+    fn is_valid(&self) -> bool {
+        self.expires.is_none_or(|expires| expires > now())
+            && self.max_uses.is_none_or(|max_uses| self.uses < max_uses)
+    }
+}
+
+/// The on-disk layout of the invites, bson needs a document at the top level
+#[derive(Serialize, Deserialize, Default)]
+struct InviteFile {
+    invites: Vec<Invite>,
+}
+
+#[derive(Default)]
+pub struct InviteList {
+    invites: Vec<Invite>,
+}
+
+impl InviteList {
+    /// Loads the invites, only a missing file starts an empty list
+    pub fn load() -> Result<InviteList, String> {
+        let file: InviteFile = match std::fs::read(INVITES_PATH) {
+            Ok(data) => bson::from_slice(&data).map_err(|e| format!("{} is damaged: {}", INVITES_PATH, e))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => InviteFile::default(),
+            Err(e) => return Err(format!("Could not read {}: {}", INVITES_PATH, e)),
+        };
+
+        Ok(InviteList { invites: file.invites })
+    }
+
+    pub fn save(&self) {
+        let file = InviteFile {
+            invites: self.invites.clone(),
+        };
+
+        let data = match bson::to_vec(&file) {
+            Ok(data) => data,
+            Err(e) => {
+                error!("Error serializing the invites: {}", e);
+                return;
+            }
+        };
+
+        std::fs::create_dir_all("data").unwrap();
+        if let Err(e) = std::fs::write(INVITES_PATH, data) {
+            error!("Error saving the invites: {}", e);
+        }
+    }
+
+    pub fn create(&mut self, channel: &str, created_by: &str, max_uses: Option<u32>, valid_for: Option<u64>) -> Result<Invite, String> {
+        let expires = match valid_for {
+            Some(seconds) => Some(now().checked_add(seconds).ok_or("That invite would be valid for too long")?),
+            None => None,
+        };
+        let invite = Invite {
+            code: create_code(),
+            channel: channel.to_string(),
+            created_by: created_by.to_string(),
+            max_uses,
+            uses: 0,
+            expires,
+        };
+
+        self.invites.retain(Invite::is_valid);
+        self.invites.push(invite.clone());
+        self.save();
+        Ok(invite)
+    }
+
+    /// Uses up a code, returns the channel it is for
+    pub fn redeem(&mut self, code: &str) -> Result<String, String> {
+        self.invites.retain(Invite::is_valid);
+
+        let invite = match self.invites.iter_mut().find(|invite| invite.code == code) {
+            Some(invite) => invite,
+            None => return Err("That invite is unknown, used up or expired".to_string()),
+        };
+        invite.uses += 1;
+        let channel = invite.channel.clone();
+
+        self.invites.retain(Invite::is_valid);
+        self.save();
+        Ok(channel)
+    }
+
+    pub fn get(&self, code: &str) -> Option<&Invite> {
+        self.invites.iter().find(|invite| invite.code == code && invite.is_valid())
+    }
+
+    pub fn revoke(&mut self, code: &str) {
+        self.invites.retain(|invite| invite.code != code);
+        self.save();
+    }
+
+    pub fn for_channel(&self, channel: &str) -> Vec<&Invite> {
+        self.invites
+            .iter()
+            .filter(|invite| invite.channel == channel && invite.is_valid())
+            .collect()
+    }
+
+    /// Keeps invites pointing at a channel that was renamed
+    pub fn rename_channel(&mut self, old: &str, new: &str) {
+        for invite in self.invites.iter_mut().filter(|invite| invite.channel == old) {
+            invite.channel = new.to_string();
+        }
+        self.save();
+    }
+
+    pub fn remove_channel(&mut self, channel: &str) {
+        self.invites.retain(|invite| invite.channel != channel);
+        self.save();
+    }
+}
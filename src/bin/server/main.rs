@@ -11,16 +11,30 @@ use log::{debug, error, info};
 use simplelog::*;
 use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::{mpsc, Mutex};
-use tokio_stream::StreamExt;
-use tokio_util::codec::{BytesCodec, Framed};
+use tokio_util::codec::{Framed, LengthDelimitedCodec};
+use client::Frames;
 use common::crypt;
 
-use common::message::{Message, MessagePayload, MessageType, Payload};
+use common::message::{HistoryPayload, Message, MessagePayload, MessageType, Payload};
 use common::user::User;
 use server::Server;
 
+mod admin;
+mod audit;
+mod bans;
+mod blobs;
 mod client;
+mod config;
+mod events;
+mod federation;
+mod http;
+mod integrations;
+mod invites;
+mod plugins;
+mod registry;
+mod search;
 mod server;
+mod websocket;
 
 fn print_logo() {
     // load logo from file
@@ -34,7 +48,13 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     print_logo();
 
-    let mut server = Server::default();
+    let mut server = match Server::load() {
+        Ok(server) => server,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    };
     let state = Arc::new(Mutex::new(server));
 
     let addr = env::args()
@@ -48,17 +68,64 @@ async fn main() -> Result<(), Box<dyn Error>> {
     // Note that this is the Tokio TcpListener, which is fully async.
     let listener = TcpListener::bind(&addr).await?;
 
+    // apply channel retention policies in the background
+    let pruner = Arc::clone(&state);
+    tokio::spawn(async move {
+        loop {
+            let interval = pruner.lock().await.get_retention_interval();
+            tokio::time::sleep(interval).await;
+            pruner.lock().await.prune_channels();
+        }
+    });
+
+    // write the search index as messages come in
+    tokio::spawn(search::keep_saved(Arc::clone(&state)));
+
+    // write read markers in batches
+    tokio::spawn(registry::keep_saved(Arc::clone(&state)));
+
+    // post events to outgoing webhooks
+    tokio::spawn(events::deliver(Arc::clone(&state)));
+
+    // keep the federation links this server dials up
+    let peers = state.lock().await.get_dialed_peers();
+    for peer in peers {
+        tokio::spawn(federation::keep_linked(Arc::clone(&state), peer.name));
+    }
+
+    // browsers connect through the WebSocket gateway, if there is one
+    let websocket_address = state.lock().await.get_websocket_address();
+    if let Some(websocket_address) = websocket_address {
+        let websocket_listener = TcpListener::bind(&websocket_address).await?;
+        println!("Listening for WebSocket connections on: {}", websocket_address);
+        tokio::spawn(accept_websockets(Arc::clone(&state), websocket_listener));
+    }
+
+    // bots and webhooks post over HTTP, if it is turned on
+    let http_address = state.lock().await.get_http_address();
+    if let Some(http_address) = http_address {
+        let http_listener = TcpListener::bind(&http_address).await?;
+        println!("Listening for HTTP requests on: {}", http_address);
+        tokio::spawn(http::serve(Arc::clone(&state), http_listener));
+    }
+
     loop {
         // Asynchronously wait for an inbound TcpStream.
         let (stream, addr) = listener.accept().await?;
 
+        // dropping the stream closes it before the handshake
+        if is_refused(&state, addr).await {
+            continue;
+        }
+
         // Clone a handle to the `Shared` state for the new connection.
         let state = Arc::clone(&state);
 
         // Spawn our handler to be run asynchronously.
         tokio::spawn(async move {
             info!("new client: {}", addr);
-            match handle_connection(state, stream, addr).await {
+            let bytes = Framed::new(stream, LengthDelimitedCodec::new());
+            match handle_connection(state, bytes, addr).await {
                 Err(e) => {
                     error!("failed to process connection: {}", e);
                 }
@@ -68,12 +135,51 @@ async fn main() -> Result<(), Box<dyn Error>> {
     }
 }
 
-async fn handle_connection(
+async fn is_refused(state: &Arc<Mutex<Server>>, addr: SocketAddr) -> bool {
+    let state = state.lock().await;
+    if state.is_banned_ip(addr.ip()) {
+        info!("Refused banned address {}", addr);
+        state.record_audit("banned_address", &addr.to_string(), "connection refused".to_string());
+        return true;
+    }
+    false
+}
+
+/// Accepts WebSocket connections, once upgraded they carry the same frames as a TCP connection
+async fn accept_websockets(state: Arc<Mutex<Server>>, listener: TcpListener) {
+    loop {
+        let (mut stream, addr) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(e) => {
+                error!("failed to accept a WebSocket connection: {}", e);
+                continue;
+            }
+        };
+
+        if is_refused(&state, addr).await {
+            continue;
+        }
+
+        let state = Arc::clone(&state);
+        tokio::spawn(async move {
+            info!("new WebSocket client: {}", addr);
+            if let Err(e) = websocket::handshake(&mut stream).await {
+                debug!("WebSocket upgrade from {} failed: {}", addr, e);
+                return;
+            }
+            let bytes = Framed::new(stream, websocket::WebSocketCodec::new());
+            if let Err(e) = handle_connection(state, bytes, addr).await {
+                error!("failed to process connection: {}", e);
+            }
+        });
+    }
+}
+
+async fn handle_connection<C: Frames>(
     server: Arc<Mutex<Server>>,
-    mut stream: TcpStream,
+    mut bytes: Framed<TcpStream, C>,
     addr: SocketAddr,
 ) -> Result<(), Box<dyn Error>> {
-    let mut bytes = Framed::new(stream, BytesCodec::new());
 
     let priv_key = crypt::deserialize_private_key(server.lock().await.get_private_key());
 
@@ -87,10 +193,11 @@ async fn handle_connection(
     bytes.send(Bytes::from(conn_message)).await?;
 
     // get the login message
-    let login_message = match bytes.next().await {
+    let login_message = match client::next_frame(&mut bytes).await {
         Some(Ok(bytes)) => bytes,
         Some(Err(e)) => {
             error!("Error: {}", e);
+            server.lock().await.record_audit("handshake_failed", &addr.to_string(), e.to_string());
             return Ok(());
         }
         None => {
@@ -101,9 +208,18 @@ async fn handle_connection(
 
     // deserialize the login message
     let login_message = Message::from_bytes(login_message.to_vec());
+
+    // other servers open federation links on the same port
+    if login_message.message_type == MessageType::PeerHello {
+        if let Err(e) = federation::accept(server.clone(), bytes, login_message).await {
+            info!("Refused a federation link from {}: {}", addr, e);
+        }
+        return Ok(());
+    }
     if login_message.message_type != MessageType::Login {
         debug!("Client sent invalid message type");
         debug!("Expected: Login");
+        server.lock().await.record_audit("handshake_failed", &addr.to_string(), "expected a login".to_string());
         return Ok(());
     }
 
@@ -114,35 +230,130 @@ async fn handle_connection(
     let user = User::from_bytes(login_message.payload);
     if user.username == "Unknown" {
         debug!("Client sent invalid username");
+        let mut state = server.lock().await;
+        state.record_audit("handshake_failed", &addr.to_string(), "invalid user".to_string());
+        state.remove_client(addr);
         return Ok(());
     } else {
-        debug!("Client logged in as {}", user.username);
-        let pub_key = User::deserialize_public_key(user.public_key);
         let mut state = server.lock().await;
+        if state.is_banned_user(user.id) {
+            info!("Refused banned user {}", user.username);
+            state.record_audit("banned_login", &user.username, format!("refused login from {}", addr));
+            state.remove_client(addr);
+            return Ok(());
+        }
+
+        let pub_key = User::deserialize_public_key(user.public_key.clone());
         let private_key = crypt::deserialize_private_key(state.get_private_key());
         let shared_key = crypt::create_shared_key(private_key, pub_key);
         state.add_shared_key(addr, shared_key);
         debug!("Shared key created, {:?}", state.get_shared_key(addr));
+
+        if let Err(e) = state.check_login(&user) {
+            info!("Refused login as {}: {}", user.username, e);
+            state.record_audit("refused_login", &user.username, format!("{} from {}", e, addr));
+            state.notice(addr, "ALL", format!("Login refused: {}", e));
+            state.remove_client(addr);
+            drop(state);
+            // the notice is all the client gets
+            while let Ok(bytes) = client.rx.try_recv() {
+                client.send(bytes).await?;
+            }
+            return Ok(());
+        }
+        debug!("Client logged in as {}", user.username);
         let message_payload = format!("{} has joined the server", user.username);
         let message_payload = MessagePayload::new("SERVER".to_string(), "ALL".to_string(), message_payload.as_bytes().to_vec()).to_bytes();
         let message = Message::new(MessageType::Message, message_payload);
         state.broadcast(None, message).await;
+        state.add_user(addr, user);
+        state.send_channel_list(addr);
+        // markers go first so clients can place the "new messages" divider in the replay
+        state.send_read_markers(addr);
+        state.send_history(addr);
     }
 
     loop {
         tokio::select! {
-            Some(bytes) = client.rx.recv() => {
-                debug!("Sending message to client {}", client.addr().to_string());
-                client.send(bytes).await?;
+            bytes = client.rx.recv() => {
+                match bytes {
+                    Some(bytes) => {
+                        debug!("Sending message to client {}", client.addr().to_string());
+                        client.send(bytes).await?;
+                    }
+                    // the server dropped our sender, the client was kicked or banned
+                    None => break,
+                }
             }
-            result = client.bytes.next() => {
+            result = client::next_frame(&mut client.bytes) => {
                 match result {
                     Some(Ok(bytes)) => {
                         let message = Message::from_bytes(bytes.to_vec());
                         match message.message_type {
                             MessageType::Message => {
                                 let mut state = server.lock().await;
-                                state.broadcast(Some(addr), message).await;
+                                state.post(addr, message).await;
+                            }
+                            MessageType::Reaction => {
+                                let mut state = server.lock().await;
+                                state.react(addr, message);
+                            }
+                            MessageType::Typing => {
+                                let mut state = server.lock().await;
+                                state.typing(addr, message);
+                            }
+                            MessageType::ReadMarker => {
+                                let mut state = server.lock().await;
+                                state.read_marker(addr, message);
+                            }
+                            MessageType::AttachmentOffer => {
+                                let mut state = server.lock().await;
+                                state.offer_attachment(addr, message);
+                            }
+                            MessageType::AttachmentChunk => {
+                                let mut state = server.lock().await;
+                                state.receive_chunk(addr, message);
+                            }
+                            MessageType::AttachmentRequest => {
+                                let state = server.lock().await;
+                                state.send_attachment(addr, message);
+                            }
+                            MessageType::Command => {
+                                let mut state = server.lock().await;
+                                state.command(addr, message);
+                            }
+                            MessageType::ChannelUpdate => {
+                                let mut state = server.lock().await;
+                                state.update_channel(addr, message);
+                            }
+                            MessageType::Invite => {
+                                let mut state = server.lock().await;
+                                state.invite(addr, message);
+                            }
+                            MessageType::Join => {
+                                let mut state = server.lock().await;
+                                state.join(addr, message);
+                            }
+                            MessageType::Part => {
+                                let mut state = server.lock().await;
+                                state.part(addr, message);
+                            }
+                            MessageType::Search => {
+                                let state = server.lock().await;
+                                state.search(addr, message);
+                            }
+                            MessageType::Who => {
+                                let state = server.lock().await;
+                                state.who(addr, message);
+                            }
+                            MessageType::Nick => {
+                                let mut state = server.lock().await;
+                                state.nick(addr, message);
+                            }
+                            MessageType::History => {
+                                let request = HistoryPayload::from_bytes(message.payload);
+                                let state = server.lock().await;
+                                state.send_requested_history(addr, request);
                             }
                             _ => {
                                 debug!("Client sent invalid message type");
@@ -0,0 +1,84 @@
+use common::permission::Role;
+use log::error;
+
+use crate::config::PluginConfig;
+use crate::integrations::Bot;
+
+mod echo;
+mod profanity;
+mod roll;
+
+/// Who a plugin is looking at and where
+pub struct Context {
+    pub username: String,
+    // the channel name, without the '#'
+    pub channel: String,
+    pub role: Role,
+}
+
+/// What a plugin answers a command with
+pub enum Reply {
+    // seen only by whoever typed the command
+    Notice(String),
+    // posted to the channel under the plugin's name, marked as a bot's
+    Post(String),
+}
+
+/// What a filter decided about a message
+pub enum Filter {
+    Pass,
+    // the message isn't posted, the sender is told why
+    Block(String),
+}
+
+/// Runs inside the server and reacts to what users post in channels. A plugin only sees
+/// channels it may use and posts with the permissions of a member, like a bot of the HTTP API
+pub trait Plugin: Send {
+    /// The slash commands it answers, without the slash
+    fn commands(&self) -> &[&str] {
+        &[]
+    }
+
+    /// Runs one of its commands, `args` is everything after the command word
+    fn command(&mut self, _context: &Context, _command: &str, _args: &str) -> Option<Reply> {
+        None
+    }
+
+    /// Looks at a message before it is posted, it may change the text or block it
+    fn filter(&mut self, _context: &Context, _text: &mut String) -> Filter {
+        Filter::Pass
+    }
+
+    /// Sees a message once it was posted, an answer is posted under the plugin's name
+    fn observe(&mut self, _context: &Context, _text: &str) -> Option<String> {
+        None
+    }
+}
+
+/// A plugin with the identity it posts and is checked under
+pub struct LoadedPlugin {
+    pub bot: Bot,
+    pub plugin: Box<dyn Plugin>,
+}
+
+/// Makes the plugins listed in the config, unknown ones are skipped
+pub fn load(configs: &[PluginConfig]) -> Vec<LoadedPlugin> {
+    let mut plugins = Vec::new();
+    for config in configs {
+        let plugin: Box<dyn Plugin> = match config.name.as_str() {
+            "echo" => Box::new(echo::Echo),
+            "roll" => Box::new(roll::Roll),
+            "profanity" => Box::new(profanity::Profanity::new(&config.settings)),
+            name => {
+                error!("There is no plugin called {}, skipping it", name);
+                continue;
+            }
+        };
+
+        plugins.push(LoadedPlugin {
+            bot: Bot::without_token(&config.name, config.channels.clone()),
+            plugin,
+        });
+    }
+    plugins
+}
@@ -0,0 +1,17 @@
+use crate::plugins::{Context, Plugin, Reply};
+
+/// `/echo <text>` posts the text back to the channel
+pub struct Echo;
+
+impl Plugin for Echo {
+    fn commands(&self) -> &[&str] {
+        &["echo"]
+    }
+
+    fn command(&mut self, _context: &Context, _command: &str, args: &str) -> Option<Reply> {
+        match args.trim() {
+            "" => Some(Reply::Notice("Usage: /echo <text>".to_string())),
+            text => Some(Reply::Post(text.to_string())),
+        }
+    }
+}
@@ -0,0 +1,77 @@
+use common::permission::Role;
+use common::search::tokenize;
+use serde::Deserialize;
+
+use crate::plugins::{Context, Filter, Plugin};
+
+/// The settings of the filter in the config
+#[derive(Deserialize)]
+#[serde(default)]
+struct Settings {
+    // the words to catch, matched whole and ignoring case
+    words: Vec<String>,
+    // mask replaces the words with asterisks, block refuses the message
+    action: String,
+    // this role and those above it aren't filtered, none filters everyone
+    exempt: Option<String>,
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            words: ["damn", "shit", "fuck", "bastard", "crap"].iter().map(|word| word.to_string()).collect(),
+            action: "mask".to_string(),
+            exempt: None,
+        }
+    }
+}
+
+/// Masks or blocks messages with words from a list
+pub struct Profanity {
+    words: Vec<String>,
+    block: bool,
+    exempt: Option<Role>,
+}
+
+impl Profanity {
+    pub fn new(settings: &serde_json::Value) -> Profanity {
+        let settings: Settings = match settings {
+            serde_json::Value::Null => Settings::default(),
+            settings => serde_json::from_value(settings.clone()).unwrap_or_default(),
+        };
+
+        Profanity {
+            words: settings.words.iter().map(|word| word.to_lowercase()).collect(),
+            block: settings.action == "block",
+            exempt: settings.exempt.as_deref().and_then(Role::from_name),
+        }
+    }
+}
+
+impl Plugin for Profanity {
+    fn filter(&mut self, context: &Context, text: &mut String) -> Filter {
+        if self.exempt.is_some_and(|exempt| context.role >= exempt) {
+            return Filter::Pass;
+        }
+
+        let found: Vec<(usize, usize)> = tokenize(text)
+            .into_iter()
+            .filter(|(_, _, word)| self.words.contains(word))
+            .map(|(start, end, _)| (start, end))
+            .collect();
+
+        if found.is_empty() {
+            return Filter::Pass;
+        }
+        if self.block {
+            return Filter::Block("Your message was blocked for its language".to_string());
+        }
+
+        // from the end so the earlier offsets stay valid
+        for (start, end) in found.into_iter().rev() {
+            let masked = "*".repeat(text[start..end].chars().count());
+            text.replace_range(start..end, &masked);
+        }
+        Filter::Pass
+    }
+}
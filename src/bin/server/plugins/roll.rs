@@ -0,0 +1,67 @@
+use rand::Rng;
+
+use crate::plugins::{Context, Plugin, Reply};
+
+// keeps a single roll from flooding the channel
+const MAX_DICE: u32 = 100;
+const MAX_SIDES: u32 = 1000;
+// more than the dice could ever add up to is no use, and keeps the total from overflowing
+const MAX_MODIFIER: i64 = MAX_DICE as i64 * MAX_SIDES as i64;
+
+/// `/roll [NdS[+M]]` rolls dice for everyone to see, one six-sided die by default
+pub struct Roll;
+
+/// Parses dice like `d20`, `2d6` or `3d8+2`, returns the count, sides and modifier
+fn parse_dice(dice: &str) -> Option<(u32, u32, i64)> {
+    let (count, rest) = dice.split_once('d')?;
+    let count = match count {
+        "" => 1,
+        count => count.parse().ok()?,
+    };
+    let (sides, modifier) = match rest.find(['+', '-']) {
+        Some(i) => (&rest[..i], rest[i..].parse().ok()?),
+        None => (rest, 0),
+    };
+    let sides = sides.parse().ok()?;
+
+    let valid = (1..=MAX_DICE).contains(&count)
+        && (2..=MAX_SIDES).contains(&sides)
+        && (-MAX_MODIFIER..=MAX_MODIFIER).contains(&modifier);
+    match valid {
+        true => Some((count, sides, modifier)),
+        false => None,
+    }
+}
+
+impl Plugin for Roll {
+    fn commands(&self) -> &[&str] {
+        &["roll"]
+    }
+
+    fn command(&mut self, context: &Context, _command: &str, args: &str) -> Option<Reply> {
+        let dice = match args.trim() {
+            "" => "1d6",
+            dice => dice,
+        };
+        let (count, sides, modifier) = match parse_dice(&dice.to_lowercase()) {
+            Some(parsed) => parsed,
+            None => {
+                return Some(Reply::Notice(format!(
+                    "{} is not a roll, try /roll 2d6 or /roll d20+3, up to {}d{}",
+                    dice, MAX_DICE, MAX_SIDES
+                )))
+            }
+        };
+
+        let mut rng = rand::thread_rng();
+        let rolls: Vec<i64> = (0..count).map(|_| rng.gen_range(1..=sides) as i64).collect();
+        let total = rolls.iter().sum::<i64>() + modifier;
+        let rolls = rolls.iter().map(|roll| roll.to_string()).collect::<Vec<String>>().join(", ");
+
+        let shown = match modifier {
+            0 => format!("[{}]", rolls),
+            modifier => format!("[{}] {:+}", rolls, modifier),
+        };
+        Some(Reply::Post(format!("{} rolled {}: {} = {}", context.username, dice, shown, total)))
+    }
+}
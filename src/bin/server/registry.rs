@@ -0,0 +1,207 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use common::channel::channel_name;
+use common::permission::Role;
+use common::user::User;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::server::Server;
+
+const REGISTRY_PATH: &str = "data/users.bson";
+// how often moved read markers are written, they move with every message read
+const SAVE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Everything the server remembers about a user between connections
+#[derive(Serialize, Deserialize, Clone)]
+pub struct UserRecord {
+    pub user: User,
+    // the id of the last message read, keyed by channel
+    pub read_markers: HashMap<String, u64>,
+    #[serde(default)]
+    pub role: Role,
+}
+
+impl UserRecord {
+    pub fn new(user: User) -> UserRecord {
+        UserRecord {
+            user,
+            read_markers: HashMap::new(),
+            role: Role::Member,
+        }
+    }
+}
+
+/// The on-disk layout of the registry, bson needs a document at the top level
+#[derive(Serialize, Deserialize, Default)]
+struct RegistryFile {
+    users: Vec<UserRecord>,
+}
+
+#[derive(Default)]
+pub struct Registry {
+    users: HashMap<u64, UserRecord>,
+    // whether a read marker moved since the registry was last written
+    changed: bool,
+}
+
+impl Registry {
+    /// Loads the registry, only a missing file starts an empty one
+    pub fn load() -> Result<Registry, String> {
+        let file: RegistryFile = match std::fs::read(REGISTRY_PATH) {
+            Ok(data) => bson::from_slice(&data).map_err(|e| format!("{} is damaged: {}", REGISTRY_PATH, e))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => RegistryFile::default(),
+            Err(e) => return Err(format!("Could not read {}: {}", REGISTRY_PATH, e)),
+        };
+
+        Ok(Registry {
+            users: file.users.into_iter().map(|record| (record.user.id, record)).collect(),
+            changed: false,
+        })
+    }
+
+    fn to_bytes(&self) -> Option<Vec<u8>> {
+        let file = RegistryFile {
+            users: self.users.values().cloned().collect(),
+        };
+
+        match bson::to_vec(&file) {
+            Ok(data) => Some(data),
+            Err(e) => {
+                error!("Error serializing the user registry: {}", e);
+                None
+            }
+        }
+    }
+
+    pub fn save(&mut self) {
+        self.changed = false;
+        let data = match self.to_bytes() {
+            Some(data) => data,
+            None => return,
+        };
+
+        std::fs::create_dir_all("data").unwrap();
+        if let Err(e) = std::fs::write(REGISTRY_PATH, data) {
+            error!("Error saving the user registry: {}", e);
+        }
+    }
+
+    /// Gets the record of a user that is logging in, creating it on their first login
+    pub fn login(&mut self, user: &User) -> &UserRecord {
+        // whoever registers first on a fresh server owns it
+        let first = self.users.is_empty();
+        let record = self.users.entry(user.id).or_insert_with(|| UserRecord::new(user.clone()));
+        if first {
+            info!("{} is the first user and owns the server", user.username);
+            record.role = Role::Owner;
+        }
+        // the name may have changed since the last login, the key stays the one the id registered with
+        if record.user.public_key.is_empty() {
+            record.user.public_key = user.public_key.clone();
+        }
+        record.user.change_username(user.username.clone());
+        self.save();
+
+        &self.users[&user.id]
+    }
+
+    /// Checks a user logging in holds the key their id registered with, anyone can send an id
+    pub fn check_key(&self, user: &User) -> Result<(), String> {
+        match self.users.get(&user.id) {
+            Some(record) if !record.user.public_key.is_empty() && record.user.public_key != user.public_key => {
+                Err("This user id is registered with another key".to_string())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Gives a user a new name, the next login keeps it as long as the client remembers it
+    pub fn rename(&mut self, id: u64, username: &str) {
+        if let Some(record) = self.users.get_mut(&id) {
+            record.user.change_username(username.to_string());
+            self.save();
+        }
+    }
+
+    pub fn get(&self, id: u64) -> Option<&UserRecord> {
+        self.users.get(&id)
+    }
+
+    pub fn find_by_username(&self, username: &str) -> Option<&UserRecord> {
+        self.users.values().find(|record| record.user.username == username)
+    }
+
+    pub fn all(&self) -> Vec<&UserRecord> {
+        let mut records: Vec<&UserRecord> = self.users.values().collect();
+        records.sort_by(|a, b| b.role.cmp(&a.role).then(a.user.username.cmp(&b.user.username)));
+        records
+    }
+
+    pub fn get_role(&self, id: u64) -> Role {
+        self.users.get(&id).map(|record| record.role).unwrap_or_default()
+    }
+
+    pub fn set_role(&mut self, id: u64, role: Role) {
+        if let Some(record) = self.users.get_mut(&id) {
+            record.role = role;
+            self.save();
+        }
+    }
+
+    /// The registry as it has to be written, None when nothing changed since the last time
+    pub fn changes(&mut self) -> Option<Vec<u8>> {
+        if !self.changed {
+            return None;
+        }
+        self.changed = false;
+        self.to_bytes()
+    }
+
+    /// Moves a user's read marker forward, a marker never moves back.
+    /// It is written with the next batch, see `keep_saved`
+    pub fn set_read_marker(&mut self, id: u64, channel: &str, message_id: u64) {
+        let record = match self.users.get_mut(&id) {
+            Some(record) => record,
+            None => return,
+        };
+
+        let marker = record.read_markers.entry(channel_name(channel).to_string()).or_insert(0);
+        if message_id > *marker {
+            *marker = message_id;
+            self.changed = true;
+        }
+    }
+
+    /// Moves everyone's read marker along with a renamed channel
+    pub fn rename_channel(&mut self, old: &str, new: &str) {
+        for record in self.users.values_mut() {
+            if let Some(marker) = record.read_markers.remove(old) {
+                record.read_markers.insert(new.to_string(), marker);
+            }
+        }
+        self.save();
+    }
+}
+
+/// Writes the registry when read markers moved, the lock is only held to serialize it
+pub async fn keep_saved(server: Arc<Mutex<Server>>) {
+    loop {
+        tokio::time::sleep(SAVE_INTERVAL).await;
+
+        let changes = server.lock().await.registry_changes();
+        let data = match changes {
+            Some(data) => data,
+            None => continue,
+        };
+        if let Err(e) = tokio::fs::create_dir_all("data").await {
+            error!("Error creating the data directory: {}", e);
+        }
+        if let Err(e) = tokio::fs::write(REGISTRY_PATH, data).await {
+            error!("Error saving the user registry: {}", e);
+        }
+    }
+}
@@ -0,0 +1,206 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+
+use common::channel::{channel_name, Channel};
+use common::id::to_timestamp;
+use common::message::{Message, MessagePayload, Payload};
+use common::search::tokenize;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::server::Server;
+
+const INDEX_PATH: &str = "data/search.bson";
+// how often a changed index is written, messages come in too often to write it for each one
+const SAVE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// What a search is filtered on, the text itself lives in the channel history
+#[derive(Serialize, Deserialize, Clone)]
+pub struct IndexedMessage {
+    pub id: u64,
+    pub channel: String,
+    pub author: String,
+}
+
+/// The on-disk layout of the index, bson needs a document at the top level
+#[derive(Serialize, Deserialize, Default)]
+struct SearchFile {
+    messages: Vec<IndexedMessage>,
+    words: HashMap<String, Vec<u64>>,
+}
+
+/// An inverted index of every message the server can read, word to message ids
+#[derive(Default)]
+pub struct SearchIndex {
+    messages: HashMap<u64, IndexedMessage>,
+    words: HashMap<String, BTreeSet<u64>>,
+    // whether the index changed since it was last written
+    changed: bool,
+}
+
+impl SearchIndex {
+    /// Loads the index and brings it up to date with the history of the given channels,
+    /// building it from that history when there is none yet
+    pub fn load<'a>(channels: impl Iterator<Item = &'a Channel>) -> SearchIndex {
+        let mut index = match std::fs::read(INDEX_PATH) {
+            Ok(data) => {
+                let file: SearchFile = bson::from_slice(&data).unwrap_or_default();
+                SearchIndex {
+                    messages: file.messages.into_iter().map(|m| (m.id, m)).collect(),
+                    words: file.words.into_iter().map(|(word, ids)| (word, ids.into_iter().collect())).collect(),
+                    changed: false,
+                }
+            }
+            Err(_) => SearchIndex::default(),
+        };
+
+        // messages that were only kept in memory are gone after a restart, and messages
+        // stored after the index was last written are still missing from it
+        let channels: Vec<&Channel> = channels.collect();
+        let kept: HashSet<u64> = channels.iter().flat_map(|channel| channel.get_all_messages()).map(|m| m.id).collect();
+        index.messages.retain(|id, _| kept.contains(id));
+        index.drop_unused_words();
+        let before = index.messages.len();
+        for channel in channels {
+            index.index_channel(channel);
+        }
+        if index.messages.len() > before {
+            info!("Indexed {} messages for search", index.messages.len() - before);
+        }
+
+        index.changed = true;
+        index
+    }
+
+    /// The index as it has to be written, None when it didn't change since the last time.
+    /// Channels in `memory_only` keep no messages on disk, so their words stay off it too
+    pub fn changes(&mut self, memory_only: &HashSet<String>) -> Option<Vec<u8>> {
+        if !self.changed {
+            return None;
+        }
+        self.changed = false;
+
+        let messages: Vec<IndexedMessage> = self.messages.values().filter(|m| !memory_only.contains(&m.channel)).cloned().collect();
+        let saved: HashSet<u64> = messages.iter().map(|m| m.id).collect();
+        let words = self
+            .words
+            .iter()
+            .map(|(word, ids)| (word.clone(), ids.iter().copied().filter(|id| saved.contains(id)).collect::<Vec<u64>>()))
+            .filter(|(_, ids)| !ids.is_empty())
+            .collect();
+
+        match bson::to_vec(&SearchFile { messages, words }) {
+            Ok(data) => Some(data),
+            Err(e) => {
+                error!("Error serializing the search index: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Indexes a message, it is written with the next changes
+    pub fn insert(&mut self, message: &Message) {
+        let payload = MessagePayload::from_bytes(message.payload.clone());
+        let text = String::from_utf8_lossy(&payload.message);
+
+        for (_, _, word) in tokenize(&text) {
+            self.words.entry(word).or_default().insert(message.id);
+        }
+        self.messages.insert(
+            message.id,
+            IndexedMessage {
+                id: message.id,
+                channel: channel_name(&payload.channel).to_string(),
+                author: payload.username,
+            },
+        );
+        self.changed = true;
+    }
+
+    /// Indexes the whole history of a channel, used when it stops being end-to-end
+    pub fn index_channel(&mut self, channel: &Channel) {
+        if channel.end_to_end {
+            return;
+        }
+        for message in channel.get_all_messages() {
+            if !self.messages.contains_key(&message.id) {
+                self.insert(&message);
+            }
+        }
+    }
+
+    /// Drops every message of a channel that isn't among `keep`, `keep` empty drops them all
+    pub fn retain_channel(&mut self, channel: &str, keep: &HashSet<u64>) {
+        self.messages.retain(|id, m| m.channel != channel || keep.contains(id));
+        self.drop_unused_words();
+        self.changed = true;
+    }
+
+    fn drop_unused_words(&mut self) {
+        let messages = &self.messages;
+        for ids in self.words.values_mut() {
+            ids.retain(|id| messages.contains_key(id));
+        }
+        self.words.retain(|_, ids| !ids.is_empty());
+    }
+
+    pub fn remove_channel(&mut self, channel: &str) {
+        self.retain_channel(channel, &HashSet::new());
+    }
+
+    pub fn rename_channel(&mut self, old: &str, new: &str) {
+        for message in self.messages.values_mut().filter(|m| m.channel == old) {
+            message.channel = new.to_string();
+        }
+        self.changed = true;
+    }
+
+    /// Finds the messages that contain every word, newest first.
+    /// `after` and `before` are unix seconds, `after` counts the second itself and `before` doesn't
+    pub fn search(&self, words: &[String], channels: &[String], author: Option<&str>, after: Option<u64>, before: Option<u64>) -> Vec<&IndexedMessage> {
+        let mut matches: Option<BTreeSet<u64>> = None;
+        for word in words {
+            let ids = match self.words.get(word) {
+                Some(ids) => ids,
+                None => return Vec::new(),
+            };
+            matches = Some(match matches {
+                Some(matches) => matches.intersection(ids).copied().collect(),
+                None => ids.clone(),
+            });
+        }
+
+        // ids start with their timestamp, so the highest id is the newest message
+        matches
+            .unwrap_or_default()
+            .iter()
+            .rev()
+            .filter_map(|id| self.messages.get(id))
+            .filter(|m| channels.contains(&m.channel))
+            .filter(|m| author.is_none_or(|author| m.author.eq_ignore_ascii_case(author)))
+            .filter(|m| after.is_none_or(|after| to_timestamp(m.id) >= after))
+            .filter(|m| before.is_none_or(|before| to_timestamp(m.id) < before))
+            .collect()
+    }
+}
+
+/// Writes the search index whenever it changed, the lock is only held to serialize it
+pub async fn keep_saved(server: Arc<Mutex<Server>>) {
+    loop {
+        tokio::time::sleep(SAVE_INTERVAL).await;
+
+        let changes = server.lock().await.search_changes();
+        let data = match changes {
+            Some(data) => data,
+            None => continue,
+        };
+        if let Err(e) = tokio::fs::create_dir_all("data").await {
+            error!("Error creating the data directory: {}", e);
+        }
+        if let Err(e) = tokio::fs::write(INDEX_PATH, data).await {
+            error!("Error saving the search index: {}", e);
+        }
+    }
+}
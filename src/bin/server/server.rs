@@ -1,29 +1,563 @@
-use common::{channel::Channel, crypt, user::User};
-use log::{debug, error};
+use common::{channel::{self, channel_name, Channel, ChannelAction, ChannelListPayload, ChannelUpdatePayload, RetentionPolicy, WhoPayload}, crypt, user::{NickPayload, User}};
+use common::invite::{InvitePayload, JoinPayload};
+use common::export::{self, ExportedMessage};
+use common::id::{create_id, to_timestamp, IdType};
+use common::federation::{qualify, split_address, PeerHelloPayload, PeerMessagePayload};
+use common::search::{tokenize, SearchPayload, SearchResult};
+use common::permission::{Permission, Role};
+use common::attachment::{AttachmentChunkPayload, AttachmentMeta, AttachmentRequestPayload};
+use common::profile::write_private;
+use log::{debug, error, info};
+use serde_json::json;
 use x25519_dalek::{PublicKey, StaticSecret};
-use std::{collections::HashMap, net::SocketAddr};
+use std::{collections::{HashMap, HashSet}, net::{IpAddr, SocketAddr}, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
 use tokio::sync::mpsc;
-use common::message::{Message, MessagePayload, Payload};
+use common::message::{is_valid_emoji, HistoryPayload, Message, MessagePayload, MessageStatusPayload, MessageType, Payload, ReactionPayload, ReadMarkerPayload, TypingPayload, CommandPayload};
 
+use crate::admin;
+use crate::audit::{AuditEvent, AuditLog};
+use crate::bans::{Ban, BanList, BanTarget, Network};
+use crate::blobs::BlobStore;
 use crate::client::Client;
+use crate::config::{PeerConfig, ServerConfig};
+use crate::events::{self, Delivery, DeliveryQueue, EventKind};
+use crate::http::{ChannelSummary, HttpError};
+use crate::integrations::{Bot, Integrations};
+use crate::invites::InviteList;
+use crate::plugins::{self, Context, Filter, LoadedPlugin, Reply};
+use crate::registry::Registry;
+use crate::search::SearchIndex;
 
 pub type Tx = mpsc::UnboundedSender<Vec<u8>>;
 pub type Rx = mpsc::UnboundedReceiver<Vec<u8>>;
 
+// where admins export channel history to and import it from
+const EXPORTS_DIR: &str = "data/exports";
+
+// the server's identity key, kept so peers and clients can recognize it across restarts
+const PRIVATE_KEY_PATH: &str = "data/server.key";
+// the public half in base64, for the config of peers
+const PUBLIC_KEY_PATH: &str = "data/server.pub";
+
+// a client's typing signals for a channel are forwarded at most this often
+const TYPING_INTERVAL: Duration = Duration::from_secs(2);
+// the longest username /nick accepts, in characters
+const MAX_USERNAME_LENGTH: usize = 32;
+
+/// A live federation link to another server
+struct Peer {
+    // tells links apart when a peer reconnects before the old link noticed it was gone
+    link: u64,
+    tx: Tx,
+    // made from both ends' keys and the nonces of this link's handshake
+    shared_key: Vec<u8>,
+    // the sequence numbers of the last relay sent and received on this link
+    sent: u64,
+    received: u64,
+    // whether the server whose name sorts first dialed this link, that link wins when both dial at once
+    preferred: bool,
+}
+
 pub struct Server {
     channels: HashMap<String, Channel>,
     clients: HashMap<SocketAddr, Tx>,
     shared_keys: HashMap<SocketAddr, Vec<u8>>,
+    users: HashMap<SocketAddr, User>,
+    // when each client last had a typing signal forwarded, per channel
+    typing: HashMap<(SocketAddr, String), Instant>,
+    registry: Registry,
+    bans: BanList,
+    invites: InviteList,
+    // bots of the HTTP API and incoming webhooks
+    integrations: Integrations,
+    // events waiting to be posted to outgoing webhooks
+    deliveries: DeliveryQueue,
+    plugins: Vec<LoadedPlugin>,
+    search: SearchIndex,
+    // muted users by id, until when
+    mutes: HashMap<u64, Instant>,
+    blobs: BlobStore,
+    config: ServerConfig,
+    audit: AuditLog,
+    // linked servers by name
+    peers: HashMap<String, Peer>,
     private_key: Vec<u8>,
+    // the id given to the last message that arrived, the next one goes after it
+    last_message_id: u64,
 }
 
 impl Server {
-    pub fn add_channel(&mut self, channel: Channel) {
+    pub fn add_channel(&mut self, actor: &str, channel: Channel) {
+        self.record_audit("channel_created", actor, format!("#{}", channel.name));
         self.channels.insert(channel.name.clone(), channel);
+        self.save_channels();
+    }
+
+    pub fn remove_channel(&mut self, actor: &str, name: &str) {
+        if let Some(channel) = self.channels.remove(name) {
+            self.record_audit("channel_deleted", actor, format!("#{}", channel.name));
+            channel.delete_archive();
+            self.blobs.release(&channel.name, &HashSet::new());
+            self.save_channels();
+        }
+    }
+
+    fn save_channels(&self) {
+        channel::save_channels(self.channels.values());
+    }
+
+    /// Sends a user that just logged in the channels they may read
+    pub fn send_channel_list(&self, addr: SocketAddr) {
+        let mut channels: Vec<_> = self
+            .channels
+            .values()
+            .filter(|channel| self.can(addr, &channel.name, Permission::Read))
+            .map(|channel| channel.info())
+            .collect();
+        channels.sort_by(|a, b| a.name.cmp(&b.name));
+
+        let message = Message::new(MessageType::ChannelList, vec![]);
+        self.send_payload(addr, message, ChannelListPayload::new(channels));
+    }
+
+    /// Creates, renames, archives or deletes a channel, or sets its topic or description,
+    /// then tells everyone who can read the channel
+    pub fn update_channel(&mut self, sender: SocketAddr, msg: Message) {
+        let mut update = ChannelUpdatePayload::from_bytes(msg.payload.clone());
+        update.channel = channel_name(&update.channel).to_string();
+        update.username = self.get_actor(sender);
+
+        // whoever could read a deleted channel still has to hear that it is gone
+        let before = match self.channels.get(&update.channel) {
+            Some(channel) => self.get_members(channel),
+            None => Vec::new(),
+        };
+        let mut members = before.clone();
+
+        if let Err(error) = self.apply_channel_update(sender, &update) {
+            self.notice(sender, &format!("#{}", update.channel), format!("Error: {}", error));
+            return;
+        }
+
+        let name = match &update.action {
+            ChannelAction::Rename(name) => name.clone(),
+            _ => update.channel.clone(),
+        };
+        if let Some(channel) = self.channels.get(&name) {
+            members = self.get_members(channel);
+        }
+
+        for addr in members.iter() {
+            self.send_payload(*addr, msg.clone(), update.clone());
+        }
+
+        // going private or public changes who is in the channel
+        for addr in before.iter().filter(|addr| !members.contains(addr)) {
+            self.send_parted(*addr, &name);
+        }
+        if update.action == ChannelAction::Public {
+            for addr in members.iter().filter(|addr| !before.contains(addr)) {
+                self.send_joined(*addr, &name);
+            }
+        }
+    }
+
+    /// Tells a client it is in a channel now and catches it up on the channel
+    fn send_joined(&self, addr: SocketAddr, channel: &str) {
+        let payload = JoinPayload::new(channel.to_string(), Vec::new());
+        self.send_payload(addr, Message::new(MessageType::Join, vec![]), payload);
+        self.send_channel_list(addr);
+        self.send_requested_history(addr, HistoryPayload::new(channel.to_string(), None, Vec::new()));
+    }
+
+    fn send_parted(&self, addr: SocketAddr, channel: &str) {
+        let payload = JoinPayload::new(channel.to_string(), Vec::new());
+        self.send_payload(addr, Message::new(MessageType::Part, vec![]), payload);
+    }
+
+    /// Invites a user into a private channel, or hands out a code to join it with
+    pub fn invite(&mut self, sender: SocketAddr, msg: Message) {
+        let mut invite = InvitePayload::from_bytes(msg.payload.clone());
+        invite.decrypt(self.get_shared_key(sender));
+        invite.channel = channel_name(&invite.channel).to_string();
+        let channel = format!("#{}", invite.channel);
+
+        match self.channels.get(&invite.channel) {
+            Some(found) if !found.private => {
+                self.notice(sender, &channel, format!("{} is public, anyone can join it", channel));
+                return;
+            }
+            Some(_) if self.can(sender, &invite.channel, Permission::Read) => {}
+            _ => {
+                self.notice(sender, "ALL", format!("You are not in {}", channel));
+                return;
+            }
+        }
+
+        let actor = self.get_actor(sender);
+        if invite.username.is_empty() {
+            let created = match self.invites.create(&invite.channel, &actor, invite.max_uses, invite.valid_for) {
+                Ok(created) => created,
+                Err(e) => {
+                    self.notice(sender, &channel, e);
+                    return;
+                }
+            };
+            self.record_audit("invite_created", &actor, format!("for {}", channel));
+
+            invite.code = created.code.into_bytes();
+            invite.expires = created.expires;
+            self.send_payload(sender, msg, invite);
+            return;
+        }
+
+        let id = match self.registry.find_by_username(&invite.username) {
+            Some(record) => record.user.id,
+            None => {
+                self.notice(sender, &channel, format!("Unknown user {}", invite.username));
+                return;
+            }
+        };
+        if self.channels[&invite.channel].users.contains(&id) {
+            self.notice(sender, &channel, format!("{} is already in {}", invite.username, channel));
+            return;
+        }
+
+        self.channels.get_mut(&invite.channel).unwrap().add_user(id);
+        self.save_channels();
+        self.record_audit("invite", &actor, format!("{} to {}", invite.username, channel));
+        self.emit(EventKind::UserJoined, Some(&invite.channel), json!({ "username": invite.username, "channel": invite.channel, "added_by": actor }));
+
+        for addr in self.get_connections(id) {
+            self.send_joined(addr, &invite.channel);
+        }
+        self.channel_notice(&channel, format!("{} added {} to {}", actor, invite.username, channel));
+    }
+
+    /// Joins a private channel with an invite code
+    pub fn join(&mut self, sender: SocketAddr, msg: Message) {
+        let mut join = JoinPayload::from_bytes(msg.payload);
+        join.decrypt(self.get_shared_key(sender));
+        let code = String::from_utf8_lossy(&join.code).to_string();
+
+        let user = match self.users.get(&sender) {
+            Some(user) => user.clone(),
+            None => return,
+        };
+
+        if let Some(invite) = self.invites.get(&code) {
+            if self.channels.get(&invite.channel).is_some_and(|channel| channel.users.contains(&user.id)) {
+                self.notice(sender, "ALL", format!("You are already in #{}", invite.channel));
+                return;
+            }
+        }
+
+        let name = match self.invites.redeem(&code) {
+            Ok(name) => name,
+            Err(error) => {
+                self.notice(sender, "ALL", format!("Error: {}", error));
+                return;
+            }
+        };
+        let channel = match self.channels.get_mut(&name) {
+            Some(channel) => channel,
+            None => return,
+        };
+
+        channel.add_user(user.id);
+        self.save_channels();
+        self.record_audit("invite_used", &user.username, format!("{} for #{}", code, name));
+        self.emit(EventKind::UserJoined, Some(&name), json!({ "username": user.username, "channel": name }));
+
+        self.send_joined(sender, &name);
+        self.channel_notice(&format!("#{}", name), format!("{} joined #{}", user.username, name));
+    }
+
+    /// Leaves a private channel, the way back in is another invite
+    pub fn part(&mut self, sender: SocketAddr, msg: Message) {
+        let join = JoinPayload::from_bytes(msg.payload);
+        let name = channel_name(&join.channel).to_string();
+
+        let user = match self.users.get(&sender) {
+            Some(user) => user.clone(),
+            None => return,
+        };
+
+        match self.channels.get_mut(&name) {
+            Some(channel) if channel.private && channel.users.contains(&user.id) => channel.remove_user(user.id),
+            Some(channel) if !channel.private => {
+                self.notice(sender, &join.channel, "Only private channels can be left".to_string());
+                return;
+            }
+            _ => {
+                self.notice(sender, "ALL", format!("You are not in #{}", name));
+                return;
+            }
+        }
+
+        self.save_channels();
+        for addr in self.get_connections(user.id) {
+            self.send_parted(addr, &name);
+        }
+        self.channel_notice(&format!("#{}", name), format!("{} left #{}", user.username, name));
+    }
+
+    /// Applies every channel's retention policy, the background task calls this
+    pub fn prune_channels(&mut self) {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        for channel in self.channels.values_mut() {
+            let removed = channel.prune(now);
+            if removed > 0 {
+                info!("Pruned {} messages from #{}", removed, channel.name);
+                let keep: HashSet<u64> = channel.get_all_messages().iter().map(|m| m.id).collect();
+                self.search.retain_channel(&channel.name, &keep);
+                self.blobs.release(&channel.name, &channel.attachment_ids());
+            }
+        }
+    }
+
+    pub fn get_retention_interval(&self) -> Duration {
+        Duration::from_secs(self.config.retention_interval.max(1))
+    }
+
+    pub fn get_websocket_address(&self) -> Option<String> {
+        self.config.websocket_address.clone()
+    }
+
+    pub fn describe_retention(&self, actor: SocketAddr, channel: &str) -> Result<String, String> {
+        if !self.can(actor, channel, Permission::Manage) {
+            return Err(format!("You may not manage {}", channel));
+        }
+
+        let channel = &self.channels[channel_name(channel)];
+        let policy = &channel.retention;
+        let limit = |limit: Option<String>| limit.unwrap_or_else(|| "no limit".to_string());
+        Ok(format!(
+            "#{}: count {}, age {}, size {}, {} in memory, {}",
+            channel.name,
+            limit(policy.max_messages.map(|count| count.to_string())),
+            limit(policy.max_age.map(|age| format!("{}s", age))),
+            limit(policy.max_size.map(|size| format!("{} bytes", size))),
+            channel.max_messages,
+            if channel.backup_messages { "older messages on disk" } else { "nothing on disk" },
+        ))
+    }
+
+    /// Changes how much history a channel keeps, pruning right away
+    pub fn set_retention(&mut self, actor: SocketAddr, channel: &str, retention: RetentionPolicy, max_messages: usize, backup_messages: bool) -> Result<String, String> {
+        if !self.can(actor, channel, Permission::Manage) {
+            return Err(format!("You may not manage {}", channel));
+        }
+
+        let found = self.channels.get_mut(channel_name(channel)).unwrap();
+        found.retention = retention;
+        found.max_messages = max_messages.max(1);
+        found.set_backup_messages(backup_messages);
+        // turning the disk off drops the archive
+        let kept = found.attachment_ids();
+        self.blobs.release(channel_name(channel), &kept);
+        self.prune_channels();
+        self.save_channels();
+
+        self.describe_retention(actor, channel)
+    }
+
+    /// Writes the whole history of a channel to data/exports, returns where it went
+    pub fn export_channel(&self, actor: SocketAddr, channel: &str, format: &str) -> Result<String, String> {
+        let found = match self.channels.get(channel_name(channel)) {
+            Some(found) => found,
+            None => return Err(format!("There is no channel {}", channel)),
+        };
+        if !self.can(actor, &found.name, Permission::Manage) {
+            return Err(format!("You may not manage {}", channel));
+        }
+
+        let mut messages = found.get_all_messages();
+        messages.sort_by_key(|m| m.id);
+        let (extension, contents) = export::export(&found.name, format, &messages)?;
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let path = format!("{}/{}-{}.{}", EXPORTS_DIR, found.name, now, extension);
+        std::fs::create_dir_all(EXPORTS_DIR).map_err(|e| e.to_string())?;
+        std::fs::write(&path, contents).map_err(|e| e.to_string())?;
+        Ok(format!("Exported {} messages from #{} to {}", messages.len(), found.name, path))
+    }
+
+    /// Reads a JSON Lines export from data/exports into a channel, messages it already has are skipped
+    pub fn import_channel(&mut self, actor: SocketAddr, channel: &str, file: &str) -> Result<String, String> {
+        let name = channel_name(channel).to_string();
+        if !self.channels.contains_key(&name) {
+            return Err(format!("There is no channel {}", channel));
+        }
+        if !self.can(actor, &name, Permission::Manage) {
+            return Err(format!("You may not manage {}", channel));
+        }
+        // only files in the exports directory, an admin command shouldn't read anything else on the server
+        if file.contains(['/', '\\']) || file.starts_with('.') {
+            return Err(format!("{} has to be a file name in {}", file, EXPORTS_DIR));
+        }
+
+        let text = std::fs::read_to_string(format!("{}/{}", EXPORTS_DIR, file)).map_err(|e| format!("Can't read {}: {}", file, e))?;
+        let messages = export::from_json_lines(&text, &name)?;
+        let total = messages.len();
+
+        let found = self.channels.get_mut(&name).unwrap();
+        if !found.backup_messages {
+            return Err(format!("#{} keeps no messages on disk, turn that on with retention {} disk on", name, name));
+        }
+        let added = found.import(messages);
+        if !found.end_to_end {
+            for message in added.iter() {
+                self.search.insert(message);
+            }
+        }
+
+        Ok(format!("Imported {} messages into #{}, {} were already there", added.len(), name, total - added.len()))
+    }
+
+    pub fn get_retention(&self, channel: &str) -> Option<(RetentionPolicy, usize, bool)> {
+        self.channels
+            .get(channel_name(channel))
+            .map(|channel| (channel.retention.clone(), channel.max_messages, channel.backup_messages))
+    }
+
+    pub fn list_invites(&self, actor: SocketAddr, channel: &str) -> Result<String, String> {
+        if !self.can(actor, channel, Permission::Manage) {
+            return Err(format!("You may not manage {}", channel));
+        }
+
+        let invites = self.invites.for_channel(channel_name(channel));
+        if invites.is_empty() {
+            return Ok(format!("{} has no open invites", channel));
+        }
+
+        Ok(invites
+            .iter()
+            .map(|invite| {
+                let uses = match invite.max_uses {
+                    Some(max_uses) => format!("{}/{}", invite.uses, max_uses),
+                    None => invite.uses.to_string(),
+                };
+                format!("{} by {} ({} uses)", invite.code, invite.created_by, uses)
+            })
+            .collect::<Vec<String>>()
+            .join(", "))
+    }
+
+    pub fn revoke_invite(&mut self, actor: SocketAddr, code: &str) -> Result<String, String> {
+        let channel = match self.invites.get(code) {
+            Some(invite) => invite.channel.clone(),
+            None => return Err(format!("Unknown invite {}", code)),
+        };
+
+        if !self.can(actor, &channel, Permission::Manage) {
+            return Err(format!("You may not manage #{}", channel));
+        }
+
+        self.invites.revoke(code);
+        Ok(format!("Revoked {}", code))
     }
 
-    pub fn remove_channel(&mut self, channel: Channel) {
-        self.channels.remove(&channel.name);
+    fn apply_channel_update(&mut self, sender: SocketAddr, update: &ChannelUpdatePayload) -> Result<(), String> {
+        let actor = update.username.clone();
+
+        if update.action == ChannelAction::Create {
+            if self.get_role(sender) < Role::Moderator {
+                return Err("Only moderators can create channels".to_string());
+            }
+            if !channel::is_valid_name(&update.channel) {
+                return Err(format!("{} is not a valid channel name", update.channel));
+            }
+            if self.channels.contains_key(&update.channel) {
+                return Err(format!("#{} already exists", update.channel));
+            }
+
+            let mut channel = Channel::new(update.channel.clone());
+            for user in self.users.values() {
+                channel.add_user(user.id);
+            }
+            self.add_channel(&actor, channel);
+            return Ok(());
+        }
+
+        if !self.channels.contains_key(&update.channel) {
+            return Err(format!("There is no channel #{}", update.channel));
+        }
+        if !self.can(sender, &update.channel, Permission::Manage) {
+            return Err(format!("You may not manage #{}", update.channel));
+        }
+
+        let channel = self.channels.get_mut(&update.channel).unwrap();
+        match &update.action {
+            ChannelAction::Rename(name) => {
+                if !channel::is_valid_name(name) {
+                    return Err(format!("{} is not a valid channel name", name));
+                }
+                if self.channels.contains_key(name) {
+                    return Err(format!("#{} already exists", name));
+                }
+
+                let mut channel = self.channels.remove(&update.channel).unwrap();
+                channel.rename(name.clone());
+                self.channels.insert(name.clone(), channel);
+                self.registry.rename_channel(&update.channel, name);
+                self.invites.rename_channel(&update.channel, name);
+                self.integrations.rename_channel(&update.channel, name);
+                self.search.rename_channel(&update.channel, name);
+                self.blobs.rename_channel(&update.channel, name);
+                self.record_audit("channel_renamed", &actor, format!("#{} to #{}", update.channel, name));
+            }
+            ChannelAction::Archive | ChannelAction::Unarchive => {
+                channel.archived = update.action == ChannelAction::Archive;
+                let kind = if channel.archived { "channel_archived" } else { "channel_unarchived" };
+                self.record_audit(kind, &actor, format!("#{}", update.channel));
+            }
+            ChannelAction::Delete => {
+                self.invites.remove_channel(&update.channel);
+                self.integrations.remove_channel(&update.channel);
+                self.search.remove_channel(&update.channel);
+                self.remove_channel(&actor, &update.channel);
+                return Ok(());
+            }
+            ChannelAction::Topic(topic) => {
+                channel.topic = topic.chars().take(256).collect();
+                self.record_audit("channel_topic", &actor, format!("#{}: {}", update.channel, topic));
+            }
+            ChannelAction::Description(description) => {
+                channel.description = description.chars().take(1024).collect();
+                self.record_audit("channel_description", &actor, format!("#{}", update.channel));
+            }
+            ChannelAction::Private => {
+                // only whoever made it private stays, everyone else needs an invite
+                let id = self.users[&sender].id;
+                channel.private = true;
+                channel.users = vec![id];
+                self.record_audit("channel_private", &actor, format!("#{}", update.channel));
+            }
+            ChannelAction::Public => {
+                channel.private = false;
+                for user in self.users.values() {
+                    if !channel.users.contains(&user.id) {
+                        channel.add_user(user.id);
+                    }
+                }
+                self.record_audit("channel_public", &actor, format!("#{}", update.channel));
+            }
+            ChannelAction::EndToEnd(end_to_end) => {
+                // the server still relays and keeps the history, but it stops indexing it
+                channel.end_to_end = *end_to_end;
+                match end_to_end {
+                    true => self.search.remove_channel(&update.channel),
+                    false => self.search.index_channel(channel),
+                }
+                let kind = if *end_to_end { "channel_e2e_on" } else { "channel_e2e_off" };
+                self.record_audit(kind, &actor, format!("#{}", update.channel));
+            }
+            ChannelAction::Create | ChannelAction::Unknown => return Err("Unknown channel action".to_string()),
+        }
+
+        self.save_channels();
+        Ok(())
     }
 
     pub fn add_client(&mut self, addr: SocketAddr, tx: Tx) {
@@ -32,70 +566,1590 @@ impl Server {
 
     pub fn remove_client(&mut self, addr: SocketAddr) {
         self.clients.remove(&addr);
+        self.shared_keys.remove(&addr);
+        self.typing.retain(|(typing_addr, _), _| typing_addr != &addr);
+
+        // private channels keep their members while they are away
+        if let Some(user) = self.users.remove(&addr) {
+            for channel in self.channels.values_mut().filter(|channel| !channel.private) {
+                channel.remove_user(user.id);
+            }
+        }
     }
 
-    pub async fn broadcast(&mut self, sender: Option<SocketAddr>, msg: Message) {
-        // lets grab the payload and decrypt it
-        let payload = msg.payload.clone();
-        let mut payload = MessagePayload::from_bytes(payload);
-        // if sender is none, then we are broadcasting a message from the server
-        // so we don't need to decrypt it
-        if let Some(sender) = sender {
-            let shared_key = self.get_shared_key(sender);
-            let decrypted_payload = crypt::decrypt_data(payload.message, self.shared_keys[&sender].clone());
-            payload.message = decrypted_payload;
+    /// Checks a user may log in, known ids have to come with the key they registered with
+    /// and the username has to be one they could pick with /nick
+    pub fn check_login(&self, user: &User) -> Result<(), String> {
+        self.registry.check_key(user)?;
+        self.check_username(user.id, &user.username)
+    }
+
+    /// Binds a logged in user to its connection and joins it to every public channel
+    pub fn add_user(&mut self, addr: SocketAddr, user: User) {
+        for channel in self.channels.values_mut().filter(|channel| !channel.private) {
+            if !channel.users.contains(&user.id) {
+                channel.add_user(user.id);
+            }
         }
 
-        for (addr, tx) in self.clients.iter_mut() {
-            // if there is a sender, then we don't want to send the message back to them
-            if let Some(sender) = sender {
-                if addr == &sender {
-                    continue;
-                }
+        self.record_audit("login", &user.username, format!("from {}", addr));
+        self.emit(EventKind::UserJoined, None, json!({ "username": user.username }));
+
+        self.registry.login(&user);
+        self.users.insert(addr, user);
+    }
+
+    /// Sends a user that just logged in what they have read so far
+    pub fn send_read_markers(&self, addr: SocketAddr) {
+        let record = match self.users.get(&addr).and_then(|user| self.registry.get(user.id)) {
+            Some(record) => record,
+            None => return,
+        };
+
+        let payload = ReadMarkerPayload::new(record.user.username.clone(), record.read_markers.clone());
+        self.send_payload(addr, Message::new(MessageType::ReadMarker, vec![]), payload);
+    }
+
+    /// Stores what a client reports as read
+    pub fn read_marker(&mut self, sender: SocketAddr, msg: Message) {
+        let payload = ReadMarkerPayload::from_bytes(msg.payload);
+        let id = match self.users.get(&sender) {
+            Some(user) => user.id,
+            None => return,
+        };
+
+        for (channel, message_id) in payload.markers.iter() {
+            if self.channels.contains_key(channel_name(channel)) {
+                self.registry.set_read_marker(id, channel, *message_id);
             }
-            debug!("Sending message to client {}", addr.to_string());
+        }
+    }
 
-            // re-encrypt the message with the shared key of the receiver
-            let mut new_message = msg.clone();
-            let mut new_payload = payload.clone();
-            new_payload.message = crypt::encrypt_data(new_payload.message, self.shared_keys[addr].clone());
-            new_message.payload = new_payload.to_bytes();
-            let message = new_message.to_bytes();
+    /// Gets the connections of everyone in a channel that may read it
+    fn get_members(&self, channel: &Channel) -> Vec<SocketAddr> {
+        self.users
+            .iter()
+            .filter(|(_, user)| channel.users.contains(&user.id))
+            .filter(|(_, user)| channel.allows(self.registry.get_role(user.id), Permission::Read))
+            .map(|(addr, _)| *addr)
+            .collect()
+    }
+
+    pub fn get_role(&self, addr: SocketAddr) -> Role {
+        match self.users.get(&addr) {
+            Some(user) => self.registry.get_role(user.id),
+            None => Role::Member,
+        }
+    }
+
+    /// Checks whether the user at a connection may do something in a channel
+    pub fn can(&self, addr: SocketAddr, channel: &str, permission: Permission) -> bool {
+        match self.channels.get(channel_name(channel)) {
+            // archived channels only take reading
+            Some(channel) if channel.archived && permission == Permission::Post => false,
+            // private channels are for their members alone
+            Some(channel) if channel.private && !self.users.get(&addr).is_some_and(|user| channel.users.contains(&user.id)) => false,
+            Some(channel) => self.users.contains_key(&addr) && channel.allows(self.get_role(addr), permission),
+            None => false,
+        }
+    }
+
+    /// Forwards a typing signal to the other members of its channel, it is never stored
+    pub fn typing(&mut self, sender: SocketAddr, msg: Message) {
+        let mut typing = TypingPayload::from_bytes(msg.payload.clone());
+
+        // don't trust the client with who is typing
+        typing.username = match self.users.get(&sender) {
+            Some(user) => user.username.clone(),
+            None => return,
+        };
+
+        if !self.can(sender, &typing.channel, Permission::Post) || self.muted_for(sender).is_some() {
+            return;
+        }
+        let channel = match self.channels.get(channel_name(&typing.channel)) {
+            Some(channel) => channel,
+            None => return,
+        };
+
+        let key = (sender, channel.name.clone());
+        if let Some(last) = self.typing.get(&key) {
+            if last.elapsed() < TYPING_INTERVAL {
+                return;
+            }
+        }
 
-            if let Err(e) = tx.send(message) {
-                error!("Error sending message to client {}: {}", addr.to_string(), e);
+        for addr in self.get_members(channel) {
+            if addr != sender {
+                self.send_payload(addr, msg.clone(), typing.clone());
             }
         }
+
+        self.typing.insert(key, Instant::now());
     }
 
-    pub fn add_shared_key(&mut self, addr: SocketAddr, shared_key: Vec<u8>) {
-        self.shared_keys.insert(addr, shared_key);
+    /// Checks the messages a new message replies to or continues the thread of are in its channel
+    fn check_references(&self, channel: &str, reply_to: Option<u64>, thread: Option<u64>) -> Result<(), String> {
+        let found = match self.channels.get(channel_name(channel)) {
+            Some(found) => found,
+            None => return Err(format!("There is no channel {}", channel)),
+        };
+        for id in reply_to.iter().chain(thread.iter()) {
+            if !found.knows_message(*id) {
+                return Err(format!("#{} has no message {}", found.name, id));
+            }
+        }
+        Ok(())
     }
 
-    pub fn get_private_key(&self) -> Vec<u8> {
-        self.private_key.clone()
+    /// Passes a client's message on to its channel, if the client may post there. The client is
+    /// told the id the message was stored under, or why it was not posted
+    pub async fn post(&mut self, sender: SocketAddr, msg: Message) {
+        let payload = MessagePayload::from_bytes(msg.payload.clone());
+        if payload.channel.starts_with('@') {
+            self.direct_message(sender, msg, payload);
+            return;
+        }
+
+        if !self.channels.contains_key(channel_name(&payload.channel)) {
+            self.refuse(sender, &msg, &payload.channel, format!("There is no channel {}", payload.channel));
+            return;
+        }
+
+        if self.channels[channel_name(&payload.channel)].archived {
+            self.refuse(sender, &msg, &payload.channel, format!("{} is archived", payload.channel));
+            return;
+        }
+
+        if !self.can(sender, &payload.channel, Permission::Post) {
+            self.refuse(sender, &msg, &payload.channel, format!("You may not post in {}", payload.channel));
+            return;
+        }
+
+        if let Some(left) = self.muted_for(sender) {
+            self.refuse(sender, &msg, &payload.channel, format!("You are muted for another {}s", left.as_secs() + 1));
+            return;
+        }
+
+        if let Err(e) = self.check_references(&payload.channel, payload.reply_to, payload.thread) {
+            self.refuse(sender, &msg, &payload.channel, e);
+            return;
+        }
+
+        let (mut msg, context, text) = match self.run_plugins(sender, msg).await {
+            Some(checked) => checked,
+            None => return,
+        };
+        let sent_id = msg.id;
+        msg.id = self.next_message_id();
+        let status = MessageStatusPayload::new(payload.channel.clone(), sent_id, msg.id);
+        self.broadcast(Some(sender), msg).await;
+        self.send_payload(sender, Message::new(MessageType::MessageStatus, vec![]), status);
+        self.observe(sender, &context, &text).await;
     }
 
-    pub fn get_shared_key(&self, addr: SocketAddr) -> Vec<u8> {
-        self.shared_keys.get(&addr).unwrap().clone()
+    /// Gives a message that arrived its id, clients don't choose where their messages go.
+    /// The id counts up from the last one within a second, unless that runs out of room
+    fn next_message_id(&mut self) -> u64 {
+        let mut id = create_id(IdType::Message);
+        let last = self.last_message_id;
+        // the low 14 bits are random, the type bits above them stay the same
+        if id <= last && to_timestamp(id) == to_timestamp(last) && (last & 0x3fff) < 0x3fff {
+            id = last + 1;
+        }
+        self.last_message_id = id;
+        id
     }
-}
 
-impl Default for Server {
-    fn default() -> Server {
-        let mut default_channels: HashMap<String, Channel> = HashMap::new();
+    /// Tells a client a message it sent was not posted, and why
+    fn refuse(&self, addr: SocketAddr, msg: &Message, channel: &str, error: String) {
+        let status = MessageStatusPayload::refused(channel.to_string(), msg.id, Some(error));
+        self.send_payload(addr, Message::new(MessageType::MessageStatus, vec![]), status);
+    }
 
-        let default_channel: Channel = Channel::new("general".to_string());
-        let default_channel2: Channel = Channel::new("random".to_string());
+    /// Passes a direct message to the connections of the user it names and the sender's other
+    /// connections, it is never stored
+    fn direct_message(&mut self, sender: SocketAddr, mut msg: Message, mut payload: MessagePayload) {
+        let target = &payload.channel[1..];
+        let from = match self.users.get(&sender) {
+            Some(user) => user.clone(),
+            None => return,
+        };
 
-        default_channels.insert("general".to_string(), default_channel);
-        default_channels.insert("random".to_string(), default_channel2);
+        if !self.users.values().any(|user| user.username == target) {
+            self.refuse(sender, &msg, &payload.channel, format!("{} is not online", target));
+            return;
+        }
 
-        Server {
-            channels: default_channels,
-            clients: HashMap::new(),
-            shared_keys: HashMap::new(),
-            private_key: crypt::serialize_private_key(crypt::create_private_key())
+        if let Some(left) = self.muted_for(sender) {
+            self.refuse(sender, &msg, &payload.channel, format!("You are muted for another {}s", left.as_secs() + 1));
+            return;
+        }
+
+        payload.message = crypt::decrypt_data(payload.message, self.get_shared_key(sender));
+        payload.username = from.username.clone();
+        payload.bot = false;
+        // attachments only live in channels
+        payload.attachments.clear();
+
+        let recipients: Vec<SocketAddr> = self
+            .users
+            .iter()
+            .filter(|(addr, user)| **addr != sender && (user.username == target || user.id == from.id))
+            .map(|(addr, _)| *addr)
+            .collect();
+        let sent_id = msg.id;
+        msg.id = self.next_message_id();
+        let status = MessageStatusPayload::new(payload.channel.clone(), sent_id, msg.id);
+        for addr in recipients {
+            self.send_payload(addr, msg.clone(), payload.clone());
+        }
+        self.send_payload(sender, Message::new(MessageType::MessageStatus, vec![]), status);
+    }
+
+    /// Tells a client who is online in a channel
+    pub fn who(&self, sender: SocketAddr, msg: Message) {
+        let mut who = WhoPayload::from_bytes(msg.payload.clone());
+        let channel = match self.channels.get(channel_name(&who.channel)) {
+            Some(channel) if self.can(sender, &channel.name, Permission::Read) => channel,
+            _ => {
+                self.notice(sender, &who.channel, format!("There is no channel {}", who.channel));
+                return;
+            }
+        };
+
+        let mut users: Vec<String> = self
+            .get_members(channel)
+            .iter()
+            .filter_map(|addr| self.users.get(addr))
+            .map(|user| user.username.clone())
+            .collect();
+        // a user may be connected more than once
+        users.sort();
+        users.dedup();
+
+        who.users = users;
+        self.send_payload(sender, msg, who);
+    }
+
+    /// Checks a username someone asked for, it may not be taken by another user
+    fn check_username(&self, id: u64, username: &str) -> Result<(), String> {
+        if username.is_empty() || username.chars().count() > MAX_USERNAME_LENGTH {
+            return Err(format!("A username has 1 to {} characters", MAX_USERNAME_LENGTH));
+        }
+        // @ qualifies names of other servers and addresses direct messages
+        if username.contains(|c: char| c == '@' || c.is_whitespace() || c.is_control()) {
+            return Err("A username can't have spaces or @ in it".to_string());
+        }
+        if username.eq_ignore_ascii_case("SERVER") || username.eq_ignore_ascii_case("ALL") || username == "Unknown" {
+            return Err(format!("{} is reserved", username));
+        }
+
+        let online = self.users.values().find(|user| user.username == username).map(|user| user.id);
+        let registered = self.registry.find_by_username(username).map(|record| record.user.id);
+        match online.or(registered) {
+            Some(owner) if owner != id => Err(format!("{} is taken", username)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Changes the username of whoever is behind a connection, everyone is told
+    pub fn nick(&mut self, sender: SocketAddr, msg: Message) {
+        let nick = NickPayload::from_bytes(msg.payload.clone());
+        let username = nick.username.trim().to_string();
+        let old = match self.users.get(&sender) {
+            Some(user) => user.clone(),
+            None => return,
+        };
+
+        if old.username == username {
+            return;
+        }
+        if let Err(e) = self.check_username(old.id, &username) {
+            self.notice(sender, "#general", format!("Error: {}", e));
+            return;
+        }
+
+        // every connection of the user takes the new name
+        let mut connections = Vec::new();
+        for (addr, user) in self.users.iter_mut().filter(|(_, user)| user.id == old.id) {
+            user.username = username.clone();
+            connections.push(*addr);
+        }
+        self.registry.rename(old.id, &username);
+        self.record_audit("nick", &old.username, format!("now {}", username));
+
+        for addr in connections {
+            self.send_payload(addr, msg.clone(), NickPayload::new(username.clone()));
+        }
+        self.channel_notice("ALL", format!("{} is now known as {}", old.username, username));
+    }
+
+    /// Whether a plugin may see a channel
+    fn plugin_reads(&self, plugin: &LoadedPlugin, channel: &str) -> bool {
+        self.integration_channel(Some(&plugin.bot), channel, Permission::Read).is_ok()
+    }
+
+    /// Runs the filters of the plugins that see a channel over a text, Err is why one blocked it
+    fn run_filters(&self, plugins: &mut [LoadedPlugin], context: &Context, text: &mut String) -> Result<(), String> {
+        for plugin in plugins.iter_mut() {
+            if !self.plugin_reads(plugin, &context.channel) {
+                continue;
+            }
+            if let Filter::Block(reason) = plugin.plugin.filter(context, text) {
+                return Err(reason);
+            }
+        }
+        Ok(())
+    }
+
+    /// Hands a user's message to the plugins before it is posted. Returns the message to post
+    /// with its text, or None when a plugin answered it as a command or a filter blocked it
+    async fn run_plugins(&mut self, sender: SocketAddr, mut msg: Message) -> Option<(Message, Context, String)> {
+        let key = self.get_shared_key(sender);
+        let mut payload = MessagePayload::from_bytes(msg.payload.clone());
+        let original = String::from_utf8_lossy(&crypt::decrypt_data(payload.message.clone(), key.clone())).to_string();
+        let mut text = original.clone();
+        let context = Context {
+            username: self.get_actor(sender),
+            channel: channel_name(&payload.channel).to_string(),
+            role: self.get_role(sender),
+        };
+
+        // the plugins are taken out while they run so they can be handed the server's state
+        let mut plugins = std::mem::take(&mut self.plugins);
+
+        if let Some(line) = text.strip_prefix('/') {
+            let (command, args) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            let found = plugins
+                .iter_mut()
+                .find(|plugin| plugin.plugin.commands().contains(&command) && self.plugin_reads(plugin, &context.channel));
+            if let Some(plugin) = found {
+                let reply = plugin.plugin.command(&context, command, args);
+                let bot = plugin.bot.clone();
+                // what a command posts goes through the filters like anything else, /echo included
+                let reply = match reply {
+                    Some(Reply::Post(mut text)) => match self.run_filters(&mut plugins, &context, &mut text) {
+                        Ok(()) => Some(Reply::Post(text)),
+                        Err(reason) => Some(Reply::Notice(reason)),
+                    },
+                    reply => reply,
+                };
+                self.plugins = plugins;
+                // the command itself is not posted
+                let status = MessageStatusPayload::refused(payload.channel.clone(), msg.id, None);
+                self.send_payload(sender, Message::new(MessageType::MessageStatus, vec![]), status);
+                match reply {
+                    Some(Reply::Notice(text)) => self.notice(sender, &payload.channel, text),
+                    Some(Reply::Post(text)) => self.post_as_plugin(sender, &bot, &context.channel, text).await,
+                    None => {}
+                }
+                return None;
+            }
+        }
+
+        let filtered = self.run_filters(&mut plugins, &context, &mut text);
+        self.plugins = plugins;
+        if let Err(reason) = filtered {
+            self.refuse(sender, &msg, &payload.channel, reason);
+            return None;
+        }
+
+        if text != original {
+            payload.message = crypt::encrypt_data(text.clone().into_bytes(), key);
+            msg.payload = payload.to_bytes();
+        }
+        Some((msg, context, text))
+    }
+
+    /// Shows a posted message to the plugins, their answers go to the channel
+    async fn observe(&mut self, sender: SocketAddr, context: &Context, text: &str) {
+        let mut plugins = std::mem::take(&mut self.plugins);
+        let mut answers = Vec::new();
+        for plugin in plugins.iter_mut() {
+            if !self.plugin_reads(plugin, &context.channel) {
+                continue;
+            }
+            if let Some(answer) = plugin.plugin.observe(context, text) {
+                answers.push((plugin.bot.clone(), answer));
+            }
+        }
+        self.plugins = plugins;
+
+        for (bot, answer) in answers {
+            self.post_as_plugin(sender, &bot, &context.channel, answer).await;
         }
     }
+
+    /// Posts a plugin's answer if it may post in the channel, otherwise tells the user who set it off
+    async fn post_as_plugin(&mut self, sender: SocketAddr, bot: &Bot, channel: &str, text: String) {
+        if let Err(e) = self.integration_channel(Some(bot), channel, Permission::Post) {
+            self.notice(sender, &format!("#{}", channel), format!("{} can't answer: {}", bot.name, e.message()));
+            return;
+        }
+        self.post_integration_message(bot.name.clone(), channel, text, None, None).await;
+    }
+
+    pub async fn broadcast(&mut self, sender: Option<SocketAddr>, msg: Message) {
+        // lets grab the payload and decrypt it
+        let payload = msg.payload.clone();
+        let mut payload = MessagePayload::from_bytes(payload);
+        // if sender is none, then we are broadcasting a message from the server
+        // so we don't need to decrypt it
+        if let Some(sender) = sender {
+            let shared_key = self.get_shared_key(sender);
+            let decrypted_payload = crypt::decrypt_data(payload.message, self.shared_keys[&sender].clone());
+            payload.message = decrypted_payload;
+            // the author is whoever is logged in on the connection, not what the client says
+            payload.username = match self.users.get(&sender) {
+                Some(user) => user.username.clone(),
+                None => return,
+            };
+            // only the server marks messages as a bot's
+            payload.bot = false;
+
+            // only pass on attachments that were actually uploaded and the sender may read,
+            // as the server knows them
+            payload.attachments = payload
+                .attachments
+                .iter()
+                .filter(|attachment| self.may_download(sender, attachment.id))
+                .filter_map(|attachment| self.blobs.get(attachment.id).cloned())
+                .collect();
+            for attachment in payload.attachments.iter() {
+                self.blobs.post(attachment.id, channel_name(&payload.channel));
+            }
+        }
+
+        // keep a plaintext copy in the channel so it can be replayed later
+        let mut stored_message = msg.clone();
+        stored_message.payload = payload.to_bytes();
+        self.store_message(stored_message.clone());
+
+        // peers sharing the channel see the sender as user@this server
+        let author = match sender {
+            Some(sender) => self.users.get(&sender).map(|user| user.username.clone()),
+            None if payload.bot => Some(payload.username.clone()),
+            None => None,
+        };
+        if let Some(author) = author {
+            let mut relayed = payload.clone();
+            relayed.username = qualify(&author, &self.config.server_name);
+            // attachments stay on this server
+            relayed.attachments.clear();
+            stored_message.payload = relayed.to_bytes();
+            self.relay(&PeerMessagePayload::new(self.config.server_name.clone(), stored_message));
+        }
+
+        // server notices to "ALL" go to everyone, anything else only to the channel
+        match self.channels.get(channel_name(&payload.channel)) {
+            Some(channel) => self.broadcast_channel(sender, channel, msg, payload),
+            None => self.broadcast_payload(sender, msg, payload),
+        }
+    }
+
+    /// Sends a plaintext payload to the members of a channel but the sender
+    fn broadcast_channel<P: Payload + Clone>(&self, sender: Option<SocketAddr>, channel: &Channel, msg: Message, payload: P) {
+        for addr in self.get_members(channel) {
+            if Some(addr) != sender {
+                self.send_payload(addr, msg.clone(), payload.clone());
+            }
+        }
+    }
+
+    /// Sends a server message to a single client, it is not stored
+    pub fn notice(&self, addr: SocketAddr, channel: &str, text: String) {
+        let payload = MessagePayload::new("SERVER".to_string(), channel.to_string(), text.into_bytes());
+        self.send_payload(addr, Message::new(MessageType::Message, vec![]), payload);
+    }
+
+    /// Sends a plaintext payload to every client but the sender, encrypted for each of them
+    pub fn broadcast_payload<P: Payload + Clone>(&self, sender: Option<SocketAddr>, msg: Message, payload: P) {
+        for addr in self.clients.keys() {
+            // if there is a sender, then we don't want to send the message back to them
+            if Some(*addr) == sender {
+                continue;
+            }
+            debug!("Sending message to client {}", addr.to_string());
+
+            // re-encrypt the message with the shared key of the receiver
+            self.send_payload(*addr, msg.clone(), payload.clone());
+        }
+    }
+
+    /// Records a reaction in its channel and passes it on to the other clients
+    pub fn react(&mut self, sender: SocketAddr, msg: Message) {
+        let mut reaction = ReactionPayload::from_bytes(msg.payload.clone());
+        if !self.can(sender, &reaction.channel, Permission::Post) || self.muted_for(sender).is_some() {
+            return;
+        }
+        if !is_valid_emoji(&reaction.emoji) {
+            debug!("Client {} reacted with {:?}, which is not an emoji", sender, reaction.emoji);
+            return;
+        }
+        // whoever is logged in on the connection reacts, not whoever the client names
+        reaction.username = match self.users.get(&sender) {
+            Some(user) => user.username.clone(),
+            None => return,
+        };
+
+        let applied = match self.channels.get_mut(channel_name(&reaction.channel)) {
+            Some(channel) => channel.add_reaction(&reaction),
+            None => false,
+        };
+
+        if !applied {
+            debug!("Client {} reacted to unknown message {}", sender, reaction.message_id);
+            return;
+        }
+
+        let channel = &self.channels[channel_name(&reaction.channel)];
+        self.broadcast_channel(Some(sender), channel, msg, reaction);
+    }
+
+    /// Runs an admin command and answers with its output
+    pub fn command(&mut self, sender: SocketAddr, msg: Message) {
+        let mut payload = CommandPayload::from_bytes(msg.payload);
+        payload.decrypt(self.get_shared_key(sender));
+        let command = String::from_utf8_lossy(&payload.command).to_string();
+
+        let output = admin::run(self, sender, &payload.channel, &command);
+        self.notice(sender, &payload.channel, output);
+    }
+
+    /// Lists every known user with their role
+    pub fn list_roles(&self, actor: SocketAddr) -> Result<String, String> {
+        if self.get_role(actor) < Role::Moderator {
+            return Err("Only moderators can see the roles".to_string());
+        }
+
+        Ok(self
+            .registry
+            .all()
+            .iter()
+            .map(|record| format!("{} ({})", record.user.username, record.role.name()))
+            .collect::<Vec<String>>()
+            .join(", "))
+    }
+
+    /// Changes a user's role, only admins may do this and only below their own role
+    pub fn set_role(&mut self, actor: SocketAddr, username: &str, role: Role) -> Result<String, String> {
+        let actor_role = self.get_role(actor);
+        if actor_role < Role::Admin {
+            return Err("Only admins can change roles".to_string());
+        }
+
+        let target = match self.registry.find_by_username(username) {
+            Some(record) => record.clone(),
+            None => return Err(format!("Unknown user {}", username)),
+        };
+
+        if self.users.get(&actor).map(|user| user.id) == Some(target.user.id) {
+            return Err("You can't change your own role".to_string());
+        }
+
+        // the owner may hand out anything, everyone else only what is below them
+        if actor_role != Role::Owner && (target.role >= actor_role || role >= actor_role) {
+            return Err(format!("You can't change {} to {}", username, role.name()));
+        }
+
+        self.registry.set_role(target.user.id, role);
+        Ok(format!("{} is now {}", username, role.name()))
+    }
+
+    /// Overrides a permission for a role in a channel, None restores the default
+    pub fn set_permission(&mut self, actor: SocketAddr, channel: &str, role: Role, permission: Permission, allow: Option<bool>) -> Result<String, String> {
+        if !self.channels.contains_key(channel_name(channel)) {
+            return Err(format!("There is no channel {}", channel));
+        }
+
+        let actor_role = self.get_role(actor);
+        if !self.can(actor, channel, Permission::Manage) {
+            return Err(format!("You may not manage {}", channel));
+        }
+
+        if actor_role != Role::Owner && role >= actor_role {
+            return Err(format!("You can't change what {} may do", role.name()));
+        }
+
+        self.channels.get_mut(channel_name(channel)).unwrap().set_permission(role, permission, allow);
+        self.save_channels();
+        let value = match allow {
+            Some(true) => "allowed",
+            Some(false) => "denied",
+            None => "back to the default",
+        };
+        Ok(format!("{} {} in {} is {}", role.name(), permission.name(), channel, value))
+    }
+
+    /// Lists the overrides of a channel
+    pub fn list_permissions(&self, channel: &str) -> Result<String, String> {
+        let channel = match self.channels.get(channel_name(channel)) {
+            Some(channel) => channel,
+            None => return Err(format!("There is no channel {}", channel)),
+        };
+
+        if channel.permissions.is_empty() {
+            return Ok(format!("{} uses the default permissions", channel.name));
+        }
+
+        Ok(channel
+            .permissions
+            .iter()
+            .map(|o| format!("{} {}: {}", o.role.name(), o.permission.name(), if o.allow { "allow" } else { "deny" }))
+            .collect::<Vec<String>>()
+            .join(", "))
+    }
+
+    /// How long the user at a connection stays muted, None if they may talk
+    fn muted_for(&self, addr: SocketAddr) -> Option<Duration> {
+        let user = self.users.get(&addr)?;
+        let until = self.mutes.get(&user.id)?;
+        until.checked_duration_since(Instant::now())
+    }
+
+    pub fn is_banned_ip(&self, ip: IpAddr) -> bool {
+        self.bans.is_ip_banned(ip)
+    }
+
+    pub fn is_banned_user(&self, id: u64) -> bool {
+        self.bans.is_user_banned(id)
+    }
+
+    /// Sends a server message to everyone who can read a channel, "ALL" reaches everyone
+    fn channel_notice(&self, channel: &str, text: String) {
+        let members = match self.channels.get(channel_name(channel)) {
+            Some(channel) => self.get_members(channel),
+            None => self.users.keys().copied().collect(),
+        };
+
+        for addr in members {
+            self.notice(addr, channel, text.clone());
+        }
+    }
+
+    /// Drops a client's sender, which ends its connection once what is queued was sent
+    fn disconnect(&mut self, addr: SocketAddr, text: String) {
+        self.notice(addr, "ALL", text);
+        self.clients.remove(&addr);
+    }
+
+    /// Makes sure a moderator may act on a user, moderators only act on roles below their own
+    fn check_moderator(&self, actor: SocketAddr, target: u64) -> Result<String, String> {
+        let actor_role = self.get_role(actor);
+        if actor_role < Role::Moderator {
+            return Err("Only moderators can do that".to_string());
+        }
+
+        if self.users.get(&actor).map(|user| user.id) == Some(target) {
+            return Err("You can't do that to yourself".to_string());
+        }
+
+        if actor_role != Role::Owner && self.registry.get_role(target) >= actor_role {
+            return Err("You can't do that to someone of your role or above".to_string());
+        }
+
+        Ok(self.users.get(&actor).map(|user| user.username.clone()).unwrap_or_default())
+    }
+
+    fn find_user(&self, username: &str) -> Result<u64, String> {
+        match self.registry.find_by_username(username) {
+            Some(record) => Ok(record.user.id),
+            None => Err(format!("Unknown user {}", username)),
+        }
+    }
+
+    fn get_connections(&self, id: u64) -> Vec<SocketAddr> {
+        self.users
+            .iter()
+            .filter(|(_, user)| user.id == id)
+            .map(|(addr, _)| *addr)
+            .collect()
+    }
+
+    pub fn kick(&mut self, actor: SocketAddr, channel: &str, username: &str, reason: &str) -> Result<String, String> {
+        let id = self.find_user(username)?;
+        let moderator = self.check_moderator(actor, id)?;
+
+        let connections = self.get_connections(id);
+        if connections.is_empty() {
+            return Err(format!("{} is not connected", username));
+        }
+
+        for addr in connections {
+            self.disconnect(addr, format!("You were kicked by {}: {}", moderator, reason));
+        }
+
+        self.channel_notice(channel, format!("{} was kicked by {}: {}", username, moderator, reason));
+        Ok(format!("Kicked {}", username))
+    }
+
+    pub fn mute(&mut self, actor: SocketAddr, channel: &str, username: &str, duration: Duration) -> Result<String, String> {
+        let id = self.find_user(username)?;
+        let moderator = self.check_moderator(actor, id)?;
+        let until = Instant::now().checked_add(duration).ok_or("That mute is too long")?;
+
+        self.mutes.insert(id, until);
+        self.channel_notice(channel, format!("{} was muted by {} for {}s", username, moderator, duration.as_secs()));
+        Ok(format!("Muted {}", username))
+    }
+
+    pub fn unmute(&mut self, actor: SocketAddr, channel: &str, username: &str) -> Result<String, String> {
+        let id = self.find_user(username)?;
+        let moderator = self.check_moderator(actor, id)?;
+
+        if self.mutes.remove(&id).is_none() {
+            return Err(format!("{} is not muted", username));
+        }
+
+        self.channel_notice(channel, format!("{} was unmuted by {}", username, moderator));
+        Ok(format!("Unmuted {}", username))
+    }
+
+    pub fn ban_user(&mut self, actor: SocketAddr, channel: &str, username: &str, reason: &str) -> Result<String, String> {
+        let id = self.find_user(username)?;
+        let moderator = self.check_moderator(actor, id)?;
+
+        self.bans.ban(Ban {
+            target: BanTarget::User(id),
+            name: username.to_string(),
+            by: moderator.clone(),
+            reason: reason.to_string(),
+        });
+
+        for addr in self.get_connections(id) {
+            self.disconnect(addr, format!("You were banned by {}: {}", moderator, reason));
+        }
+
+        self.channel_notice(channel, format!("{} was banned by {}: {}", username, moderator, reason));
+        Ok(format!("Banned {}", username))
+    }
+
+    pub fn unban_user(&mut self, actor: SocketAddr, channel: &str, username: &str) -> Result<String, String> {
+        let id = self.find_user(username)?;
+        let moderator = self.check_moderator(actor, id)?;
+
+        if !self.bans.unban(&BanTarget::User(id)) {
+            return Err(format!("{} is not banned", username));
+        }
+
+        self.channel_notice(channel, format!("{} was unbanned by {}", username, moderator));
+        Ok(format!("Unbanned {}", username))
+    }
+
+    /// Bans an address or a range, everyone connected from it is disconnected
+    pub fn ban_network(&mut self, actor: SocketAddr, channel: &str, network: &str, reason: &str) -> Result<String, String> {
+        if self.get_role(actor) < Role::Moderator {
+            return Err("Only moderators can do that".to_string());
+        }
+
+        let parsed = match Network::parse(network) {
+            Some(parsed) => parsed,
+            None => return Err(format!("{} is not an address or range", network)),
+        };
+
+        if parsed.contains(actor.ip()) {
+            return Err(format!("{} includes your own address", parsed));
+        }
+
+        // the same rule as check_moderator, for everyone connected from the range
+        let actor_role = self.get_role(actor);
+        let protected = self
+            .users
+            .iter()
+            .filter(|(addr, _)| parsed.contains(addr.ip()))
+            .find(|(_, user)| actor_role != Role::Owner && self.registry.get_role(user.id) >= actor_role);
+        if let Some((_, user)) = protected {
+            return Err(format!("{} includes {}, who is of your role or above", parsed, user.username));
+        }
+
+        let moderator = self.users.get(&actor).map(|user| user.username.clone()).unwrap_or_default();
+        self.bans.ban(Ban {
+            target: BanTarget::Network(parsed.to_string()),
+            name: parsed.to_string(),
+            by: moderator.clone(),
+            reason: reason.to_string(),
+        });
+
+        let connections: Vec<SocketAddr> = self.clients.keys().filter(|addr| parsed.contains(addr.ip())).copied().collect();
+        for addr in connections {
+            self.disconnect(addr, format!("Your address was banned by {}: {}", moderator, reason));
+        }
+
+        self.channel_notice(channel, format!("{} was banned by {}: {}", parsed, moderator, reason));
+        Ok(format!("Banned {}", parsed))
+    }
+
+    pub fn unban_network(&mut self, actor: SocketAddr, channel: &str, network: &str) -> Result<String, String> {
+        if self.get_role(actor) < Role::Moderator {
+            return Err("Only moderators can do that".to_string());
+        }
+
+        let parsed = match Network::parse(network) {
+            Some(parsed) => parsed,
+            None => return Err(format!("{} is not an address or range", network)),
+        };
+
+        if !self.bans.unban(&BanTarget::Network(parsed.to_string())) {
+            return Err(format!("{} is not banned", parsed));
+        }
+
+        let moderator = self.users.get(&actor).map(|user| user.username.clone()).unwrap_or_default();
+        self.channel_notice(channel, format!("{} was unbanned by {}", parsed, moderator));
+        Ok(format!("Unbanned {}", parsed))
+    }
+
+    pub fn record_audit(&self, kind: &str, actor: &str, detail: String) {
+        self.audit.record(AuditEvent::new(kind, actor, detail));
+    }
+
+    /// Gets the name of whoever is behind a connection for the audit log
+    pub fn get_actor(&self, addr: SocketAddr) -> String {
+        match self.users.get(&addr) {
+            Some(user) => user.username.clone(),
+            None => addr.to_string(),
+        }
+    }
+
+    pub fn query_audit(&self, actor: SocketAddr, kind: Option<&str>, count: usize) -> Result<String, String> {
+        if self.get_role(actor) < Role::Admin {
+            return Err("Only admins can read the audit log".to_string());
+        }
+
+        let events = self.audit.query(kind, count);
+        if events.is_empty() {
+            return Ok("The audit log has no such events".to_string());
+        }
+
+        Ok(events.iter().map(|event| event.to_string()).collect::<Vec<String>>().join("\n"))
+    }
+
+    /// Reads data/config.json again
+    pub fn reload_config(&mut self, actor: SocketAddr) -> Result<String, String> {
+        if self.get_role(actor) < Role::Admin {
+            return Err("Only admins can reload the config".to_string());
+        }
+
+        self.config = ServerConfig::load();
+        self.plugins = plugins::load(&self.config.plugins);
+        Ok("Reloaded the config".to_string())
+    }
+
+    pub fn list_bans(&self, actor: SocketAddr) -> Result<String, String> {
+        if self.get_role(actor) < Role::Moderator {
+            return Err("Only moderators can see the bans".to_string());
+        }
+
+        if self.bans.all().is_empty() {
+            return Ok("Nobody is banned".to_string());
+        }
+
+        Ok(self
+            .bans
+            .all()
+            .iter()
+            .map(|ban| format!("{} (by {}: {})", ban.name, ban.by, ban.reason))
+            .collect::<Vec<String>>()
+            .join(", "))
+    }
+
+    fn store_message(&mut self, message: Message) {
+        let payload = MessagePayload::from_bytes(message.payload.clone());
+        // server notices go to "ALL" and are not part of any timeline
+        if let Some(channel) = self.channels.get_mut(channel_name(&payload.channel)) {
+            let end_to_end = channel.end_to_end;
+            if !end_to_end {
+                self.search.insert(&message);
+            }
+            let dropped = channel.add_message(message.clone());
+            // attachments of dropped messages may have been posted in no other message
+            if dropped.iter().any(|m| !MessagePayload::from_bytes(m.payload.clone()).attachments.is_empty()) {
+                let name = channel.name.clone();
+                let kept = channel.attachment_ids();
+                self.blobs.release(&name, &kept);
+            }
+
+            // nobody outside can read what the members encrypt among themselves
+            if !end_to_end {
+                let name = channel.name.clone();
+                self.emit(EventKind::Message, Some(&name), json!(ExportedMessage::from_message(&message)));
+            }
+        }
+    }
+
+    /// Sends the timeline of every channel to a client that just logged in
+    pub fn send_history(&self, addr: SocketAddr) {
+        for channel in self.channels.values() {
+            if !self.can(addr, &channel.name, Permission::Read) {
+                continue;
+            }
+
+            let mut payload = HistoryPayload::new(channel.name.clone(), None, channel.messages.clone());
+            payload.set_reactions(channel.get_reactions(&channel.messages));
+            self.send_payload(addr, Message::new(MessageType::History, vec![]), payload);
+        }
+    }
+
+    /// Answers a client's history request, either for a channel timeline or a single thread
+    pub fn send_requested_history(&self, addr: SocketAddr, request: HistoryPayload) {
+        let channel = match self.channels.get(channel_name(&request.channel)) {
+            Some(channel) => channel,
+            None => {
+                debug!("Client {} requested history of unknown channel {}", addr, request.channel);
+                return;
+            }
+        };
+
+        if !self.can(addr, &channel.name, Permission::Read) {
+            debug!("Client {} may not read {}", addr, channel.name);
+            return;
+        }
+
+        let messages = match request.thread {
+            Some(thread) => channel.get_thread(thread),
+            None => channel.messages.clone(),
+        };
+
+        let mut payload = HistoryPayload::new(request.channel, request.thread, messages.clone());
+        payload.set_reactions(channel.get_reactions(&messages));
+        self.send_payload(addr, Message::new(MessageType::History, vec![]), payload);
+    }
+
+    /// Answers a search with one page of the messages that match, in the channels the client
+    /// may read and that aren't end-to-end
+    pub fn search(&self, sender: SocketAddr, msg: Message) {
+        let mut search = SearchPayload::from_bytes(msg.payload.clone());
+        search.decrypt(self.get_shared_key(sender));
+
+        if let Some(name) = &search.channel {
+            match self.channels.get(name) {
+                Some(channel) if channel.end_to_end => {
+                    self.notice(sender, "#general", format!("#{} is end-to-end, it can't be searched", name));
+                    return;
+                }
+                Some(_) if self.can(sender, name, Permission::Read) => {}
+                _ => {
+                    self.notice(sender, "#general", format!("There is no channel #{}", name));
+                    return;
+                }
+            }
+        }
+
+        let channels: Vec<String> = self
+            .channels
+            .values()
+            .filter(|channel| !channel.end_to_end && self.can(sender, &channel.name, Permission::Read))
+            .filter(|channel| search.channel.as_ref().is_none_or(|name| name == &channel.name))
+            .map(|channel| channel.name.clone())
+            .collect();
+        let words: Vec<String> = tokenize(&search.get_query()).into_iter().map(|(_, _, word)| word).collect();
+        let found = self.search.search(&words, &channels, search.author.as_deref(), search.after, search.before);
+
+        search.per_page = search.per_page.clamp(1, 100);
+        search.total = found.len();
+        let page: Vec<_> = found.iter().skip(search.page.saturating_mul(search.per_page)).take(search.per_page).collect();
+
+        // each channel's history is only read once for the whole page
+        let mut histories: HashMap<&str, Vec<Message>> = HashMap::new();
+        search.results = Vec::new();
+        for indexed in page {
+            let history = histories
+                .entry(indexed.channel.as_str())
+                .or_insert_with(|| self.channels[&indexed.channel].get_all_messages());
+            let message = match history.iter().find(|m| m.id == indexed.id) {
+                Some(message) => message.clone(),
+                None => continue,
+            };
+
+            let payload = MessagePayload::from_bytes(message.payload.clone());
+            let highlights = tokenize(&String::from_utf8_lossy(&payload.message))
+                .into_iter()
+                .filter(|(_, _, word)| words.contains(word))
+                .map(|(start, end, _)| (start, end))
+                .collect();
+            search.results.push(SearchResult { message, highlights });
+        }
+
+        self.send_payload(sender, Message::new(MessageType::Search, vec![]), search);
+    }
+
+    /// Starts or resumes an upload for the user at the given connection
+    pub fn offer_attachment(&mut self, sender: SocketAddr, msg: Message) {
+        let owner = match self.users.get(&sender) {
+            Some(user) => user.id,
+            None => return,
+        };
+
+        let meta = AttachmentMeta::from_bytes(msg.payload);
+        let status = self.blobs.offer(owner, meta, &self.config);
+        if let Some(error) = &status.error {
+            debug!("Rejected upload from {}: {}", sender, error);
+        }
+        self.send_payload(sender, Message::new(MessageType::AttachmentStatus, vec![]), status);
+    }
+
+    /// Stores an uploaded chunk and tells the client what to send next
+    pub fn receive_chunk(&mut self, sender: SocketAddr, msg: Message) {
+        let owner = match self.users.get(&sender) {
+            Some(user) => user.id,
+            None => return,
+        };
+
+        let mut chunk = AttachmentChunkPayload::from_bytes(msg.payload);
+        chunk.decrypt(self.get_shared_key(sender));
+        let status = self.blobs.receive(owner, chunk);
+        self.send_payload(sender, Message::new(MessageType::AttachmentStatus, vec![]), status);
+    }
+
+    /// Whether a user may download an attachment: they uploaded it or can read a channel it was posted in
+    fn may_download(&self, addr: SocketAddr, id: u64) -> bool {
+        let (attachment, user) = match (self.blobs.get_stored(id), self.users.get(&addr)) {
+            (Some(attachment), Some(user)) => (attachment, user),
+            _ => return false,
+        };
+        attachment.owner == user.id || attachment.channels.iter().any(|channel| self.can(addr, channel, Permission::Read))
+    }
+
+    /// Sends a client the chunk of an attachment that starts where its copy ends, the client
+    /// asks for the next one once it wrote it
+    pub fn send_attachment(&self, addr: SocketAddr, msg: Message) {
+        let request = AttachmentRequestPayload::from_bytes(msg.payload);
+        if !self.may_download(addr, request.id) {
+            debug!("Client {} may not download attachment {}", addr, request.id);
+            return;
+        }
+
+        match self.blobs.read_chunk(request.id, request.offset) {
+            Some(chunk) => self.send_payload(addr, Message::new(MessageType::AttachmentChunk, vec![]), chunk),
+            None => debug!("Client {} requested unknown attachment {}", addr, request.id),
+        }
+    }
+
+    fn send_payload<P: Payload>(&self, addr: SocketAddr, mut msg: Message, mut payload: P) {
+        let (tx, shared_key) = match (self.clients.get(&addr), self.shared_keys.get(&addr)) {
+            (Some(tx), Some(shared_key)) => (tx, shared_key),
+            _ => return,
+        };
+
+        payload.encrypt(shared_key.clone());
+        msg.payload = payload.to_bytes();
+
+        if let Err(e) = tx.send(msg.to_bytes()) {
+            error!("Error sending message to client {}: {}", addr.to_string(), e);
+        }
+    }
+
+    pub fn get_http_address(&self) -> Option<String> {
+        self.config.http_address.clone()
+    }
+
+    pub fn find_bot(&self, token: &str) -> Option<Bot> {
+        self.integrations.find_bot(token).cloned()
+    }
+
+    /// Finds a channel a bot or webhook wants to use. Bots have the permissions of a member
+    /// and only the channels they were given, webhooks may post to the channel they belong to
+    fn integration_channel(&self, bot: Option<&Bot>, channel: &str, permission: Permission) -> Result<&Channel, HttpError> {
+        let found = match self.channels.get(channel_name(channel)) {
+            Some(found) => found,
+            None => return Err(HttpError::NotFound(format!("There is no channel {}", channel))),
+        };
+
+        if let Some(bot) = bot {
+            let listed = bot.channels.contains(&found.name);
+            if (found.private || !bot.channels.is_empty()) && !listed {
+                return Err(HttpError::Forbidden(format!("{} may not use #{}", bot.name, found.name)));
+            }
+            if !found.allows(Role::Member, permission) {
+                return Err(HttpError::Forbidden(format!("{} may not {} #{}", bot.name, permission.name(), found.name)));
+            }
+        }
+        if found.archived && permission == Permission::Post {
+            return Err(HttpError::Forbidden(format!("#{} is archived", found.name)));
+        }
+        // the server can't read or write what the members encrypt among themselves
+        if found.end_to_end {
+            return Err(HttpError::Forbidden(format!("#{} is end-to-end encrypted", found.name)));
+        }
+
+        Ok(found)
+    }
+
+    /// The channels a bot may read, by name
+    pub fn api_channels(&self, bot: &Bot) -> Vec<ChannelSummary> {
+        let mut channels: Vec<ChannelSummary> = self
+            .channels
+            .values()
+            .filter(|channel| self.integration_channel(Some(bot), &channel.name, Permission::Read).is_ok())
+            .map(|channel| ChannelSummary {
+                name: channel.name.clone(),
+                topic: channel.topic.clone(),
+                description: channel.description.clone(),
+                archived: channel.archived,
+            })
+            .collect();
+        channels.sort_by(|a, b| a.name.cmp(&b.name));
+        channels
+    }
+
+    /// The latest messages of a channel, threads included, oldest first
+    pub fn api_history(&self, bot: &Bot, channel: &str, limit: usize) -> Result<Vec<ExportedMessage>, HttpError> {
+        let found = self.integration_channel(Some(bot), channel, Permission::Read)?;
+
+        let mut messages = found.get_all_messages();
+        messages.sort_by_key(|m| m.id);
+        let skip = messages.len().saturating_sub(limit);
+        Ok(messages[skip..].iter().map(ExportedMessage::from_message).collect())
+    }
+
+    pub async fn post_as_bot(&mut self, bot: &Bot, channel: &str, text: String, reply_to: Option<u64>, thread: Option<u64>) -> Result<u64, HttpError> {
+        let found = self.integration_channel(Some(bot), channel, Permission::Post)?;
+        if text.trim().is_empty() {
+            return Err(HttpError::BadRequest("The message has no text".to_string()));
+        }
+        let name = found.name.clone();
+        self.check_references(&name, reply_to, thread).map_err(HttpError::BadRequest)?;
+
+        Ok(self.post_integration_message(bot.name.clone(), &name, text, reply_to, thread).await)
+    }
+
+    pub async fn post_to_webhook(&mut self, id: &str, token: &str, username: Option<String>, text: String) -> Result<u64, HttpError> {
+        let webhook = match self.integrations.find_webhook(id, token) {
+            Some(webhook) => webhook.clone(),
+            None => return Err(HttpError::NotFound("Unknown webhook".to_string())),
+        };
+        self.integration_channel(None, &webhook.channel, Permission::Post)?;
+        if text.trim().is_empty() {
+            return Err(HttpError::BadRequest("The message has no text".to_string()));
+        }
+
+        // a post may name who it is from, as long as it doesn't pass for the server or a remote user
+        let username = username
+            .map(|username| username.trim().chars().take(32).collect::<String>())
+            .filter(|username| !username.is_empty() && !username.contains('@') && !username.eq_ignore_ascii_case("SERVER"))
+            .unwrap_or(webhook.name);
+        Ok(self.post_integration_message(username, &webhook.channel, text, None, None).await)
+    }
+
+    async fn post_integration_message(&mut self, username: String, channel: &str, text: String, reply_to: Option<u64>, thread: Option<u64>) -> u64 {
+        let mut payload = MessagePayload::new(username, format!("#{}", channel), text.into_bytes());
+        payload.set_reply_to(reply_to);
+        payload.set_thread(thread);
+        payload.bot = true;
+
+        let mut message = Message::new(MessageType::Message, payload.to_bytes());
+        message.id = self.next_message_id();
+        let id = message.id;
+        self.broadcast(None, message).await;
+        id
+    }
+
+    pub fn list_bots(&self, actor: SocketAddr) -> Result<String, String> {
+        if self.get_role(actor) < Role::Admin {
+            return Err("Only admins can manage bots".to_string());
+        }
+
+        let bots = self.integrations.bots();
+        if bots.is_empty() {
+            return Ok("There are no bots".to_string());
+        }
+
+        Ok(bots
+            .iter()
+            .map(|bot| {
+                let channels = match bot.channels.is_empty() {
+                    true => "every public channel".to_string(),
+                    false => bot.channels.iter().map(|channel| format!("#{}", channel)).collect::<Vec<String>>().join(" "),
+                };
+                format!("{} by {} in {}", bot.name, bot.created_by, channels)
+            })
+            .collect::<Vec<String>>()
+            .join(", "))
+    }
+
+    /// Adds a bot for the HTTP API, limited to `channels` unless there are none
+    pub fn add_bot(&mut self, actor: SocketAddr, name: &str, channels: &[&str]) -> Result<String, String> {
+        if self.get_role(actor) < Role::Admin {
+            return Err("Only admins can manage bots".to_string());
+        }
+        if !channel::is_valid_name(name) {
+            return Err(format!("{} is not a valid bot name", name));
+        }
+
+        let mut names = Vec::new();
+        for channel in channels {
+            match self.channels.get(channel_name(channel)) {
+                Some(channel) => names.push(channel.name.clone()),
+                None => return Err(format!("There is no channel {}", channel)),
+            }
+        }
+
+        let created_by = self.get_actor(actor);
+        let token = self.integrations.add_bot(name, names, &created_by)?;
+        Ok(format!("Added the bot {}, its token is {} and won't be shown again", name, token))
+    }
+
+    pub fn remove_bot(&mut self, actor: SocketAddr, name: &str) -> Result<String, String> {
+        if self.get_role(actor) < Role::Admin {
+            return Err("Only admins can manage bots".to_string());
+        }
+
+        match self.integrations.remove_bot(name) {
+            true => Ok(format!("Removed the bot {}", name)),
+            false => Err(format!("There is no bot called {}", name)),
+        }
+    }
+
+    pub fn list_webhooks(&self, actor: SocketAddr, channel: &str) -> Result<String, String> {
+        if !self.can(actor, channel, Permission::Manage) {
+            return Err(format!("You may not manage {}", channel));
+        }
+
+        let webhooks = self.integrations.webhooks_for(channel_name(channel));
+        if webhooks.is_empty() {
+            return Ok(format!("{} has no webhooks", channel));
+        }
+
+        Ok(webhooks
+            .iter()
+            .map(|webhook| format!("{} posting as {} by {}", webhook.id, webhook.name, webhook.created_by))
+            .collect::<Vec<String>>()
+            .join(", "))
+    }
+
+    /// Adds an incoming webhook to a channel, returns its URL
+    pub fn add_webhook(&mut self, actor: SocketAddr, channel: &str, name: &str) -> Result<String, String> {
+        let found = match self.channels.get(channel_name(channel)) {
+            Some(found) => found.name.clone(),
+            None => return Err(format!("There is no channel {}", channel)),
+        };
+        if !self.can(actor, &found, Permission::Manage) {
+            return Err(format!("You may not manage {}", channel));
+        }
+
+        let created_by = self.get_actor(actor);
+        let (webhook, token) = self.integrations.add_webhook(&found, name, &created_by);
+        let path = format!("/hooks/{}/{}", webhook.id, token);
+        Ok(match &self.config.http_address {
+            Some(address) => format!("Added webhook {} to #{}, post to http://{}{}, the URL won't be shown again", webhook.id, found, address, path),
+            None => format!("Added webhook {} to #{} at {}, set http_address in the config to take posts", webhook.id, found, path),
+        })
+    }
+
+    pub fn remove_webhook(&mut self, actor: SocketAddr, id: &str) -> Result<String, String> {
+        let channel = match self.integrations.get_webhook(id) {
+            Some(webhook) => webhook.channel.clone(),
+            None => return Err(format!("Unknown webhook {}", id)),
+        };
+        if !self.can(actor, &channel, Permission::Manage) {
+            return Err(format!("You may not manage #{}", channel));
+        }
+
+        self.integrations.remove_webhook(id);
+        Ok(format!("Removed webhook {}", id))
+    }
+
+    /// Queues an event for every outgoing webhook subscribed to it, `channel` is None for
+    /// events that don't happen in a channel
+    fn emit(&mut self, kind: EventKind, channel: Option<&str>, data: serde_json::Value) {
+        let urls: Vec<String> = self
+            .config
+            .outgoing_webhooks
+            .iter()
+            .filter(|webhook| webhook.events.is_empty() || webhook.events.iter().any(|event| event == kind.name()))
+            .filter(|webhook| match channel {
+                Some(channel) => webhook.channels.is_empty() || webhook.channels.iter().any(|name| channel_name(name) == channel),
+                None => true,
+            })
+            .map(|webhook| webhook.url.clone())
+            .collect();
+        if urls.is_empty() {
+            return;
+        }
+
+        let body = events::event_body(kind, data);
+        for url in urls.iter() {
+            self.deliveries.push(url, kind, &body);
+        }
+    }
+
+    /// Queues a moderation event, `target` is who or what the action was taken against
+    pub fn emit_moderation(&mut self, actor: SocketAddr, action: &str, channel: &str, target: &str, detail: &str) {
+        let moderator = self.get_actor(actor);
+        let data = json!({
+            "action": action,
+            "moderator": moderator,
+            "target": target,
+            "channel": channel_name(channel),
+            "detail": detail,
+        });
+        self.emit(EventKind::Moderation, None, data);
+    }
+
+    pub fn get_webhook_secret(&self, url: &str) -> Option<String> {
+        self.config
+            .outgoing_webhooks
+            .iter()
+            .find(|webhook| webhook.url == url)
+            .map(|webhook| webhook.secret.clone())
+    }
+
+    pub fn search_changes(&mut self) -> Option<Vec<u8>> {
+        let memory_only: HashSet<String> = self.channels.values().filter(|channel| !channel.backup_messages).map(|channel| channel.name.clone()).collect();
+        self.search.changes(&memory_only)
+    }
+
+    pub fn registry_changes(&mut self) -> Option<Vec<u8>> {
+        self.registry.changes()
+    }
+
+    pub fn due_deliveries(&self) -> Vec<Delivery> {
+        self.deliveries.due()
+    }
+
+    pub fn delivery_changes(&mut self) -> Option<Vec<u8>> {
+        self.deliveries.changes()
+    }
+
+    pub fn delivered(&mut self, id: u64) {
+        self.deliveries.remove(id);
+    }
+
+    pub fn delivery_failed(&mut self, delivery: &Delivery, reason: String) {
+        if self.deliveries.retry(delivery.id) {
+            info!("Delivering a {} event to {} failed, {}, retrying", delivery.kind, delivery.url, reason);
+            return;
+        }
+
+        error!("Gave up delivering a {} event to {}: {}", delivery.kind, delivery.url, reason);
+        self.record_audit("webhook_failed", "SERVER", format!("gave up on a {} event to {}: {}", delivery.kind, delivery.url, reason));
+    }
+
+    pub fn get_public_key(&self) -> Vec<u8> {
+        let private_key = crypt::deserialize_private_key(self.private_key.clone());
+        crypt::serialize_public_key(crypt::create_public_key(private_key))
+    }
+
+    pub fn get_peer_config(&self, name: &str) -> Option<PeerConfig> {
+        self.config.peers.iter().find(|peer| peer.name == name).cloned()
+    }
+
+    /// The peers this server dials, the ones with an address
+    pub fn get_dialed_peers(&self) -> Vec<PeerConfig> {
+        self.config.peers.iter().filter(|peer| peer.address.is_some()).cloned().collect()
+    }
+
+    pub fn is_linked(&self, name: &str) -> bool {
+        self.peers.contains_key(name)
+    }
+
+    /// Checks who is on the other end of a new link against the config,
+    /// returns the key both ends derive from their identity keys
+    pub fn check_peer(&self, name: &str, public_key: &[u8]) -> Result<Vec<u8>, String> {
+        let peer = match self.get_peer_config(name) {
+            Some(peer) => peer,
+            None => return Err(format!("{} is not a known peer", name)),
+        };
+        if public_key.len() != 32 || base64::decode(&peer.public_key).ok().as_deref() != Some(public_key) {
+            return Err(format!("{} has a different key than the config says", name));
+        }
+
+        let private_key = crypt::deserialize_private_key(self.private_key.clone());
+        Ok(crypt::create_shared_key(private_key, crypt::deserialize_public_key(public_key.to_vec())))
+    }
+
+    pub fn peer_hello(&self) -> PeerHelloPayload {
+        PeerHelloPayload::new(self.config.server_name.clone(), self.get_public_key())
+    }
+
+    /// Registers a link that passed the handshake, returns its id, or None when a better link
+    /// to the same peer is already up. `dialed` is whether this server opened it
+    pub fn add_peer(&mut self, name: &str, tx: Tx, shared_key: Vec<u8>, dialed: bool) -> Option<u64> {
+        let preferred = match dialed {
+            true => self.config.server_name.as_str() < name,
+            false => name < self.config.server_name.as_str(),
+        };
+        if self.peers.get(name).is_some_and(|peer| peer.preferred || !preferred) {
+            return None;
+        }
+
+        let link = create_id(IdType::Unknown);
+        self.peers.insert(name.to_string(), Peer { link, tx, shared_key, sent: 0, received: 0, preferred });
+        self.record_audit("peer_linked", name, "federation link up".to_string());
+        Some(link)
+    }
+
+    pub fn remove_peer(&mut self, name: &str, link: u64) {
+        if self.peers.get(name).is_some_and(|peer| peer.link == link) {
+            self.peers.remove(name);
+            self.record_audit("peer_unlinked", name, "federation link down".to_string());
+        }
+    }
+
+    fn shares(&self, peer: &str, channel: &str) -> bool {
+        self.get_peer_config(peer)
+            .is_some_and(|peer| peer.channels.iter().any(|shared| channel_name(shared) == channel))
+    }
+
+    /// Relays a plaintext channel message to every linked peer that shares the channel
+    /// and hasn't seen the message yet
+    fn relay(&mut self, payload: &PeerMessagePayload) {
+        let channel = MessagePayload::from_bytes(payload.message.payload.clone()).channel;
+        let targets: Vec<String> = self
+            .peers
+            .keys()
+            .filter(|name| !payload.seen.contains(name) && self.shares(name, channel_name(&channel)))
+            .cloned()
+            .collect();
+        for name in targets {
+            let peer = self.peers.get_mut(&name).unwrap();
+            let mut relayed = payload.clone();
+            peer.sent += 1;
+            relayed.sequence = peer.sent;
+            relayed.encrypt(peer.shared_key.clone());
+            let message = Message::new(MessageType::PeerMessage, relayed.to_bytes());
+            if let Err(e) = peer.tx.send(message.to_bytes()) {
+                error!("Error relaying a message to {}: {}", name, e);
+            }
+        }
+    }
+
+    /// Takes a message a peer relayed and shows it to the local members of the channel. It is
+    /// not passed on, a server only takes messages from the server they were posted on
+    pub fn receive_from_peer(&mut self, peer: &str, msg: Message) {
+        let link = match self.peers.get_mut(peer) {
+            Some(link) => link,
+            None => return,
+        };
+        let mut payload = PeerMessagePayload::from_bytes(msg.payload);
+        if !payload.verify(&link.shared_key) {
+            debug!("{} relayed a message with a bad signature", peer);
+            return;
+        }
+        if payload.sequence <= link.received {
+            debug!("{} relayed message {} again", peer, payload.sequence);
+            return;
+        }
+        link.received = payload.sequence;
+        payload.decrypt(link.shared_key.clone());
+
+        // a peer speaks for its own users only, the rest of the payload is whatever it says
+        if payload.origin != peer {
+            debug!("{} relayed a message from {}, which it can't speak for", peer, payload.origin);
+            return;
+        }
+
+        let mut message_payload = MessagePayload::from_bytes(payload.message.payload.clone());
+        let name = channel_name(&message_payload.channel).to_string();
+        if !self.shares(peer, &name) {
+            debug!("{} relayed a message for #{}, which isn't shared with it", peer, name);
+            return;
+        }
+        match self.channels.get(&name) {
+            // the same message can come around more than one way
+            Some(channel) if !channel.has_message(payload.message.id) => {}
+            _ => return,
+        }
+
+        // a server only speaks for its own users
+        let (user, _) = split_address(&message_payload.username);
+        message_payload.username = format!("{}@{}", user, payload.origin);
+        payload.message.payload = message_payload.to_bytes();
+
+        self.store_message(payload.message.clone());
+        self.broadcast_channel(None, &self.channels[&name], payload.message.clone(), message_payload);
+    }
+
+    /// Who this server is and how its links are doing
+    pub fn describe_federation(&self, actor: SocketAddr) -> Result<String, String> {
+        if self.get_role(actor) < Role::Admin {
+            return Err("Only admins can see the federation".to_string());
+        }
+
+        let mut lines = vec![format!("This server is {}, key {}", self.config.server_name, base64::encode(self.get_public_key()))];
+        for peer in self.config.peers.iter() {
+            let status = if self.is_linked(&peer.name) { "linked" } else { "not linked" };
+            lines.push(format!("{}: {}, sharing {}", peer.name, status, peer.channels.join(", ")));
+        }
+        Ok(lines.join("\n"))
+    }
+
+    pub fn add_shared_key(&mut self, addr: SocketAddr, shared_key: Vec<u8>) {
+        self.shared_keys.insert(addr, shared_key);
+    }
+
+    pub fn get_private_key(&self) -> Vec<u8> {
+        self.private_key.clone()
+    }
+
+    pub fn get_shared_key(&self, addr: SocketAddr) -> Vec<u8> {
+        self.shared_keys.get(&addr).unwrap().clone()
+    }
+}
+
+impl Server {
+    /// Loads the server's state from data/, failing when a file is there but can't be read
+    pub fn load() -> Result<Server, String> {
+        let channels: HashMap<String, Channel> = channel::load_channels()?
+            .into_iter()
+            .map(|channel| (channel.name.clone(), channel))
+            .collect();
+
+        let search = SearchIndex::load(channels.values());
+
+        let audit = AuditLog;
+        let private_key = load_private_key(&audit);
+        let config = ServerConfig::load();
+
+        Ok(Server {
+            channels,
+            clients: HashMap::new(),
+            shared_keys: HashMap::new(),
+            users: HashMap::new(),
+            typing: HashMap::new(),
+            registry: Registry::load()?,
+            bans: BanList::load()?,
+            invites: InviteList::load()?,
+            integrations: Integrations::load(),
+            deliveries: DeliveryQueue::load(),
+            plugins: plugins::load(&config.plugins),
+            search,
+            mutes: HashMap::new(),
+            blobs: BlobStore::load(),
+            config,
+            audit,
+            peers: HashMap::new(),
+            private_key,
+            last_message_id: 0,
+        })
+    }
+}
+
+/// Loads the server's identity key, or makes one on the first start
+fn load_private_key(audit: &AuditLog) -> Vec<u8> {
+    if let Ok(key) = std::fs::read(PRIVATE_KEY_PATH) {
+        if key.len() == 32 {
+            // keys from older versions were written readable by everyone
+            #[cfg(unix)]
+            if let Err(e) = std::fs::set_permissions(PRIVATE_KEY_PATH, std::os::unix::fs::PermissionsExt::from_mode(0o600)) {
+                error!("Error making {} private: {}", PRIVATE_KEY_PATH, e);
+            }
+            return key;
+        }
+        error!("{} is not a key, making a new one", PRIVATE_KEY_PATH);
+    }
+
+    let private_key = crypt::create_private_key();
+    let public_key = crypt::serialize_public_key(crypt::create_public_key(private_key.clone()));
+    let private_key = crypt::serialize_private_key(private_key);
+
+    std::fs::create_dir_all("data").unwrap();
+    if let Err(e) = write_private(PRIVATE_KEY_PATH, &private_key) {
+        error!("Error saving the server key, it will change on the next start: {}", e);
+    }
+    if let Err(e) = std::fs::write(PUBLIC_KEY_PATH, base64::encode(&public_key)) {
+        error!("Error saving the public server key: {}", e);
+    }
+    audit.record(AuditEvent::new("server_key", "SERVER", "created a new server key".to_string()));
+    private_key
 }
@@ -0,0 +1,356 @@
+use std::io;
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use crypto::digest::Digest;
+use crypto::sha1::Sha1;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_util::codec::{Decoder, Encoder};
+
+// appended to the client's key before hashing, from RFC 6455
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+// the upgrade request has to fit in this many bytes
+const MAX_REQUEST_SIZE: usize = 8 * 1024;
+// the largest message a client may send, frames of a fragmented message count together
+const MAX_MESSAGE_SIZE: usize = 64 * 1024 * 1024;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xa;
+// control frames can't be fragmented or carry more than this
+const MAX_CONTROL_SIZE: usize = 125;
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// The Sec-WebSocket-Accept answer to a client's Sec-WebSocket-Key
+fn accept_key(key: &str) -> String {
+    let mut sha1 = Sha1::new();
+    sha1.input_str(key);
+    sha1.input_str(ACCEPT_GUID);
+    let mut hash = [0u8; 20];
+    sha1.result(&mut hash);
+    base64::encode(hash)
+}
+
+/// Reads the HTTP upgrade request off a new connection and switches it to WebSocket
+pub async fn handshake(stream: &mut TcpStream) -> io::Result<()> {
+    let mut request = Vec::new();
+    let mut buffer = [0u8; 1024];
+    while !request.ends_with(b"\r\n\r\n") {
+        if request.len() > MAX_REQUEST_SIZE {
+            return Err(invalid("the upgrade request is too large"));
+        }
+        let read = stream.read(&mut buffer).await?;
+        if read == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        request.extend_from_slice(&buffer[..read]);
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let header = |name: &str| {
+        request
+            .lines()
+            .skip(1)
+            .filter_map(|line| line.split_once(':'))
+            .find(|(key, _)| key.trim().eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.trim().to_string())
+    };
+
+    let is_upgrade = header("Upgrade").is_some_and(|upgrade| upgrade.eq_ignore_ascii_case("websocket"));
+    let key = match (request.starts_with("GET "), is_upgrade, header("Sec-WebSocket-Key")) {
+        (true, true, Some(key)) => key,
+        _ => {
+            stream.write_all(b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n").await?;
+            return Err(invalid("not a WebSocket upgrade request"));
+        }
+    };
+
+    let response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+        accept_key(&key)
+    );
+    stream.write_all(response.as_bytes()).await
+}
+
+/// Carries one `Message` per binary WebSocket message, so a WebSocket connection
+/// frames the same bytes a TCP connection does with LengthDelimitedCodec
+#[derive(Default)]
+pub struct WebSocketCodec {
+    // the frames of a fragmented message received so far
+    partial: BytesMut,
+    // the client sent a close frame, everything after it is ignored
+    closed: bool,
+    // pongs and the closing frame waiting to be written back to the client
+    replies: BytesMut,
+}
+
+impl WebSocketCodec {
+    pub fn new() -> WebSocketCodec {
+        WebSocketCodec::default()
+    }
+
+    /// Takes the control frames the decoder owes the client
+    pub fn take_replies(&mut self) -> Option<BytesMut> {
+        if self.replies.is_empty() {
+            None
+        } else {
+            Some(self.replies.split())
+        }
+    }
+}
+
+/// Writes the header of an unmasked frame that carries `length` bytes
+fn put_header(dst: &mut BytesMut, opcode: u8, length: usize) {
+    dst.put_u8(0x80 | opcode);
+    match length {
+        length if length < 126 => dst.put_u8(length as u8),
+        length if length <= u16::MAX as usize => {
+            dst.put_u8(126);
+            dst.put_u16(length as u16);
+        }
+        length => {
+            dst.put_u8(127);
+            dst.put_u64(length as u64);
+        }
+    }
+}
+
+impl Decoder for WebSocketCodec {
+    type Item = BytesMut;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<BytesMut>> {
+        loop {
+            if self.closed {
+                src.clear();
+                return Ok(None);
+            }
+            if src.len() < 2 {
+                return Ok(None);
+            }
+
+            let fin = src[0] & 0x80 != 0;
+            let opcode = src[0] & 0x0f;
+            // clients always mask what they send
+            if src[1] & 0x80 == 0 {
+                return Err(invalid("the client sent an unmasked frame"));
+            }
+
+            let (length, mut offset) = match src[1] & 0x7f {
+                126 if src.len() >= 4 => (u16::from_be_bytes([src[2], src[3]]) as usize, 4),
+                127 if src.len() >= 10 => {
+                    let length = u64::from_be_bytes(src[2..10].try_into().unwrap());
+                    (usize::try_from(length).map_err(|_| invalid("the message is too large"))?, 10)
+                }
+                126 | 127 => return Ok(None),
+                length => (length as usize, 2),
+            };
+            if opcode & 0x8 != 0 {
+                if !fin || length > MAX_CONTROL_SIZE {
+                    return Err(invalid("the client sent an oversized or fragmented control frame"));
+                }
+            } else {
+                match self.partial.len().checked_add(length) {
+                    Some(total) if total <= MAX_MESSAGE_SIZE => {}
+                    _ => return Err(invalid("the message is too large")),
+                }
+            }
+            // the length is at most MAX_MESSAGE_SIZE here, so this can't overflow
+            let frame_length = offset + 4 + length;
+            if src.len() < frame_length {
+                src.reserve(frame_length - src.len());
+                return Ok(None);
+            }
+
+            let mask = [src[offset], src[offset + 1], src[offset + 2], src[offset + 3]];
+            offset += 4;
+            src.advance(offset);
+            let mut payload = src.split_to(length);
+            for (i, byte) in payload.iter_mut().enumerate() {
+                *byte ^= mask[i % 4];
+            }
+
+            match opcode {
+                OPCODE_BINARY | OPCODE_CONTINUATION => {
+                    self.partial.put(payload);
+                    if fin {
+                        return Ok(Some(self.partial.split()));
+                    }
+                }
+                OPCODE_PING => {
+                    // a pong echoes the ping's payload, the empty item wakes the connection to send it
+                    put_header(&mut self.replies, OPCODE_PONG, payload.len());
+                    self.replies.put(payload);
+                    return Ok(Some(BytesMut::new()));
+                }
+                OPCODE_CLOSE => {
+                    // answer with the status code the client closed with, if it sent one
+                    let code = if payload.len() >= 2 { &payload[..2] } else { &[][..] };
+                    put_header(&mut self.replies, OPCODE_CLOSE, code.len());
+                    self.replies.put_slice(code);
+                    self.closed = true;
+                    return Ok(Some(BytesMut::new()));
+                }
+                // pongs and text carry nothing for us
+                _ => {}
+            }
+        }
+    }
+}
+
+impl Encoder<Bytes> for WebSocketCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, item: Bytes, dst: &mut BytesMut) -> io::Result<()> {
+        // one unmasked binary frame per message
+        put_header(dst, OPCODE_BINARY, item.len());
+        dst.put(item);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MASK: [u8; 4] = [0x12, 0x34, 0x56, 0x78];
+
+    /// A frame the way a client sends it, masked and with the shortest length encoding
+    fn client_frame(fin: bool, opcode: u8, payload: &[u8]) -> BytesMut {
+        let mut frame = BytesMut::new();
+        put_header(&mut frame, opcode, payload.len());
+        if !fin {
+            frame[0] &= 0x7f;
+        }
+        frame[1] |= 0x80;
+        frame.put_slice(&MASK);
+        frame.extend(payload.iter().enumerate().map(|(i, byte)| byte ^ MASK[i % 4]));
+        frame
+    }
+
+    #[test]
+    fn decodes_every_length_encoding() {
+        for length in [0, 125, 126, 127, 65535, 65536] {
+            let payload: Vec<u8> = (0..length).map(|i| i as u8).collect();
+            let mut src = client_frame(true, OPCODE_BINARY, &payload);
+            let header_length = match length {
+                0..=125 => 2,
+                126..=65535 => 4,
+                _ => 10,
+            };
+            assert_eq!(src.len(), header_length + 4 + length);
+
+            let decoded = WebSocketCodec::new().decode(&mut src).unwrap().unwrap();
+            assert_eq!(&decoded[..], &payload[..], "length {}", length);
+            assert!(src.is_empty());
+        }
+    }
+
+    #[test]
+    fn waits_for_the_whole_frame() {
+        let frame = client_frame(true, OPCODE_BINARY, &[7; 300]);
+        let mut codec = WebSocketCodec::new();
+        let mut src = BytesMut::new();
+        for byte in &frame[..frame.len() - 1] {
+            src.put_u8(*byte);
+            assert!(codec.decode(&mut src).unwrap().is_none());
+        }
+        src.put_u8(frame[frame.len() - 1]);
+        assert_eq!(&codec.decode(&mut src).unwrap().unwrap()[..], &[7; 300][..]);
+    }
+
+    #[test]
+    fn refuses_unmasked_frames() {
+        let mut src = client_frame(true, OPCODE_BINARY, b"hello");
+        src[1] &= 0x7f;
+        assert!(WebSocketCodec::new().decode(&mut src).is_err());
+    }
+
+    #[test]
+    fn joins_fragmented_messages() {
+        let mut src = client_frame(false, OPCODE_BINARY, b"hel");
+        src.unsplit(client_frame(true, OPCODE_PING, b"?"));
+        src.unsplit(client_frame(false, OPCODE_CONTINUATION, b"lo "));
+        src.unsplit(client_frame(true, OPCODE_CONTINUATION, b"there"));
+
+        let mut codec = WebSocketCodec::new();
+        // the ping in between is answered without breaking up the message
+        assert!(codec.decode(&mut src).unwrap().unwrap().is_empty());
+        assert!(codec.take_replies().is_some());
+        assert_eq!(&codec.decode(&mut src).unwrap().unwrap()[..], b"hello there");
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn refuses_oversized_messages() {
+        // the length alone is enough to refuse it, the payload never has to arrive
+        let mut src = BytesMut::new();
+        src.put_u8(0x80 | OPCODE_BINARY);
+        src.put_u8(0x80 | 127);
+        src.put_u64(MAX_MESSAGE_SIZE as u64 + 1);
+        assert!(WebSocketCodec::new().decode(&mut src).is_err());
+
+        // a length that would overflow when added to what was received so far
+        let mut src = client_frame(false, OPCODE_BINARY, b"start");
+        src.put_u8(OPCODE_CONTINUATION);
+        src.put_u8(0x80 | 127);
+        src.put_u64(u64::MAX);
+        let mut codec = WebSocketCodec::new();
+        assert!(codec.decode(&mut src).is_err());
+
+        // fragments count together
+        let half = vec![0; MAX_MESSAGE_SIZE / 2 + 1];
+        let mut src = client_frame(false, OPCODE_BINARY, &half);
+        src.unsplit(client_frame(true, OPCODE_CONTINUATION, &half));
+        assert!(WebSocketCodec::new().decode(&mut src).is_err());
+    }
+
+    #[test]
+    fn refuses_oversized_control_frames() {
+        let mut src = client_frame(true, OPCODE_PING, &[0; 126]);
+        assert!(WebSocketCodec::new().decode(&mut src).is_err());
+
+        let mut src = client_frame(false, OPCODE_PING, b"ping");
+        assert!(WebSocketCodec::new().decode(&mut src).is_err());
+    }
+
+    #[test]
+    fn answers_pings_with_pongs() {
+        let mut src = client_frame(true, OPCODE_PING, b"are you there");
+        let mut codec = WebSocketCodec::new();
+        assert!(codec.decode(&mut src).unwrap().unwrap().is_empty());
+
+        let mut expected = BytesMut::new();
+        put_header(&mut expected, OPCODE_PONG, 13);
+        expected.put_slice(b"are you there");
+        assert_eq!(codec.take_replies(), Some(expected));
+        assert_eq!(codec.take_replies(), None);
+    }
+
+    #[test]
+    fn answers_close_and_ignores_the_rest() {
+        let mut src = client_frame(true, OPCODE_CLOSE, &[0x03, 0xe8, b'b', b'y', b'e']);
+        src.unsplit(client_frame(true, OPCODE_BINARY, b"too late"));
+        let mut codec = WebSocketCodec::new();
+        assert!(codec.decode(&mut src).unwrap().unwrap().is_empty());
+        // the reply echoes the status code
+        assert_eq!(&codec.take_replies().unwrap()[..], &[0x80 | OPCODE_CLOSE, 2, 0x03, 0xe8]);
+        assert!(codec.decode(&mut src).unwrap().is_none());
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn encodes_unmasked_binary_frames() {
+        for (length, header) in [(125, &[0x82, 125][..]), (126, &[0x82, 126, 0, 126][..]), (65536, &[0x82, 127, 0, 0, 0, 0, 0, 1, 0, 0][..])] {
+            let mut dst = BytesMut::new();
+            WebSocketCodec::new().encode(Bytes::from(vec![1; length]), &mut dst).unwrap();
+            assert_eq!(&dst[..header.len()], header);
+            assert_eq!(dst.len(), header.len() + length);
+        }
+    }
+}
@@ -0,0 +1,273 @@
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use serde::{Deserialize, Serialize};
+
+use crate::crypt;
+use crate::message::Payload;
+
+/// How many bytes of a file travel in one chunk
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Hashes file contents, attachments are identified by this hash on disk
+///
+/// # Arguments
+///
+/// * `data`: The contents of the file
+///
+/// returns: String, the hex encoded sha256 of the data
+///
+/// # Examples
+///
+/// ```
+/// let hash = common::attachment::hash_data(b"hello");
+/// assert_eq!(hash, "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824");
+/// ```
+pub fn hash_data(data: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.input(data);
+    hasher.result_str()
+}
+
+/// Checks that a hash is a hex encoded sha256, the server uses hashes as file names
+pub fn is_valid_hash(hash: &str) -> bool {
+    hash.len() == 64 && hash.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AttachmentMeta {
+    // assigned by the server, 0 until the server accepted the upload
+    pub id: u64,
+    pub name: String,
+    pub size: u64,
+    pub hash: String,
+}
+
+impl AttachmentMeta {
+    pub fn new(name: String, data: &[u8]) -> AttachmentMeta {
+        AttachmentMeta {
+            id: 0,
+            name,
+            size: data.len() as u64,
+            hash: hash_data(data),
+        }
+    }
+}
+
+// an upload offer, client -> server
+impl Payload for AttachmentMeta {
+    fn to_bytes(&self) -> Vec<u8> {
+        rmp_serde::to_vec(self).unwrap()
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> AttachmentMeta {
+        rmp_serde::from_slice(&bytes).unwrap_or_else(|_| AttachmentMeta {
+            id: 0,
+            name: "unknown".to_string(),
+            size: 0,
+            hash: String::new(),
+        })
+    }
+
+    // like the channel name, the file name and hash carry no file content
+    fn encrypt(&mut self, _key: Vec<u8>) {}
+
+    fn decrypt(&mut self, _key: Vec<u8>) {}
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AttachmentStatusPayload {
+    pub id: u64,
+    pub hash: String,
+    // how many bytes the server has, the next chunk starts here
+    pub received: u64,
+    pub complete: bool,
+    pub error: Option<String>,
+}
+
+impl AttachmentStatusPayload {
+    pub fn new(id: u64, hash: String, received: u64, complete: bool) -> AttachmentStatusPayload {
+        AttachmentStatusPayload {
+            id,
+            hash,
+            received,
+            complete,
+            error: None,
+        }
+    }
+
+    pub fn error(hash: String, error: String) -> AttachmentStatusPayload {
+        AttachmentStatusPayload {
+            id: 0,
+            hash,
+            received: 0,
+            complete: false,
+            error: Some(error),
+        }
+    }
+}
+
+impl Payload for AttachmentStatusPayload {
+    fn to_bytes(&self) -> Vec<u8> {
+        rmp_serde::to_vec(self).unwrap()
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> AttachmentStatusPayload {
+        rmp_serde::from_slice(&bytes).unwrap_or_else(
+            |_| AttachmentStatusPayload::error(String::new(), "invalid status".to_string())
+        )
+    }
+
+    fn encrypt(&mut self, _key: Vec<u8>) {}
+
+    fn decrypt(&mut self, _key: Vec<u8>) {}
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AttachmentChunkPayload {
+    pub id: u64,
+    pub offset: u64,
+    pub data: Vec<u8>,
+}
+
+impl AttachmentChunkPayload {
+    pub fn new(id: u64, offset: u64, data: Vec<u8>) -> AttachmentChunkPayload {
+        AttachmentChunkPayload { id, offset, data }
+    }
+}
+
+impl Payload for AttachmentChunkPayload {
+    fn to_bytes(&self) -> Vec<u8> {
+        rmp_serde::to_vec(self).unwrap()
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> AttachmentChunkPayload {
+        rmp_serde::from_slice(&bytes).unwrap_or_else(|_| AttachmentChunkPayload::new(0, 0, Vec::new()))
+    }
+
+    fn encrypt(&mut self, key: Vec<u8>) {
+        self.data = crypt::encrypt_data(self.data.clone(), key);
+    }
+
+    fn decrypt(&mut self, key: Vec<u8>) {
+        self.data = crypt::decrypt_data(self.data.clone(), key);
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AttachmentRequestPayload {
+    pub id: u64,
+    // what the client already has, the download resumes from here
+    pub offset: u64,
+}
+
+impl AttachmentRequestPayload {
+    pub fn new(id: u64, offset: u64) -> AttachmentRequestPayload {
+        AttachmentRequestPayload { id, offset }
+    }
+}
+
+impl Payload for AttachmentRequestPayload {
+    fn to_bytes(&self) -> Vec<u8> {
+        rmp_serde::to_vec(self).unwrap()
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> AttachmentRequestPayload {
+        rmp_serde::from_slice(&bytes).unwrap_or_else(|_| AttachmentRequestPayload::new(0, 0))
+    }
+
+    fn encrypt(&mut self, _key: Vec<u8>) {}
+
+    fn decrypt(&mut self, _key: Vec<u8>) {}
+}
+
+/// A file a client is sending to the server, one chunk per status the server sends back
+pub struct Upload {
+    pub meta: AttachmentMeta,
+    data: Vec<u8>,
+}
+
+impl Upload {
+    pub fn from_file(path: &str) -> std::io::Result<Upload> {
+        let data = std::fs::read(path)?;
+        let name = Path::new(path)
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_else(|| "file".to_string());
+
+        Ok(Upload {
+            meta: AttachmentMeta::new(name, &data),
+            data,
+        })
+    }
+
+    /// Gets the chunk that follows what the server reported, None once everything was sent
+    pub fn next_chunk(&mut self, status: &AttachmentStatusPayload) -> Option<AttachmentChunkPayload> {
+        self.meta.id = status.id;
+        let start = status.received as usize;
+        if status.complete || start >= self.data.len() {
+            return None;
+        }
+
+        let end = (start + CHUNK_SIZE).min(self.data.len());
+        Some(AttachmentChunkPayload::new(status.id, status.received, self.data[start..end].to_vec()))
+    }
+}
+
+/// A file a client is receiving, written to `<name>.part` until it is complete
+pub struct Download {
+    pub meta: AttachmentMeta,
+    pub path: PathBuf,
+    part_path: PathBuf,
+}
+
+impl Download {
+    pub fn new(meta: AttachmentMeta, directory: &str) -> Download {
+        std::fs::create_dir_all(directory).unwrap();
+
+        // never let the sender pick where the file goes
+        let name = Path::new(&meta.name)
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_else(|| meta.hash.clone());
+        let path = Path::new(directory).join(&name);
+        let part_path = Path::new(directory).join(format!("{}.part", name));
+
+        Download { meta, path, part_path }
+    }
+
+    /// How much of the file is already on disk, to resume an interrupted download
+    pub fn received(&self) -> u64 {
+        std::fs::metadata(&self.part_path).map(|m| m.len()).unwrap_or(0)
+    }
+
+    pub fn request(&self) -> AttachmentRequestPayload {
+        AttachmentRequestPayload::new(self.meta.id, self.received())
+    }
+
+    /// Writes a chunk, returns true once the whole file arrived and matches its hash
+    pub fn write_chunk(&mut self, chunk: &AttachmentChunkPayload) -> std::io::Result<bool> {
+        if chunk.offset != self.received() {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "chunk out of order"));
+        }
+
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.part_path)?;
+        file.write_all(&chunk.data)?;
+
+        if self.received() < self.meta.size {
+            return Ok(false);
+        }
+
+        let data = std::fs::read(&self.part_path)?;
+        if hash_data(&data) != self.meta.hash {
+            std::fs::remove_file(&self.part_path)?;
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "hash mismatch"));
+        }
+
+        std::fs::rename(&self.part_path, &self.path)?;
+        Ok(true)
+    }
+}
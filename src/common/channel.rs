@@ -1,9 +1,159 @@
-use crate::message::{Message, MessageType};
+use std::collections::{HashMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+
+use crate::id::to_timestamp;
+use crate::permission::{is_allowed, Permission, PermissionOverride, Role};
+use crate::message::{Message, MessagePayload, MessageType, Payload, ReactionPayload, Reactions};
+
+const CHANNELS_PATH: &str = "data/channels.bson";
+
+/// The on-disk layout of `data/channels/<name>.bson`, bson needs a document at the top level
+#[derive(Serialize, Deserialize, Default)]
+struct ChannelArchive {
+    messages: Vec<Message>,
+    // the reactions on archived messages, they are kept and go with their messages
+    #[serde(default)]
+    reactions: Vec<ArchivedReactions>,
+}
+
+/// The reactions on one archived message, bson only takes strings as keys
+#[derive(Serialize, Deserialize)]
+struct ArchivedReactions {
+    message_id: u64,
+    reactions: Reactions,
+}
+
+/// What is saved of a channel in `data/channels.bson`, its messages are archived separately
+#[derive(Serialize, Deserialize)]
+struct ChannelRecord {
+    info: ChannelInfo,
+    permissions: Vec<PermissionOverride>,
+    // only private channels keep their members, everyone is in a public channel
+    #[serde(default)]
+    users: Vec<u64>,
+    #[serde(default)]
+    retention: RetentionPolicy,
+    #[serde(default = "default_max_messages")]
+    max_messages: usize,
+    #[serde(default = "default_backup_messages")]
+    backup_messages: bool,
+}
+
+fn default_max_messages() -> usize {
+    100
+}
+
+fn default_backup_messages() -> bool {
+    true
+}
+
+/// How much of a channel's history is kept, in memory and on disk together.
+/// Anything over a limit goes, oldest first; None means no limit
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct RetentionPolicy {
+    pub max_messages: Option<usize>,
+    // in seconds, measured with the timestamps in the message ids
+    pub max_age: Option<u64>,
+    // in bytes of stored payload
+    pub max_size: Option<u64>,
+}
+
+impl RetentionPolicy {
+    pub fn is_empty(&self) -> bool {
+        self == &RetentionPolicy::default()
+    }
+
+    /// Removes what the policy does not keep, returns how many messages went
+    ///
+    /// # Arguments
+    ///
+    /// * `messages`: The history to prune, oldest first
+    /// * `now`: The current time in unix seconds
+    ///
+    /// returns: usize
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use common::channel::RetentionPolicy;
+    /// use common::message::{Message, MessageType};
+    ///
+    /// let mut messages = vec![Message::new(MessageType::Message, vec![]); 5];
+    /// let policy = RetentionPolicy { max_messages: Some(2), ..RetentionPolicy::default() };
+    /// assert_eq!(policy.prune(&mut messages, 0), 3);
+    /// assert_eq!(messages.len(), 2);
+    /// ```
+    pub fn prune(&self, messages: &mut Vec<Message>, now: u64) -> usize {
+        let count = messages.len();
+
+        if let Some(max_age) = self.max_age {
+            let cutoff = now.saturating_sub(max_age);
+            messages.retain(|m| to_timestamp(m.id) >= cutoff);
+        }
+
+        if let Some(max_messages) = self.max_messages {
+            let excess = messages.len().saturating_sub(max_messages);
+            messages.drain(..excess);
+        }
+
+        if let Some(max_size) = self.max_size {
+            // keep the newest messages that fit
+            let mut size = 0;
+            let keep = messages
+                .iter()
+                .rev()
+                .take_while(|m| {
+                    size += m.payload.len() as u64;
+                    size <= max_size
+                })
+                .count();
+            let excess = messages.len() - keep;
+            messages.drain(..excess);
+        }
+
+        count - messages.len()
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct ChannelsFile {
+    channels: Vec<ChannelRecord>,
+}
+
+/// What clients get to know about a channel
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct ChannelInfo {
+    pub name: String,
+    pub topic: String,
+    pub description: String,
+    // archived channels keep their history but take no new messages
+    pub archived: bool,
+    // private channels are only listed to their members, who join with an invite
+    #[serde(default)]
+    pub private: bool,
+    // the server keeps no searchable copy of end-to-end channels
+    #[serde(default)]
+    pub end_to_end: bool,
+}
 
 pub struct Channel {
     pub name: String,
     pub users: Vec<u64>,
     pub messages: Vec<Message>,
+    // thread replies keyed by the id of the message that started the thread
+    pub threads: HashMap<u64, Vec<Message>>,
+    // reactions on the messages in memory keyed by message id, archived ones are in the archive
+    pub reactions: HashMap<u64, Reactions>,
+    // what roles may do here, on top of the server wide defaults
+    pub permissions: Vec<PermissionOverride>,
+    pub topic: String,
+    pub description: String,
+    pub archived: bool,
+    pub private: bool,
+    pub end_to_end: bool,
+    pub retention: RetentionPolicy,
+    // how many messages stay in memory, older ones go to disk if backup_messages is set
     pub max_messages: usize,
     pub backup_messages: bool,
 }
@@ -27,6 +177,15 @@ impl Channel {
             name,
             users: Vec::new(),
             messages: Vec::new(),
+            threads: HashMap::new(),
+            reactions: HashMap::new(),
+            permissions: Vec::new(),
+            topic: String::new(),
+            description: String::new(),
+            archived: false,
+            private: false,
+            end_to_end: false,
+            retention: RetentionPolicy::default(),
             max_messages: 100,
             backup_messages: true,
         }
@@ -55,26 +214,268 @@ impl Channel {
         self.users.retain(|&x| x != user);
     }
 
-    pub fn add_message(&mut self, message: Message) {
+    /// Adds a message to the timeline or its thread, returns the messages that went away to make
+    /// room for it, which is none when older messages go to disk
+    pub fn add_message(&mut self, message: Message) -> Vec<Message> {
+        let mut dropped = Vec::new();
         if message.message_type != MessageType::Message {
-            return;
+            return dropped;
         }
 
-        // if we have 100 messages, remove and backup the first one
-        if self.messages.len() == 100 {
-            let message = self.messages.remove(0);
-            self.save_message(message);
+        // once the channel holds max_messages, thread replies included, the oldest goes to disk,
+        // or away if nothing is kept on disk
+        while self.in_memory() >= self.max_messages {
+            let oldest = match self.take_oldest() {
+                Some(oldest) => oldest,
+                None => break,
+            };
+            if self.backup_messages {
+                self.save_message(oldest);
+            } else {
+                self.reactions.remove(&oldest.id);
+                dropped.push(oldest);
+            }
         }
 
-        if self.messages.len() == 100 {
-            // remove the oldest message
-            let last_message = self.messages.remove(0);
-            if self.backup_messages {
-                self.save_message(last_message);
+        // thread replies live next to the timeline, not in it, and messages from other servers
+        // can arrive late, both are kept in id order
+        let payload = MessagePayload::from_bytes(message.payload.clone());
+        let messages = match payload.thread {
+            Some(thread) => self.threads.entry(thread).or_default(),
+            None => &mut self.messages,
+        };
+        let i = messages.partition_point(|m| m.id <= message.id);
+        messages.insert(i, message);
+        dropped
+    }
+
+    /// How many messages are in memory, the timeline and the thread replies
+    fn in_memory(&self) -> usize {
+        self.messages.len() + self.threads.values().map(Vec::len).sum::<usize>()
+    }
+
+    /// Takes the oldest message in memory out of the timeline or the thread it is in
+    fn take_oldest(&mut self) -> Option<Message> {
+        let oldest_reply = self.threads.iter().filter_map(|(thread, replies)| replies.first().map(|m| (*thread, m.id))).min_by_key(|(_, id)| *id);
+        match (self.messages.first(), oldest_reply) {
+            (Some(first), Some((_, id))) if first.id < id => Some(self.messages.remove(0)),
+            (_, Some((thread, _))) => {
+                let replies = self.threads.get_mut(&thread)?;
+                let oldest = replies.remove(0);
+                if replies.is_empty() {
+                    self.threads.remove(&thread);
+                }
+                Some(oldest)
             }
+            (Some(_), None) => Some(self.messages.remove(0)),
+            (None, None) => None,
+        }
+    }
+
+    /// Whether a message is in memory, in the timeline or a thread
+    pub fn has_message(&self, id: u64) -> bool {
+        self.messages.iter().chain(self.threads.values().flatten()).any(|m| m.id == id)
+    }
+
+    /// Whether a message is anywhere in the history, the archive on disk included
+    pub fn knows_message(&self, id: u64) -> bool {
+        self.has_message(id) || self.load_archive().iter().any(|m| m.id == id)
+    }
+
+    /// Gets the replies of a thread, oldest first, the archived ones included
+    ///
+    /// # Arguments
+    ///
+    /// * `thread`: The id of the message that started the thread
+    ///
+    /// returns: Vec<Message>
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let channel = common::channel::Channel::new("test".to_string());
+    /// assert!(channel.get_thread(1).is_empty());
+    /// ```
+    pub fn get_thread(&self, thread: u64) -> Vec<Message> {
+        let mut replies: Vec<Message> = self
+            .load_archive()
+            .into_iter()
+            .filter(|m| MessagePayload::from_bytes(m.payload.clone()).thread == Some(thread))
+            .collect();
+        replies.extend(self.threads.get(&thread).into_iter().flatten().cloned());
+        replies
+    }
+
+    /// Adds or removes a reaction, returns false if the message is not in this channel
+    pub fn add_reaction(&mut self, reaction: &ReactionPayload) -> bool {
+        if self.has_message(reaction.message_id) {
+            let reactions = self.reactions.entry(reaction.message_id).or_default();
+            reaction.apply(reactions);
+            if reactions.is_empty() {
+                self.reactions.remove(&reaction.message_id);
+            }
+            return true;
+        }
+
+        // reactions on archived messages are written to the archive straight away
+        let mut archive = self.read_archive();
+        if !archive.messages.iter().any(|m| m.id == reaction.message_id) {
+            return false;
+        }
+
+        let i = match archive.reactions.iter().position(|r| r.message_id == reaction.message_id) {
+            Some(i) => i,
+            None => {
+                archive.reactions.push(ArchivedReactions { message_id: reaction.message_id, reactions: Reactions::new() });
+                archive.reactions.len() - 1
+            }
+        };
+        reaction.apply(&mut archive.reactions[i].reactions);
+        if archive.reactions[i].reactions.is_empty() {
+            archive.reactions.remove(i);
+        }
+        self.write_file(&archive);
+
+        true
+    }
+
+    /// Gets the reactions on the given messages, for replaying them with the messages
+    pub fn get_reactions(&self, messages: &[Message]) -> HashMap<u64, Reactions> {
+        let mut reactions: HashMap<u64, Reactions> = messages
+            .iter()
+            .filter_map(|m| self.reactions.get(&m.id).map(|r| (m.id, r.clone())))
+            .collect();
+
+        // only look in the archive when some of the messages came from there
+        if messages.iter().any(|m| !self.has_message(m.id)) {
+            let ids: HashSet<u64> = messages.iter().map(|m| m.id).collect();
+            let archived = self.read_archive().reactions.into_iter().filter(|r| ids.contains(&r.message_id));
+            reactions.extend(archived.map(|r| (r.message_id, r.reactions)));
+        }
+
+        reactions
+    }
+
+    pub fn allows(&self, role: Role, permission: Permission) -> bool {
+        is_allowed(role, permission, &self.permissions)
+    }
+
+    /// Overrides a permission for a role in this channel, None goes back to the default
+    pub fn set_permission(&mut self, role: Role, permission: Permission, allow: Option<bool>) {
+        self.permissions.retain(|o| !(o.role == role && o.permission == permission));
+        if let Some(allow) = allow {
+            self.permissions.push(PermissionOverride { role, permission, allow });
+        }
+    }
+
+    pub fn info(&self) -> ChannelInfo {
+        ChannelInfo {
+            name: self.name.clone(),
+            topic: self.topic.clone(),
+            description: self.description.clone(),
+            archived: self.archived,
+            private: self.private,
+            end_to_end: self.end_to_end,
+        }
+    }
+
+    /// Renames the channel, its messages and its archive move along
+    pub fn rename(&mut self, name: String) {
+        let mut archive = self.read_archive();
+        let old_path = format!("data/channels/{}.bson", self.name);
+
+        self.name = name;
+        rename_messages(&mut self.messages, &self.name);
+        for replies in self.threads.values_mut() {
+            rename_messages(replies, &self.name);
+        }
+
+        if !archive.messages.is_empty() {
+            rename_messages(&mut archive.messages, &self.name);
+            self.write_file(&archive);
+            let _ = std::fs::remove_file(old_path);
+        }
+    }
+
+    /// Applies the retention policy to the archive and the messages in memory,
+    /// returns how many messages were removed
+    pub fn prune(&mut self, now: u64) -> usize {
+        if self.retention.is_empty() {
+            return 0;
+        }
+
+        // the policy covers the whole history, which is the archive followed by what is in memory
+        let archive = self.load_archive();
+        let archived = archive.len();
+        let mut history = archive;
+        history.append(&mut self.messages);
+
+        let removed = self.retention.prune(&mut history, now);
+        let removed_archived = removed.min(archived);
+        self.messages = history.split_off(archived - removed_archived);
+        if removed_archived > 0 {
+            self.write_archive(history);
+        }
+
+        // thread replies and reactions go with the age of the messages
+        if let Some(max_age) = self.retention.max_age {
+            let cutoff = now.saturating_sub(max_age);
+            for replies in self.threads.values_mut() {
+                replies.retain(|m| to_timestamp(m.id) >= cutoff);
+            }
+            self.threads.retain(|_, replies| !replies.is_empty());
+        }
+        let messages = &self.messages;
+        let threads = &self.threads;
+        self.reactions.retain(|id, _| messages.iter().any(|m| m.id == *id) || threads.values().flatten().any(|m| m.id == *id));
+
+        removed
+    }
+
+    /// The ids of the attachments posted in the channel's stored messages
+    pub fn attachment_ids(&self) -> HashSet<u64> {
+        self.get_all_messages()
+            .iter()
+            .flat_map(|m| MessagePayload::from_bytes(m.payload.clone()).attachments)
+            .map(|attachment| attachment.id)
+            .collect()
+    }
+
+    /// Gets every stored message, the archive, the timeline in memory and the thread replies
+    pub fn get_all_messages(&self) -> Vec<Message> {
+        let mut messages = self.load_archive();
+        messages.extend(self.messages.iter().cloned());
+        messages.extend(self.threads.values().flatten().cloned());
+        messages
+    }
+
+    /// Adds imported messages to the archive, skipping ids the channel already has,
+    /// returns the messages that were added
+    pub fn import(&mut self, messages: Vec<Message>) -> Vec<Message> {
+        let mut known: HashSet<u64> = self.get_all_messages().iter().map(|m| m.id).collect();
+        let added: Vec<Message> = messages.into_iter().filter(|m| known.insert(m.id)).collect();
+        if added.is_empty() {
+            return added;
         }
 
-        self.messages.push(message);
+        let mut archive = self.load_archive();
+        archive.extend(added.iter().cloned());
+        archive.sort_by_key(|m| m.id);
+        self.write_archive(archive);
+        added
+    }
+
+    /// Stops keeping messages on disk, or starts again
+    pub fn set_backup_messages(&mut self, backup_messages: bool) {
+        self.backup_messages = backup_messages;
+        if !backup_messages {
+            self.delete_archive();
+        }
+    }
+
+    /// Removes the archived messages of a channel that is being deleted
+    pub fn delete_archive(&self) {
+        let _ = std::fs::remove_file(format!("data/channels/{}.bson", self.name));
     }
 
     pub fn refresh_messages(&mut self) {
@@ -83,10 +484,9 @@ impl Channel {
 
     pub fn backup(&mut self) {
         // save messages to file using bson
-        let data = bson::to_vec(&self.messages).unwrap();
-
-        // save to file
-        std::fs::write(format!("data/channels/{}.bson", self.name), data).unwrap();
+        let mut messages = self.load_archive();
+        messages.append(&mut self.messages);
+        self.write_archive(messages);
 
         // clear messages for memory management
         self.refresh_messages();
@@ -94,20 +494,332 @@ impl Channel {
 
     pub fn save_message(&mut self, message: Message) {
         // load messages from file using bson
-        let data = std::fs::read(format!("data/channels/{}.bson", self.name)).unwrap();
-        let mut messages: Vec<Message> = bson::from_slice(&data).unwrap();
+        let mut messages = self.load_archive();
 
         // add message to messages
         messages.push(message);
 
         // save messages to file using bson
-        let data = bson::to_vec(&messages).unwrap();
+        self.write_archive(messages);
+    }
+
+    pub fn load_archive(&self) -> Vec<Message> {
+        self.read_archive().messages
+    }
+
+    fn read_archive(&self) -> ChannelArchive {
+        // a channel that never overflowed has no file yet
+        match std::fs::read(format!("data/channels/{}.bson", self.name)) {
+            Ok(data) => bson::from_slice(&data).unwrap_or_default(),
+            Err(_) => ChannelArchive::default(),
+        }
+    }
+
+    /// Replaces the archived messages, reactions go to disk with their messages and the
+    /// archived reactions of messages that are no longer there are dropped
+    pub fn write_archive(&mut self, messages: Vec<Message>) {
+        let ids: HashSet<u64> = messages.iter().map(|m| m.id).collect();
+        let mut reactions = self.read_archive().reactions;
+        reactions.retain(|r| ids.contains(&r.message_id));
+        for id in ids.iter() {
+            if let Some(moved) = self.reactions.remove(id) {
+                reactions.push(ArchivedReactions { message_id: *id, reactions: moved });
+            }
+        }
+
+        self.write_file(&ChannelArchive { messages, reactions });
+    }
+
+    fn write_file(&self, archive: &ChannelArchive) {
+        let data = bson::to_vec(archive).unwrap();
 
         // save to file
+        std::fs::create_dir_all("data/channels").unwrap();
         std::fs::write(format!("data/channels/{}.bson", self.name), data).unwrap();
     }
 }
 
+/// Clients address channels as "#general" while the server keys them as "general"
+///
+/// # Examples
+///
+/// ```
+/// assert_eq!(common::channel::channel_name("#general"), "general");
+/// ```
+pub fn channel_name(channel: &str) -> &str {
+    channel.trim_start_matches('#')
+}
+
+/// Checks a channel name, names are lowercase letters, digits, '-' and '_'
+///
+/// # Examples
+///
+/// ```
+/// use common::channel::is_valid_name;
+///
+/// assert!(is_valid_name("off-topic"));
+/// assert!(!is_valid_name("Off Topic"));
+/// assert!(!is_valid_name(""));
+/// ```
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 32
+        && name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+}
+
+/// Points messages at another channel, for when their channel is renamed
+pub fn rename_messages(messages: &mut [Message], name: &str) {
+    for message in messages.iter_mut() {
+        let mut payload = MessagePayload::from_bytes(message.payload.clone());
+        payload.channel = format!("#{}", name);
+        message.payload = payload.to_bytes();
+    }
+}
+
+/// Loads the channels saved in `data/channels.bson`, a new server starts with the defaults
+pub fn load_channels() -> Result<Vec<Channel>, String> {
+    let file: ChannelsFile = match std::fs::read(CHANNELS_PATH) {
+        Ok(data) => bson::from_slice(&data).map_err(|e| format!("{} is damaged: {}", CHANNELS_PATH, e))?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(get_default_channels()),
+        Err(e) => return Err(format!("Could not read {}: {}", CHANNELS_PATH, e)),
+    };
+
+    let channels = file.channels
+        .into_iter()
+        .map(|record| {
+            let mut channel = Channel::new(record.info.name);
+            channel.topic = record.info.topic;
+            channel.description = record.info.description;
+            channel.archived = record.info.archived;
+            channel.private = record.info.private;
+            channel.end_to_end = record.info.end_to_end;
+            channel.users = record.users;
+            channel.permissions = record.permissions;
+            channel.retention = record.retention;
+            channel.max_messages = record.max_messages;
+            channel.backup_messages = record.backup_messages;
+            channel
+        })
+        .collect();
+    Ok(channels)
+}
+
+pub fn save_channels<'a>(channels: impl Iterator<Item = &'a Channel>) {
+    let file = ChannelsFile {
+        channels: channels
+            .map(|channel| ChannelRecord {
+                info: channel.info(),
+                permissions: channel.permissions.clone(),
+                users: if channel.private { channel.users.clone() } else { Vec::new() },
+                retention: channel.retention.clone(),
+                max_messages: channel.max_messages,
+                backup_messages: channel.backup_messages,
+            })
+            .collect(),
+    };
+
+    match bson::to_vec(&file) {
+        Ok(data) => {
+            std::fs::create_dir_all("data").unwrap();
+            std::fs::write(CHANNELS_PATH, data).unwrap();
+        }
+        Err(e) => log::error!("Error serializing the channel list: {}", e),
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum ChannelAction {
+    Unknown,
+    Create,
+    Rename(String),
+    Archive,
+    Unarchive,
+    Delete,
+    Topic(String),
+    Description(String),
+    Private,
+    Public,
+    EndToEnd(bool),
+}
+
+/// A change to a channel, clients ask for it and the server tells everyone once it happened
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChannelUpdatePayload {
+    // who made the change, filled in by the server
+    pub username: String,
+    pub channel: String,
+    pub action: ChannelAction,
+}
+
+impl ChannelUpdatePayload {
+    pub fn new(username: String, channel: String, action: ChannelAction) -> ChannelUpdatePayload {
+        ChannelUpdatePayload { username, channel, action }
+    }
+
+    /// Parses the arguments of a `/channel` command, like `topic general Say hi`
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use common::channel::{ChannelAction, ChannelUpdatePayload};
+    ///
+    /// let update = ChannelUpdatePayload::from_command("alice", "topic #general Say hi").unwrap();
+    /// assert_eq!(update.channel, "general");
+    /// assert_eq!(update.action, ChannelAction::Topic("Say hi".to_string()));
+    /// assert!(ChannelUpdatePayload::from_command("alice", "rename general").is_err());
+    /// ```
+    pub fn from_command(username: &str, command: &str) -> Result<ChannelUpdatePayload, String> {
+        let mut words = command.trim().splitn(3, ' ');
+        let action = words.next().unwrap_or_default();
+        let channel = match words.next() {
+            Some(channel) => channel_name(channel).to_string(),
+            None => return Err("Usage: /channel <create|rename|archive|unarchive|delete|topic|description|private|public|e2e> <channel> [...]".to_string()),
+        };
+        let rest = words.next().unwrap_or_default().trim().to_string();
+
+        let action = match action {
+            "create" => ChannelAction::Create,
+            "rename" if !rest.is_empty() => ChannelAction::Rename(channel_name(&rest).to_string()),
+            "rename" => return Err("Usage: /channel rename <channel> <new name>".to_string()),
+            "archive" => ChannelAction::Archive,
+            "unarchive" => ChannelAction::Unarchive,
+            "delete" => ChannelAction::Delete,
+            "topic" => ChannelAction::Topic(rest),
+            "description" => ChannelAction::Description(rest),
+            "private" => ChannelAction::Private,
+            "public" => ChannelAction::Public,
+            "e2e" if rest == "on" || rest == "off" => ChannelAction::EndToEnd(rest == "on"),
+            "e2e" => return Err("Usage: /channel e2e <channel> <on|off>".to_string()),
+            _ => return Err(format!("Unknown channel action {}", action)),
+        };
+
+        Ok(ChannelUpdatePayload::new(username.to_string(), channel, action))
+    }
+
+    /// Applies the change to a client's list of channels
+    pub fn apply(&self, channels: &mut Vec<ChannelInfo>) {
+        if self.action == ChannelAction::Create {
+            if !channels.iter().any(|c| c.name == self.channel) {
+                channels.push(ChannelInfo {
+                    name: self.channel.clone(),
+                    ..ChannelInfo::default()
+                });
+            }
+            return;
+        }
+
+        if self.action == ChannelAction::Delete {
+            channels.retain(|c| c.name != self.channel);
+            return;
+        }
+
+        let info = match channels.iter_mut().find(|c| c.name == self.channel) {
+            Some(info) => info,
+            None => return,
+        };
+        match &self.action {
+            ChannelAction::Rename(name) => info.name = name.clone(),
+            ChannelAction::Archive => info.archived = true,
+            ChannelAction::Unarchive => info.archived = false,
+            ChannelAction::Topic(topic) => info.topic = topic.clone(),
+            ChannelAction::Description(description) => info.description = description.clone(),
+            ChannelAction::Private => info.private = true,
+            ChannelAction::Public => info.private = false,
+            ChannelAction::EndToEnd(end_to_end) => info.end_to_end = *end_to_end,
+            _ => {}
+        }
+    }
+
+    /// Describes the change for people, like "alice set the topic of #general to ..."
+    pub fn describe(&self) -> String {
+        match &self.action {
+            ChannelAction::Create => format!("{} created #{}", self.username, self.channel),
+            ChannelAction::Rename(name) => format!("{} renamed #{} to #{}", self.username, self.channel, name),
+            ChannelAction::Archive => format!("{} archived #{}", self.username, self.channel),
+            ChannelAction::Unarchive => format!("{} unarchived #{}", self.username, self.channel),
+            ChannelAction::Delete => format!("{} deleted #{}", self.username, self.channel),
+            ChannelAction::Topic(topic) => format!("{} set the topic of #{} to \"{}\"", self.username, self.channel, topic),
+            ChannelAction::Description(_) => format!("{} changed the description of #{}", self.username, self.channel),
+            ChannelAction::Private => format!("{} made #{} private", self.username, self.channel),
+            ChannelAction::Public => format!("{} made #{} public", self.username, self.channel),
+            ChannelAction::EndToEnd(true) => format!("{} made #{} end-to-end, it can't be searched", self.username, self.channel),
+            ChannelAction::EndToEnd(false) => format!("{} turned off end-to-end for #{}", self.username, self.channel),
+            ChannelAction::Unknown => format!("{} changed #{}", self.username, self.channel),
+        }
+    }
+}
+
+impl Payload for ChannelUpdatePayload {
+    fn to_bytes(&self) -> Vec<u8> {
+        rmp_serde::to_vec(self).unwrap()
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> ChannelUpdatePayload {
+        rmp_serde::from_slice(&bytes).unwrap_or_else(
+            |_| ChannelUpdatePayload::new("unknown".to_string(), "unknown".to_string(), ChannelAction::Unknown)
+        )
+    }
+
+    // channel names, topics and descriptions are public to everyone on the server
+    fn encrypt(&mut self, _key: Vec<u8>) {}
+
+    fn decrypt(&mut self, _key: Vec<u8>) {}
+}
+
+/// Every channel a client may read, sent after login
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ChannelListPayload {
+    pub channels: Vec<ChannelInfo>,
+}
+
+impl ChannelListPayload {
+    pub fn new(channels: Vec<ChannelInfo>) -> ChannelListPayload {
+        ChannelListPayload { channels }
+    }
+}
+
+impl Payload for ChannelListPayload {
+    fn to_bytes(&self) -> Vec<u8> {
+        rmp_serde::to_vec(self).unwrap()
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> ChannelListPayload {
+        rmp_serde::from_slice(&bytes).unwrap_or_default()
+    }
+
+    fn encrypt(&mut self, _key: Vec<u8>) {}
+
+    fn decrypt(&mut self, _key: Vec<u8>) {}
+}
+
+/// Who is online in a channel, the client leaves `users` empty
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct WhoPayload {
+    pub channel: String,
+    pub users: Vec<String>,
+}
+
+impl WhoPayload {
+    pub fn new(channel: String) -> WhoPayload {
+        WhoPayload { channel, users: Vec::new() }
+    }
+}
+
+impl Payload for WhoPayload {
+    fn to_bytes(&self) -> Vec<u8> {
+        rmp_serde::to_vec(self).unwrap()
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> WhoPayload {
+        rmp_serde::from_slice(&bytes).unwrap_or_default()
+    }
+
+    // the members of a channel see each other's names anyway
+    fn encrypt(&mut self, _key: Vec<u8>) {}
+
+    fn decrypt(&mut self, _key: Vec<u8>) {}
+}
+
 pub fn get_default_channels() -> Vec<Channel> {
     let mut channels = Vec::new();
 
@@ -116,3 +828,63 @@ pub fn get_default_channels() -> Vec<Channel> {
 
     channels
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // message ids keep seconds since 2000 above the type and random bits
+    const EPOCH: u64 = 946684800;
+    const NOW: u64 = EPOCH + 1_000_000;
+
+    /// A message sent `age` seconds before NOW with a payload of `size` bytes
+    fn message(age: u64, size: usize) -> Message {
+        let mut message = Message::new(MessageType::Message, vec![0; size]);
+        message.id = ((NOW - age - EPOCH) << 16) | (1 << 14);
+        message
+    }
+
+    #[test]
+    fn keeps_everything_without_limits() {
+        let mut messages = vec![message(100_000, 10), message(5, 10)];
+        assert!(RetentionPolicy::default().is_empty());
+        assert_eq!(RetentionPolicy::default().prune(&mut messages, NOW), 0);
+        assert_eq!(messages.len(), 2);
+    }
+
+    #[test]
+    fn drops_messages_older_than_the_age_limit() {
+        let mut messages = vec![message(120, 1), message(60, 1), message(59, 1), message(0, 1)];
+        let policy = RetentionPolicy { max_age: Some(60), ..RetentionPolicy::default() };
+        assert_eq!(policy.prune(&mut messages, NOW), 1);
+        assert_eq!(messages.iter().map(|m| NOW - to_timestamp(m.id)).collect::<Vec<u64>>(), vec![60, 59, 0]);
+    }
+
+    #[test]
+    fn keeps_the_newest_messages_that_fit() {
+        let mut messages = vec![message(3, 40), message(2, 40), message(1, 30), message(0, 30)];
+        let newest: Vec<u64> = messages[2..].iter().map(|m| m.id).collect();
+        let policy = RetentionPolicy { max_size: Some(70), ..RetentionPolicy::default() };
+        assert_eq!(policy.prune(&mut messages, NOW), 2);
+        assert_eq!(messages.iter().map(|m| m.id).collect::<Vec<u64>>(), newest);
+
+        // a single message over the limit goes too
+        let mut messages = vec![message(0, 100)];
+        assert_eq!(policy.prune(&mut messages, NOW), 1);
+        assert!(messages.is_empty());
+    }
+
+    #[test]
+    fn applies_every_limit() {
+        let mut messages: Vec<Message> = (0..10).rev().map(|age| message(age * 10, 10)).collect();
+        let policy = RetentionPolicy {
+            max_messages: Some(6),
+            max_age: Some(75),
+            max_size: Some(50),
+        };
+        // age leaves 8, the count 6 and the size 5
+        assert_eq!(policy.prune(&mut messages, NOW), 5);
+        assert_eq!(messages.len(), 5);
+        assert_eq!(NOW - to_timestamp(messages[0].id), 40);
+    }
+}
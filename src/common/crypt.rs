@@ -1,11 +1,19 @@
 use crypto::aes;
 use crypto::aes::KeySize;
 use crypto::buffer::{ReadBuffer, RefReadBuffer, RefWriteBuffer, WriteBuffer};
+use crypto::digest::Digest;
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::pbkdf2::pbkdf2;
+use crypto::sha2::Sha256;
 use crypto::symmetriccipher::{Decryptor, Encryptor};
 use log::debug;
 use rand_core::RngCore;
 use x25519_dalek::{PublicKey, StaticSecret};
 
+// rounds of PBKDF2 when turning a passphrase into a key
+const KEY_ITERATIONS: u32 = 100_000;
+
 fn convert_vec_u8(v: Vec<u8>) -> [u8; 32] {
     /*let mut vec = [0u8; 32];
     for (i, byte) in v.iter().enumerate() {
@@ -104,4 +112,71 @@ pub fn decrypt_data(data: Vec<u8>, key: Vec<u8>) -> Vec<u8> {
         }
     }
     decrypted_data
-}
\ No newline at end of file
+}
+
+/// Derives a key for `encrypt_data` from a passphrase with PBKDF2-HMAC-SHA256
+///
+/// # Arguments
+///
+/// * `passphrase`: What the user typed
+/// * `salt`: Random bytes stored next to whatever the key protects, see `create_salt`
+///
+/// returns: Vec<u8>, a 32 byte key
+pub fn derive_key(passphrase: &str, salt: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::new(Sha256::new(), passphrase.as_bytes());
+    let mut key = vec![0u8; 32];
+    pbkdf2(&mut mac, salt, KEY_ITERATIONS, &mut key);
+    key
+}
+
+pub fn create_salt() -> Vec<u8> {
+    let mut salt = vec![0u8; 16];
+    rand_core::OsRng.fill_bytes(&mut salt);
+    salt
+}
+
+/// Signs data with HMAC-SHA256
+///
+/// # Examples
+///
+/// ```
+/// let signature = common::crypt::sign(b"hello", b"key");
+/// assert_eq!(signature.len(), 32);
+/// assert!(common::crypt::verify(b"hello", b"key", &signature));
+/// assert!(!common::crypt::verify(b"hello", b"other key", &signature));
+/// ```
+pub fn sign(data: &[u8], key: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::new(Sha256::new(), key);
+    mac.input(data);
+    mac.result().code().to_vec()
+}
+
+/// Checks a signature made by `sign`, in constant time
+pub fn verify(data: &[u8], key: &[u8], signature: &[u8]) -> bool {
+    crypto::util::fixed_time_eq(&sign(data, key), signature)
+}
+
+/// A short, readable digest of a public key, to check a server is the one seen before
+///
+/// # Examples
+///
+/// ```
+/// use common::crypt;
+///
+/// let key = crypt::serialize_public_key(crypt::create_public_key(crypt::create_private_key()));
+/// let fingerprint = crypt::fingerprint(&key);
+/// assert_eq!(fingerprint.len(), 39);
+/// assert_eq!(fingerprint, crypt::fingerprint(&key));
+/// assert_ne!(fingerprint, crypt::fingerprint(&[0u8; 32]));
+/// ```
+pub fn fingerprint(public_key: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.input(public_key);
+    let mut hash = [0u8; 32];
+    hasher.result(&mut hash);
+    hash[..16]
+        .chunks(2)
+        .map(|pair| format!("{:02x}{:02x}", pair[0], pair[1]))
+        .collect::<Vec<_>>()
+        .join(":")
+}
@@ -0,0 +1,154 @@
+use serde::{Deserialize, Serialize};
+
+use crate::attachment::AttachmentMeta;
+use crate::channel::channel_name;
+use crate::id::to_timestamp_string;
+use crate::message::{Message, MessagePayload, MessageType, Payload};
+
+/// One line of a JSON Lines export, a message with its text decrypted
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ExportedMessage {
+    pub id: u64,
+    // only for whoever reads the export, the id is what counts on import
+    #[serde(default)]
+    pub timestamp: String,
+    pub channel: String,
+    pub author: String,
+    pub text: String,
+    #[serde(default)]
+    pub reply_to: Option<u64>,
+    #[serde(default)]
+    pub thread: Option<u64>,
+    #[serde(default)]
+    pub attachments: Vec<AttachmentMeta>,
+    #[serde(default)]
+    pub bot: bool,
+    // a /me message
+    #[serde(default)]
+    pub action: bool,
+}
+
+impl ExportedMessage {
+    /// Takes a message whose payload is already decrypted
+    pub fn from_message(message: &Message) -> ExportedMessage {
+        let payload = MessagePayload::from_bytes(message.payload.clone());
+        ExportedMessage {
+            id: message.id,
+            timestamp: to_timestamp_string(message.id),
+            channel: channel_name(&payload.channel).to_string(),
+            author: payload.username,
+            text: String::from_utf8_lossy(&payload.message).to_string(),
+            reply_to: payload.reply_to,
+            thread: payload.thread,
+            attachments: payload.attachments,
+            bot: payload.bot,
+            action: payload.action,
+        }
+    }
+
+    /// Makes the message back, in `channel` whatever channel it was exported from
+    pub fn to_message(&self, channel: &str) -> Message {
+        let mut payload = MessagePayload::new(self.author.clone(), format!("#{}", channel_name(channel)), self.text.clone().into_bytes());
+        payload.reply_to = self.reply_to;
+        payload.thread = self.thread;
+        payload.attachments = self.attachments.clone();
+        payload.bot = self.bot;
+        payload.action = self.action;
+
+        Message::create_all(self.id, MessageType::Message, payload.to_bytes())
+    }
+}
+
+/// Writes decrypted messages as JSON Lines, one message per line
+///
+/// # Examples
+///
+/// ```
+/// use common::export::{from_json_lines, to_json_lines};
+/// use common::message::{Message, MessagePayload, MessageType, Payload};
+///
+/// let payload = MessagePayload::new("alice".to_string(), "#general".to_string(), b"hi".to_vec());
+/// let message = Message::new(MessageType::Message, payload.to_bytes());
+///
+/// let lines = to_json_lines(&[message.clone()]);
+/// let imported = from_json_lines(&lines, "general").unwrap();
+/// assert_eq!(imported[0].id, message.id);
+/// assert_eq!(MessagePayload::from_bytes(imported[0].payload.clone()).message, b"hi".to_vec());
+/// ```
+pub fn to_json_lines(messages: &[Message]) -> String {
+    messages
+        .iter()
+        .map(|message| serde_json::to_string(&ExportedMessage::from_message(message)).unwrap() + "\n")
+        .collect()
+}
+
+/// Reads a JSON Lines export back into messages for `channel`, skipping blank lines
+pub fn from_json_lines(text: &str, channel: &str) -> Result<Vec<Message>, String> {
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| match serde_json::from_str::<ExportedMessage>(line) {
+            Ok(exported) => Ok(exported.to_message(channel)),
+            Err(e) => Err(format!("Line {} is not an exported message: {}", i + 1, e)),
+        })
+        .collect()
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Writes decrypted messages as a standalone HTML page, for reading rather than importing
+pub fn to_html(channel: &str, messages: &[Message]) -> String {
+    let title = escape_html(&format!("#{}", channel_name(channel)));
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n\
+<style>body{{font-family:sans-serif}} .time{{color:#888}} .author{{font-weight:bold}} .context,.attachment{{color:#666;margin-left:2em}}</style>\n\
+</head>\n<body>\n<h1>{}</h1>\n",
+        title, title
+    );
+
+    for message in messages {
+        let exported = ExportedMessage::from_message(message);
+        html.push_str(&format!(
+            "<div class=\"message\" id=\"{}\"><span class=\"time\">[{}]</span> {}<span class=\"author\">{}</span>{} {}",
+            exported.id,
+            exported.timestamp,
+            if exported.action { "* " } else { "" },
+            escape_html(&exported.author) + if exported.bot { " [bot]" } else { "" },
+            if exported.action { "" } else { ":" },
+            escape_html(&exported.text)
+        ));
+        match (exported.thread, exported.reply_to) {
+            (Some(thread), _) => html.push_str(&format!("<div class=\"context\">in the thread of <a href=\"#{}\">{}</a></div>", thread, thread)),
+            (None, Some(reply_to)) => html.push_str(&format!("<div class=\"context\">replying to <a href=\"#{}\">{}</a></div>", reply_to, reply_to)),
+            (None, None) => {}
+        }
+        for attachment in exported.attachments.iter() {
+            html.push_str(&format!(
+                "<div class=\"attachment\">attachment {}: {} ({} bytes, sha256 {})</div>",
+                attachment.id,
+                escape_html(&attachment.name),
+                attachment.size,
+                attachment.hash
+            ));
+        }
+        html.push_str("</div>\n");
+    }
+
+    html.push_str("</body>\n</html>\n");
+    html
+}
+
+/// Exports decrypted messages in the format named by `format`, `jsonl` or `html`,
+/// returns the file extension and the contents
+pub fn export(channel: &str, format: &str, messages: &[Message]) -> Result<(&'static str, String), String> {
+    match format {
+        "jsonl" | "json" => Ok(("jsonl", to_json_lines(messages))),
+        "html" => Ok(("html", to_html(channel, messages))),
+        _ => Err(format!("Unknown export format {}, use jsonl or html", format)),
+    }
+}
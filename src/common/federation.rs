@@ -0,0 +1,180 @@
+use serde::{Deserialize, Serialize};
+
+use crate::crypt;
+use crate::message::{Message, MessagePayload, MessageType, Payload};
+
+/// Splits an address like `alice@example` into the user and the server, local users have no server
+///
+/// # Examples
+///
+/// ```
+/// use common::federation::split_address;
+///
+/// assert_eq!(split_address("alice@example"), ("alice", Some("example")));
+/// assert_eq!(split_address("bob"), ("bob", None));
+/// ```
+pub fn split_address(address: &str) -> (&str, Option<&str>) {
+    match address.rsplit_once('@') {
+        Some((user, server)) => (user, Some(server)),
+        None => (address, None),
+    }
+}
+
+/// Addresses a user as `user@server`, unless they already are
+///
+/// # Examples
+///
+/// ```
+/// use common::federation::qualify;
+///
+/// assert_eq!(qualify("alice", "example"), "alice@example");
+/// assert_eq!(qualify("bob@elsewhere", "example"), "bob@elsewhere");
+/// ```
+pub fn qualify(username: &str, server: &str) -> String {
+    match split_address(username) {
+        (_, Some(_)) => username.to_string(),
+        (user, None) => format!("{}@{}", user, server),
+    }
+}
+
+/// What a server signs to prove it holds the private key behind its public key. The signature
+/// uses the key both ends derive from their identity keys, which only the two of them can make,
+/// over the nonce the other end just picked, so a captured proof is no good for another link
+fn proof_data(name: &str, nonce: &[u8]) -> Vec<u8> {
+    let mut data = format!("yuttari federation {} ", name).into_bytes();
+    data.extend_from_slice(nonce);
+    data
+}
+
+/// The key a link encrypts and signs its relays with, it only holds for the link the two nonces opened
+pub fn link_key(shared_key: &[u8], dialer_nonce: &[u8], acceptor_nonce: &[u8]) -> Vec<u8> {
+    crypt::sign(&[dialer_nonce, acceptor_nonce].concat(), shared_key)
+}
+
+/// Opens a link between two servers. The connecting server sends it after the usual
+/// ConnectionReceive, the other answers with its own proving it holds its key, then the
+/// connecting server sends it again with its proof
+///
+/// # Examples
+///
+/// ```
+/// use common::federation::PeerHelloPayload;
+///
+/// let shared_key = vec![7u8; 32];
+/// let mut hello = PeerHelloPayload::new("a".to_string(), vec![]);
+/// let mut reply = PeerHelloPayload::new("b".to_string(), vec![]);
+/// reply.prove(&hello.nonce, &shared_key);
+/// assert!(reply.verify(&hello.nonce, &shared_key));
+///
+/// // a proof only answers the nonce it was made for
+/// hello.prove(&reply.nonce, &shared_key);
+/// assert!(hello.verify(&reply.nonce, &shared_key));
+/// assert!(!hello.verify(&PeerHelloPayload::new("b".to_string(), vec![]).nonce, &shared_key));
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct PeerHelloPayload {
+    pub name: String,
+    pub public_key: Vec<u8>,
+    // random bytes the other end has to sign
+    #[serde(default)]
+    pub nonce: Vec<u8>,
+    // over the other end's nonce, empty in the connecting server's first hello
+    pub proof: Vec<u8>,
+}
+
+impl PeerHelloPayload {
+    pub fn new(name: String, public_key: Vec<u8>) -> PeerHelloPayload {
+        PeerHelloPayload {
+            name,
+            public_key,
+            nonce: crypt::create_salt(),
+            proof: Vec::new(),
+        }
+    }
+
+    /// Signs the nonce the other end sent
+    pub fn prove(&mut self, nonce: &[u8], shared_key: &[u8]) {
+        self.proof = crypt::sign(&proof_data(&self.name, nonce), shared_key);
+    }
+
+    /// Checks the proof is over the nonce this end sent
+    pub fn verify(&self, nonce: &[u8], shared_key: &[u8]) -> bool {
+        crypt::verify(&proof_data(&self.name, nonce), shared_key, &self.proof)
+    }
+}
+
+impl Payload for PeerHelloPayload {
+    fn to_bytes(&self) -> Vec<u8> {
+        rmp_serde::to_vec(self).unwrap()
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> PeerHelloPayload {
+        rmp_serde::from_slice(&bytes).unwrap_or_default()
+    }
+
+    // the proof only means something to whoever holds the shared key
+    fn encrypt(&mut self, _key: Vec<u8>) {}
+
+    fn decrypt(&mut self, _key: Vec<u8>) {}
+}
+
+/// A channel message relayed between servers
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PeerMessagePayload {
+    // the server the message was posted on
+    pub origin: String,
+    // the servers that sent the message on, it is never sent back to any of them
+    pub seen: Vec<String>,
+    pub message: Message,
+    // counts up on each link, a relay that doesn't come after the last one is a replay
+    #[serde(default)]
+    pub sequence: u64,
+    // over everything above once encrypted, so a link can't be fed messages without the key
+    pub signature: Vec<u8>,
+}
+
+impl PeerMessagePayload {
+    pub fn new(origin: String, message: Message) -> PeerMessagePayload {
+        PeerMessagePayload {
+            seen: vec![origin.clone()],
+            origin,
+            message,
+            sequence: 0,
+            signature: Vec::new(),
+        }
+    }
+
+    fn signed_bytes(&self) -> Vec<u8> {
+        rmp_serde::to_vec(&(&self.origin, &self.seen, &self.message, self.sequence)).unwrap()
+    }
+
+    /// Checks the signature, call it before decrypting
+    pub fn verify(&self, key: &[u8]) -> bool {
+        crypt::verify(&self.signed_bytes(), key, &self.signature)
+    }
+}
+
+impl Payload for PeerMessagePayload {
+    fn to_bytes(&self) -> Vec<u8> {
+        rmp_serde::to_vec(self).unwrap()
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> PeerMessagePayload {
+        // an unsigned empty payload, it fails verification
+        rmp_serde::from_slice(&bytes)
+            .unwrap_or_else(|_| PeerMessagePayload::new(String::new(), Message::create_all(0, MessageType::Unknown, Vec::new())))
+    }
+
+    fn encrypt(&mut self, key: Vec<u8>) {
+        let mut payload = MessagePayload::from_bytes(self.message.payload.clone());
+        payload.encrypt(key.clone());
+        self.message.payload = payload.to_bytes();
+        self.signature = crypt::sign(&self.signed_bytes(), &key);
+    }
+
+    fn decrypt(&mut self, key: Vec<u8>) {
+        let mut payload = MessagePayload::from_bytes(self.message.payload.clone());
+        payload.decrypt(key);
+        self.message.payload = payload.to_bytes();
+    }
+}
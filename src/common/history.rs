@@ -0,0 +1,256 @@
+use std::fs::OpenOptions;
+use std::io::Write;
+
+use serde::{Deserialize, Serialize};
+
+use crate::channel::channel_name;
+use crate::crypt;
+use crate::message::{Message, MessagePayload, Payload};
+use crate::search::{tokenize, SearchResult};
+
+// signed with the derived key, tells a wrong passphrase apart from a damaged file
+const CHECK: &[u8] = b"yuttari history";
+// signed with the derived key to make the key the records are signed with
+const RECORD_KEY: &[u8] = b"yuttari history records";
+
+/// The first document of a history file, every record follows it
+#[derive(Serialize, Deserialize, Default)]
+struct HistoryHeader {
+    salt: Vec<u8>,
+    check: Vec<u8>,
+}
+
+/// A batch of messages appended to a history file, so a new message doesn't mean
+/// writing the whole file again
+#[derive(Serialize, Deserialize, Default)]
+struct Record {
+    // the messages as msgpack, encrypted with the derived key
+    messages: Vec<u8>,
+    // HMAC-SHA256 of the encrypted messages, checked before they are decrypted
+    mac: Vec<u8>,
+}
+
+/// Splits a file into the bson documents it is made of, a document cut off by a crash
+/// while it was appended is left out
+fn documents(data: &[u8]) -> Vec<&[u8]> {
+    let mut documents = Vec::new();
+    let mut rest = data;
+    while rest.len() >= 4 {
+        // every document starts with its length, the length included
+        let length = i32::from_le_bytes([rest[0], rest[1], rest[2], rest[3]]);
+        let length = match usize::try_from(length) {
+            Ok(length) if length >= 5 && length <= rest.len() => length,
+            _ => break,
+        };
+        let (document, next) = rest.split_at(length);
+        documents.push(document);
+        rest = next;
+    }
+    documents
+}
+
+/// Messages a client has seen, decrypted from the connection and kept on disk encrypted
+/// with a key derived from the user's passphrase. There is one per user and server
+pub struct LocalHistory {
+    path: String,
+    salt: Vec<u8>,
+    key: Vec<u8>,
+    messages: Vec<Message>,
+}
+
+impl LocalHistory {
+    /// Opens the history of a user on a server, or starts an empty one
+    ///
+    /// # Arguments
+    ///
+    /// * `username`: Who the history belongs to
+    /// * `server`: The address of the server, each server keeps its own history
+    /// * `passphrase`: What the key is derived from
+    ///
+    /// returns: Result<LocalHistory, String>, an error if the passphrase is wrong or the file is damaged
+    pub fn open(username: &str, server: &str, passphrase: &str) -> Result<LocalHistory, String> {
+        let directory = format!("{}/history", crate::get_config_dir());
+        std::fs::create_dir_all(&directory).map_err(|e| e.to_string())?;
+        // ':' can't be part of a file name on windows
+        let path = format!("{}/{}@{}.bson", directory, username, server).replace(':', "_");
+
+        let data = match std::fs::read(&path) {
+            Ok(data) => data,
+            Err(_) => {
+                let salt = crypt::create_salt();
+                let key = crypt::derive_key(passphrase, &salt);
+                let history = LocalHistory { path, salt, key, messages: Vec::new() };
+                history.save();
+                return Ok(history);
+            }
+        };
+
+        let damaged = |e: String| format!("The history at {} is damaged: {}", path, e);
+        let documents = documents(&data);
+        let header = documents.first().ok_or_else(|| damaged("it is empty".to_string()))?;
+        let header: HistoryHeader = bson::from_slice(header).map_err(|e| damaged(e.to_string()))?;
+
+        let key = crypt::derive_key(passphrase, &header.salt);
+        if !crypt::verify(CHECK, &key, &header.check) {
+            return Err("Wrong passphrase for the local history".to_string());
+        }
+
+        let mut history = LocalHistory {
+            path: path.clone(),
+            salt: header.salt,
+            key,
+            messages: Vec::new(),
+        };
+        let record_key = history.record_key();
+        for document in &documents[1..] {
+            let record: Record = bson::from_slice(document).map_err(|e| damaged(e.to_string()))?;
+            if !crypt::verify(&record.messages, &record_key, &record.mac) {
+                return Err(damaged("a record was changed".to_string()));
+            }
+            let messages: Vec<Message> = rmp_serde::from_slice(&crypt::decrypt_data(record.messages, history.key.clone()))
+                .map_err(|e| damaged(e.to_string()))?;
+            for message in messages {
+                history.insert(message);
+            }
+        }
+
+        // later records would be appended after what is left of the cut off one
+        if documents.iter().map(|document| document.len()).sum::<usize>() < data.len() {
+            log::warn!("The history at {} ends in a record that was cut off, dropping it", path);
+            history.save();
+        }
+        Ok(history)
+    }
+
+    fn record_key(&self) -> Vec<u8> {
+        crypt::sign(RECORD_KEY, &self.key)
+    }
+
+    fn record(&self, messages: &[Message]) -> Result<Vec<u8>, String> {
+        let messages = crypt::encrypt_data(rmp_serde::to_vec(messages).map_err(|e| e.to_string())?, self.key.clone());
+        let mac = crypt::sign(&messages, &self.record_key());
+        bson::to_vec(&Record { messages, mac }).map_err(|e| e.to_string())
+    }
+
+    /// Writes the whole history again, as a header and a single record
+    fn save(&self) {
+        let header = HistoryHeader {
+            salt: self.salt.clone(),
+            check: crypt::sign(CHECK, &self.key),
+        };
+
+        let mut data = match bson::to_vec(&header) {
+            Ok(data) => data,
+            Err(e) => {
+                log::error!("Error serializing the local history: {}", e);
+                return;
+            }
+        };
+        if !self.messages.is_empty() {
+            match self.record(&self.messages) {
+                Ok(record) => data.extend(record),
+                Err(e) => {
+                    log::error!("Error serializing the local history: {}", e);
+                    return;
+                }
+            }
+        }
+
+        if let Err(e) = std::fs::write(&self.path, data) {
+            log::error!("Error saving the local history: {}", e);
+        }
+    }
+
+    /// Appends a record of new messages to the file
+    fn append(&self, messages: &[Message]) {
+        let record = match self.record(messages) {
+            Ok(record) => record,
+            Err(e) => {
+                log::error!("Error serializing the local history: {}", e);
+                return;
+            }
+        };
+
+        let written = OpenOptions::new().append(true).open(&self.path).and_then(|mut file| file.write_all(&record));
+        if let Err(e) = written {
+            log::error!("Error saving the local history: {}", e);
+        }
+    }
+
+    fn insert(&mut self, message: Message) -> bool {
+        match self.messages.binary_search_by_key(&message.id, |m| m.id) {
+            Ok(_) => false,
+            Err(i) => {
+                self.messages.insert(i, message);
+                true
+            }
+        }
+    }
+
+    /// Keeps a decrypted message, returns false if it was already kept
+    pub fn add(&mut self, message: Message) -> bool {
+        let added = self.insert(message.clone());
+        if added {
+            self.append(&[message]);
+        }
+        added
+    }
+
+    /// Keeps a batch of decrypted messages, like a history replay, and appends them at once
+    pub fn add_all(&mut self, messages: Vec<Message>) {
+        let added: Vec<Message> = messages.into_iter().filter(|message| self.insert(message.clone())).collect();
+        if !added.is_empty() {
+            self.append(&added);
+        }
+    }
+
+    pub fn contains(&self, id: u64) -> bool {
+        self.messages.binary_search_by_key(&id, |m| m.id).is_ok()
+    }
+
+    /// Every kept message, oldest first
+    pub fn messages(&self) -> &[Message] {
+        &self.messages
+    }
+
+    /// The last `count` messages of a channel, oldest first
+    pub fn recent(&self, channel: &str, count: usize) -> Vec<Message> {
+        let mut messages: Vec<Message> = self
+            .messages
+            .iter()
+            .rev()
+            .filter(|m| channel_name(&MessagePayload::from_bytes(m.payload.clone()).channel) == channel_name(channel))
+            .take(count)
+            .cloned()
+            .collect();
+        messages.reverse();
+        messages
+    }
+
+    /// Finds the kept messages that contain every word of the query, newest first
+    pub fn search(&self, query: &str) -> Vec<SearchResult> {
+        let words: Vec<String> = tokenize(query).into_iter().map(|(_, _, word)| word).collect();
+        if words.is_empty() {
+            return Vec::new();
+        }
+
+        self.messages
+            .iter()
+            .rev()
+            .filter_map(|message| {
+                let payload = MessagePayload::from_bytes(message.payload.clone());
+                let tokens = tokenize(&String::from_utf8_lossy(&payload.message));
+                if !words.iter().all(|word| tokens.iter().any(|(_, _, token)| token == word)) {
+                    return None;
+                }
+
+                let highlights = tokens
+                    .into_iter()
+                    .filter(|(_, _, token)| words.contains(token))
+                    .map(|(start, end, _)| (start, end))
+                    .collect();
+                Some(SearchResult { message: message.clone(), highlights })
+            })
+            .collect()
+    }
+}
@@ -7,6 +7,7 @@ pub enum IdType {
     Unknown,
     Message,
     User,
+    Attachment,
 }
 
 pub fn create_id(id_type: IdType) -> u64 {
@@ -31,6 +32,7 @@ pub fn create_id(id_type: IdType) -> u64 {
         IdType::Unknown => 0,
         IdType::Message => 1,
         IdType::User => 2,
+        IdType::Attachment => 3,
     };
 
     let mut id_type = format!("{:b}", id_type);
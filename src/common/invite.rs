@@ -0,0 +1,147 @@
+use serde::{Deserialize, Serialize};
+
+use crate::crypt;
+use crate::message::Payload;
+use rand_core::RngCore;
+
+/// Makes an invite code out of 128 random bits, written in base 36 to keep it short enough to type
+///
+/// returns: String, lowercase letters and digits
+///
+/// # Examples
+///
+/// ```
+/// let code = common::invite::create_code();
+/// assert!(code.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit()));
+/// assert_ne!(code, common::invite::create_code());
+/// ```
+pub fn create_code() -> String {
+    let mut bytes = [0u8; 16];
+    rand_core::OsRng.fill_bytes(&mut bytes);
+    let mut value = u128::from_le_bytes(bytes);
+    let mut code = Vec::new();
+    while value > 0 {
+        code.push(std::char::from_digit((value % 36) as u32, 36).unwrap());
+        value /= 36;
+    }
+    code.iter().rev().collect()
+}
+
+/// Asks for an invite to a channel, or invites someone directly when `username` is set.
+/// The server answers with the same payload, `code` and `expires` filled in
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct InvitePayload {
+    pub channel: String,
+    // who to add to the channel, empty for an invite code
+    pub username: String,
+    // utf-8, kept as bytes so it can be encrypted like a message
+    pub code: Vec<u8>,
+    // how many times the code may be used, None for no limit
+    pub max_uses: Option<u32>,
+    // how long the code is valid in seconds when asking, when it expires in unix seconds when answered
+    pub valid_for: Option<u64>,
+    pub expires: Option<u64>,
+}
+
+impl InvitePayload {
+    pub fn new(channel: String) -> InvitePayload {
+        InvitePayload {
+            channel,
+            ..InvitePayload::default()
+        }
+    }
+
+    /// Parses the arguments of an `/invite` command: `<channel> [user]` invites someone,
+    /// `<channel> code [uses] [seconds]` asks for a code
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use common::invite::InvitePayload;
+    ///
+    /// let invite = InvitePayload::from_command("#secret code 5 3600").unwrap();
+    /// assert_eq!(invite.max_uses, Some(5));
+    /// assert_eq!(invite.valid_for, Some(3600));
+    /// assert_eq!(InvitePayload::from_command("#secret bob").unwrap().username, "bob");
+    /// assert!(InvitePayload::from_command("#secret code 4294967296").is_err());
+    /// ```
+    pub fn from_command(command: &str) -> Result<InvitePayload, String> {
+        let args: Vec<&str> = command.split_whitespace().collect();
+        let usage = "Usage: /invite <channel> <user> or /invite <channel> code [uses] [seconds]";
+
+        let mut invite = match args.first() {
+            Some(channel) => InvitePayload::new(channel.to_string()),
+            None => return Err(usage.to_string()),
+        };
+
+        match args.as_slice() {
+            [_, "code", rest @ ..] if rest.len() <= 2 => {
+                let numbers: Vec<u64> = match rest.iter().map(|n| n.parse()).collect() {
+                    Ok(numbers) => numbers,
+                    Err(_) => return Err(usage.to_string()),
+                };
+                invite.max_uses = match numbers.first().map(|&uses| u32::try_from(uses)) {
+                    Some(Ok(uses)) => Some(uses),
+                    Some(Err(_)) => return Err(format!("A code can be used at most {} times", u32::MAX)),
+                    None => None,
+                };
+                invite.valid_for = numbers.get(1).copied();
+            }
+            [_, username] => invite.username = username.to_string(),
+            _ => return Err(usage.to_string()),
+        }
+
+        Ok(invite)
+    }
+}
+
+impl Payload for InvitePayload {
+    fn to_bytes(&self) -> Vec<u8> {
+        rmp_serde::to_vec(self).unwrap()
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> InvitePayload {
+        rmp_serde::from_slice(&bytes).unwrap_or_default()
+    }
+
+    // anyone who overhears a code can join with it
+    fn encrypt(&mut self, key: Vec<u8>) {
+        self.code = crypt::encrypt_data(self.code.clone(), key);
+    }
+
+    fn decrypt(&mut self, key: Vec<u8>) {
+        self.code = crypt::decrypt_data(self.code.clone(), key);
+    }
+}
+
+/// Joins a channel with an invite code or leaves a private channel.
+/// The server answers with the channel that was joined or left
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct JoinPayload {
+    pub channel: String,
+    pub code: Vec<u8>,
+}
+
+impl JoinPayload {
+    pub fn new(channel: String, code: Vec<u8>) -> JoinPayload {
+        JoinPayload { channel, code }
+    }
+}
+
+impl Payload for JoinPayload {
+    fn to_bytes(&self) -> Vec<u8> {
+        rmp_serde::to_vec(self).unwrap()
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> JoinPayload {
+        rmp_serde::from_slice(&bytes).unwrap_or_default()
+    }
+
+    fn encrypt(&mut self, key: Vec<u8>) {
+        self.code = crypt::encrypt_data(self.code.clone(), key);
+    }
+
+    fn decrypt(&mut self, key: Vec<u8>) {
+        self.code = crypt::decrypt_data(self.code.clone(), key);
+    }
+}
@@ -1,7 +1,15 @@
+pub mod attachment;
 pub mod channel;
 pub mod crypt;
+pub mod export;
+pub mod federation;
+pub mod history;
 pub mod id;
+pub mod invite;
 pub mod message;
+pub mod permission;
+pub mod profile;
+pub mod search;
 pub mod user;
 
 #[cfg(target_os = "windows")]
@@ -11,7 +19,7 @@ pub fn get_config_dir() -> String {
 
     // create the directory if it doesn't exist
     if !std::path::Path::new(&path).exists() {
-        std::fs::create_dir(&path).unwrap();
+        std::fs::create_dir_all(&path).unwrap();
     }
 
     path
@@ -24,7 +32,7 @@ pub fn get_config_dir() -> String {
 
     // create the directory if it doesn't exist
     if !std::path::Path::new(&path).exists() {
-        std::fs::create_dir(&path).unwrap();
+        std::fs::create_dir_all(&path).unwrap();
     }
 
     path
@@ -37,7 +45,7 @@ pub fn get_config_dir() -> String {
 
     // create the directory if it doesn't exist
     if !std::path::Path::new(&path).exists() {
-        std::fs::create_dir(&path).unwrap();
+        std::fs::create_dir_all(&path).unwrap();
     }
 
     path
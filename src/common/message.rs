@@ -1,6 +1,10 @@
+use std::collections::HashMap;
+
 use crate::id::create_id;
 use crate::id::IdType;
 use serde::{Deserialize, Serialize};
+use unicode_segmentation::UnicodeSegmentation;
+use crate::attachment::AttachmentMeta;
 use crate::crypt;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -9,7 +13,27 @@ pub enum MessageType {
     Message,           // both client -> server and server -> client, used to send a message
     ConnectionReceive, // server -> client, used to send a connection message
     Login, // client -> server, used to login
-    Connect, // client -> self, used to connect to the server
+    Connect, // client -> self, used to connect to the server and to report how that went
+    History, // both client -> server and server -> client, used to fetch a channel timeline or a thread
+    Reaction, // both client -> server and server -> client, used to add or remove an emoji on a message
+    Typing, // both client -> server and server -> client, ephemeral, used to show who is typing
+    ReadMarker, // client -> server to report what was read, server -> client at login
+    AttachmentOffer, // client -> server, used to start or resume an upload
+    AttachmentStatus, // server -> client, used to report how much of an upload arrived
+    AttachmentChunk, // client -> server while uploading, server -> client while downloading
+    AttachmentRequest, // client -> server, used to download an attachment
+    Command, // client -> server, used to run an admin command, answered with a server message
+    ChannelList, // server -> client, the channels a client may read, sent after login
+    ChannelUpdate, // client -> server to change a channel, server -> client once it changed
+    Invite, // client -> server to invite someone or get a code, server -> client with the invite
+    Join, // client -> server to join with a code, server -> client once a channel was joined
+    Part, // client -> server to leave a private channel, server -> client once a channel was left
+    Search, // client -> server with a query, server -> client with a page of results
+    PeerHello, // server <-> server to open a federation link
+    PeerMessage, // server <-> server, a channel message relayed over a federation link
+    Who, // client -> server to ask who is online in a channel, server -> client with the names
+    Nick, // client -> server to change username, server -> client once it changed
+    MessageStatus, // server -> client, the id a sent message was stored under or why it was not
 }
 
 impl PartialEq for MessageType {
@@ -20,6 +44,26 @@ impl PartialEq for MessageType {
             (MessageType::ConnectionReceive, MessageType::ConnectionReceive) => true,
             (MessageType::Login, MessageType::Login) => true,
             (MessageType::Connect, MessageType::Connect) => true,
+            (MessageType::History, MessageType::History) => true,
+            (MessageType::Reaction, MessageType::Reaction) => true,
+            (MessageType::Typing, MessageType::Typing) => true,
+            (MessageType::ReadMarker, MessageType::ReadMarker) => true,
+            (MessageType::AttachmentOffer, MessageType::AttachmentOffer) => true,
+            (MessageType::AttachmentStatus, MessageType::AttachmentStatus) => true,
+            (MessageType::AttachmentChunk, MessageType::AttachmentChunk) => true,
+            (MessageType::AttachmentRequest, MessageType::AttachmentRequest) => true,
+            (MessageType::Command, MessageType::Command) => true,
+            (MessageType::ChannelList, MessageType::ChannelList) => true,
+            (MessageType::ChannelUpdate, MessageType::ChannelUpdate) => true,
+            (MessageType::Invite, MessageType::Invite) => true,
+            (MessageType::Join, MessageType::Join) => true,
+            (MessageType::Part, MessageType::Part) => true,
+            (MessageType::Search, MessageType::Search) => true,
+            (MessageType::PeerHello, MessageType::PeerHello) => true,
+            (MessageType::PeerMessage, MessageType::PeerMessage) => true,
+            (MessageType::Who, MessageType::Who) => true,
+            (MessageType::Nick, MessageType::Nick) => true,
+            (MessageType::MessageStatus, MessageType::MessageStatus) => true,
             _ => false,
         }
     }
@@ -83,6 +127,21 @@ pub struct MessagePayload {
     pub username: String,
     pub channel: String,
     pub message: Vec<u8>,
+    // the id of the message this one quotes, shown inline in the channel timeline
+    #[serde(default)]
+    pub reply_to: Option<u64>,
+    // the id of the message that started the thread this message belongs to
+    #[serde(default)]
+    pub thread: Option<u64>,
+    // uploaded files, the message text is usually empty or a caption
+    #[serde(default)]
+    pub attachments: Vec<AttachmentMeta>,
+    // posted through the HTTP API or a webhook rather than by a connected user, only the server sets it
+    #[serde(default)]
+    pub bot: bool,
+    // a /me message, shown as something the author does
+    #[serde(default)]
+    pub action: bool,
 }
 
 impl MessagePayload {
@@ -91,8 +150,25 @@ impl MessagePayload {
             username,
             channel,
             message,
+            reply_to: None,
+            thread: None,
+            attachments: Vec::new(),
+            bot: false,
+            action: false,
         }
     }
+
+    pub fn set_reply_to(&mut self, reply_to: Option<u64>) {
+        self.reply_to = reply_to;
+    }
+
+    pub fn set_thread(&mut self, thread: Option<u64>) {
+        self.thread = thread;
+    }
+
+    pub fn add_attachment(&mut self, attachment: AttachmentMeta) {
+        self.attachments.push(attachment);
+    }
 }
 
 impl Payload for MessagePayload {
@@ -113,4 +189,294 @@ impl Payload for MessagePayload {
     fn decrypt(&mut self, key: Vec<u8>) {
         self.message = crypt::decrypt_data(self.message.clone(), key);
     }
-}
\ No newline at end of file
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HistoryPayload {
+    pub channel: String,
+    // when set, the payload holds the replies of this thread instead of the channel timeline
+    pub thread: Option<u64>,
+    pub messages: Vec<Message>,
+    // the aggregated reactions of the messages above, keyed by message id
+    #[serde(default)]
+    pub reactions: HashMap<u64, Reactions>,
+}
+
+impl HistoryPayload {
+    pub fn new(channel: String, thread: Option<u64>, messages: Vec<Message>) -> HistoryPayload {
+        HistoryPayload {
+            channel,
+            thread,
+            messages,
+            reactions: HashMap::new(),
+        }
+    }
+
+    pub fn set_reactions(&mut self, reactions: HashMap<u64, Reactions>) {
+        self.reactions = reactions;
+    }
+}
+
+impl Payload for HistoryPayload {
+    fn to_bytes(&self) -> Vec<u8> {
+        rmp_serde::to_vec(self).unwrap()
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> HistoryPayload {
+        rmp_serde::from_slice(&bytes).unwrap_or_else(
+            |_| HistoryPayload::new("unknown".to_string(), None, Vec::new())
+        )
+    }
+
+    fn encrypt(&mut self, key: Vec<u8>) {
+        for message in self.messages.iter_mut() {
+            let mut payload = MessagePayload::from_bytes(message.payload.clone());
+            payload.encrypt(key.clone());
+            message.payload = payload.to_bytes();
+        }
+    }
+
+    fn decrypt(&mut self, key: Vec<u8>) {
+        for message in self.messages.iter_mut() {
+            let mut payload = MessagePayload::from_bytes(message.payload.clone());
+            payload.decrypt(key.clone());
+            message.payload = payload.to_bytes();
+        }
+    }
+}
+
+/// The reactions on a single message, each emoji mapped to the users that reacted with it
+pub type Reactions = HashMap<String, Vec<String>>;
+
+/// The most bytes an emoji may take, enough for the longest joined emoji
+const MAX_EMOJI: usize = 32;
+
+/// Checks that a reaction is a single short character, as users see it
+///
+/// # Examples
+///
+/// ```
+/// use common::message::is_valid_emoji;
+///
+/// assert!(is_valid_emoji("👍"));
+/// assert!(is_valid_emoji("👍🏽"));
+/// assert!(!is_valid_emoji("+1"));
+/// assert!(!is_valid_emoji(" "));
+/// assert!(!is_valid_emoji(""));
+/// ```
+pub fn is_valid_emoji(emoji: &str) -> bool {
+    emoji.len() <= MAX_EMOJI
+        && emoji.graphemes(true).count() == 1
+        && !emoji.chars().any(|c| c.is_whitespace() || c.is_control())
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReactionPayload {
+    pub username: String,
+    pub channel: String,
+    pub message_id: u64,
+    pub emoji: String,
+    // false removes the reaction
+    pub add: bool,
+}
+
+impl ReactionPayload {
+    pub fn new(username: String, channel: String, message_id: u64, emoji: String, add: bool) -> ReactionPayload {
+        ReactionPayload {
+            username,
+            channel,
+            message_id,
+            emoji,
+            add,
+        }
+    }
+
+    /// Applies the reaction to the reactions of its message
+    ///
+    /// # Arguments
+    ///
+    /// * `reactions`: The reactions of the message this reaction targets
+    ///
+    /// returns: None
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use common::message::{ReactionPayload, Reactions};
+    ///
+    /// let mut reactions = Reactions::new();
+    /// ReactionPayload::new("alice".to_string(), "general".to_string(), 1, "👍".to_string(), true).apply(&mut reactions);
+    /// assert_eq!(reactions["👍"], vec!["alice".to_string()]);
+    /// ReactionPayload::new("alice".to_string(), "general".to_string(), 1, "👍".to_string(), false).apply(&mut reactions);
+    /// assert!(reactions.is_empty());
+    /// ```
+    pub fn apply(&self, reactions: &mut Reactions) {
+        let users = reactions.entry(self.emoji.clone()).or_default();
+        if self.add {
+            if !users.contains(&self.username) {
+                users.push(self.username.clone());
+            }
+        } else {
+            users.retain(|user| user != &self.username);
+        }
+
+        // drop emoji nobody uses anymore
+        reactions.retain(|_, users| !users.is_empty());
+    }
+}
+
+impl Payload for ReactionPayload {
+    fn to_bytes(&self) -> Vec<u8> {
+        rmp_serde::to_vec(self).unwrap()
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> ReactionPayload {
+        rmp_serde::from_slice(&bytes).unwrap_or_else(
+            |_| ReactionPayload::new("unknown".to_string(), "unknown".to_string(), 0, String::new(), false)
+        )
+    }
+
+    // like the username and channel, reactions carry no message content
+    fn encrypt(&mut self, _key: Vec<u8>) {}
+
+    fn decrypt(&mut self, _key: Vec<u8>) {}
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TypingPayload {
+    pub username: String,
+    pub channel: String,
+}
+
+impl TypingPayload {
+    pub fn new(username: String, channel: String) -> TypingPayload {
+        TypingPayload { username, channel }
+    }
+}
+
+impl Payload for TypingPayload {
+    fn to_bytes(&self) -> Vec<u8> {
+        rmp_serde::to_vec(self).unwrap()
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> TypingPayload {
+        rmp_serde::from_slice(&bytes).unwrap_or_else(
+            |_| TypingPayload::new("unknown".to_string(), "unknown".to_string())
+        )
+    }
+
+    fn encrypt(&mut self, _key: Vec<u8>) {}
+
+    fn decrypt(&mut self, _key: Vec<u8>) {}
+}
+
+/// What became of a message a client sent, only that client is told
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MessageStatusPayload {
+    pub channel: String,
+    // the id the client gave the message
+    pub sent_id: u64,
+    // the id the server stored it under, None if it was not posted
+    pub id: Option<u64>,
+    // why it was not posted, None when a plugin answered it as a command
+    pub error: Option<String>,
+}
+
+impl MessageStatusPayload {
+    pub fn new(channel: String, sent_id: u64, id: u64) -> MessageStatusPayload {
+        MessageStatusPayload {
+            channel,
+            sent_id,
+            id: Some(id),
+            error: None,
+        }
+    }
+
+    pub fn refused(channel: String, sent_id: u64, error: Option<String>) -> MessageStatusPayload {
+        MessageStatusPayload {
+            channel,
+            sent_id,
+            id: None,
+            error,
+        }
+    }
+}
+
+impl Payload for MessageStatusPayload {
+    fn to_bytes(&self) -> Vec<u8> {
+        rmp_serde::to_vec(self).unwrap()
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> MessageStatusPayload {
+        rmp_serde::from_slice(&bytes).unwrap_or_else(
+            |_| MessageStatusPayload::refused("unknown".to_string(), 0, Some("invalid status".to_string()))
+        )
+    }
+
+    // the error is a server notice and the rest are ids, nothing is encrypted
+    fn encrypt(&mut self, _key: Vec<u8>) {}
+
+    fn decrypt(&mut self, _key: Vec<u8>) {}
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReadMarkerPayload {
+    pub username: String,
+    // the id of the last message read, keyed by channel
+    pub markers: HashMap<String, u64>,
+}
+
+impl ReadMarkerPayload {
+    pub fn new(username: String, markers: HashMap<String, u64>) -> ReadMarkerPayload {
+        ReadMarkerPayload { username, markers }
+    }
+}
+
+impl Payload for ReadMarkerPayload {
+    fn to_bytes(&self) -> Vec<u8> {
+        rmp_serde::to_vec(self).unwrap()
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> ReadMarkerPayload {
+        rmp_serde::from_slice(&bytes).unwrap_or_else(
+            |_| ReadMarkerPayload::new("unknown".to_string(), HashMap::new())
+        )
+    }
+
+    fn encrypt(&mut self, _key: Vec<u8>) {}
+
+    fn decrypt(&mut self, _key: Vec<u8>) {}
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CommandPayload {
+    // the channel the command was typed in, the answer goes there
+    pub channel: String,
+    pub command: Vec<u8>,
+}
+
+impl CommandPayload {
+    pub fn new(channel: String, command: Vec<u8>) -> CommandPayload {
+        CommandPayload { channel, command }
+    }
+}
+
+impl Payload for CommandPayload {
+    fn to_bytes(&self) -> Vec<u8> {
+        rmp_serde::to_vec(self).unwrap()
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> CommandPayload {
+        rmp_serde::from_slice(&bytes).unwrap_or_else(
+            |_| CommandPayload::new("unknown".to_string(), Vec::new())
+        )
+    }
+
+    fn encrypt(&mut self, key: Vec<u8>) {
+        self.command = crypt::encrypt_data(self.command.clone(), key);
+    }
+
+    fn decrypt(&mut self, key: Vec<u8>) {
+        self.command = crypt::decrypt_data(self.command.clone(), key);
+    }
+}
@@ -0,0 +1,168 @@
+use serde::{Deserialize, Serialize};
+
+/// Server wide roles, later variants outrank earlier ones
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum Role {
+    #[default]
+    Member,
+    Moderator,
+    Admin,
+    Owner,
+}
+
+impl Role {
+    /// Parses a role name as typed in an admin command
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use common::permission::Role;
+    ///
+    /// assert_eq!(Role::from_name("Admin"), Some(Role::Admin));
+    /// assert_eq!(Role::from_name("king"), None);
+    /// ```
+    pub fn from_name(name: &str) -> Option<Role> {
+        match name.to_lowercase().as_str() {
+            "member" => Some(Role::Member),
+            "moderator" | "mod" => Some(Role::Moderator),
+            "admin" => Some(Role::Admin),
+            "owner" => Some(Role::Owner),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Role::Member => "member",
+            Role::Moderator => "moderator",
+            Role::Admin => "admin",
+            Role::Owner => "owner",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Permission {
+    Read,   // see the channel, its history and its messages
+    Post,   // send messages and reactions to the channel
+    Manage, // change the channel and its permissions
+}
+
+impl Permission {
+    pub fn from_name(name: &str) -> Option<Permission> {
+        match name.to_lowercase().as_str() {
+            "read" => Some(Permission::Read),
+            "post" => Some(Permission::Post),
+            "manage" => Some(Permission::Manage),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Permission::Read => "read",
+            Permission::Post => "post",
+            Permission::Manage => "manage",
+        }
+    }
+}
+
+/// Allows or denies a permission to a role in a single channel
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PermissionOverride {
+    pub role: Role,
+    pub permission: Permission,
+    pub allow: bool,
+}
+
+/// What a role may do in a channel without overrides, everyone reads and posts and moderators manage
+pub fn default_allowed(role: Role, permission: Permission) -> bool {
+    match permission {
+        Permission::Read | Permission::Post => true,
+        Permission::Manage => role >= Role::Moderator,
+    }
+}
+
+/// Checks a role against a channel's overrides, the owner can always do everything
+///
+/// # Arguments
+///
+/// * `role`: The role of the user
+/// * `permission`: What the user wants to do
+/// * `overrides`: The overrides of the channel
+///
+/// returns: bool
+///
+/// # Examples
+///
+/// ```
+/// use common::permission::{is_allowed, Permission, PermissionOverride, Role};
+///
+/// let overrides = vec![PermissionOverride { role: Role::Member, permission: Permission::Post, allow: false }];
+/// assert!(!is_allowed(Role::Member, Permission::Post, &overrides));
+/// assert!(is_allowed(Role::Moderator, Permission::Post, &overrides));
+/// ```
+pub fn is_allowed(role: Role, permission: Permission, overrides: &[PermissionOverride]) -> bool {
+    if role == Role::Owner {
+        return true;
+    }
+
+    match overrides.iter().find(|o| o.role == role && o.permission == permission) {
+        Some(o) => o.allow,
+        None => default_allowed(role, permission),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn deny(role: Role, permission: Permission) -> PermissionOverride {
+        PermissionOverride { role, permission, allow: false }
+    }
+
+    #[test]
+    fn roles_outrank_the_ones_before_them() {
+        assert!(Role::Member < Role::Moderator);
+        assert!(Role::Moderator < Role::Admin);
+        assert!(Role::Admin < Role::Owner);
+        assert_eq!(Role::default(), Role::Member);
+        for role in [Role::Member, Role::Moderator, Role::Admin, Role::Owner] {
+            assert_eq!(Role::from_name(role.name()), Some(role));
+        }
+    }
+
+    #[test]
+    fn only_moderators_manage_by_default() {
+        for role in [Role::Member, Role::Moderator, Role::Admin] {
+            assert!(is_allowed(role, Permission::Read, &[]));
+            assert!(is_allowed(role, Permission::Post, &[]));
+        }
+        assert!(!is_allowed(Role::Member, Permission::Manage, &[]));
+        assert!(is_allowed(Role::Moderator, Permission::Manage, &[]));
+        assert!(is_allowed(Role::Admin, Permission::Manage, &[]));
+    }
+
+    #[test]
+    fn overrides_apply_to_their_role_alone() {
+        let overrides = vec![
+            deny(Role::Member, Permission::Read),
+            PermissionOverride { role: Role::Member, permission: Permission::Manage, allow: true },
+            deny(Role::Admin, Permission::Post),
+        ];
+
+        assert!(!is_allowed(Role::Member, Permission::Read, &overrides));
+        assert!(is_allowed(Role::Member, Permission::Manage, &overrides));
+        assert!(is_allowed(Role::Member, Permission::Post, &overrides));
+        // an override for one role says nothing about the roles above it
+        assert!(is_allowed(Role::Moderator, Permission::Read, &overrides));
+        assert!(!is_allowed(Role::Admin, Permission::Post, &overrides));
+    }
+
+    #[test]
+    fn the_owner_can_not_be_locked_out() {
+        let overrides = vec![deny(Role::Owner, Permission::Read), deny(Role::Owner, Permission::Manage)];
+        assert!(is_allowed(Role::Owner, Permission::Read, &overrides));
+        assert!(is_allowed(Role::Owner, Permission::Manage, &overrides));
+    }
+}
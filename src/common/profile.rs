@@ -0,0 +1,319 @@
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+
+use serde::{Deserialize, Serialize};
+
+use crate::crypt;
+use crate::message::Payload;
+use crate::user::User;
+
+/// Where the profiles are kept in the config directory
+const PROFILES_FILE: &str = "profiles.yut";
+/// The single user older GUI versions saved
+const LEGACY_GUI_FILE: &str = "config.yut";
+
+/// A server a profile connects to
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SavedServer {
+    pub name: String,
+    // a host name or IP address with a port
+    pub address: String,
+    // the fingerprint of the server's key, pinned on the first login
+    #[serde(default)]
+    pub fingerprint: Option<String>,
+}
+
+/// Checks an address looks like `host:port` before trying to connect to it
+///
+/// # Examples
+///
+/// ```
+/// use common::profile::check_address;
+///
+/// assert!(check_address("chat.example.com:4000").is_ok());
+/// assert!(check_address("127.0.0.1:4000").is_ok());
+/// assert!(check_address("[::1]:4000").is_ok());
+/// assert!(check_address("chat.example.com").is_err());
+/// assert!(check_address("chat.example.com:chat").is_err());
+/// assert!(check_address(":4000").is_err());
+/// ```
+pub fn check_address(address: &str) -> Result<(), String> {
+    let (host, port) = address.rsplit_once(':').ok_or("The address needs a port, like host:4000")?;
+    if host.is_empty() || host.contains(char::is_whitespace) {
+        return Err("The address needs a host name or IP address before the port".to_string());
+    }
+    match port.parse::<u16>() {
+        Ok(port) if port > 0 => Ok(()),
+        _ => Err(format!("{} is not a port", port)),
+    }
+}
+
+/// Between the GUI and its connect task: the server to connect to and the key it should have,
+/// then the key it had or why connecting failed
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ConnectPayload {
+    pub address: String,
+    pub fingerprint: Option<String>,
+    pub error: Option<String>,
+}
+
+impl ConnectPayload {
+    pub fn new(address: String, fingerprint: Option<String>) -> ConnectPayload {
+        ConnectPayload {
+            address,
+            fingerprint,
+            error: None,
+        }
+    }
+}
+
+impl Payload for ConnectPayload {
+    fn to_bytes(&self) -> Vec<u8> {
+        rmp_serde::to_vec(self).unwrap()
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> ConnectPayload {
+        rmp_serde::from_slice(&bytes).unwrap_or_default()
+    }
+
+    // never leaves the client
+    fn encrypt(&mut self, _key: Vec<u8>) {}
+
+    fn decrypt(&mut self, _key: Vec<u8>) {}
+}
+
+/// One identity: the user servers know, the key it logs in with and the servers it uses
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Profile {
+    // what the profile is called locally, the username can change without it
+    pub name: String,
+    pub user: User,
+    // the x25519 secret that goes with the user's public key
+    pub secret_key: Vec<u8>,
+    // the most recent first
+    #[serde(default)]
+    pub servers: Vec<SavedServer>,
+    // whether the GUI connects to the last server on start
+    #[serde(default = "auto_connect_default")]
+    pub auto_connect: bool,
+}
+
+fn auto_connect_default() -> bool {
+    true
+}
+
+impl Profile {
+    /// Makes a profile for a new user with a fresh key pair
+    pub fn new(name: &str, username: &str) -> Profile {
+        Profile::from_user(name, User::new(username.to_string()))
+    }
+
+    /// Makes a profile for a user saved before there were profiles, it gets a new key pair
+    pub fn from_user(name: &str, mut user: User) -> Profile {
+        let secret = crypt::create_private_key();
+        user.set_public_key(crypt::serialize_public_key(crypt::create_public_key(secret.clone())));
+        Profile {
+            name: name.to_string(),
+            user,
+            secret_key: crypt::serialize_private_key(secret),
+            servers: Vec::new(),
+            auto_connect: true,
+        }
+    }
+
+    /// Remembers a server, moving it to the front if it was known. A known server keeps its pinned key
+    pub fn remember_server(&mut self, name: &str, address: &str) {
+        let fingerprint = self.get_server(address).and_then(|server| server.fingerprint.clone());
+        self.servers.retain(|server| server.address != address);
+        self.servers.insert(0, SavedServer {
+            name: name.to_string(),
+            address: address.to_string(),
+            fingerprint,
+        });
+    }
+
+    /// Bookmarks a server without making it the most recent, or renames the bookmark
+    pub fn save_server(&mut self, name: &str, address: &str) {
+        match self.servers.iter_mut().find(|server| server.address == address) {
+            Some(server) => server.name = name.to_string(),
+            None => self.servers.push(SavedServer {
+                name: name.to_string(),
+                address: address.to_string(),
+                fingerprint: None,
+            }),
+        }
+    }
+
+    pub fn get_server(&self, address: &str) -> Option<&SavedServer> {
+        self.servers.iter().find(|server| server.address == address)
+    }
+
+    /// The server connected to last
+    pub fn last_server(&self) -> Option<&SavedServer> {
+        self.servers.first()
+    }
+
+    /// Pins the key of a server, later logins fail if it changes
+    pub fn pin_server(&mut self, address: &str, fingerprint: &str) {
+        if let Some(server) = self.servers.iter_mut().find(|server| server.address == address) {
+            server.fingerprint = Some(fingerprint.to_string());
+        }
+    }
+
+    pub fn forget_server(&mut self, address: &str) {
+        self.servers.retain(|server| server.address != address);
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        rmp_serde::to_vec(self).unwrap()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Profile, String> {
+        rmp_serde::from_slice(bytes).map_err(|e| format!("Not a profile: {}", e))
+    }
+}
+
+/// Every profile on this machine, shared by the CLI and the GUI
+///
+/// # Examples
+///
+/// ```
+/// use common::profile::{Profile, ProfileStore};
+///
+/// let mut store = ProfileStore::default();
+/// store.add(Profile::new("work", "alice")).unwrap();
+/// store.add(Profile::new("home", "ally")).unwrap();
+/// assert!(store.add(Profile::new("work", "bob")).is_err());
+///
+/// // the first profile is the default until another one is picked
+/// assert_eq!(store.default_profile().unwrap().name, "work");
+/// store.set_default("home").unwrap();
+/// assert_eq!(store.default_profile().unwrap().user.username, "ally");
+///
+/// store.rename("home", "alison").unwrap();
+/// assert_eq!(store.get("home").unwrap().user.username, "alison");
+///
+/// store.remove("home").unwrap();
+/// assert_eq!(store.default_profile().unwrap().name, "work");
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ProfileStore {
+    profiles: Vec<Profile>,
+    // the profile used when none is asked for
+    #[serde(default)]
+    default: Option<String>,
+}
+
+/// Writes a file only its owner can read, for files that hold secret keys
+pub fn write_private(path: &str, data: &[u8]) -> io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    let mut file = options.open(path)?;
+    // the mode only applies to new files, older ones may still be readable by others
+    #[cfg(unix)]
+    file.set_permissions(std::os::unix::fs::PermissionsExt::from_mode(0o600))?;
+    file.write_all(data)
+}
+
+impl ProfileStore {
+    fn path(file: &str) -> String {
+        format!("{}/{}", crate::get_config_dir(), file)
+    }
+
+    /// Reads the profiles, taking in the user of an older GUI the first time. A profiles file
+    /// that can't be read is an error, an empty store would be saved over it and lose every key
+    pub fn load() -> Result<ProfileStore, String> {
+        let path = ProfileStore::path(PROFILES_FILE);
+        match std::fs::read(&path) {
+            Ok(data) => return rmp_serde::from_slice(&data).map_err(|e| format!("{} is damaged: {}", path, e)),
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(format!("Could not read {}: {}", path, e)),
+            Err(_) => {}
+        }
+
+        let mut store = ProfileStore::default();
+        if let Ok(data) = std::fs::read(ProfileStore::path(LEGACY_GUI_FILE)) {
+            if let Ok(user) = rmp_serde::from_slice::<User>(&data) {
+                let name = user.username.clone();
+                // a fresh store has no names to clash with
+                store.add(Profile::from_user(&name, user)).unwrap();
+                store.save();
+            }
+        }
+        Ok(store)
+    }
+
+    pub fn save(&self) {
+        if let Err(e) = write_private(&ProfileStore::path(PROFILES_FILE), &rmp_serde::to_vec(self).unwrap()) {
+            log::error!("Error saving the profiles: {}", e);
+        }
+    }
+
+    pub fn profiles(&self) -> &[Profile] {
+        &self.profiles
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Profile> {
+        self.profiles.iter().find(|profile| profile.name == name)
+    }
+
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut Profile> {
+        self.profiles.iter_mut().find(|profile| profile.name == name)
+    }
+
+    /// The profile picked as the default, or the first one
+    pub fn default_profile(&self) -> Option<&Profile> {
+        self.default.as_deref().and_then(|name| self.get(name)).or(self.profiles.first())
+    }
+
+    pub fn set_default(&mut self, name: &str) -> Result<(), String> {
+        if self.get(name).is_none() {
+            return Err(format!("There is no profile {}", name));
+        }
+        self.default = Some(name.to_string());
+        Ok(())
+    }
+
+    pub fn add(&mut self, profile: Profile) -> Result<(), String> {
+        if profile.name.trim().is_empty() {
+            return Err("A profile needs a name".to_string());
+        }
+        if self.get(&profile.name).is_some() {
+            return Err(format!("There already is a profile {}", profile.name));
+        }
+        self.profiles.push(profile);
+        Ok(())
+    }
+
+    /// Gives the user of a profile a new username, servers see it on the next login
+    pub fn rename(&mut self, name: &str, username: &str) -> Result<(), String> {
+        let profile = self.get_mut(name).ok_or(format!("There is no profile {}", name))?;
+        profile.user.change_username(username.to_string());
+        Ok(())
+    }
+
+    pub fn remove(&mut self, name: &str) -> Result<Profile, String> {
+        let i = self.profiles.iter().position(|profile| profile.name == name).ok_or(format!("There is no profile {}", name))?;
+        if self.default.as_deref() == Some(name) {
+            self.default = None;
+        }
+        Ok(self.profiles.remove(i))
+    }
+
+    /// Writes a profile to a file, its secret key included, so it can be imported elsewhere
+    pub fn export(&self, name: &str, path: &str) -> Result<(), String> {
+        let profile = self.get(name).ok_or(format!("There is no profile {}", name))?;
+        write_private(path, &profile.to_bytes()).map_err(|e| format!("Could not write {}: {}", path, e))
+    }
+
+    /// Adds a profile from an exported file, returns its name
+    pub fn import(&mut self, path: &str) -> Result<String, String> {
+        let data = std::fs::read(path).map_err(|e| format!("Could not read {}: {}", path, e))?;
+        let profile = Profile::from_bytes(&data)?;
+        let name = profile.name.clone();
+        self.add(profile)?;
+        Ok(name)
+    }
+}
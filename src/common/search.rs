@@ -0,0 +1,166 @@
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+
+use crate::channel::channel_name;
+use crate::crypt;
+use crate::message::{Message, MessagePayload, Payload};
+
+/// How many results fit on a page unless the client asks for another size
+pub const RESULTS_PER_PAGE: usize = 20;
+
+/// Splits text into lowercase words, each with where it starts and ends in the text, in bytes
+///
+/// # Arguments
+///
+/// * `text`: The text to split
+///
+/// returns: Vec<(usize, usize, String)>, the start, end and lowercase word
+///
+/// # Examples
+///
+/// ```
+/// let words = common::search::tokenize("Hello, World!");
+/// assert_eq!(words, vec![(0, 5, "hello".to_string()), (7, 12, "world".to_string())]);
+/// ```
+pub fn tokenize(text: &str) -> Vec<(usize, usize, String)> {
+    let mut words = Vec::new();
+    let mut start = None;
+
+    for (i, c) in text.char_indices() {
+        match (c.is_alphanumeric(), start) {
+            (true, None) => start = Some(i),
+            (false, Some(s)) => {
+                words.push((s, i, text[s..i].to_lowercase()));
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    if let Some(s) = start {
+        words.push((s, text.len(), text[s..].to_lowercase()));
+    }
+
+    words
+}
+
+/// A message that matched, with where the matching words are in its text
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SearchResult {
+    pub message: Message,
+    // start and end of each match in the message text, in bytes
+    pub highlights: Vec<(usize, usize)>,
+}
+
+/// A search request, answered with the same payload and a page of results, newest first
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct SearchPayload {
+    // the words to look for, every one has to match
+    pub query: Vec<u8>,
+    pub channel: Option<String>,
+    pub author: Option<String>,
+    // unix seconds
+    pub after: Option<u64>,
+    pub before: Option<u64>,
+    pub page: usize,
+    pub per_page: usize,
+    // how many messages matched over all pages
+    pub total: usize,
+    pub results: Vec<SearchResult>,
+}
+
+impl SearchPayload {
+    pub fn new(query: String) -> SearchPayload {
+        SearchPayload {
+            query: query.into_bytes(),
+            per_page: RESULTS_PER_PAGE,
+            ..SearchPayload::default()
+        }
+    }
+
+    /// Parses the arguments of a `/search` command. Words are searched for, and
+    /// `in:#channel`, `from:user`, `after:2024-01-31`, `before:2024-02-28` and `page:2` filter
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use common::search::SearchPayload;
+    ///
+    /// let search = SearchPayload::from_command("release notes in:#dev from:alice page:2").unwrap();
+    /// assert_eq!(search.query, b"release notes".to_vec());
+    /// assert_eq!(search.channel, Some("dev".to_string()));
+    /// assert_eq!(search.author, Some("alice".to_string()));
+    /// assert_eq!(search.page, 1);
+    /// assert!(SearchPayload::from_command("after:yesterday").is_err());
+    /// ```
+    pub fn from_command(command: &str) -> Result<SearchPayload, String> {
+        let mut search = SearchPayload::new(String::new());
+        let mut words = Vec::new();
+
+        for word in command.split_whitespace() {
+            match word.split_once(':') {
+                Some(("in", channel)) => search.channel = Some(channel_name(channel).to_string()),
+                Some(("from", author)) => search.author = Some(author.to_string()),
+                Some(("after", date)) => search.after = Some(parse_date(date)?),
+                // before a date means before the day starts
+                Some(("before", date)) => search.before = Some(parse_date(date)?),
+                Some(("page", page)) => match page.parse::<usize>() {
+                    Ok(page) if page > 0 => search.page = page - 1,
+                    _ => return Err(format!("{} is not a page", page)),
+                },
+                _ => words.push(word),
+            }
+        }
+
+        if words.is_empty() {
+            return Err("Usage: /search <words> [in:#channel] [from:user] [after:yyyy-mm-dd] [before:yyyy-mm-dd] [page:n]".to_string());
+        }
+
+        search.query = words.join(" ").into_bytes();
+        Ok(search)
+    }
+
+    pub fn get_query(&self) -> String {
+        String::from_utf8_lossy(&self.query).to_string()
+    }
+
+    /// How many pages the results span
+    pub fn pages(&self) -> usize {
+        self.total.div_ceil(self.per_page.max(1))
+    }
+}
+
+fn parse_date(date: &str) -> Result<u64, String> {
+    match NaiveDate::parse_from_str(date, "%Y-%m-%d") {
+        Ok(date) => Ok(date.and_hms_opt(0, 0, 0).unwrap().timestamp().max(0) as u64),
+        Err(_) => Err(format!("{} is not a date like 2024-01-31", date)),
+    }
+}
+
+impl Payload for SearchPayload {
+    fn to_bytes(&self) -> Vec<u8> {
+        rmp_serde::to_vec(self).unwrap()
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> SearchPayload {
+        rmp_serde::from_slice(&bytes).unwrap_or_default()
+    }
+
+    // the query says as much as the messages it finds
+    fn encrypt(&mut self, key: Vec<u8>) {
+        self.query = crypt::encrypt_data(self.query.clone(), key.clone());
+        for result in self.results.iter_mut() {
+            let mut payload = MessagePayload::from_bytes(result.message.payload.clone());
+            payload.encrypt(key.clone());
+            result.message.payload = payload.to_bytes();
+        }
+    }
+
+    fn decrypt(&mut self, key: Vec<u8>) {
+        self.query = crypt::decrypt_data(self.query.clone(), key.clone());
+        for result in self.results.iter_mut() {
+            let mut payload = MessagePayload::from_bytes(result.message.payload.clone());
+            payload.decrypt(key.clone());
+            result.message.payload = payload.to_bytes();
+        }
+    }
+}
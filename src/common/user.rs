@@ -1,4 +1,5 @@
 use crate::id::{create_id, IdType};
+use crate::message::Payload;
 use serde::{Deserialize, Serialize};
 use x25519_dalek::{PublicKey, StaticSecret};
 
@@ -58,4 +59,31 @@ impl Clone for User {
     fn clone(&self) -> Self {
         User::create_all(self.id, self.username.clone(), self.public_key.clone())
     }
-}
\ No newline at end of file
+}
+
+/// Asks for a new username, the server answers with the one it accepted
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct NickPayload {
+    pub username: String,
+}
+
+impl NickPayload {
+    pub fn new(username: String) -> NickPayload {
+        NickPayload { username }
+    }
+}
+
+impl Payload for NickPayload {
+    fn to_bytes(&self) -> Vec<u8> {
+        rmp_serde::to_vec(self).unwrap()
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> NickPayload {
+        rmp_serde::from_slice(&bytes).unwrap_or_default()
+    }
+
+    // usernames are public
+    fn encrypt(&mut self, _key: Vec<u8>) {}
+
+    fn decrypt(&mut self, _key: Vec<u8>) {}
+}